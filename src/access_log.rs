@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use tracing::Instrument;
+
+// Query parameters whose values never reach the log
+const REDACTED_PARAMS: &[&str] = &["token", "access_token", "key", "access_key", "secret", "password"];
+
+// Taken from the request when a proxy set it, and echoed on the response
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Source of request ids when the client sent none
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How the access log writes each request, set by --access-log-format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Method, path, status, duration and client address on one line
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers such as Loki
+    Json,
+}
+
+impl std::str::FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(AccessLogFormat::Text),
+            "json" => Ok(AccessLogFormat::Json),
+            other => Err(format!("unknown access log format `{}`, expected `text` or `json`", other)),
+        }
+    }
+}
+
+/// Attached to a response by /save so the access log can report what was written
+#[derive(Debug, Clone)]
+pub struct SaveOutcome {
+    /// Path of the file written
+    pub file: String,
+    /// Its size on disk
+    pub bytes: u64,
+}
+
+// Replace the values of sensitive query parameters
+fn redact_query(query: &str) -> String {
+    query.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if REDACTED_PARAMS.iter().any(|p| p.eq_ignore_ascii_case(name)) => {
+                format!("{}=REDACTED", name)
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// The client's X-Request-Id if it is short and printable, else a new one
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers().get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:08x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// Middleware logging every request with its outcome and duration. The
+/// handler runs inside a span with the method, path and request id.
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let format = req.app_data::<web::Data<AccessLogFormat>>().map(|f| *f.get_ref()).unwrap_or_default();
+    let method = req.method().to_string();
+    let path = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), redact_query(query)),
+    };
+    let remote = req.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "-".to_string());
+    let request_id = request_id(&req);
+    let span = tracing::info_span!("request", method = %method, path = %path, request_id = %request_id);
+    let started = Instant::now();
+
+    let mut result = next.call(req).instrument(span.clone()).await;
+    if let (Ok(res), Ok(id)) = (&mut result, HeaderValue::from_str(&request_id)) {
+        res.headers_mut().insert(REQUEST_ID, id);
+    }
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (status, outcome) = match &result {
+        Ok(res) => (res.status().as_u16(), res.response().extensions().get::<SaveOutcome>().cloned()),
+        // Errors become 500 responses further out
+        Err(e) => (e.as_response_error().status_code().as_u16(), None),
+    };
+
+    let line = match format {
+        AccessLogFormat::Text => {
+            let mut line = format!("{} {} {} {:.1}ms {}", method, path, status, duration_ms, remote);
+            if let Some(outcome) = &outcome {
+                line.push_str(&format!(" saved={} bytes={}", outcome.file, outcome.bytes));
+            }
+            line
+        }
+        AccessLogFormat::Json => {
+            let mut line = serde_json::json!({
+                "method": method,
+                "path": path,
+                "status": status,
+                "duration_ms": (duration_ms * 10.0).round() / 10.0,
+                "remote": remote,
+            });
+            if let Some(outcome) = &outcome {
+                line["saved_file"] = outcome.file.clone().into();
+                line["saved_bytes"] = outcome.bytes.into();
+            }
+            line.to_string()
+        }
+    };
+    let _request = span.enter();
+    if status >= 400 {
+        tracing::warn!(target: "access", "{}", line);
+    } else {
+        tracing::info!(target: "access", "{}", line);
+    }
+    result
+}
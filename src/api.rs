@@ -0,0 +1,85 @@
+use actix_web::HttpResponse;
+use serde::Serialize;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+// Body of every JSON error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        ErrorResponse { error: error.into() }
+    }
+}
+
+// Body of a 422 from /save when too little audio is buffered
+#[derive(Serialize, ToSchema)]
+pub struct ShortBufferResponse {
+    pub error: String,
+    /// What the save would have held, in seconds
+    pub buffered_seconds: f64,
+    /// The least a save may hold, from --min-save-seconds; 0 refuses only an empty buffer
+    pub minimum_seconds: f64,
+}
+
+// Declares the optional bearer token used by the auth middleware
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "misteragent-voice", description = "Control API for the Misteragent voice recorder"),
+    paths(
+        crate::start_recording,
+        crate::pause_recording,
+        crate::stop_recording,
+        crate::toggle_recording,
+        crate::mark_recording,
+        crate::status,
+        crate::health,
+        crate::health_detail,
+        crate::save_audio,
+        crate::record_once,
+        crate::jobs::get_job,
+        crate::maintenance::maintenance_status,
+        crate::reload_wakeword,
+        crate::process::process_audio,
+        crate::stt::transcribe_audio,
+        crate::halt_server,
+        crate::recordings::list_recordings,
+        crate::recordings::download_recording,
+        crate::recordings::delete_recording,
+        crate::upload::upload_recording,
+        crate::live_stream::stream_audio,
+        crate::events::stream_events,
+        crate::events::wait_for_detection,
+        crate::config::get_config,
+        crate::config::patch_config,
+        openapi_json,
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = []), ()),
+)]
+pub struct ApiDoc;
+
+/// OpenAPI document describing every route
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI 3 document", content_type = "application/json")),
+)]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
@@ -0,0 +1,45 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+
+use crate::api::ErrorResponse;
+
+// Bearer token required on every route, or None for open access
+pub struct ApiToken(pub Option<String>);
+
+// Compare without short-circuiting so timing doesn't reveal the token prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_authorized(req: &ServiceRequest, expected: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.trim().as_bytes(), expected.as_bytes()))
+        .unwrap_or(false)
+}
+
+// Middleware rejecting requests without the configured bearer token
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let expected = req.app_data::<web::Data<ApiToken>>().and_then(|t| t.0.clone());
+    match expected {
+        Some(expected) if !is_authorized(&req, &expected) => {
+            tracing::warn!("Rejected unauthenticated request to {}", req.path());
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(ErrorResponse::new("Missing or invalid bearer token"));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        _ => next.call(req).await.map(ServiceResponse::map_into_left_body),
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::capture_audio::SaveWindow;
+use crate::{filename, save_in_background, AudioState, SaveResponse};
+
+/// Shortest --auto-save-interval, so a typo can't flood the output directory
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A duration parsed from the CLI: seconds, or a number with an s, m, h or d suffix, e.g. `90` or `10m`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval(pub Duration);
+
+impl std::str::FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim().to_ascii_lowercase();
+        let unit_start = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(unit_start);
+        let multiplier = match unit {
+            "" | "s" => 1.0,
+            "m" => 60.0,
+            "h" => 60.0 * 60.0,
+            "d" => 24.0 * 60.0 * 60.0,
+            _ => return Err(format!("invalid duration `{}`, expected a number with an optional s, m, h or d suffix", s)),
+        };
+        number.parse::<f64>()
+            .ok()
+            .and_then(|n| Duration::try_from_secs_f64(n * multiplier).ok())
+            .map(Interval)
+            .ok_or_else(|| format!("invalid duration `{}`, expected a number with an optional s, m, h or d suffix", s))
+    }
+}
+
+// Save the audio captured from absolute buffer position `since` on, and
+// return the save (None when nothing new arrived) with the position the next
+// one starts from
+pub(crate) async fn save_new_audio(state: &Arc<AudioState>, since: u64) -> std::io::Result<(Option<SaveResponse>, u64)> {
+    let (response, span) = save_in_background(state, SaveWindow::since(since), filename::Trigger::Auto).await?;
+    if span.start > since {
+        let config = state.input_config();
+        tracing::warn!(
+            "Auto-save is missing {:.1}s that left the buffer since the last one",
+            (span.start - since) as f64 / config.channels().max(1) as f64 / config.sample_rate().0 as f64
+        );
+    }
+    Ok((response, span.end))
+}
+
+/// Save whatever was captured every `interval` until shutdown, starting with
+/// the audio that arrives after the task starts
+pub fn spawn(state: Arc<AudioState>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+        // A save that overruns delays the next one rather than bunching them up
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut since = state.samples_written.load(Ordering::Relaxed);
+        loop {
+            ticks.tick().await;
+            if state.is_halting.load(Ordering::Relaxed) {
+                break;
+            }
+            match save_new_audio(&state, since).await {
+                Ok((Some(response), end)) => {
+                    tracing::info!("Auto-saved {:.1}s to {}", response.duration_seconds, response.path);
+                    since = end;
+                }
+                Ok((None, end)) => {
+                    tracing::debug!("Nothing captured since the last auto-save");
+                    since = end;
+                }
+                // Keep the position, so the next attempt picks up this audio too
+                Err(e) => tracing::error!("Auto-save failed: {}", e),
+            }
+        }
+    })
+}
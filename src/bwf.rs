@@ -0,0 +1,132 @@
+use chrono::Timelike;
+
+use crate::recordings::Sidecar;
+
+// Fixed part of a version 1 `bext` chunk (EBU Tech 3285), before the coding history
+const BEXT_LEN: usize = 602;
+
+const ORIGINATOR: &str = "misteragent-voice";
+
+// Broadcast Wave metadata added to saved WAV files, from the sidecar's fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct BroadcastInfo {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    // Wall-clock time of the first sample
+    pub origination: chrono::NaiveDateTime,
+    // First sample counted from midnight, at the file's sample rate
+    pub time_reference: u64,
+    pub artist: String,
+    pub comment: String,
+}
+
+impl BroadcastInfo {
+    pub fn from_sidecar(sidecar: &Sidecar) -> Self {
+        let started = sidecar.started_at.naive_local();
+        let since_midnight = started.time().num_seconds_from_midnight() as u64 * sidecar.sample_rate as u64
+            + started.time().nanosecond() as u64 * sidecar.sample_rate as u64 / 1_000_000_000;
+        let trigger = sidecar.trigger.clone();
+        BroadcastInfo {
+            description: format!("{} recorded on {} ({})", sidecar.recording, sidecar.device, trigger),
+            originator: ORIGINATOR.to_string(),
+            originator_reference: sidecar.recording.clone(),
+            origination: started,
+            time_reference: since_midnight,
+            artist: sidecar.device.clone(),
+            comment: trigger,
+        }
+    }
+
+    // The `bext` and LIST/INFO chunks, to be written just before the audio data
+    pub fn chunks(&self) -> Vec<u8> {
+        [self.bext(), self.info()].concat()
+    }
+
+    fn bext(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(BEXT_LEN);
+        push_fixed(&mut body, &self.description, 256);
+        push_fixed(&mut body, &self.originator, 32);
+        push_fixed(&mut body, &self.originator_reference, 32);
+        push_fixed(&mut body, &self.origination.format("%Y-%m-%d").to_string(), 10);
+        push_fixed(&mut body, &self.origination.format("%H:%M:%S").to_string(), 8);
+        body.extend_from_slice(&self.time_reference.to_le_bytes());
+        // Version, then the UMID and reserved bytes left empty
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.resize(BEXT_LEN, 0);
+        chunk(b"bext", &body)
+    }
+
+    fn info(&self) -> Vec<u8> {
+        let mut body = b"INFO".to_vec();
+        let date = self.origination.format("%Y-%m-%d").to_string();
+        for (id, text) in [(b"IART", &self.artist), (b"ICMT", &self.comment), (b"ICRD", &date)] {
+            let mut value = text.as_bytes().to_vec();
+            value.push(0);
+            body.extend_from_slice(&chunk(id, &value));
+        }
+        body.extend_from_slice(&chunk(b"ISFT", format!("{}\0", ORIGINATOR).as_bytes()));
+        chunk(b"LIST", &body)
+    }
+}
+
+// Text in a fixed-width field, truncated or padded with NULs
+fn push_fixed(out: &mut Vec<u8>, text: &str, width: usize) {
+    let bytes = text.as_bytes();
+    let len = bytes.len().min(width);
+    out.extend_from_slice(&bytes[..len]);
+    out.resize(out.len() + width - len, 0);
+}
+
+// A RIFF chunk: id, little-endian size, body and a pad byte when the size is odd
+fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 9);
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+#[cfg(test)]
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+// A top-level chunk as (id, offset of the chunk header, body)
+#[cfg(test)]
+pub type Chunk<'a> = ([u8; 4], usize, &'a [u8]);
+
+// The top-level chunks of a RIFF/WAVE file
+#[cfg(test)]
+pub fn chunks(bytes: &[u8]) -> std::io::Result<Vec<Chunk<'_>>> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+    let mut found = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id: [u8; 4] = bytes[offset..offset + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let end = (offset + 8).checked_add(size).filter(|&end| end <= bytes.len())
+            .ok_or_else(|| invalid("chunk runs past the end of the file"))?;
+        found.push((id, offset, &bytes[offset + 8..end]));
+        offset = end + size % 2;
+    }
+    Ok(found)
+}
+
+// Origination fields of a `bext` body: (description, date, time, time reference)
+#[cfg(test)]
+pub fn read_bext(body: &[u8]) -> Option<(String, String, String, u64)> {
+    if body.len() < BEXT_LEN {
+        return None;
+    }
+    let text = |range: std::ops::Range<usize>| {
+        String::from_utf8_lossy(&body[range]).trim_end_matches('\0').to_string()
+    };
+    let time_reference = u64::from_le_bytes(body[338..346].try_into().ok()?);
+    Some((text(0..256), text(320..330), text(330..338), time_reference))
+}
@@ -1,138 +1,1673 @@
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use std::time::Duration;
-use ringbuf::traits::*;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use log;
-use hound;
-use std::path::Path;
-
-use crate::AudioState;
-use crate::wakeword_listener::get_wakeword_listener;
-
-// Get the input config
-pub fn get_input_config() -> cpal::SupportedStreamConfig {
-    let host = cpal::default_host();
-    let device = host.default_input_device()
-        .expect("Failed to get default input device");
-    device.default_input_config()
-        .expect("Failed to get default input config")
-}
-
-// Audio capture function
-pub async fn capture_audio(state: Arc<AudioState>) {
-    log::info!("Initializing audio capture");
-    let host = cpal::default_host();
-    let device = host.default_input_device()
-        .expect("Failed to get default input device");
-    
-    log::info!("Using input device: {}", device.name().unwrap_or_default());
-    
-    let config = device.default_input_config()
-        .expect("Failed to get default input config");
-    
-    log::debug!("Audio config: {:?}", config);
-
-    // Initialize Porcupine
-    let porcupine = get_wakeword_listener();
-    let frame_length = porcupine.frame_length() as usize;
-    log::info!("Porcupine initialized with frame length: {}", frame_length);
-    
-    let state_clone = Arc::clone(&state);
-    let stream = device.build_input_stream(
-        &config.into(),
-        move |data: &[f32], _: &_| {
-            // Store in recording buffer if recording
-            if state_clone.is_recording.load(Ordering::Relaxed) {
-                let mut buffer = state_clone.buffer.lock();
-                for &sample in data {
-                    buffer.push_overwrite(sample);
-                }
-            }
-
-            // Convert samples to i16, logging any potential conversion issues
-            let i16_samples: Vec<i16> = data.iter()
-                .map(|&x| {
-                    let scaled = x * i16::MAX as f32;
-                    if scaled > i16::MAX as f32 || scaled < i16::MIN as f32 {
-                        log::warn!("Sample value {} out of i16 range after scaling", scaled);
-                    }
-                    scaled as i16
-                })
-                .collect();
-            
-            // Process with Porcupine in chunks of the required size
-            for chunk in i16_samples.chunks(frame_length) {
-                if chunk.len() == frame_length {
-                    match porcupine.process(chunk) {
-                        Ok(keyword_index) => {
-                            if keyword_index >= 0 {
-                                log::info!("Wakeword detected: {}", keyword_index);
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("Error processing audio: {:?}", err);
-                        }
-                    }
-                }
-            }
-        },
-
-        |err| log::error!("Error in audio stream: {}", err),
-        Some(Duration::from_secs(1)),
-
-
-    ).expect("Failed to build input stream");
-
-    log::info!("Starting audio stream");
-    stream.play().expect("Failed to start audio stream");
-
-    // Keep the stream alive until the server is halted
-    while !state.is_halting.load(Ordering::Relaxed) {
-        tokio::time::sleep(Duration::from_millis(100)).await;
-    }
-    log::info!("Shutting down capture audio thread");
-    
-    // Explicitly drop the stream before the function ends
-    drop(stream);
-}
-
-pub fn save_audio_to_file(
-    state: &AudioState,
-    filepath: &Path,
-    config: &cpal::SupportedStreamConfig
-) -> std::io::Result<usize> {
-    let spec = hound::WavSpec {
-        channels: config.channels() as u16,
-        sample_rate: config.sample_rate().0,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
-    };
-
-    log::debug!("Creating WAV with spec: {:?}", spec);
-
-    // Create output directory if it doesn't exist
-    if let Some(parent) = filepath.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
-
-    let mut writer = hound::WavWriter::create(filepath, spec)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-
-    // Convert ring buffer to vec and write to file
-    let buffer_contents: Vec<f32> = {
-        let buffer = state.buffer.lock();
-        buffer.iter().copied().collect()
-    };
-    
-    log::info!("Writing {} samples to WAV file", buffer_contents.len());
-    for &sample in &buffer_contents {
-        writer.write_sample(sample)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    }
-
-    writer.finalize()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    
-    Ok(buffer_contents.len())
-}
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use std::path::Path;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::AudioState;
+use crate::error::VoiceError;
+use crate::{encoding, events, filename, recordings, stt, wakeword_listener};
+use crate::level_trigger::{Capture, LevelEvent, LevelTrigger, SilenceStop};
+use crate::live_stream::encode_frame;
+use crate::encoding::{
+    downmix, downmix_into, encode_mp3, encode_opus, encode_raw, resample, resample_interleaved, write_g711_wav,
+    write_samples, write_wav, HighPass, OutputFormat, OutputOptions, G711_SAMPLE_RATE, OPUS_SAMPLE_RATE,
+};
+
+// Whole seconds averaged for /status's effective_sample_rate
+const THROUGHPUT_WINDOW_SECS: usize = 10;
+
+// Frames copied per lock while taking a snapshot; at 48 kHz stereo one chunk
+// is 128 KiB, copied in well under a millisecond
+const SNAPSHOT_CHUNK_FRAMES: usize = 16 * 1024;
+
+// First and longest waits between attempts to open the input device
+const DEVICE_RETRY_INITIAL: Duration = Duration::from_millis(250);
+const DEVICE_RETRY_MAX: Duration = Duration::from_secs(5);
+
+// Longest wait between attempts to bring capture back after it failed
+const CAPTURE_RETRY_MAX: Duration = Duration::from_secs(30);
+
+// Failed attempts in a row at rebuilding a broken stream before capture is
+// failed over to supervise_capture, which reopens the device
+const MAX_STREAM_RESTARTS: u32 = 5;
+
+// Longest the wakeword thread sleeps without being woken by the callback
+const WAKEWORD_IDLE: Duration = Duration::from_millis(100);
+
+/// Why the capture stream could not be started
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    /// The device could not be found or has no usable input config
+    #[error("failed to open input device: {0}")]
+    Device(String),
+    /// The device refused the stream config
+    #[error("failed to build input stream: {0}")]
+    BuildStream(#[from] cpal::BuildStreamError),
+    /// The stream was built but would not start
+    #[error("failed to start audio stream: {0}")]
+    Play(#[from] cpal::PlayStreamError),
+    /// The thread driving the source could not be spawned
+    #[error("failed to start the capture thread: {0}")]
+    Thread(#[from] std::io::Error),
+}
+
+/// Why a recording could not be saved, by the step that failed. Each keeps
+/// the underlying I/O error, so a full disk can be told from a permission
+/// problem or an encoder failure.
+#[derive(Debug, thiserror::Error)]
+pub enum SaveError {
+    /// The output directory could not be created
+    #[error("cannot create output directory {}: {source}", path.display())]
+    CreateDir {
+        /// The directory
+        path: std::path::PathBuf,
+        /// Why it could not be created
+        source: std::io::Error,
+    },
+    /// The file could not be created
+    #[error("failed to create the recording: {0}")]
+    CreateWriter(#[source] std::io::Error),
+    /// Writing the samples failed
+    #[error("failed to write samples: {0}")]
+    WriteSamples(#[source] std::io::Error),
+    /// Flushing or closing the file failed
+    #[error("failed to finalize the recording: {0}")]
+    Finalize(#[source] std::io::Error),
+}
+
+impl SaveError {
+    /// The I/O error behind the failure
+    pub fn io_error(&self) -> &std::io::Error {
+        match self {
+            SaveError::CreateDir { source, .. } => source,
+            SaveError::CreateWriter(e) | SaveError::WriteSamples(e) | SaveError::Finalize(e) => e,
+        }
+    }
+}
+
+// Keeps the kind of the underlying error, which is what handlers map to a status
+impl From<SaveError> for std::io::Error {
+    fn from(e: SaveError) -> Self {
+        std::io::Error::new(e.io_error().kind(), e)
+    }
+}
+
+/// Receives each block of captured audio, interleaved, on the source's thread
+pub type BlockCallback = Box<dyn FnMut(&[f32]) + Send>;
+/// Told when the source fails, so capture can rebuild its stream
+pub type ErrorCallback = Box<dyn FnMut(String) + Send>;
+
+/// Where captured audio comes from: an input device through cpal
+/// ([`CpalSource`]), or a generated signal for development and CI
+/// ([`crate::synthetic::SyntheticSource`]). Capture treats every source
+/// alike, so buffering, wakeword detection, gain and saves behave the same.
+pub trait AudioSource: Send {
+    /// Get ready to capture and report the format blocks will have. Called
+    /// again whenever capture is retried, e.g. after the device went away.
+    fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String>;
+
+    /// Name reported as the device, once opened
+    fn name(&self) -> String;
+
+    /// Deliver blocks in `config`'s format, each about its buffer size, until
+    /// the returned stream is dropped
+    fn start(
+        &mut self,
+        config: &cpal::StreamConfig,
+        on_block: BlockCallback,
+        on_error: ErrorCallback,
+    ) -> Result<SourceStream, CaptureError>;
+}
+
+/// A running source; dropping it stops delivery
+pub struct SourceStream {
+    _stream: Box<dyn std::any::Any>,
+}
+
+impl SourceStream {
+    /// Keep `stream` running until the returned value is dropped
+    pub fn new(stream: impl std::any::Any) -> Self {
+        SourceStream { _stream: Box::new(stream) }
+    }
+}
+
+/// The input device named by --input-device, or the host default
+pub struct CpalSource {
+    name: Option<String>,
+    device: Option<cpal::Device>,
+}
+
+impl CpalSource {
+    /// The device called `name`, or the default one for None
+    pub fn new(name: Option<String>) -> Self {
+        CpalSource { name, device: None }
+    }
+}
+
+impl AudioSource for CpalSource {
+    fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String> {
+        let (device, config) = open_device(self.name.as_deref())?;
+        self.device = Some(device);
+        Ok(config)
+    }
+
+    fn name(&self) -> String {
+        self.device.as_ref().and_then(|device| device.name().ok()).unwrap_or_default()
+    }
+
+    fn start(
+        &mut self,
+        config: &cpal::StreamConfig,
+        mut on_block: BlockCallback,
+        mut on_error: ErrorCallback,
+    ) -> Result<SourceStream, CaptureError> {
+        let device = self.device.as_ref().ok_or_else(|| CaptureError::Device("the device is not open".to_string()))?;
+        let stream = device.build_input_stream(
+            config,
+            move |data: &[f32], _: &_| on_block(data),
+            move |err| on_error(err.to_string()),
+            Some(Duration::from_secs(1)),
+        )?;
+        stream.play()?;
+        Ok(SourceStream::new(stream))
+    }
+}
+
+/// Another source mixed down to one channel by --force-mono. Blocks are
+/// averaged across channels before anything sees them, so the buffer holds
+/// a single sample per frame and Porcupine gets a true mix rather than the
+/// first channel.
+pub struct MonoSource {
+    inner: Box<dyn AudioSource>,
+    channels: u16,
+}
+
+impl MonoSource {
+    /// Mix `inner` down to mono
+    pub fn new(inner: Box<dyn AudioSource>) -> Self {
+        MonoSource { inner, channels: 1 }
+    }
+}
+
+impl AudioSource for MonoSource {
+    fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String> {
+        let config = self.inner.open()?;
+        self.channels = config.channels().max(1);
+        Ok(channel_config(&config))
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn start(
+        &mut self,
+        config: &cpal::StreamConfig,
+        mut on_block: BlockCallback,
+        on_error: ErrorCallback,
+    ) -> Result<SourceStream, CaptureError> {
+        // The device still delivers every channel
+        let device_config = cpal::StreamConfig { channels: self.channels, ..config.clone() };
+        let channels = self.channels;
+        if channels == 1 {
+            return self.inner.start(&device_config, on_block, on_error);
+        }
+        // Mixed into the same buffer every time, so the callback doesn't allocate
+        let mut mixed = Vec::with_capacity(match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames as usize,
+            cpal::BufferSize::Default => 0,
+        });
+        let mix = move |data: &[f32]| {
+            downmix_into(&mut mixed, data, channels);
+            on_block(&mixed);
+        };
+        self.inner.start(&device_config, Box::new(mix), on_error)
+    }
+}
+
+// Pick the device called `wanted`: an exact name first, then a
+// case-insensitive substring, so "Monitor of" style names can be shortened
+fn match_device_name(names: &[String], wanted: &str) -> Option<usize> {
+    names.iter().position(|name| name == wanted).or_else(|| {
+        let wanted = wanted.to_lowercase();
+        names.iter().position(|name| name.to_lowercase().contains(&wanted))
+    })
+}
+
+/// Open an input device by name, or the host default when `name` is None.
+///
+/// Monitor/loopback sources need nothing special here, but what cpal can see
+/// depends on the host. On Linux cpal talks to ALSA, which lists PCMs such as
+/// `pulse`, `pipewire` and `hw:CARD=...` rather than individual PulseAudio or
+/// PipeWire sources. To record system output, open `pulse` (or `pipewire`) and
+/// select the monitor with `PULSE_SOURCE=<name>.monitor`, or make the monitor
+/// the default source. On Windows, WASAPI lists loopback-capable devices
+/// directly, and macOS needs a virtual device such as BlackHole.
+pub fn open_input_device(name: Option<&str>) -> Result<cpal::Device, String> {
+    let host = cpal::default_host();
+    let Some(wanted) = name else {
+        return host.default_input_device().ok_or_else(|| "No default input device".to_string());
+    };
+    let mut devices: Vec<cpal::Device> = host.input_devices()
+        .map_err(|e| format!("Unable to list input devices: {}", e))?
+        .collect();
+    let names: Vec<String> = devices.iter().map(|d| d.name().unwrap_or_default()).collect();
+    match match_device_name(&names, wanted) {
+        Some(index) => Ok(devices.swap_remove(index)),
+        None => Err(format!(
+            "No input device matching `{}` on {}; available: {}",
+            wanted, host.id().name(), names.join(", ")
+        )),
+    }
+}
+
+/// Open the source and read its config, retrying with exponential backoff
+/// until `timeout` has passed. USB microphones in particular may not be
+/// enumerated yet when the service starts at boot.
+pub async fn wait_for_source(source: &mut dyn AudioSource, timeout: Duration) -> Result<cpal::SupportedStreamConfig, String> {
+    let started = std::time::Instant::now();
+    let mut delay = DEVICE_RETRY_INITIAL;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match source.open() {
+            Ok(found) => return Ok(found),
+            Err(e) => e,
+        };
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(format!("{} (gave up after {} attempts)", error, attempt));
+        }
+        let wait = delay.min(remaining);
+        tracing::warn!("Input device not available (attempt {}): {}; retrying in {:.1}s", attempt, error, wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        delay = (delay * 2).min(DEVICE_RETRY_MAX);
+    }
+}
+
+/// Open an input device and read its default config, once
+pub fn open_device(name: Option<&str>) -> Result<(cpal::Device, cpal::SupportedStreamConfig), String> {
+    let device = open_input_device(name)?;
+    let config = get_input_config(&device)?;
+    Ok((device, config))
+}
+
+/// Get the input config
+pub fn get_input_config(device: &cpal::Device) -> Result<cpal::SupportedStreamConfig, String> {
+    device.default_input_config()
+        .map_err(|e| format!("Unable to get an input config: {}", e))
+}
+
+/// Every input device on the host with the formats it supports, as printed
+/// by --list-devices. The default device is marked with `*`.
+pub fn describe_input_devices() -> Result<String, String> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().and_then(|device| device.name().ok());
+    let devices = host.input_devices().map_err(|e| format!("Unable to list input devices: {}", e))?;
+    let mut out = format!("Input devices on {}:\n", host.id().name());
+    for device in devices {
+        let name = device.name().unwrap_or_else(|e| format!("<unnamed: {}>", e));
+        let marker = if default_name.as_deref() == Some(name.as_str()) { "*" } else { " " };
+        out.push_str(&format!("{} {}\n", marker, name));
+        match get_input_config(&device) {
+            Ok(config) => out.push_str(&format!(
+                "      default: {} ch, {} Hz, {:?}\n",
+                config.channels(), config.sample_rate().0, config.sample_format()
+            )),
+            Err(e) => out.push_str(&format!("      default: {}\n", e)),
+        }
+        match device.supported_input_configs() {
+            Ok(configs) => {
+                for range in configs {
+                    let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+                    let rates = if min == max { format!("{} Hz", min) } else { format!("{}-{} Hz", min, max) };
+                    out.push_str(&format!("      {} ch, {}, {:?}\n", range.channels(), rates, range.sample_format()));
+                }
+            }
+            Err(e) => out.push_str(&format!("      unable to read supported formats: {}\n", e)),
+        }
+    }
+    if default_name.is_none() {
+        out.push_str("No default input device\n");
+    }
+    Ok(out)
+}
+
+/// Name of the audio host (ALSA, CoreAudio, WASAPI, ...), for reporting
+pub fn host_name() -> &'static str {
+    cpal::default_host().id().name()
+}
+
+/// What to do when the ring buffer fills up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferMode {
+    /// Discard the oldest audio (rolling buffer)
+    Overwrite,
+    /// Stop recording so the beginning is kept
+    Stop,
+}
+
+impl std::str::FromStr for BufferMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(BufferMode::Overwrite),
+            "stop" => Ok(BufferMode::Stop),
+            other => Err(format!("unknown buffer mode `{}`, expected `overwrite` or `stop`", other)),
+        }
+    }
+}
+
+/// Which [`AudioSource`] to capture from, from --source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// An input device, through cpal
+    Cpal,
+    /// A generated signal, from --synthetic-signal
+    Synthetic,
+}
+
+impl std::str::FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpal" => Ok(SourceKind::Cpal),
+            "synthetic" => Ok(SourceKind::Synthetic),
+            other => Err(format!("unknown source `{}`, expected `cpal` or `synthetic`", other)),
+        }
+    }
+}
+
+/// Settings for opening the capture stream
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureOptions {
+    /// Requested device buffer length; lower means faster detection
+    pub latency: Duration,
+    /// Audio queued between the callback and the wakeword thread, from
+    /// --wakeword-queue-ms; what doesn't fit is left unheard
+    pub wakeword_queue: Duration,
+}
+
+impl CaptureOptions {
+    // Callbacks the wakeword queue holds; each delivers about `latency` of audio
+    fn wakeword_queue_len(&self) -> usize {
+        let latency = self.latency.as_secs_f64().max(0.001);
+        ((self.wakeword_queue.as_secs_f64() / latency).ceil() as usize).max(2)
+    }
+
+    // Samples the wakeword queue holds, interleaved, in the stream's format
+    fn wakeword_queue_samples(&self, config: &cpal::StreamConfig) -> usize {
+        let latency = self.latency.as_secs_f64().max(0.001);
+        let seconds = self.wakeword_queue.as_secs_f64().max(2.0 * latency);
+        (seconds * config.sample_rate.0 as f64).ceil() as usize * config.channels.max(1) as usize
+    }
+}
+
+// Convert the requested latency into a fixed buffer size the device supports
+fn buffer_size_for_latency(config: &cpal::SupportedStreamConfig, latency: Duration) -> cpal::BufferSize {
+    let requested = (config.sample_rate().0 as f64 * latency.as_secs_f64()).round().max(1.0) as u32;
+    let frames = match *config.buffer_size() {
+        cpal::SupportedBufferSize::Range { min, max } => {
+            let clamped = requested.clamp(min, max);
+            if clamped != requested {
+                tracing::warn!(
+                    "Capture latency of {} frames is outside the device range {}..={}, using {}",
+                    requested, min, max, clamped
+                );
+            }
+            clamped
+        }
+        cpal::SupportedBufferSize::Unknown => requested,
+    };
+    tracing::info!(
+        "Capture buffer: {} frames ({:.1} ms)",
+        frames,
+        frames as f64 * 1000.0 / config.sample_rate().0 as f64
+    );
+    cpal::BufferSize::Fixed(frames)
+}
+
+// Where one callback's audio, queued for the wakeword thread, came from.
+// Its samples follow in the sample queue.
+#[derive(Clone, Copy)]
+struct WakewordBlock {
+    // Position of the first sample among everything captured
+    captured_at: u64,
+    // Buffer position of the first sample and how many were buffered
+    buffered: Option<(u64, usize)>,
+    len: usize,
+}
+
+// The callback's end of the wakeword queue: samples go into a preallocated
+// ring and each block's origin into another, so queueing never allocates
+struct WakewordQueue {
+    blocks: HeapProd<WakewordBlock>,
+    samples: HeapProd<f32>,
+}
+
+impl WakewordQueue {
+    // Queue a block whole, or not at all when either ring is full
+    fn push(&mut self, block: WakewordBlock, samples: &[f32]) -> bool {
+        if self.blocks.is_full() || self.samples.vacant_len() < samples.len() {
+            return false;
+        }
+        // Samples first, so they are there by the time the block is seen
+        self.samples.push_slice(samples);
+        self.blocks.try_push(block).is_ok()
+    }
+}
+
+// Run Porcupine over what the capture callback queues, until the stream
+// holding the other end is dropped. Frames carry over from one callback to
+// the next; a gap left by a full queue starts them over.
+fn detect_wakewords(state: &AudioState, mut blocks: HeapCons<WakewordBlock>, mut queued: HeapCons<f32>) {
+    let mut pending: Vec<i16> = Vec::new();
+    let mut samples: Vec<f32> = Vec::new();
+    let mut frame_length = 0;
+    // Captured position just past the last queued audio
+    let mut next_captured = None;
+    loop {
+        let Some(frames) = blocks.try_pop() else {
+            if !blocks.write_is_held() {
+                break;
+            }
+            std::thread::park_timeout(WAKEWORD_IDLE);
+            continue;
+        };
+        samples.resize(frames.len, 0.0);
+        let popped = queued.pop_slice(&mut samples);
+        samples.truncate(popped);
+        // /wakeword/reload may have swapped the engine, so the frame length
+        // is re-read every time; there is none with --no-wakeword, and the
+        // audio is just drained
+        let Some(porcupine) = state.wakeword.lock().clone() else {
+            pending.clear();
+            next_captured = None;
+            continue;
+        };
+        let length = porcupine.frame_length() as usize;
+        if next_captured != Some(frames.captured_at) || length != frame_length {
+            pending.clear();
+            frame_length = length;
+        }
+        next_captured = Some(frames.captured_at + samples.len() as u64);
+
+        // Convert samples to i16, logging any potential conversion issues
+        let carried = pending.len();
+        pending.extend(samples.iter().map(|&x| {
+            let scaled = x * i16::MAX as f32;
+            if scaled > i16::MAX as f32 || scaled < i16::MIN as f32 {
+                tracing::warn!("Sample value {} out of i16 range after scaling", scaled);
+            }
+            scaled as i16
+        }));
+
+        // Process with Porcupine in chunks of the required size
+        let mut processed = 0;
+        while pending.len() - processed >= frame_length {
+            let chunk = &pending[processed..processed + frame_length];
+            processed += frame_length;
+            match porcupine.process(chunk) {
+                Ok(keyword_index) => {
+                    if keyword_index >= 0 && accept_detection(state) {
+                        // Detections are placed at the end of the frame that
+                        // triggered them, which always lies in these frames
+                        let end = processed - carried;
+                        let captured = frames.captured_at + end as u64;
+                        let keyword = wakeword_listener::keyword_name(keyword_index);
+                        tracing::info!(keyword, captured_sample = captured, "Wakeword detected");
+                        state.publish(events::Event::WakewordDetected {
+                            keyword: keyword.to_string(),
+                            captured_sample: captured,
+                        });
+                        let at = frames.buffered
+                            .filter(|&(_, pushed)| end <= pushed)
+                            .map(|(start, _)| start + end as u64);
+                        if let Some(at) = at {
+                            let detection = Detection { at, captured, keyword };
+                            record_detection(state, detection);
+                            stt::queue_detection(state, detection);
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("Error processing audio: {:?}", err);
+                }
+            }
+        }
+        pending.drain(..processed);
+    }
+}
+
+// Start `source`, feeding its blocks to the buffer, the level triggers, live
+// listeners and the wakeword thread
+fn start_source(
+    source: &mut dyn AudioSource,
+    config: &cpal::StreamConfig,
+    state: &Arc<AudioState>,
+    options: &CaptureOptions,
+) -> Result<SourceStream, CaptureError> {
+    // Block records are tiny, so allow for callbacks far shorter than the
+    // latency asked for; the samples are what the queue's length bounds
+    let (blocks, queued_blocks) = HeapRb::<WakewordBlock>::new(options.wakeword_queue_len() * 8).split();
+    let (samples, queued_samples) = HeapRb::<f32>::new(options.wakeword_queue_samples(config)).split();
+    let mut wakeword_queue = WakewordQueue { blocks, samples };
+    let detector_state = Arc::clone(state);
+    // The stream's threads log inside the capture span too
+    let span = tracing::Span::current();
+    let detector_span = span.clone();
+    let detector = std::thread::spawn(move || {
+        detector_span.in_scope(|| detect_wakewords(&detector_state, queued_blocks, queued_samples))
+    })
+    .thread()
+    .clone();
+    let error_span = span.clone();
+    let state_clone = Arc::clone(state);
+    let error_state = Arc::clone(state);
+    let channels = config.channels;
+    // From --highpass-hz; its state carries from one callback to the next
+    let mut high_pass = state.highpass_hz.map(|hz| HighPass::new(hz, config.sample_rate.0, channels));
+    // From --trigger-level-db; a rebuilt stream starts over armed
+    let mut level_trigger = state.level_trigger.map(|options| LevelTrigger::new(options, config.sample_rate.0, channels));
+    let mut silence_stop = state.auto_stop.map(|options| SilenceStop::new(options, config.sample_rate.0, channels));
+    // Reused from one callback to the next, so the stream thread only
+    // allocates while they grow to the block size
+    let (mut amplified, mut high_passed) = (Vec::new(), Vec::new());
+    source.start(
+        config,
+        Box::new(move |data: &[f32]| {
+            // Heartbeat for the stall watchdog
+            let now = now_millis();
+            state_clone.last_frame_at.store(now, Ordering::Relaxed);
+            state_clone.throughput.lock().record(now, (data.len() / channels.max(1) as usize) as u64);
+            // Position of this callback's first sample among everything captured
+            let captured_at = state_clone.samples_captured.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+            let gain = f32::from_bits(state_clone.gain.load(Ordering::Relaxed));
+            let data = if gain == 1.0 {
+                data
+            } else {
+                amplified.clear();
+                amplified.extend(data.iter().map(|&x| x * gain));
+                amplified.as_slice()
+            };
+
+            // The wakeword engine hears the filtered audio; the buffer only
+            // does with --highpass-buffer
+            let filtered = high_pass.as_mut().map(|filter| {
+                high_passed.clear();
+                high_passed.extend_from_slice(data);
+                filter.process(&mut high_passed);
+                high_passed.as_slice()
+            });
+            let data = match filtered {
+                Some(filtered) if state_clone.highpass_buffer => filtered,
+                _ => data,
+            };
+
+            // Absolute buffer position of the first sample and how many were buffered
+            let buffered = state_clone.push_samples(data);
+
+            // Follow the level of what the wakeword engine hears; only buffered audio can be saved
+            let level = buffered
+                .filter(|_| level_trigger.is_some() || silence_stop.is_some())
+                .map(|(start, pushed)| (start, pushed, encoding::rms_dbfs(&filtered.unwrap_or(data)[..pushed])));
+            if let (Some(trigger), Some((start, pushed, level_db))) = (level_trigger.as_mut(), level) {
+                match trigger.process(start, pushed, level_db) {
+                    Some(LevelEvent::Started { level_db, .. }) => {
+                        tracing::info!(parent: &span, "Sound trigger at {:.1} dBFS, captured sample {}", level_db, captured_at);
+                        state_clone.publish(events::Event::CaptureTriggered {
+                            trigger: filename::Trigger::Level.as_str().to_string(),
+                            level_db,
+                            captured_sample: captured_at,
+                        });
+                    }
+                    Some(LevelEvent::Finished { from, to, peak_db }) => {
+                        tracing::info!(parent: &span, "Sound-triggered capture ended, peaking at {:.1} dBFS", peak_db);
+                        queue_capture(&state_clone, Capture { from, to, trigger: filename::Trigger::Level });
+                    }
+                    None => {}
+                }
+            }
+            if let (Some(stop), Some((start, pushed, level_db))) = (silence_stop.as_mut(), level) {
+                if let Some((from, to)) = stop.process(start, pushed, level_db) {
+                    tracing::info!(parent: &span, "Silence after speech, pausing recording");
+                    state_clone.pause();
+                    if state_clone.auto_stop.is_some_and(|options| options.save) {
+                        queue_capture(&state_clone, Capture { from, to, trigger: filename::Trigger::Silence });
+                    }
+                }
+            }
+
+            // Feed WebSocket listeners, if any; send never blocks on slow receivers
+            if state_clone.live_audio.receiver_count() > 0 {
+                let _ = state_clone.live_audio.send(encode_frame(data, channels));
+            }
+
+            // Queue what the wakeword engine hears; the detector thread checks
+            // for an engine, so the callback takes no lock for it
+            let heard = filtered.unwrap_or(data);
+            let block = WakewordBlock { captured_at, buffered, len: heard.len() };
+            if !wakeword_queue.push(block, heard)
+                && state_clone.wakeword_dropped.fetch_add(1, Ordering::Relaxed) == 0
+            {
+                tracing::warn!(parent: &span, "Wakeword detection is falling behind; dropping audio");
+            }
+            detector.unpark();
+        }),
+
+        // An xrun or suspend can leave the stream stalled for good; have the
+        // keep-alive loop in capture_audio rebuild it
+        Box::new(move |err| {
+            tracing::error!(parent: &error_span, "Error in audio stream: {}", err);
+            error_state.restart_capture();
+        }),
+    )
+}
+
+/// Frames delivered per second, averaged over the last few whole seconds.
+/// Well below the configured rate means the device is dropping audio.
+#[derive(Debug, Default)]
+pub struct Throughput {
+    // Frames in each complete second, oldest first
+    seconds: std::collections::VecDeque<u64>,
+    // The second being counted, as Unix seconds, and its frames so far
+    current_second: u64,
+    current_frames: u64,
+}
+
+impl Throughput {
+    /// Count `frames` delivered at `now_ms`, in Unix milliseconds
+    pub fn record(&mut self, now_ms: u64, frames: u64) {
+        let second = now_ms / 1000;
+        if second != self.current_second {
+            self.roll_to(second);
+        }
+        self.current_frames += frames;
+    }
+
+    // Close the current second, counting seconds without callbacks as empty
+    fn roll_to(&mut self, second: u64) {
+        if self.current_second != 0 && second > self.current_second {
+            self.seconds.push_back(self.current_frames);
+            let empty = (second - self.current_second - 1).min(THROUGHPUT_WINDOW_SECS as u64);
+            self.seconds.extend(std::iter::repeat_n(0, empty as usize));
+            while self.seconds.len() > THROUGHPUT_WINDOW_SECS {
+                self.seconds.pop_front();
+            }
+        }
+        self.current_second = second;
+        self.current_frames = 0;
+    }
+
+    /// Average frames per second over the window, once a whole second has been seen
+    pub fn frames_per_second(&self, now_ms: u64) -> Option<f64> {
+        let mut settled = Throughput { seconds: self.seconds.clone(), ..*self };
+        settled.roll_to(now_ms / 1000);
+        if settled.seconds.is_empty() {
+            return None;
+        }
+        Some(settled.seconds.iter().sum::<u64>() as f64 / settled.seconds.len() as f64)
+    }
+}
+
+// Record a detection unless it falls within the cooldown of the previous one
+fn accept_detection(state: &AudioState) -> bool {
+    let cooldown = state.settings.read().wakeword_cooldown_ms;
+    let now = now_millis();
+    let last = state.last_detection_at.load(Ordering::Relaxed);
+    if last != 0 && now.saturating_sub(last) < cooldown {
+        tracing::debug!("Ignoring detection within the {} ms cooldown", cooldown);
+        return false;
+    }
+    state.last_detection_at.store(now, Ordering::Relaxed);
+    true
+}
+
+// Hand a finished capture to the saver without blocking the callback
+fn queue_capture(state: &AudioState, capture: Capture) {
+    let queued = state.captures.get().is_some_and(|captures| captures.try_send(capture).is_ok());
+    if !queued {
+        tracing::warn!("Captures are waiting to be saved; dropping this {}-triggered one", capture.trigger.as_str());
+    }
+}
+
+// Remember a detection in the buffered audio for /save, forgetting those
+// whose audio has since been overwritten
+fn record_detection(state: &AudioState, detection: Detection) {
+    let capacity = state.buffer.lock().capacity() as u64;
+    let mut detections = state.detections.lock();
+    detections.retain(|earlier| earlier.at + capacity > detection.at);
+    detections.push(detection);
+}
+
+/// Remember a /mark at the newest buffered sample, forgetting marks whose
+/// audio has since been overwritten
+pub fn record_marker(state: &AudioState, label: Option<String>) -> Marker {
+    let capacity = state.buffer.lock().capacity() as u64;
+    let marker = Marker {
+        at: state.samples_written.load(Ordering::Relaxed),
+        captured: state.samples_captured.load(Ordering::Relaxed),
+        label,
+    };
+    let mut markers = state.markers.lock();
+    markers.retain(|earlier| earlier.at + capacity > marker.at);
+    markers.push(marker.clone());
+    marker
+}
+
+/// Open the source and run the capture stream until the server is halted,
+/// buffering into `state` and feeding its wakeword engine
+pub fn capture_audio(state: &Arc<AudioState>, options: &CaptureOptions, source: &mut dyn AudioSource) -> Result<(), CaptureError> {
+    tracing::info!("Initializing audio capture");
+    let config = source.open().map_err(CaptureError::Device)?;
+
+    let device_name = source.name();
+    tracing::info!("Using input device: {}", device_name);
+    tracing::Span::current().record("device", device_name.as_str()).record("sample_rate", config.sample_rate().0);
+
+    tracing::debug!("Audio config: {:?}", config);
+    let buffer_size = buffer_size_for_latency(&config, options.latency);
+    let (buffer_range, sample_format) = (*config.buffer_size(), config.sample_format());
+    let mut config: cpal::StreamConfig = config.into();
+    config.buffer_size = buffer_size;
+    // The device may have changed since startup; saves describe what this stream delivers
+    state.set_input_config(cpal::SupportedStreamConfig::new(
+        config.channels,
+        config.sample_rate,
+        buffer_range,
+        sample_format,
+    ));
+
+    tracing::info!("Starting audio stream");
+    let stream = start_source(source, &config, state, options)?;
+    *state.capture_error.lock() = None;
+    let mut stream = Some(stream);
+
+    // Keep the stream alive until the server is halted, rebuilding it with
+    // the same config when the error callback or the stall watchdog asks.
+    // The buffer is left alone, so what was captured before survives.
+    let mut failed_restarts = 0;
+    let mut retry_delay = DEVICE_RETRY_INITIAL;
+    while !state.wait_for_capture(None, true) {
+        if state.restart_stream.swap(false, Ordering::Relaxed) {
+            tracing::warn!("Restarting audio stream");
+            // Drop the old stream before opening the device again
+            stream = None;
+            match start_source(source, &config, state, options) {
+                Ok(new_stream) => {
+                    stream = Some(new_stream);
+                    let restarts = state.stream_restarts.fetch_add(1, Ordering::Relaxed) + 1;
+                    tracing::info!("Audio stream restarted ({} restarts so far)", restarts);
+                    (failed_restarts, retry_delay) = (0, DEVICE_RETRY_INITIAL);
+                }
+                Err(e) if failed_restarts + 1 >= MAX_STREAM_RESTARTS => {
+                    tracing::error!("Giving up on the audio stream after {} failed restarts", MAX_STREAM_RESTARTS);
+                    return Err(e);
+                }
+                Err(e) => {
+                    failed_restarts += 1;
+                    tracing::error!(
+                        "Failed to restart audio stream (attempt {}/{}): {}; retrying in {:.1}s",
+                        failed_restarts, MAX_STREAM_RESTARTS, e, retry_delay.as_secs_f64()
+                    );
+                    if state.wait_for_capture(Some(retry_delay), false) {
+                        break;
+                    }
+                    retry_delay = (retry_delay * 2).min(DEVICE_RETRY_MAX);
+                    state.restart_stream.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    tracing::info!("Shutting down capture audio thread");
+    
+    // Explicitly drop the stream before the function ends
+    drop(stream);
+    Ok(())
+}
+
+/// Run [`capture_audio`] until the server is halted. When the stream can't be
+/// started, the error is kept for /status and /health and initialization is
+/// retried with exponential backoff, so a flaky device can't leave the
+/// server running without capture. Blocks the calling thread, which
+/// [`AudioState::request_shutdown`] wakes at once.
+pub fn supervise_capture(state: &Arc<AudioState>, options: CaptureOptions, mut source: Box<dyn AudioSource>) {
+    let mut delay = DEVICE_RETRY_INITIAL;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let Err(e) = capture_audio(state, &options, source.as_mut()) else {
+            break;
+        };
+        tracing::error!("Audio capture failed (attempt {}): {}", attempt, e);
+        *state.capture_error.lock() = Some(e.to_string());
+        if state.is_halting.load(Ordering::Relaxed) {
+            break;
+        }
+        tracing::warn!("Retrying audio capture in {:.1}s", delay.as_secs_f64());
+        if state.wait_for_capture(Some(delay), false) {
+            break;
+        }
+        delay = (delay * 2).min(CAPTURE_RETRY_MAX);
+    }
+}
+
+/// Milliseconds since the Unix epoch, used for the frame heartbeat
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Watch the capture heartbeat and warn when frames stop arriving,
+/// optionally asking the capture loop to rebuild the stream
+pub async fn watch_capture(state: Arc<AudioState>, timeout: Duration, restart: bool) {
+    let started_at = now_millis();
+    let mut stalled = false;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    while !state.is_halting.load(Ordering::Relaxed) {
+        interval.tick().await;
+        let last = match state.last_frame_at.load(Ordering::Relaxed) {
+            0 => started_at,
+            last => last,
+        };
+        let silent_for = Duration::from_millis(now_millis().saturating_sub(last));
+        if silent_for >= timeout {
+            if !stalled {
+                tracing::warn!("No audio frames received for {:.1}s; capture may be stalled", silent_for.as_secs_f64());
+                if restart {
+                    state.restart_capture();
+                }
+                stalled = true;
+            }
+        } else if stalled {
+            tracing::info!("Audio frames are arriving again");
+            stalled = false;
+        }
+    }
+}
+
+/// Portion of the buffer to save, as offsets in seconds before "now".
+/// `from` is the older edge and `to` the newer one; `None` means the
+/// start of the buffer and the most recent sample respectively.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SaveWindow {
+    /// Seconds before now where the window starts
+    pub from: Option<f64>,
+    /// Seconds before now where the window ends
+    pub to: Option<f64>,
+    /// Absolute buffer positions the window may not start before or end
+    /// after, as for Gap::at
+    pub since: Option<u64>,
+    /// See `since`
+    pub until: Option<u64>,
+}
+
+impl SaveWindow {
+    /// The most recent `seconds` of audio
+    pub fn last_seconds(seconds: f64) -> Self {
+        SaveWindow { from: Some(seconds), ..SaveWindow::default() }
+    }
+
+    /// Everything buffered from absolute position `position` on
+    pub fn since(position: u64) -> Self {
+        SaveWindow { since: Some(position), ..SaveWindow::default() }
+    }
+
+    /// The same window on another buffer. `since` and `until` count samples
+    /// of the buffer `written` samples in `config`'s format have gone into;
+    /// each becomes the position as far back from the newest sample of the
+    /// buffer `other_written` samples in `other`'s format have gone into.
+    pub fn on_timeline(
+        &self,
+        written: u64,
+        config: &cpal::SupportedStreamConfig,
+        other_written: u64,
+        other: &cpal::SupportedStreamConfig,
+    ) -> Self {
+        let (channels, rate) = (config.channels().max(1) as u64, config.sample_rate().0.max(1) as f64);
+        let other_channels = other.channels().max(1) as u64;
+        let convert = |position: u64| {
+            let frames_ago = written.saturating_sub(position) / channels;
+            let other_frames = (frames_ago as f64 * other.sample_rate().0 as f64 / rate).round() as u64;
+            other_written.saturating_sub(other_frames * other_channels)
+        };
+        SaveWindow { since: self.since.map(convert), until: self.until.map(convert), ..*self }
+    }
+
+    /// Reject negative/NaN offsets and inverted windows
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("from", self.from), ("to", self.to)] {
+            if let Some(v) = value {
+                if !v.is_finite() || v < 0.0 {
+                    return Err(format!("`{}` must be a non-negative number of seconds", name));
+                }
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from < to {
+                return Err("`from` must be further in the past than `to`".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // Resolve the window to a (skip, take) range over `buffered` interleaved
+    // samples, clamped to what is actually buffered and aligned to whole frames
+    fn sample_range(&self, buffered: usize, sample_rate: u32, channels: u16) -> (usize, usize) {
+        let channels = channels.max(1) as usize;
+        let frames = buffered / channels;
+        let to_frames = |secs: f64| ((secs * sample_rate as f64).round() as usize).min(frames);
+        let start_ago = self.from.map(to_frames).unwrap_or(frames);
+        let end_ago = self.to.map(to_frames).unwrap_or(0).min(start_ago);
+        let skip = (buffered - frames * channels) + (frames - start_ago) * channels;
+        (skip, (start_ago - end_ago) * channels)
+    }
+}
+
+/// A pause in the buffered audio: `silent_frames` of time that were not
+/// recorded before the sample at absolute position `at`
+#[derive(Debug, Clone, Copy)]
+pub struct Gap {
+    /// Absolute buffer position of the first sample after the pause
+    pub at: u64,
+    /// How long the pause lasted, in frames
+    pub silent_frames: u64,
+}
+
+/// A wakeword detection inside the buffered audio
+#[derive(Debug, Clone, Copy)]
+pub struct Detection {
+    /// Absolute buffer position, as for Gap::at
+    pub at: u64,
+    /// Samples captured before the detection, buffered or not
+    pub captured: u64,
+    /// Name of the keyword heard
+    pub keyword: &'static str,
+}
+
+/// A label dropped into the buffered audio with /mark
+#[derive(Debug, Clone)]
+pub struct Marker {
+    /// Absolute buffer position, as for Gap::at
+    pub at: u64,
+    /// Samples captured before the mark, buffered or not
+    pub captured: u64,
+    /// Text given to /mark, if any
+    pub label: Option<String>,
+}
+
+/// How /save treats pauses inside the saved window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GapMode {
+    /// Concatenate the audio on either side of the pause
+    #[default]
+    Ignore,
+    /// Insert silence for the time spent paused
+    Silence,
+    /// Write one file per uninterrupted segment
+    Split,
+}
+
+/// Samples copied from the buffer, with the pauses that fall inside them
+pub struct Snapshot {
+    /// Interleaved samples, oldest first
+    pub samples: Vec<f32>,
+    /// (sample offset into `samples`, silent frames), in order
+    pub gaps: Vec<(usize, u64)>,
+    /// (sample offset just past the triggering frame, detection), in order
+    pub detections: DetectionOffsets,
+    /// (sample offset just past the audio before the mark, marker), in order
+    pub markers: MarkerOffsets,
+    /// Wall-clock time of the first sample
+    pub started_at: chrono::DateTime<chrono::Local>,
+    /// Absolute buffer position just past the last sample
+    pub end: u64,
+}
+
+/// Detections by sample offset into a snapshot or segment
+pub type DetectionOffsets = Vec<(usize, Detection)>;
+/// Markers by sample offset into a snapshot or segment
+pub type MarkerOffsets = Vec<(usize, Marker)>;
+
+/// One uninterrupted part of a snapshot
+pub struct Segment<'a> {
+    /// Interleaved samples of this part
+    pub samples: &'a [f32],
+    /// Offsets relative to the start of the part
+    pub detections: DetectionOffsets,
+    /// Offsets relative to the start of the part
+    pub markers: MarkerOffsets,
+    /// Wall-clock time of the first sample
+    pub started_at: chrono::DateTime<chrono::Local>,
+}
+
+// Move each offset past the silence inserted before it; one right at a pause
+// belongs to the audio before it
+fn shift_offsets<T: Clone>(offsets: &[(usize, T)], gaps: &[(usize, u64)], silence: &[usize]) -> Vec<(usize, T)> {
+    offsets.iter()
+        .map(|(offset, item)| {
+            let inserted: usize = gaps.iter().zip(silence)
+                .filter(|(&(gap, _), _)| gap < *offset)
+                .map(|(_, &len)| len)
+                .sum();
+            (offset + inserted, item.clone())
+        })
+        .collect()
+}
+
+// The offsets in `start..=end`, made relative to `start`
+fn offsets_within<T: Clone>(offsets: &[(usize, T)], start: usize, end: usize) -> Vec<(usize, T)> {
+    offsets.iter()
+        .filter(|&&(offset, _)| offset > start && offset <= end)
+        .map(|(offset, item)| (offset - start, item.clone()))
+        .collect()
+}
+
+impl Snapshot {
+    /// Fill each pause with silence, at most `max_samples` per pause,
+    /// shifting detections and markers along with their audio
+    pub fn with_silence(self, channels: u16, max_samples: usize) -> Snapshot {
+        if self.gaps.is_empty() {
+            return self;
+        }
+        let silence: Vec<usize> = self.gaps.iter()
+            .map(|&(_, frames)| (frames as usize).saturating_mul(channels as usize).min(max_samples))
+            .collect();
+        let mut out = Vec::with_capacity(self.samples.len() + silence.iter().sum::<usize>());
+        let mut start = 0;
+        for (&(offset, _), &len) in self.gaps.iter().zip(&silence) {
+            out.extend_from_slice(&self.samples[start..offset]);
+            out.resize(out.len() + len, 0.0);
+            start = offset;
+        }
+        out.extend_from_slice(&self.samples[start..]);
+
+        let detections = shift_offsets(&self.detections, &self.gaps, &silence);
+        let markers = shift_offsets(&self.markers, &self.gaps, &silence);
+        Snapshot { samples: out, gaps: Vec::new(), detections, markers, ..self }
+    }
+
+    /// Cut the samples at every pause
+    pub fn segments(&self, channels: u16, sample_rate: u32) -> Vec<Segment<'_>> {
+        let ends = self.gaps.iter().map(|&(offset, _)| offset).chain([self.samples.len()]);
+        let mut segments = Vec::with_capacity(self.gaps.len() + 1);
+        let (mut start, mut paused_frames) = (0, 0);
+        for (index, end) in ends.enumerate() {
+            let frames = (start / channels.max(1) as usize) as u64 + paused_frames;
+            segments.push(Segment {
+                samples: &self.samples[start..end],
+                detections: offsets_within(&self.detections, start, end),
+                markers: offsets_within(&self.markers, start, end),
+                started_at: self.started_at + frames_duration(frames, sample_rate),
+            });
+            paused_frames += self.gaps.get(index).map_or(0, |&(_, silent)| silent);
+            start = end;
+        }
+        segments
+    }
+}
+
+fn frames_duration(frames: u64, sample_rate: u32) -> chrono::Duration {
+    chrono::Duration::microseconds((frames as f64 * 1e6 / sample_rate.max(1) as f64).round() as i64)
+}
+
+/// What actually ended up in a saved file
+#[derive(Debug, Clone)]
+pub struct SavedAudio {
+    /// Interleaved samples, across all channels
+    pub samples: usize,
+    /// Length of the audio
+    pub duration_seconds: f64,
+    /// Frames per second
+    pub sample_rate: u32,
+    /// Channels per frame
+    pub channels: u16,
+    /// None for compressed formats
+    pub bits_per_sample: Option<u16>,
+    /// Hex SHA-256 of the file as written; None when appending or encoding in memory
+    pub sha256: Option<String>,
+}
+
+/// Copy the requested window of the ring buffer. The window is fixed by
+/// absolute position and its first chunk copied under the same lock, then the
+/// rest follows oldest first in chunks of SNAPSHOT_CHUNK_FRAMES, releasing the
+/// lock between chunks so the capture callback never waits for more than one
+/// chunk's memcpy. Audio that arrives meanwhile only overwrites samples that
+/// were already copied, unless the copy falls a whole buffer behind; anything
+/// lost that way is saved as silence so gaps and detections stay aligned.
+pub fn snapshot_buffer(
+    state: &AudioState,
+    config: &cpal::SupportedStreamConfig,
+    window: SaveWindow,
+) -> Snapshot {
+    let (rate, channels) = (config.sample_rate().0, config.channels());
+    let chunk = SNAPSHOT_CHUNK_FRAMES * channels.max(1) as usize;
+    let mut samples = Vec::with_capacity(chunk);
+    let (start, take, written) = {
+        let buffer = state.buffer.lock();
+        let (skip, take) = window.sample_range(buffer.occupied_len(), rate, channels);
+        // Absolute position of the first sample to copy
+        let written = state.samples_written.load(Ordering::Relaxed);
+        let oldest = written - buffer.occupied_len() as u64;
+        let start = oldest + skip as u64;
+        // Positions only advance by whole frames, so the trimmed window stays aligned
+        let trimmed = window.since.map_or(0, |since| since.saturating_sub(start).min(take as u64));
+        let (start, take) = (start + trimmed, take - trimmed as usize);
+        let take = window.until.map_or(take, |until| take.min(until.saturating_sub(start) as usize));
+        // Nothing can have been overwritten between fixing the window and this chunk
+        let first = (start - oldest) as usize;
+        buffer.copy_range(&mut samples, first, chunk.min(take));
+        parking_lot::MutexGuard::unlock_fair(buffer);
+        (start, take, written)
+    };
+    // The newest buffered sample arrived just now, or when recording paused
+    let newest_at = match state.paused_at.load(Ordering::Relaxed) {
+        0 => chrono::Local::now(),
+        paused_at => chrono::DateTime::from_timestamp_millis(paused_at as i64)
+            .map(|at| at.with_timezone(&chrono::Local))
+            .unwrap_or_else(chrono::Local::now),
+    };
+    samples.reserve_exact(take - samples.len());
+
+    let mut lost = 0;
+    while samples.len() < take {
+        let position = start + samples.len() as u64;
+        let buffer = state.buffer.lock();
+        let oldest = state.samples_written.load(Ordering::Relaxed) - buffer.occupied_len() as u64;
+        if position < oldest {
+            // Overwritten before we got to it, or cleared by /stop
+            let missing = ((oldest - position) as usize).min(take - samples.len());
+            drop(buffer);
+            samples.resize(samples.len() + missing, 0.0);
+            lost += missing;
+            continue;
+        }
+        let skip = (position - oldest) as usize;
+        let len = chunk.min(take - samples.len()).min(buffer.occupied_len().saturating_sub(skip));
+        if len == 0 {
+            break;
+        }
+        buffer.copy_range(&mut samples, skip, len);
+        // Hand the lock straight to a waiting callback instead of retaking it
+        parking_lot::MutexGuard::unlock_fair(buffer);
+    }
+    if lost > 0 {
+        tracing::warn!("{} samples were overwritten while saving and are replaced with silence", lost);
+    }
+
+    let end = start + take as u64;
+    let (gaps, paused_frames) = {
+        let all_gaps = state.gaps.lock();
+        let gaps = all_gaps.iter()
+            .filter(|gap| gap.at > start && gap.at < end)
+            .map(|gap| ((gap.at - start) as usize, gap.silent_frames))
+            .collect();
+        // Time spent paused between the first sample and the newest
+        let paused: u64 = all_gaps.iter().filter(|gap| gap.at > start).map(|gap| gap.silent_frames).sum();
+        (gaps, paused)
+    };
+    let frames_since = (written - start) / channels.max(1) as u64 + paused_frames;
+    let started_at = newest_at - frames_duration(frames_since, rate);
+    let detections = state.detections.lock().iter()
+        .filter(|detection| detection.at > start && detection.at <= end)
+        .map(|detection| ((detection.at - start) as usize, *detection))
+        .collect();
+    let markers = state.markers.lock().iter()
+        .filter(|marker| marker.at > start && marker.at <= end)
+        .map(|marker| ((marker.at - start) as usize, marker.clone()))
+        .collect();
+    Snapshot { samples, gaps, detections, markers, started_at, end }
+}
+
+// Encode samples as a complete file in the configured format, writing
+// `target` front to back. Once the file's parameters are known and before any
+// audio goes out, `metadata` returns RIFF chunks to place ahead of the audio
+// data; formats other than WAV and G.711 ignore them.
+fn write_recording<W: std::io::Write>(
+    mut target: W,
+    samples: &[f32],
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+    metadata: impl FnOnce(&SavedAudio) -> std::io::Result<Vec<u8>>,
+) -> Result<SavedAudio, SaveError> {
+    let resampled: Vec<f32>;
+    let resampled_config: cpal::SupportedStreamConfig;
+    let (samples, config) = match output.sample_rate {
+        Some(rate) if rate != config.sample_rate().0 => {
+            let from = config.sample_rate().0;
+            if rate > from {
+                tracing::info!("Upsampling from {} to {} Hz; this adds no information", from, rate);
+            } else {
+                tracing::info!("Resampling from {} to {} Hz", from, rate);
+            }
+            resampled = resample_interleaved(samples, config.channels(), from, rate);
+            resampled_config = cpal::SupportedStreamConfig::new(
+                config.channels(),
+                cpal::SampleRate(rate),
+                *config.buffer_size(),
+                config.sample_format(),
+            );
+            (resampled.as_slice(), &resampled_config)
+        }
+        _ => (samples, config),
+    };
+
+    if output.format == OutputFormat::Raw {
+        let (channels, sample_rate) = (config.channels(), config.sample_rate().0);
+        let frames = samples.len() / channels.max(1) as usize;
+        let saved = SavedAudio {
+            samples: samples.len(),
+            duration_seconds: frames as f64 / sample_rate as f64,
+            sample_rate,
+            channels,
+            bits_per_sample: Some(output.raw.bytes_per_sample() * 8),
+            sha256: None,
+        };
+        metadata(&saved).map_err(SaveError::Finalize)?;
+        tracing::info!("Writing {} samples as raw {}", samples.len(), output.raw.name());
+        target.write_all(&encode_raw(samples, output.raw)).map_err(SaveError::WriteSamples)?;
+        target.flush().map_err(SaveError::Finalize)?;
+        return Ok(saved);
+    }
+
+    if output.format == OutputFormat::Mp3 {
+        let (channels, sample_rate) = (config.channels(), config.sample_rate().0);
+        tracing::info!("Encoding {} samples to MP3 at {} kbps", samples.len(), output.mp3_bitrate_kbps);
+        let mp3 = encode_mp3(samples, channels, sample_rate, output.mp3_bitrate_kbps).map_err(SaveError::WriteSamples)?;
+        let frames = samples.len() / channels.max(1) as usize;
+        let saved = SavedAudio {
+            samples: samples.len(),
+            duration_seconds: frames as f64 / sample_rate as f64,
+            sample_rate,
+            // encode_mp3 downmixes anything beyond stereo to mono
+            channels: if channels > 2 { 1 } else { channels },
+            bits_per_sample: None,
+            sha256: None,
+        };
+        metadata(&saved).map_err(SaveError::Finalize)?;
+        target.write_all(&mp3).map_err(SaveError::WriteSamples)?;
+        target.flush().map_err(SaveError::Finalize)?;
+        return Ok(saved);
+    }
+
+    if output.format == OutputFormat::Opus {
+        let (channels, sample_rate) = (config.channels(), config.sample_rate().0);
+        tracing::info!("Encoding {} samples to Opus at {} kbps", samples.len(), output.opus_bitrate_kbps);
+        let (opus, channels, frames) = encode_opus(samples, channels, sample_rate, output.opus_bitrate_kbps)
+            .map_err(SaveError::WriteSamples)?;
+        let saved = SavedAudio {
+            samples: frames * channels as usize,
+            duration_seconds: frames as f64 / OPUS_SAMPLE_RATE as f64,
+            sample_rate: OPUS_SAMPLE_RATE,
+            channels,
+            bits_per_sample: None,
+            sha256: None,
+        };
+        metadata(&saved).map_err(SaveError::Finalize)?;
+        target.write_all(&opus).map_err(SaveError::WriteSamples)?;
+        target.flush().map_err(SaveError::Finalize)?;
+        return Ok(saved);
+    }
+
+    if output.format != OutputFormat::Wav {
+        // G.711 is 8 kHz mono, whatever the device delivers
+        let mono = downmix(samples, config.channels());
+        let narrowband = resample(&mono, config.sample_rate().0, G711_SAMPLE_RATE);
+        let saved = SavedAudio {
+            samples: narrowband.len(),
+            duration_seconds: narrowband.len() as f64 / G711_SAMPLE_RATE as f64,
+            sample_rate: G711_SAMPLE_RATE,
+            channels: 1,
+            bits_per_sample: Some(8),
+            sha256: None,
+        };
+        let chunks = metadata(&saved).map_err(SaveError::Finalize)?;
+        tracing::info!("Writing {} {:?} samples", narrowband.len(), output.format);
+        write_g711_wav(&mut target, &narrowband, output.format, &chunks).map_err(SaveError::WriteSamples)?;
+        return Ok(saved);
+    }
+
+    let spec = output.wav.spec(config.channels(), config.sample_rate().0);
+    tracing::debug!("Creating WAV with spec: {:?}", spec);
+    let frames = samples.len() / spec.channels.max(1) as usize;
+    let saved = SavedAudio {
+        samples: samples.len(),
+        duration_seconds: frames as f64 / spec.sample_rate as f64,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits_per_sample: Some(spec.bits_per_sample),
+        sha256: None,
+    };
+    let chunks = metadata(&saved).map_err(SaveError::Finalize)?;
+
+    tracing::info!("Writing {} samples to WAV", samples.len());
+    write_wav(&mut target, samples, spec, output.wav, &chunks).map_err(SaveError::WriteSamples)?;
+    Ok(saved)
+}
+
+/// Encode interleaved `samples` in the `config` format as `output` asks and
+/// write them to `filepath`, which only appears once complete
+pub fn save_audio_to_file(
+    samples: &[f32],
+    filepath: &Path,
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+) -> Result<SavedAudio, VoiceError> {
+    check_length(samples.len(), config, 0.0)?;
+    Ok(save_one(samples, filepath, config, output)?)
+}
+
+/// Refuse to save `samples` interleaved samples in the `config` format when
+/// there are none or they last under `minimum` seconds
+pub fn check_length(samples: usize, config: &cpal::SupportedStreamConfig, minimum: f64) -> Result<(), VoiceError> {
+    let frames = samples / config.channels().max(1) as usize;
+    let buffered = frames as f64 / config.sample_rate().0 as f64;
+    match frames == 0 || buffered < minimum {
+        true => Err(VoiceError::TooShort { buffered, minimum }),
+        false => Ok(()),
+    }
+}
+
+fn save_one(
+    samples: &[f32],
+    filepath: &Path,
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+) -> Result<SavedAudio, SaveError> {
+    save_audio_to_files(&[(samples, filepath)], config, output, |_, _| Ok(Vec::new())).map(|mut saved| saved.remove(0))
+}
+
+/// Lowercase hex of `bytes`, as used for SHA-256 digests
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hex SHA-256 of `bytes`
+pub fn sha256(bytes: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, bytes).as_ref())
+}
+
+// Hashes everything written through it on its way to `inner`
+struct HashingWriter<W> {
+    inner: W,
+    digest: ring::digest::Context,
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Create `path` and fill it through `write`, hashing each byte on its way to
+// disk. Returns what `write` did along with the file's hex SHA-256.
+fn write_hashed<T>(
+    path: &Path,
+    write: impl FnOnce(&mut HashingWriter<std::io::BufWriter<std::fs::File>>) -> Result<T, SaveError>,
+) -> Result<(T, String), SaveError> {
+    let file = std::fs::File::create(path).map_err(SaveError::CreateWriter)?;
+    let mut out = HashingWriter {
+        inner: std::io::BufWriter::with_capacity(64 * 1024, file),
+        digest: ring::digest::Context::new(&ring::digest::SHA256),
+    };
+    let written = write(&mut out)?;
+    let HashingWriter { inner, digest } = out;
+    let file = inner.into_inner().map_err(|e| SaveError::WriteSamples(e.into_error()))?;
+    file.sync_all().map_err(SaveError::Finalize)?;
+    Ok((written, hex(digest.finish().as_ref())))
+}
+
+// Remove files left by a failed save, ignoring any that were never created
+fn remove_partial(paths: &[std::path::PathBuf]) {
+    for path in paths {
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Save several files as a unit: either all of them appear or none do. Each
+/// is written next to its final path and only renamed into place once every
+/// one is complete, so a crash or full disk never leaves a truncated file
+/// under a recording's name. The encoder streams each file to disk, hashing it
+/// on the way, and `metadata` returns RIFF chunks for the file at that index,
+/// e.g. Broadcast Wave fields, which go in ahead of the audio. A failing
+/// `metadata` counts as failing to finalize. With `output.encryption` the file
+/// is built in memory and encrypted as a whole, and the hash is of what lands
+/// on disk.
+pub fn save_audio_to_files(
+    files: &[(&[f32], &Path)],
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+    mut metadata: impl FnMut(usize, &SavedAudio) -> std::io::Result<Vec<u8>>,
+) -> Result<Vec<SavedAudio>, SaveError> {
+    // Create output directories if they don't exist
+    for parent in files.iter().filter_map(|(_, path)| path.parent()) {
+        std::fs::create_dir_all(parent)
+            .map_err(|source| SaveError::CreateDir { path: parent.to_path_buf(), source })?;
+    }
+
+    let temps: Vec<_> = files.iter().map(|(_, path)| recordings::temp_path(path)).collect();
+    let written = files.iter().zip(&temps).enumerate()
+        .map(|(index, (&(samples, _), temp))| {
+            let metadata = |saved: &SavedAudio| metadata(index, saved);
+            let (mut saved, sha256) = match output.encryption {
+                None => write_hashed(temp, |out| write_recording(out, samples, config, output, metadata))?,
+                Some(key) => {
+                    let mut bytes = crate::encryption::file_buffer();
+                    let saved = write_recording(&mut bytes, samples, config, output, metadata)?;
+                    let sealed = key.encrypt(bytes).map_err(SaveError::Finalize)?;
+                    write_hashed(temp, |out| std::io::Write::write_all(out, &sealed).map(|()| saved).map_err(SaveError::WriteSamples))?
+                }
+            };
+            saved.sha256 = Some(sha256);
+            Ok(saved)
+        })
+        .collect::<Result<Vec<_>, SaveError>>();
+    let saved = match written {
+        Ok(saved) => saved,
+        Err(e) => {
+            remove_partial(&temps);
+            return Err(e);
+        }
+    };
+
+    for (index, ((_, path), temp)) in files.iter().zip(&temps).enumerate() {
+        if let Err(e) = std::fs::rename(temp, path) {
+            // Take back the files already in place along with the remaining temps
+            let renamed: Vec<_> = files[..index].iter().map(|(_, path)| path.to_path_buf()).collect();
+            remove_partial(&renamed);
+            remove_partial(&temps[index..]);
+            return Err(SaveError::Finalize(e));
+        }
+    }
+    Ok(saved)
+}
+
+/// Append to the WAV at `filepath`, creating it on first use. Samples go after
+/// the existing data and the header lengths are rewritten when the writer is
+/// finalized, so an interrupted append leaves the file readable with its old
+/// contents. Returns what was appended and how many frames preceded it.
+pub fn append_audio_to_file(
+    samples: &[f32],
+    filepath: &Path,
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+) -> std::io::Result<(SavedAudio, u64)> {
+    if output.format != OutputFormat::Wav {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "only WAV recordings can be appended to"));
+    }
+    if !filepath.exists() {
+        return Ok((save_one(samples, filepath, config, output)?, 0));
+    }
+
+    let hound_error = |e: hound::Error| match e {
+        hound::Error::IoError(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", filepath.display(), e)),
+    };
+    let spec = output.wav.spec(config.channels(), config.sample_rate().0);
+    let mut writer = hound::WavWriter::append(filepath).map_err(hound_error)?;
+    let existing = writer.spec();
+    if existing != spec {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(
+            "cannot append to {}: it is {} Hz, {} channels, {} bits, but recordings are {} Hz, {} channels, {} bits",
+            filepath.display(), existing.sample_rate, existing.channels, existing.bits_per_sample,
+            spec.sample_rate, spec.channels, spec.bits_per_sample,
+        )));
+    }
+    let frames_before = writer.len() as u64 / spec.channels.max(1) as u64;
+
+    tracing::info!("Appending {} samples to {} after {} frames", samples.len(), filepath.display(), frames_before);
+    write_samples(&mut writer, samples, output.wav).map_err(hound_error)?;
+    writer.finalize().map_err(hound_error)?;
+    std::fs::File::open(filepath)?.sync_all()?;
+
+    let frames = samples.len() / spec.channels.max(1) as usize;
+    let saved = SavedAudio {
+        samples: samples.len(),
+        duration_seconds: frames as f64 / spec.sample_rate as f64,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits_per_sample: Some(spec.bits_per_sample),
+        sha256: None,
+    };
+    Ok((saved, frames_before))
+}
+
+/// The device format narrowed to a single channel, for files holding one
+/// channel of a split recording
+pub fn channel_config(config: &cpal::SupportedStreamConfig) -> cpal::SupportedStreamConfig {
+    cpal::SupportedStreamConfig::new(1, config.sample_rate(), *config.buffer_size(), config.sample_format())
+}
+
+/// Build the file in memory, e.g. to return it in an HTTP response
+pub fn encode_recording(
+    samples: &[f32],
+    config: &cpal::SupportedStreamConfig,
+    output: OutputOptions,
+) -> std::io::Result<(Vec<u8>, SavedAudio)> {
+    let mut bytes = Vec::new();
+    let saved = write_recording(&mut bytes, samples, config, output, |_| Ok(Vec::new()))?;
+    Ok((bytes, saved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_device_name, wait_for_source, AudioSource, CpalSource, MonoSource, SourceStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn device_names_match_exactly_before_by_substring() {
+        let names: Vec<String> = ["default", "pulse", "Monitor of Built-in Audio", "pulse_monitor"]
+            .iter().map(|s| s.to_string()).collect();
+        assert_eq!(match_device_name(&names, "pulse"), Some(1));
+        assert_eq!(match_device_name(&names, "monitor of built-in"), Some(2));
+        assert_eq!(match_device_name(&names, "MONITOR"), Some(2));
+        assert_eq!(match_device_name(&names, "hdmi"), None);
+    }
+
+    // Delivers one stereo block as soon as it starts
+    struct Stereo;
+
+    impl AudioSource for Stereo {
+        fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String> {
+            Ok(cpal::SupportedStreamConfig::new(2, cpal::SampleRate(48_000), cpal::SupportedBufferSize::Unknown, cpal::SampleFormat::F32))
+        }
+
+        fn name(&self) -> String {
+            "stereo".to_string()
+        }
+
+        fn start(
+            &mut self,
+            config: &cpal::StreamConfig,
+            mut on_block: super::BlockCallback,
+            _on_error: super::ErrorCallback,
+        ) -> Result<SourceStream, super::CaptureError> {
+            assert_eq!(config.channels, 2, "the device is opened with all its channels");
+            on_block(&[0.5, 0.1, -1.0, 0.0]);
+            on_block(&[0.2, 0.2]);
+            Ok(SourceStream::new(()))
+        }
+    }
+
+    #[test]
+    fn forced_mono_averages_the_channels() {
+        let mut source = MonoSource::new(Box::new(Stereo));
+        let config = source.open().unwrap();
+        assert_eq!((config.channels(), config.sample_rate().0), (1, 48_000));
+        assert_eq!(source.name(), "stereo");
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let delivered = Arc::clone(&blocks);
+        let on_block = Box::new(move |data: &[f32]| delivered.lock().unwrap().push(data.to_vec()));
+        let _stream = source.start(&config.config(), on_block, Box::new(|_| ())).unwrap();
+        // The mix buffer is reused, not appended to
+        assert_eq!(*blocks.lock().unwrap(), [vec![0.3, -0.5], vec![0.2]]);
+    }
+
+    #[tokio::test]
+    async fn missing_devices_are_retried_until_the_timeout() {
+        let started = std::time::Instant::now();
+        let mut source = CpalSource::new(Some("no such device, surely".to_string()));
+        let Err(error) = wait_for_source(&mut source, Duration::from_millis(300)).await else {
+            panic!("found a device that shouldn't exist");
+        };
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(error.contains("gave up after") && !error.contains("after 1 attempts"), "{}", error);
+    }
+
+    #[test]
+    fn throughput_averages_whole_seconds_and_counts_silence() {
+        let mut throughput = super::Throughput::default();
+        assert_eq!(throughput.frames_per_second(1_000), None);
+        for second in 1..=3u64 {
+            for tick in 0..10 {
+                throughput.record(second * 1000 + tick * 100, 1600);
+            }
+        }
+        // The second in progress isn't counted yet
+        assert_eq!(throughput.frames_per_second(3_500), Some(16_000.0));
+        // Two seconds without callbacks pull the average down
+        assert_eq!(throughput.frames_per_second(6_000), Some(16_000.0 * 3.0 / 5.0));
+    }
+
+    #[test]
+    fn wakeword_queue_holds_the_requested_audio() {
+        let options = |latency_ms, queue_ms| super::CaptureOptions {
+            latency: Duration::from_millis(latency_ms),
+            wakeword_queue: Duration::from_millis(queue_ms),
+        };
+        assert_eq!(options(100, 1000).wakeword_queue_len(), 10);
+        assert_eq!(options(30, 1000).wakeword_queue_len(), 34);
+        // Never so short that one late wakeup drops audio
+        assert_eq!(options(2000, 10).wakeword_queue_len(), 2);
+
+        let stereo = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(16_000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        assert_eq!(options(100, 1000).wakeword_queue_samples(&stereo), 32_000);
+        assert_eq!(options(2000, 10).wakeword_queue_samples(&stereo), 128_000);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::AudioState;
+use crate::api::ErrorResponse;
+use crate::error::VoiceError;
+
+// Highest gain accepted, about +26 dB
+const MAX_GAIN: f32 = 20.0;
+
+/// Settings that can be changed while running via PATCH /config
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Settings {
+    /// Directory new recordings are written to
+    pub output_dir: String,
+    /// Linear gain applied to captured samples before buffering and detection
+    pub gain: f32,
+    /// Detections closer together than this are ignored
+    pub wakeword_cooldown_ms: u64,
+    /// Maximum frame age before /health fails
+    pub health_timeout_secs: u64,
+    /// Length of the ring buffer; changing it keeps the newest audio
+    pub buffer_seconds: u32,
+}
+
+/// Settings fixed at startup, reported but rejected on PATCH
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct FixedSettings {
+    /// TCP address served on, from --bind
+    pub bind: Option<String>,
+    /// Unix socket served on, from --uds
+    pub uds: Option<String>,
+    /// Resolved device name, not the --input-device pattern
+    pub device: String,
+    /// Captured alongside `device` from a repeated --input-device
+    pub other_devices: Vec<String>,
+    /// Frames per second captured
+    pub sample_rate: u32,
+    /// Channels per frame captured
+    pub channels: u16,
+    /// What happens once the buffer is full, from --buffer-mode
+    pub buffer_mode: String,
+    /// Whether each channel has a ring buffer of its own
+    pub split_channels: bool,
+    /// Sample type the ring buffer stores
+    pub buffer_sample_type: String,
+    /// Format /save writes by default
+    pub output_format: String,
+    /// Latency asked of the input device
+    pub capture_latency_ms: u64,
+    /// Audio the wakeword queue holds
+    pub wakeword_queue_ms: u64,
+    /// Empty with --no-wakeword
+    pub wakewords: Vec<String>,
+    /// Porcupine sensitivity of every keyword
+    pub wakeword_sensitivity: f32,
+    /// Detection high-pass cutoff
+    pub highpass_hz: Option<f32>,
+    /// Whether the buffer is high-pass filtered too
+    pub highpass_buffer: bool,
+    /// Threshold of sound-activated captures
+    pub trigger_level_db: Option<f64>,
+    /// Silence after speech that pauses recording
+    pub auto_stop_silence_ms: Option<u64>,
+    /// Whether requests need the API token; the token itself is never reported
+    pub auth_enabled: bool,
+    /// Whether the server speaks HTTPS
+    pub tls_enabled: bool,
+    /// The --config file options were read from, if any
+    pub config_file: Option<String>,
+}
+
+impl FixedSettings {
+    fn names() -> &'static [&'static str] {
+        &[
+            "bind", "uds", "device", "other_devices", "sample_rate", "channels", "buffer_mode", "split_channels",
+            "buffer_sample_type", "output_format", "capture_latency_ms", "wakeword_queue_ms", "wakewords", "wakeword_sensitivity", "highpass_hz", "highpass_buffer", "trigger_level_db", "auto_stop_silence_ms", "auth_enabled", "tls_enabled", "config_file",
+        ]
+    }
+}
+
+/// Reply to GET /config
+#[derive(Serialize, ToSchema)]
+pub struct ConfigResponse {
+    runtime: Settings,
+    fixed: FixedSettings,
+}
+
+/// Partial update; omitted fields keep their current value
+#[derive(Deserialize, ToSchema)]
+pub struct ConfigPatch {
+    output_dir: Option<String>,
+    gain: Option<f32>,
+    wakeword_cooldown_ms: Option<u64>,
+    health_timeout_secs: Option<u64>,
+    buffer_seconds: Option<u32>,
+    // Anything else, so fixed and unknown fields get a clear error
+    #[serde(flatten)]
+    #[schema(ignore)]
+    other: serde_json::Map<String, Value>,
+}
+
+/// One field's value before and after a PATCH
+#[derive(Serialize, ToSchema)]
+pub struct ConfigChange {
+    #[schema(value_type = Object)]
+    old: Value,
+    #[schema(value_type = Object)]
+    new: Value,
+}
+
+/// Reply to PATCH /config
+#[derive(Serialize, ToSchema)]
+pub struct PatchResponse {
+    // Only the fields whose value actually changed
+    changed: BTreeMap<String, ConfigChange>,
+    runtime: Settings,
+}
+
+impl ConfigPatch {
+    /// Patch of the settings a /start body can override
+    pub fn for_session(output_dir: Option<String>, buffer_seconds: Option<u32>) -> Self {
+        ConfigPatch {
+            output_dir,
+            gain: None,
+            wakeword_cooldown_ms: None,
+            health_timeout_secs: None,
+            buffer_seconds,
+            other: serde_json::Map::new(),
+        }
+    }
+}
+
+impl Settings {
+    // Apply a patch to a copy of these settings, validating every field first
+    fn patched(&self, patch: &ConfigPatch) -> Result<Settings, String> {
+        if let Some(name) = patch.other.keys().next() {
+            return Err(if FixedSettings::names().contains(&name.as_str()) {
+                format!("`{}` cannot be changed at runtime; restart with the new value", name)
+            } else {
+                format!("unknown setting `{}`", name)
+            });
+        }
+
+        let mut next = self.clone();
+        if let Some(dir) = &patch.output_dir {
+            if dir.trim().is_empty() {
+                return Err("`output_dir` must not be empty".to_string());
+            }
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("`output_dir` {} is not usable: {}", dir, e))?;
+            next.output_dir = dir.clone();
+        }
+        if let Some(gain) = patch.gain {
+            if !gain.is_finite() || gain <= 0.0 || gain > MAX_GAIN {
+                return Err(format!("`gain` must be greater than 0 and at most {}", MAX_GAIN));
+            }
+            next.gain = gain;
+        }
+        if let Some(cooldown) = patch.wakeword_cooldown_ms {
+            next.wakeword_cooldown_ms = cooldown;
+        }
+        if let Some(timeout) = patch.health_timeout_secs {
+            if timeout == 0 {
+                return Err("`health_timeout_secs` must be at least 1".to_string());
+            }
+            next.health_timeout_secs = timeout;
+        }
+        if let Some(seconds) = patch.buffer_seconds {
+            if seconds == 0 {
+                return Err("`buffer_seconds` must be at least 1".to_string());
+            }
+            next.buffer_seconds = seconds;
+        }
+        Ok(next)
+    }
+}
+
+// Field-by-field difference between two settings values
+fn diff(old: &Settings, new: &Settings) -> BTreeMap<String, ConfigChange> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(mut new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return BTreeMap::new();
+    };
+    old.into_iter()
+        .filter_map(|(key, old)| {
+            let new = new.remove(&key)?;
+            (old != new).then_some((key, ConfigChange { old, new }))
+        })
+        .collect()
+}
+
+/// Effective configuration, split into runtime-adjustable and fixed settings
+#[utoipa::path(get, path = "/config", responses((status = 200, body = ConfigResponse)))]
+pub async fn get_config(
+    state: web::Data<Arc<AudioState>>,
+    fixed: web::Data<FixedSettings>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(ConfigResponse {
+        runtime: state.settings.read().clone(),
+        fixed: fixed.get_ref().clone(),
+    })
+}
+
+/// Change runtime settings; the whole patch is rejected if any field is invalid
+#[utoipa::path(
+    patch,
+    path = "/config",
+    request_body = ConfigPatch,
+    responses(
+        (status = 200, body = PatchResponse),
+        (status = 400, description = "Invalid, unknown or fixed setting", body = ErrorResponse),
+    ),
+)]
+pub async fn patch_config(
+    state: web::Data<Arc<AudioState>>,
+    patch: web::Json<ConfigPatch>,
+) -> Result<HttpResponse, VoiceError> {
+    let changed = apply_patch(&state, &patch).map_err(VoiceError::Config)?;
+    Ok(HttpResponse::Ok().json(PatchResponse { changed, runtime: state.settings.read().clone() }))
+}
+
+/// Validate and apply a patch, resizing the buffer if its length changed.
+/// Returns the fields whose value changed.
+pub fn apply_patch(state: &AudioState, patch: &ConfigPatch) -> Result<BTreeMap<String, ConfigChange>, String> {
+    // Validate against a copy so the capture callback isn't blocked on directory creation
+    let current = state.settings.read().clone();
+    let next = current.patched(patch).inspect_err(|e| tracing::warn!("Rejected configuration change: {}", e))?;
+    if next.buffer_seconds != current.buffer_seconds {
+        let configs: Vec<_> = std::iter::once(state).chain(state.other_devices.iter().map(|other| &**other))
+            .map(AudioState::input_config)
+            .collect();
+        crate::buffer_bytes(&configs.iter().collect::<Vec<_>>(), next.buffer_seconds, state.buffer_sample_type, state.max_buffer_bytes)
+            .map_err(|e| format!("`buffer_seconds` is too long: {}", e))
+            .inspect_err(|e| tracing::warn!("Rejected configuration change: {}", e))?;
+        state.resize_buffer(next.buffer_seconds);
+    }
+
+    let mut settings = state.settings.write();
+    let changed = diff(&settings, &next);
+    for (name, change) in &changed {
+        tracing::info!("Configuration changed: {} {} -> {}", name, change.old, change.new);
+    }
+    // Other devices follow, so every file of a save lands in the same place
+    for other in &state.other_devices {
+        if next.buffer_seconds != current.buffer_seconds {
+            other.resize_buffer(next.buffer_seconds);
+        }
+        *other.settings.write() = next.clone();
+        other.gain.store(next.gain.to_bits(), Ordering::Relaxed);
+    }
+    state.gain.store(next.gain.to_bits(), Ordering::Relaxed);
+    *settings = next;
+    Ok(changed)
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// TOML type a key's value must have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A boolean, for a switch
+    Switch,
+    /// A non-negative integer
+    Integer,
+    /// A number, integer or not
+    Float,
+    /// Any string
+    String,
+    /// A string, or an array of strings for an option given repeatedly
+    List,
+}
+
+impl Kind {
+    fn expected(self) -> &'static str {
+        match self {
+            Kind::Switch => "a boolean",
+            Kind::Integer => "a non-negative integer",
+            Kind::Float => "a number",
+            Kind::String => "a string",
+            Kind::List => "a string or an array of strings",
+        }
+    }
+}
+
+/// A setting the file may hold, named like its option with underscores
+pub struct Key {
+    /// Name in the file, and of the option with dashes for underscores
+    pub name: &'static str,
+    /// Type the value must have
+    pub kind: Kind,
+    /// Whether there is a command-line option, `--name` in kebab case
+    pub flag: bool,
+    /// Environment variables that take precedence over the file, handed to
+    /// the parser as the option when the command line doesn't set it.
+    /// Without a flag, the file's value is handed on through the first of them.
+    pub env: &'static [&'static str],
+    /// Never logged
+    pub secret: bool,
+}
+
+impl Key {
+    /// A key that also has a command-line option
+    pub const fn option(name: &'static str, kind: Kind) -> Self {
+        Key { name, kind, flag: true, env: &[], secret: false }
+    }
+
+    /// A key without an option, passed on through the environment variable
+    pub const fn variable(name: &'static str, env: &'static [&'static str]) -> Self {
+        Key { name, kind: Kind::String, flag: false, env, secret: false }
+    }
+
+    /// Read from `env` too, ahead of the file
+    pub const fn env(self, env: &'static [&'static str]) -> Self {
+        Key { env, ..self }
+    }
+
+    /// Keep the value out of logs
+    pub const fn secret(self) -> Self {
+        Key { secret: true, ..self }
+    }
+
+    fn flag_name(&self) -> String {
+        format!("--{}", self.name.replace('_', "-"))
+    }
+}
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Given on the command line
+    CommandLine,
+    /// Set through the named environment variable
+    Environment(&'static str),
+    /// Read from the --config file
+    File,
+}
+
+/// Settings read from a --config file, checked against the known keys
+#[derive(Debug)]
+pub struct ConfigFile {
+    /// The file read
+    pub path: PathBuf,
+    // Each known key set, as the strings passed to its option
+    values: BTreeMap<&'static str, Vec<String>>,
+    /// Unknown keys, each naming the key
+    pub warnings: Vec<String>,
+}
+
+// The value as option arguments, or what was expected instead
+fn option_values(key: &Key, value: &toml_edit::Value) -> Result<Vec<String>, &'static str> {
+    use toml_edit::Value;
+    match (key.kind, value) {
+        (Kind::Switch, Value::Boolean(on)) => Ok(if *on.value() { vec!["true".to_string()] } else { Vec::new() }),
+        (Kind::Integer, Value::Integer(n)) if *n.value() >= 0 => Ok(vec![n.value().to_string()]),
+        (Kind::Float, Value::Integer(n)) => Ok(vec![n.value().to_string()]),
+        (Kind::Float, Value::Float(x)) => Ok(vec![x.value().to_string()]),
+        (Kind::String | Kind::List, Value::String(s)) => Ok(vec![s.value().clone()]),
+        (Kind::List, Value::Array(items)) => items.iter()
+            .map(|item| item.as_str().map(str::to_string).ok_or(key.kind.expected()))
+            .collect(),
+        _ => Err(key.kind.expected()),
+    }
+}
+
+impl ConfigFile {
+    /// Read `path`. Tables nest one level, so `[s3] bucket` is `s3_bucket`.
+    /// Syntax errors and values of the wrong type fail, naming the key and
+    /// the type expected; unknown keys only warn.
+    pub fn load(path: &Path, keys: &[Key]) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(path, &text, keys)
+    }
+
+    /// Check `text` as if read from `path`, which only names it in messages
+    pub fn parse(path: &Path, text: &str, keys: &[Key]) -> Result<Self, String> {
+        let document = toml_edit::Document::parse(text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut entries = Vec::new();
+        for (name, item) in document.as_table().iter() {
+            match item.as_table_like() {
+                Some(table) => entries.extend(table.iter().map(|(inner, item)| (format!("{}_{}", name, inner), item))),
+                None => entries.push((name.to_string(), item)),
+            }
+        }
+
+        let mut file = ConfigFile { path: path.to_path_buf(), values: BTreeMap::new(), warnings: Vec::new() };
+        let mut errors = Vec::new();
+        for (name, item) in entries {
+            let Some(key) = keys.iter().find(|key| key.name == name) else {
+                file.warnings.push(format!("{}: unknown key `{}` is ignored", path.display(), name));
+                continue;
+            };
+            let found = item.type_name();
+            match item.as_value().ok_or(key.kind.expected()).and_then(|value| option_values(key, value)) {
+                Ok(values) => {
+                    file.values.insert(key.name, values);
+                }
+                Err(expected) => errors.push(format!("`{}` must be {}, got {}", name, expected, found)),
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(file),
+            false => Err(format!("{}: {}", path.display(), errors.join("; "))),
+        }
+    }
+}
+
+/// Value of `--name` on the command line, before any parsing
+pub fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let flag = format!("--{}", name);
+    args.windows(2).find(|pair| pair[0] == flag).map(|pair| pair[1].as_str())
+}
+
+/// A setting not left at its default
+#[derive(Debug, PartialEq)]
+pub struct Setting {
+    /// Key's name
+    pub name: &'static str,
+    /// Where the value came from
+    pub source: Source,
+    /// As given, or `<redacted>` for a secret
+    pub value: String,
+}
+
+/// Command line and environment with the file applied underneath
+pub struct Merged {
+    /// The arguments to parse: the file's options, then the command line's
+    pub args: Vec<String>,
+    /// Environment variables to set from the file
+    pub env: Vec<(&'static str, String)>,
+    /// Each setting not left at its default, for the startup log
+    pub sources: Vec<Setting>,
+}
+
+/// Apply `file` where neither `args`, the command line without the program
+/// name, nor the environment set a key: command line over environment over
+/// file over defaults
+pub fn merge(file: Option<&ConfigFile>, keys: &[Key], args: &[String]) -> Merged {
+    // What the command line sets, walking it as the parser will
+    let mut given: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut tokens = args.iter();
+    while let Some(token) = tokens.next() {
+        let Some(key) = keys.iter().find(|key| key.flag && key.flag_name() == *token) else {
+            continue;
+        };
+        let values = given.entry(key.name).or_default();
+        match key.kind {
+            Kind::Switch => values.push("true"),
+            _ => values.extend(tokens.next().map(String::as_str)),
+        }
+    }
+
+    let mut merged = Merged { args: Vec::new(), env: Vec::new(), sources: Vec::new() };
+    for key in keys {
+        let shown = |values: &[&str]| match key.secret {
+            true => "<redacted>".to_string(),
+            false => values.join(", "),
+        };
+        if let Some(values) = given.get(key.name) {
+            merged.sources.push(Setting { name: key.name, source: Source::CommandLine, value: shown(values) });
+            continue;
+        }
+        let set = key.env.iter().find_map(|&name| {
+            std::env::var(name).ok().filter(|value| !value.is_empty()).map(|value| (name, value))
+        });
+        if let Some((name, value)) = set {
+            merged.sources.push(Setting { name: key.name, source: Source::Environment(name), value: shown(&[&value]) });
+            // So the variable is read here and nowhere else
+            if key.flag {
+                merged.args.push(key.flag_name());
+                merged.args.push(value);
+            }
+            continue;
+        }
+        let Some(values) = file.and_then(|file| file.values.get(key.name)).filter(|values| !values.is_empty()) else {
+            continue;
+        };
+        match (key.flag, key.env.first()) {
+            (true, _) => {
+                for value in values {
+                    merged.args.push(key.flag_name());
+                    if key.kind != Kind::Switch {
+                        merged.args.push(value.clone());
+                    }
+                }
+            }
+            (false, Some(&name)) => merged.env.extend(values.first().map(|value| (name, value.clone()))),
+            (false, None) => continue,
+        }
+        let values: Vec<&str> = values.iter().map(String::as_str).collect();
+        merged.sources.push(Setting { name: key.name, source: Source::File, value: shown(&values) });
+    }
+    merged.args.extend(args.iter().cloned());
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{merge, ConfigFile, Key, Kind, Source};
+
+    const KEYS: &[Key] = &[
+        Key::option("seconds", Kind::Integer),
+        Key::option("gain", Kind::Float),
+        Key::option("highpass_buffer", Kind::Switch),
+        Key::option("cors_origin", Kind::List),
+        Key::option("s3_bucket", Kind::String),
+        Key::option("bind", Kind::String).env(&["CONFIG_FILE_TEST_BIND"]),
+        Key::variable("s3_access_key_id", &["CONFIG_FILE_TEST_KEY"]).secret(),
+    ];
+
+    #[test]
+    fn file_values_sit_below_the_command_line_and_environment() {
+        let text = r#"
+            seconds = 120
+            gain = 2
+            highpass_buffer = true
+            cors_origin = ["https://a.example", "https://b.example"]
+            bind = "0.0.0.0:9000"
+            s3_access_key_id = "AKIA"
+            wakewords = ["porcupine"]
+
+            [s3]
+            bucket = "recordings"
+        "#;
+        let file = ConfigFile::parse(Path::new("test.toml"), text, KEYS).unwrap();
+        assert_eq!(file.warnings, ["test.toml: unknown key `wakewords` is ignored"]);
+
+        std::env::set_var("CONFIG_FILE_TEST_BIND", "127.0.0.1:7000");
+        let cli = ["--seconds".to_string(), "30".to_string(), "--highpass-buffer".to_string()];
+        let merged = merge(Some(&file), KEYS, &cli);
+        assert_eq!(merged.args, [
+            "--gain", "2", "--cors-origin", "https://a.example", "--cors-origin", "https://b.example",
+            "--s3-bucket", "recordings", "--bind", "127.0.0.1:7000", "--seconds", "30", "--highpass-buffer",
+        ]);
+        assert_eq!(merged.env, [("CONFIG_FILE_TEST_KEY", "AKIA".to_string())]);
+        let source = |name| merged.sources.iter().find(|setting| setting.name == name).map(|setting| (setting.source.clone(), setting.value.as_str()));
+        assert_eq!(source("seconds"), Some((Source::CommandLine, "30")));
+        assert_eq!(source("highpass_buffer"), Some((Source::CommandLine, "true")));
+        assert_eq!(source("bind"), Some((Source::Environment("CONFIG_FILE_TEST_BIND"), "127.0.0.1:7000")));
+        assert_eq!(source("s3_access_key_id"), Some((Source::File, "<redacted>")));
+    }
+
+    #[test]
+    fn type_errors_name_the_key_and_the_type_expected() {
+        let text = "seconds = \"sixty\"\ngain = true\ncors_origin = [1]\n";
+        let error = ConfigFile::parse(Path::new("test.toml"), text, KEYS).unwrap_err();
+        assert_eq!(error, "test.toml: `seconds` must be a non-negative integer, got string; \
+            `gain` must be a number, got boolean; `cors_origin` must be a string or an array of strings, got array");
+        assert!(ConfigFile::parse(Path::new("test.toml"), "seconds = ", KEYS).unwrap_err().starts_with("test.toml: "));
+    }
+}
@@ -0,0 +1,886 @@
+use std::io::{Seek, Write};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Sample representation in the WAV file, parsed from the CLI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleKind {
+    /// Signed integer PCM
+    Int,
+    /// IEEE float
+    Float,
+}
+
+impl std::str::FromStr for SampleKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int" => Ok(SampleKind::Int),
+            "float" => Ok(SampleKind::Float),
+            other => Err(format!("unknown sample format `{}`, expected `int` or `float`", other)),
+        }
+    }
+}
+
+/// Validated sample format and bit depth for WAV output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavEncoding {
+    /// Integer or float samples
+    pub kind: SampleKind,
+    /// Bits in each sample
+    pub bits_per_sample: u16,
+}
+
+impl WavEncoding {
+    /// Check that `bits_per_sample` can be written as `kind`
+    pub fn new(kind: SampleKind, bits_per_sample: u16) -> Result<Self, String> {
+        let valid = match kind {
+            SampleKind::Int => matches!(bits_per_sample, 8 | 16 | 24 | 32),
+            SampleKind::Float => bits_per_sample == 32,
+        };
+        if !valid {
+            return Err(format!(
+                "{}-bit {} samples are not supported in WAV output (int: 8/16/24/32, float: 32)",
+                bits_per_sample,
+                match kind { SampleKind::Int => "int", SampleKind::Float => "float" },
+            ));
+        }
+        Ok(WavEncoding { kind, bits_per_sample })
+    }
+
+    /// Closest WAV encoding to what the device delivers natively
+    pub fn for_device(format: cpal::SampleFormat) -> Self {
+        use cpal::SampleFormat::*;
+        let (kind, bits) = match format {
+            I8 | U8 => (SampleKind::Int, 8),
+            I16 | U16 => (SampleKind::Int, 16),
+            I32 | U32 | I64 | U64 => (SampleKind::Int, 32),
+            _ => (SampleKind::Float, 32),
+        };
+        WavEncoding { kind, bits_per_sample: bits }
+    }
+
+    /// Device default with optional CLI overrides applied on top
+    pub fn resolve(
+        device: cpal::SampleFormat,
+        kind: Option<SampleKind>,
+        bits_per_sample: Option<u16>,
+    ) -> Result<Self, String> {
+        let default = Self::for_device(device);
+        let kind = kind.unwrap_or(default.kind);
+        let bits = bits_per_sample.unwrap_or(match kind {
+            _ if kind == default.kind => default.bits_per_sample,
+            SampleKind::Int => 16,
+            SampleKind::Float => 32,
+        });
+        Self::new(kind, bits)
+    }
+
+    /// The hound spec for writing this encoding
+    pub fn spec(&self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: self.bits_per_sample,
+            sample_format: match self.kind {
+                SampleKind::Int => hound::SampleFormat::Int,
+                SampleKind::Float => hound::SampleFormat::Float,
+            },
+        }
+    }
+}
+
+/// Sample layout of --output-format raw, parsed from the CLI
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RawEncoding {
+    /// 32-bit float, little-endian, as ffmpeg's `-f f32le`
+    F32Le,
+    /// 16-bit signed integer, little-endian, as ffmpeg's `-f s16le`
+    #[default]
+    S16Le,
+}
+
+impl std::str::FromStr for RawEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32le" => Ok(RawEncoding::F32Le),
+            "s16le" => Ok(RawEncoding::S16Le),
+            other => Err(format!("unknown raw encoding `{}`, expected `f32le` or `s16le`", other)),
+        }
+    }
+}
+
+impl RawEncoding {
+    /// Bytes each sample takes in the file
+    pub fn bytes_per_sample(&self) -> u16 {
+        match self {
+            RawEncoding::F32Le => 4,
+            RawEncoding::S16Le => 2,
+        }
+    }
+
+    /// ffmpeg's name for the format, e.g. for `-f`
+    pub fn name(&self) -> &'static str {
+        match self {
+            RawEncoding::F32Le => "f32le",
+            RawEncoding::S16Le => "s16le",
+        }
+    }
+}
+
+/// Interleaved samples as headerless PCM; integers are clamped like WAV output
+pub fn encode_raw(samples: &[f32], encoding: RawEncoding) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * encoding.bytes_per_sample() as usize);
+    for &sample in samples {
+        match encoding {
+            RawEncoding::F32Le => bytes.extend_from_slice(&sample.to_le_bytes()),
+            RawEncoding::S16Le => bytes.extend_from_slice(&to_i16(sample).to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Decode a WAV file into interleaved f32 samples in [-1, 1]
+pub fn read_wav(bytes: &[u8]) -> hound::Result<(hound::WavSpec, Vec<f32>)> {
+    let reader = hound::WavReader::new(std::io::Cursor::new(bytes))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().collect::<hound::Result<Vec<_>>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.into_samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 * scale))
+                .collect::<hound::Result<Vec<_>>>()?
+        }
+    };
+    Ok((spec, samples))
+}
+
+// Samples converted at a time by write_wav
+const WAV_BLOCK_SAMPLES: usize = 64 * 1024;
+
+// KSDATAFORMAT_SUBTYPE_PCM; the float subtype differs only in its first byte
+const SUBTYPE_PCM: [u8; 16] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+/// Write a complete WAV in the layout hound uses, with the header sizes known
+/// up front so `out` is only ever appended to and can be hashed as it goes.
+/// `chunks` are placed between the fmt chunk and the audio data, e.g.
+/// Broadcast Wave metadata. Samples are converted a block at a time, so the
+/// file never exists in memory as a whole.
+pub fn write_wav<W: Write>(
+    mut out: W,
+    samples: &[f32],
+    spec: hound::WavSpec,
+    encoding: WavEncoding,
+    chunks: &[u8],
+) -> std::io::Result<()> {
+    let bytes_per_sample = encoding.bits_per_sample as usize / 8;
+    let block_align = spec.channels as u32 * bytes_per_sample as u32;
+    // Hound writes the older PCMWAVEFORMAT when it can, as more readers take it
+    let extensible = spec.channels > 2 || encoding.bits_per_sample > 16;
+    let fmt_len: u32 = if extensible { 40 } else { 16 };
+    let data_len = samples.len() as u64 * bytes_per_sample as u64;
+    let padding = data_len % 2;
+    let riff_len = 4 + (8 + fmt_len as u64) + chunks.len() as u64 + 8 + data_len + padding;
+    let (Ok(riff_len), Ok(data_len)) = (u32::try_from(riff_len), u32::try_from(data_len)) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "recording is too large for a WAV file"));
+    };
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&riff_len.to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&fmt_len.to_le_bytes())?;
+    let format_tag: u16 = match (extensible, encoding.kind) {
+        (true, _) => 0xfffe,
+        (false, SampleKind::Int) => 1,
+        (false, SampleKind::Float) => 3,
+    };
+    out.write_all(&format_tag.to_le_bytes())?;
+    out.write_all(&spec.channels.to_le_bytes())?;
+    out.write_all(&spec.sample_rate.to_le_bytes())?;
+    out.write_all(&(spec.sample_rate * block_align).to_le_bytes())?;
+    out.write_all(&(block_align as u16).to_le_bytes())?;
+    out.write_all(&encoding.bits_per_sample.to_le_bytes())?;
+    if extensible {
+        out.write_all(&22u16.to_le_bytes())?; // extension size
+        out.write_all(&encoding.bits_per_sample.to_le_bytes())?; // valid bits
+        // The first channels in order, as hound assigns them
+        let channel_mask = (0..spec.channels.min(18) as u32).fold(0u32, |mask, channel| mask | 1 << channel);
+        out.write_all(&channel_mask.to_le_bytes())?;
+        let mut subtype = SUBTYPE_PCM;
+        if encoding.kind == SampleKind::Float {
+            subtype[0] = 0x03;
+        }
+        out.write_all(&subtype)?;
+    }
+
+    out.write_all(chunks)?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    let max = ((1i64 << (encoding.bits_per_sample - 1)) - 1) as f32;
+    let mut block = Vec::with_capacity(WAV_BLOCK_SAMPLES.min(samples.len()) * bytes_per_sample);
+    for part in samples.chunks(WAV_BLOCK_SAMPLES) {
+        block.clear();
+        match (encoding.kind, encoding.bits_per_sample) {
+            (SampleKind::Float, _) => part.iter().for_each(|sample| block.extend_from_slice(&sample.to_le_bytes())),
+            // 8-bit WAV samples are unsigned
+            (SampleKind::Int, 8) => block.extend(part.iter().map(|&sample| ((sample.clamp(-1.0, 1.0) * max) as i32 + 128) as u8)),
+            (SampleKind::Int, _) => {
+                for &sample in part {
+                    let value = (sample.clamp(-1.0, 1.0) * max) as i32;
+                    block.extend_from_slice(&value.to_le_bytes()[..bytes_per_sample]);
+                }
+            }
+        }
+        out.write_all(&block)?;
+    }
+    if padding == 1 {
+        out.write_all(&[0])?;
+    }
+    out.flush()
+}
+
+/// Write f32 samples, converting to the writer's integer depth when needed
+pub fn write_samples<W: Write + Seek>(
+    writer: &mut hound::WavWriter<W>,
+    samples: &[f32],
+    encoding: WavEncoding,
+) -> hound::Result<()> {
+    match encoding.kind {
+        SampleKind::Float => {
+            for &sample in samples {
+                writer.write_sample(sample)?;
+            }
+        }
+        SampleKind::Int => {
+            let max = ((1i64 << (encoding.bits_per_sample - 1)) - 1) as f32;
+            for &sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * max) as i32)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Container/codec written by /save
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// PCM WAV in --bits-per-sample and --sample-format
+    Wav,
+    /// G.711 mu-law WAV, 8 kHz mono
+    Ulaw,
+    /// G.711 A-law WAV, 8 kHz mono
+    Alaw,
+    /// MPEG layer III via LAME
+    Mp3,
+    /// Opus in an Ogg container, 48 kHz
+    Opus,
+    /// Headerless interleaved PCM in --raw-encoding, at the capture rate
+    Raw,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wav" => Ok(OutputFormat::Wav),
+            "ulaw" => Ok(OutputFormat::Ulaw),
+            "alaw" => Ok(OutputFormat::Alaw),
+            "mp3" => Ok(OutputFormat::Mp3),
+            "opus" => Ok(OutputFormat::Opus),
+            "raw" => Ok(OutputFormat::Raw),
+            other => Err(format!("unknown output format `{}`, expected `wav`, `ulaw`, `alaw`, `mp3`, `opus` or `raw`", other)),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// File extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Opus => "opus",
+            OutputFormat::Raw => "raw",
+            _ => "wav",
+        }
+    }
+
+    /// Whether files are RIFF/WAVE containers that can carry extra chunks
+    pub fn is_riff(&self) -> bool {
+        matches!(self, OutputFormat::Wav | OutputFormat::Ulaw | OutputFormat::Alaw)
+    }
+
+    /// MIME type served for downloads
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Mp3 => "audio/mpeg",
+            OutputFormat::Opus => "audio/ogg",
+            OutputFormat::Raw => "application/octet-stream",
+            _ => "audio/wav",
+        }
+    }
+}
+
+/// Everything needed to encode a saved recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputOptions {
+    /// Container and codec
+    pub format: OutputFormat,
+    /// Sample encoding of PCM WAV output
+    pub wav: WavEncoding,
+    /// Bitrate of MP3 output
+    pub mp3_bitrate_kbps: u16,
+    /// Bitrate of Opus output
+    pub opus_bitrate_kbps: u16,
+    /// Sample encoding of raw output
+    pub raw: RawEncoding,
+    /// Resample WAV, MP3 and raw output to this rate; None keeps the capture rate
+    pub sample_rate: Option<u32>,
+    /// Encrypt files as they are saved; encodes kept in memory, such as
+    /// downloads, stay plain
+    pub encryption: Option<crate::encryption::Key>,
+}
+
+impl OutputOptions {
+    /// Upper estimate of the encoded size of `samples` interleaved samples,
+    /// used to check the output budget before encoding
+    pub fn estimated_size(&self, samples: usize, channels: u16, sample_rate: u32) -> u64 {
+        let frames = (samples / channels.max(1) as usize) as u64;
+        let seconds = frames as f64 / sample_rate.max(1) as f64;
+        let output_samples = match self.sample_rate {
+            Some(rate) => (seconds * rate as f64).ceil() as u64 * channels.max(1) as u64,
+            None => samples as u64,
+        };
+        // At the bitrate, plus container headers and some slack for framing
+        let at_bitrate = |kbps: u16| (seconds * kbps as f64 * 125.0).ceil() as u64 + 8192;
+        match self.format {
+            // Headers plus room for the Broadcast Wave metadata chunks
+            OutputFormat::Wav => output_samples * (self.wav.bits_per_sample / 8) as u64 + 44 + 1024,
+            OutputFormat::Ulaw | OutputFormat::Alaw => frames * G711_SAMPLE_RATE as u64 / sample_rate.max(1) as u64 + 58 + 1024,
+            OutputFormat::Mp3 => at_bitrate(self.mp3_bitrate_kbps),
+            OutputFormat::Opus => at_bitrate(self.opus_bitrate_kbps),
+            OutputFormat::Raw => output_samples * self.raw.bytes_per_sample() as u64,
+        }
+    }
+}
+
+/// Telephony rate required by G.711
+pub const G711_SAMPLE_RATE: u32 = 8000;
+
+/// Average interleaved channels into one
+pub fn downmix(samples: &[f32], channels: u16) -> Vec<f32> {
+    let mut mono = Vec::with_capacity(samples.len() / channels.max(1) as usize);
+    downmix_into(&mut mono, samples, channels);
+    mono
+}
+
+/// [`downmix`] into `out` in place of what it held, so a buffer reused from
+/// one block to the next stops allocating once it is big enough
+pub fn downmix_into(out: &mut Vec<f32>, samples: &[f32], channels: u16) {
+    let channels = channels.max(1) as usize;
+    out.clear();
+    if channels == 1 {
+        out.extend_from_slice(samples);
+        return;
+    }
+    out.extend(samples.chunks_exact(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32));
+}
+
+/// Default peak level for /save?normalize=true
+pub const DEFAULT_NORMALIZE_TARGET_DBFS: f64 = -1.0;
+
+// Peaks below this (-60 dBFS) are taken as silence and left alone, rather
+// than raising the noise floor to full scale
+const NORMALIZE_SILENCE_PEAK: f32 = 0.001;
+
+/// What peak normalization did to a save
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Normalization {
+    /// Whether the samples were scaled
+    pub applied: bool,
+    /// Peak before scaling; None when every sample is zero
+    pub peak_dbfs: Option<f64>,
+    /// Gain applied in dB; 0 when nothing was scaled
+    pub gain_db: f64,
+    /// Why nothing was scaled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+}
+
+fn to_dbfs(amplitude: f32) -> f64 {
+    20.0 * (amplitude as f64).log10()
+}
+
+/// RMS level of interleaved samples in dBFS; silence is negative infinity
+pub fn rms_dbfs(samples: &[f32]) -> f64 {
+    let mean_square = samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>() / samples.len().max(1) as f64;
+    to_dbfs(mean_square.sqrt() as f32)
+}
+
+/// Scale `samples` so the loudest one lands at `target_dbfs`. This may also
+/// turn clipped audio down; essentially silent audio is left as it is.
+pub fn normalize_peak(samples: &mut [f32], target_dbfs: f64) -> Normalization {
+    let peak = samples.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()));
+    let peak_dbfs = (peak > 0.0).then(|| to_dbfs(peak));
+    if peak < NORMALIZE_SILENCE_PEAK {
+        return Normalization {
+            applied: false,
+            peak_dbfs,
+            gain_db: 0.0,
+            skipped: Some(format!("peak is below {:.0} dBFS, treated as silence", to_dbfs(NORMALIZE_SILENCE_PEAK))),
+        };
+    }
+    let gain = (10f64.powf(target_dbfs / 20.0) / peak as f64) as f32;
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+    Normalization { applied: true, peak_dbfs, gain_db: to_dbfs(gain), skipped: None }
+}
+
+/// De-interleave into one buffer per channel
+pub fn split_channels(samples: &[f32], channels: u16) -> Vec<Vec<f32>> {
+    let channels = channels.max(1) as usize;
+    (0..channels)
+        .map(|channel| samples.iter().skip(channel).step_by(channels).copied().collect())
+        .collect()
+}
+
+// Zero crossings of the resampling sinc on each side of its centre
+const SINC_ZERO_CROSSINGS: f64 = 16.0;
+// Cutoff as a fraction of the lower Nyquist frequency, so the kernel has
+// rolled off by the time it reaches it
+const SINC_CUTOFF: f64 = 0.9;
+
+/// Resample mono audio by Blackman-windowed sinc interpolation. The sinc cuts
+/// off below the lower of the two Nyquist frequencies, so downsampling
+/// low-passes first and nothing above the new Nyquist folds back as an alias.
+pub fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = from_rate as f64 / to_rate as f64;
+    let out_len = (samples.len() as f64 / ratio).floor() as usize;
+    // Relative to the input's Nyquist frequency
+    let cutoff = SINC_CUTOFF * ratio.recip().min(1.0);
+    // In input samples
+    let half_width = SINC_ZERO_CROSSINGS / cutoff;
+    let kernel = |offset: f64| {
+        let t = std::f64::consts::PI * offset * cutoff;
+        let sinc = if t == 0.0 { 1.0 } else { t.sin() / t };
+        let u = std::f64::consts::PI * offset / half_width;
+        sinc * (0.42 + 0.5 * u.cos() + 0.08 * (2.0 * u).cos())
+    };
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let first = (pos - half_width).ceil().max(0.0) as usize;
+            let last = ((pos + half_width).floor() as usize).min(samples.len() - 1);
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for (index, &sample) in samples.iter().enumerate().take(last + 1).skip(first) {
+                let weight = kernel(pos - index as f64);
+                sum += sample as f64 * weight;
+                weights += weight;
+            }
+            // Normalised so a constant signal keeps its level, even where the
+            // kernel runs off either end of the input
+            if weights.abs() < f64::EPSILON { 0.0 } else { (sum / weights) as f32 }
+        })
+        .collect()
+}
+
+/// Resample interleaved audio one channel at a time
+pub fn resample_interleaved(samples: &[f32], channels: u16, from_rate: u32, to_rate: u32) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    if channels == 1 {
+        return resample(samples, from_rate, to_rate);
+    }
+    let resampled: Vec<Vec<f32>> = (0..channels)
+        .map(|channel| {
+            let plane: Vec<f32> = samples.iter().skip(channel).step_by(channels).copied().collect();
+            resample(&plane, from_rate, to_rate)
+        })
+        .collect();
+    let frames = resampled.iter().map(Vec::len).min().unwrap_or(0);
+    (0..frames)
+        .flat_map(|frame| resampled.iter().map(move |plane| plane[frame]))
+        .collect()
+}
+
+/// One-pole high-pass filter over interleaved audio, keeping each channel's
+/// state between calls so consecutive blocks filter as one stream
+pub struct HighPass {
+    coefficient: f32,
+    // Previous input and output of each channel
+    previous: Vec<(f32, f32)>,
+}
+
+impl HighPass {
+    /// A first-order filter at `cutoff_hz` over `channels` interleaved channels
+    pub fn new(cutoff_hz: f32, sample_rate: u32, channels: u16) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate as f32;
+        HighPass { coefficient: rc / (rc + dt), previous: vec![(0.0, 0.0); channels.max(1) as usize] }
+    }
+
+    /// Filter interleaved samples in place, carrying state over between calls
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.previous.len();
+        for (index, sample) in samples.iter_mut().enumerate() {
+            let (input, output) = &mut self.previous[index % channels];
+            let filtered = self.coefficient * (*output + *sample - *input);
+            (*input, *output) = (*sample, filtered);
+            *sample = filtered;
+        }
+    }
+}
+
+/// Clamp a float sample to [-1, 1] and scale it to 16 bits
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// ITU-T G.711 mu-law compression of a 16-bit sample
+pub fn linear_to_ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let mut value = sample as i32;
+    let sign = if value < 0 {
+        value = -value;
+        0x80
+    } else {
+        0
+    };
+    value = value.min(CLIP) + BIAS;
+    let exponent = 7 - ((value >> 7) as u8).leading_zeros() as i32;
+    let mantissa = (value >> (exponent + 3)) & 0x0F;
+    !((sign | (exponent << 4) | mantissa) as u8)
+}
+
+/// ITU-T G.711 A-law compression of a 16-bit sample
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    const SEGMENT_END: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+    let mut value = (sample as i32) >> 3;
+    let mask = if value >= 0 {
+        0xD5
+    } else {
+        value = -value - 1;
+        0x55
+    };
+    let Some(segment) = SEGMENT_END.iter().position(|&end| value <= end) else {
+        return 0x7F ^ mask;
+    };
+    let quantized = if segment < 2 { (value >> 1) & 0x0F } else { (value >> segment) & 0x0F };
+    (((segment as i32) << 4) | quantized) as u8 ^ mask
+}
+
+/// Write a mono G.711 WAV (format tag 7 for mu-law, 6 for A-law) with the
+/// `fact` chunk non-PCM formats require, and `chunks` just before the audio
+/// data. Returns the number of samples written.
+pub fn write_g711_wav<W: Write>(mut out: W, mono: &[f32], format: OutputFormat, chunks: &[u8]) -> std::io::Result<usize> {
+    let (format_tag, compress): (u16, fn(i16) -> u8) = match format {
+        OutputFormat::Ulaw => (7, linear_to_ulaw),
+        OutputFormat::Alaw => (6, linear_to_alaw),
+        _ => return Err(std::io::Error::other("not a G.711 format")),
+    };
+    let data: Vec<u8> = mono.iter().map(|&s| compress(to_i16(s))).collect();
+    let data_len = data.len() as u32;
+    let padding = data_len % 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(4 + (8 + 18) + (8 + 4) + chunks.len() as u32 + 8 + data_len + padding).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&18u32.to_le_bytes())?;
+    out.write_all(&format_tag.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // channels
+    out.write_all(&G711_SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&G711_SAMPLE_RATE.to_le_bytes())?; // byte rate: one byte per sample
+    out.write_all(&1u16.to_le_bytes())?; // block align
+    out.write_all(&8u16.to_le_bytes())?; // bits per sample
+    out.write_all(&0u16.to_le_bytes())?; // extension size
+
+    out.write_all(b"fact")?;
+    out.write_all(&4u32.to_le_bytes())?;
+    out.write_all(&data_len.to_le_bytes())?;
+
+    out.write_all(chunks)?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    out.write_all(&data)?;
+    if padding == 1 {
+        out.write_all(&[0])?;
+    }
+    out.flush()?;
+    Ok(data.len())
+}
+
+/// Map a kbps value onto the bitrates LAME accepts
+pub fn mp3_bitrate(kbps: u16) -> Result<mp3lame_encoder::Bitrate, String> {
+    use mp3lame_encoder::Bitrate::*;
+    Ok(match kbps {
+        8 => Kbps8,
+        16 => Kbps16,
+        24 => Kbps24,
+        32 => Kbps32,
+        40 => Kbps40,
+        48 => Kbps48,
+        64 => Kbps64,
+        80 => Kbps80,
+        96 => Kbps96,
+        112 => Kbps112,
+        128 => Kbps128,
+        160 => Kbps160,
+        192 => Kbps192,
+        224 => Kbps224,
+        256 => Kbps256,
+        320 => Kbps320,
+        other => return Err(format!(
+            "unsupported MP3 bitrate {} kbps, expected one of 8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320",
+            other
+        )),
+    })
+}
+
+/// Encode interleaved f32 samples to MP3. LAME takes mono or stereo, so any
+/// other channel count is downmixed to mono first.
+pub fn encode_mp3(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    bitrate_kbps: u16,
+) -> std::io::Result<Vec<u8>> {
+    use mp3lame_encoder::{Builder, FlushNoGap, InterleavedPcm, MonoPcm};
+    let lame_error = |e: &dyn std::fmt::Display| std::io::Error::other(format!("MP3 encoder: {}", e));
+
+    let (samples, channels) = match channels {
+        1 | 2 => (std::borrow::Cow::Borrowed(samples), channels),
+        _ => (std::borrow::Cow::Owned(downmix(samples, channels)), 1),
+    };
+
+    let mut builder = Builder::new().ok_or_else(|| std::io::Error::other("MP3 encoder: failed to allocate LAME"))?;
+    builder.set_num_channels(channels as u8).map_err(|e| lame_error(&e))?;
+    builder.set_sample_rate(sample_rate).map_err(|e| lame_error(&e))?;
+    builder.set_brate(mp3_bitrate(bitrate_kbps).map_err(std::io::Error::other)?).map_err(|e| lame_error(&e))?;
+    let mut encoder = builder.build().map_err(|e| lame_error(&e))?;
+
+    let frames = samples.len() / channels as usize;
+    let mut out = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(frames));
+    if channels == 1 {
+        encoder.encode_to_vec(MonoPcm(&samples[..]), &mut out).map_err(|e| lame_error(&e))?;
+    } else {
+        encoder.encode_to_vec(InterleavedPcm(&samples[..frames * 2]), &mut out).map_err(|e| lame_error(&e))?;
+    }
+    // Room for the final frames held back by the encoder
+    out.reserve(7200);
+    encoder.flush_to_vec::<FlushNoGap>(&mut out).map_err(|e| lame_error(&e))?;
+    Ok(out)
+}
+
+/// Opus always runs at 48 kHz here; the input rate is only recorded in the header
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+/// 20 ms per packet
+pub const OPUS_FRAME_SIZE: usize = 960;
+// Upper bound libopus recommends for a single packet
+const OPUS_MAX_PACKET: usize = 4000;
+/// Supported bitrate range in kbps
+pub const OPUS_BITRATE_RANGE: std::ops::RangeInclusive<u16> = 6..=510;
+
+fn opus_error(action: &str, code: i32) -> std::io::Error {
+    std::io::Error::other(format!("Opus encoder: {} failed with error {}", action, code))
+}
+
+// Frees the libopus encoder state when encoding finishes or fails
+struct OpusEncoder(*mut unsafe_libopus::OpusEncoder);
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { unsafe_libopus::opus_encoder_destroy(self.0) }
+    }
+}
+
+// RFC 7845 identification header, channel mapping family 0
+fn opus_head(channels: u16, pre_skip: u16, input_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1); // version
+    head.push(channels as u8);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // mapping family
+    head
+}
+
+// RFC 7845 comment header with no user comments
+fn opus_tags() -> Vec<u8> {
+    let vendor = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+/// Encode interleaved f32 samples to Ogg Opus in 20 ms packets. Audio is
+/// resampled to 48 kHz; anything beyond stereo is downmixed to mono. Returns
+/// the file with the channel count and frames per channel encoded.
+pub fn encode_opus(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    bitrate_kbps: u16,
+) -> std::io::Result<(Vec<u8>, u16, usize)> {
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+    use unsafe_libopus::{OPUS_APPLICATION_VOIP, OPUS_GET_LOOKAHEAD_REQUEST, OPUS_OK, OPUS_SET_BITRATE_REQUEST};
+
+    let (samples, channels) = match channels {
+        1 | 2 => (std::borrow::Cow::Borrowed(samples), channels),
+        _ => (std::borrow::Cow::Owned(downmix(samples, channels)), 1),
+    };
+    let pcm = resample_interleaved(&samples, channels, sample_rate, OPUS_SAMPLE_RATE);
+    let width = channels as usize;
+    let frames = pcm.len() / width;
+
+    let mut error = OPUS_OK;
+    let encoder = unsafe {
+        unsafe_libopus::opus_encoder_create(OPUS_SAMPLE_RATE as i32, channels as i32, OPUS_APPLICATION_VOIP, &mut error)
+    };
+    if encoder.is_null() || error != OPUS_OK {
+        return Err(opus_error("create", error));
+    }
+    let encoder = OpusEncoder(encoder);
+    let result = unsafe { unsafe_libopus::opus_encoder_ctl!(encoder.0, OPUS_SET_BITRATE_REQUEST, bitrate_kbps as i32 * 1000) };
+    if result != OPUS_OK {
+        return Err(opus_error("setting the bitrate", result));
+    }
+    // The decoder drops this many leading samples, so encode that much extra
+    let mut lookahead = 0i32;
+    let result = unsafe { unsafe_libopus::opus_encoder_ctl!(encoder.0, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead) };
+    if result != OPUS_OK {
+        return Err(opus_error("reading the lookahead", result));
+    }
+    let pre_skip = lookahead.max(0) as usize;
+
+    // A fixed serial is fine: every file holds exactly one logical stream
+    let serial = 1;
+    let mut writer = PacketWriter::new(Vec::new());
+    writer.write_packet(opus_head(channels, pre_skip as u16, sample_rate), serial, PacketWriteEndInfo::EndPage, 0)?;
+    writer.write_packet(opus_tags(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    let total = frames + pre_skip;
+    let packets = total.div_ceil(OPUS_FRAME_SIZE);
+    let mut frame = vec![0.0f32; OPUS_FRAME_SIZE * width];
+    let mut packet = vec![0u8; OPUS_MAX_PACKET];
+    for index in 0..packets {
+        // The final frame is padded with silence
+        let start = (index * OPUS_FRAME_SIZE * width).min(pcm.len());
+        let end = (start + OPUS_FRAME_SIZE * width).min(pcm.len());
+        frame.fill(0.0);
+        frame[..end - start].copy_from_slice(&pcm[start..end]);
+
+        let len = unsafe {
+            unsafe_libopus::opus_encode_float(
+                encoder.0,
+                frame.as_ptr(),
+                OPUS_FRAME_SIZE as i32,
+                packet.as_mut_ptr(),
+                packet.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(opus_error("encoding", len));
+        }
+        // The last granule position trims the padding back off
+        let granule = ((index + 1) * OPUS_FRAME_SIZE).min(total) as u64;
+        let end_info = if index + 1 == packets { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::NormalPacket };
+        writer.write_packet(packet[..len as usize].to_vec(), serial, end_info, granule)?;
+    }
+    Ok((writer.into_inner(), channels, frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn high_pass_removes_rumble_and_keeps_speech() {
+        let rate = 16_000;
+        let tone = |hz: f32| -> Vec<f32> {
+            (0..rate).map(|i| 0.5 * (2.0 * std::f32::consts::PI * hz * i as f32 / rate as f32).sin()).collect()
+        };
+        // Filtered in two blocks, as consecutive callbacks deliver it
+        let filter = |mut samples: Vec<f32>| {
+            let mut high_pass = HighPass::new(100.0, rate, 1);
+            let (first, second) = samples.split_at_mut(rate as usize / 3);
+            high_pass.process(first);
+            high_pass.process(second);
+            rms(&samples[rate as usize / 2..])
+        };
+        let input = rms(&tone(20.0));
+        assert!(filter(tone(20.0)) < input * 0.25);
+        assert!(filter(tone(1000.0)) > input * 0.95);
+    }
+
+    #[test]
+    fn downsampling_drops_tones_above_the_new_nyquist() {
+        let tone = |hz: f32| -> Vec<f32> {
+            (0..48_000).map(|i| 0.5 * (2.0 * std::f32::consts::PI * hz * i as f32 / 48_000.0).sin()).collect()
+        };
+        // Away from the ends, where the kernel runs out of input
+        let resampled = |hz: f32| rms(&resample(&tone(hz), 48_000, 16_000)[1000..15_000]);
+        let input = rms(&tone(1000.0));
+        // 10 kHz would alias to 6 kHz at 16 kHz
+        assert!(resampled(10_000.0) < input * 0.001, "{}", resampled(10_000.0));
+        assert!(resampled(1000.0) > input * 0.99);
+        // A ratio that isn't a whole number keeps the level too
+        assert!((rms(&resample(&tone(1000.0), 48_000, 44_100)[1000..40_000]) - input).abs() < input * 0.01);
+    }
+
+    #[test]
+    fn wav_files_match_what_hound_writes() {
+        let samples: Vec<f32> = (0..1200).map(|i| (i as f32 / 100.0).sin() * 1.2).collect();
+        for (kind, bits, channels) in [
+            (SampleKind::Int, 8, 1),
+            (SampleKind::Int, 16, 2),
+            (SampleKind::Int, 24, 2),
+            (SampleKind::Int, 32, 1),
+            (SampleKind::Float, 32, 2),
+            (SampleKind::Int, 16, 4),
+            (SampleKind::Float, 32, 3),
+        ] {
+            let encoding = WavEncoding::new(kind, bits).unwrap();
+            let spec = encoding.spec(channels, 16_000);
+            let mut expected = std::io::Cursor::new(Vec::new());
+            let mut writer = hound::WavWriter::new(&mut expected, spec).unwrap();
+            write_samples(&mut writer, &samples, encoding).unwrap();
+            writer.finalize().unwrap();
+
+            let mut written = Vec::new();
+            write_wav(&mut written, &samples, spec, encoding, &[]).unwrap();
+            assert!(written == expected.into_inner(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn raw_pcm_is_little_endian_without_a_header() {
+        assert_eq!(encode_raw(&[0.5, -2.0], RawEncoding::S16Le), [0xff, 0x3f, 0x01, 0x80]);
+        assert_eq!(encode_raw(&[1.0], RawEncoding::F32Le), 1.0f32.to_le_bytes());
+        assert_eq!("f32le".parse(), Ok(RawEncoding::F32Le));
+        assert!("s16be".parse::<RawEncoding>().is_err());
+    }
+}
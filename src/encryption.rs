@@ -0,0 +1,143 @@
+use std::path::Path;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Start of every encrypted recording: the magic and format version
+pub const MAGIC: &[u8; 8] = b"MAVENC\x00\x01";
+
+// Magic and nonce; the ciphertext follows, ending in the GCM tag
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN;
+
+/// AES-256-GCM key for recordings at rest, from --encrypt-key
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+// Never shows the key, since output options are logged
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+impl std::str::FromStr for Key {
+    type Err = String;
+
+    /// 64 hex digits, as from `openssl rand -hex 32`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format!("expected 64 hex digits, got {} characters", s.chars().count()));
+        }
+        let mut key = [0; 32];
+        for (byte, pair) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|e| e.to_string())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| format!("`{}` is not hex", pair))?;
+        }
+        Ok(Key(key))
+    }
+}
+
+impl Key {
+    /// Read a key file holding the key in hex
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        text.parse().map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn sealing(&self) -> LessSafeKey {
+        // Only fails for a key of the wrong length, which the type rules out
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("AES-256 key is 32 bytes"))
+    }
+
+    /// Encrypt a whole file under a fresh random nonce. `file` is what was
+    /// written after the room [`file_buffer`] leaves for the header, which is
+    /// filled in here and authenticated along with the rest, so it can't be
+    /// altered either. The bytes are sealed where they are; only the tag is
+    /// appended.
+    pub fn encrypt(&self, mut file: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        if file.len() < HEADER_LEN || file[..HEADER_LEN].iter().any(|&byte| byte != 0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no room left for the header"));
+        }
+        let (header, bytes) = file.split_at_mut(HEADER_LEN);
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        let nonce = &mut header[MAGIC.len()..];
+        SystemRandom::new().fill(nonce).map_err(|_| std::io::Error::other("no randomness for a nonce"))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| std::io::Error::other("bad nonce"))?;
+        let tag = self.sealing()
+            .seal_in_place_separate_tag(nonce, Aad::from(&*header), bytes)
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        file.extend_from_slice(tag.as_ref());
+        Ok(file)
+    }
+
+    /// The plaintext of what [`Key::encrypt`] produced. A wrong key and a
+    /// damaged file look alike: both fail authentication.
+    pub fn decrypt(&self, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+        if !is_encrypted(sealed) || sealed.len() < HEADER_LEN {
+            return Err(invalid("not an encrypted recording"));
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&header[MAGIC.len()..]).map_err(|_| invalid("bad nonce"))?;
+        let mut plaintext = ciphertext.to_vec();
+        let length = self.sealing()
+            .open_in_place(nonce, Aad::from(header), &mut plaintext)
+            .map_err(|_| invalid("wrong key, or the file is damaged"))?
+            .len();
+        plaintext.truncate(length);
+        Ok(plaintext)
+    }
+}
+
+/// An empty file for [`Key::encrypt`]: room for the header, which the
+/// recording is written after
+pub fn file_buffer() -> Vec<u8> {
+    vec![0; HEADER_LEN]
+}
+
+/// Whether `bytes` start like an encrypted recording
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Whether the file at `path` is an encrypted recording, reading only its header
+pub fn is_encrypted_file(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|()| is_encrypted(&magic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_buffer, is_encrypted, Key, MAGIC};
+
+    fn file(bytes: &[u8]) -> Vec<u8> {
+        let mut file = file_buffer();
+        file.extend_from_slice(bytes);
+        file
+    }
+
+    #[test]
+    fn recordings_round_trip_and_reject_tampering() {
+        let key: Key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff".parse().unwrap();
+        let sealed = key.encrypt(file(b"RIFF....WAVE")).unwrap();
+        assert!(is_encrypted(&sealed) && sealed.starts_with(MAGIC));
+        assert_eq!(sealed.len(), MAGIC.len() + 12 + 12 + 16);
+        assert_eq!(key.decrypt(&sealed).unwrap(), b"RIFF....WAVE");
+        // Each file gets its own nonce
+        assert_ne!(key.encrypt(file(b"RIFF....WAVE")).unwrap(), sealed);
+        // Without room for the header nothing is sealed
+        assert!(key.encrypt(b"RIFF....WAVE".to_vec()).is_err());
+
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(key.decrypt(&flipped).is_err());
+        let other: Key = "ff".repeat(32).parse().unwrap();
+        assert!(other.decrypt(&sealed).is_err());
+        assert!(key.decrypt(b"RIFF....WAVE").is_err());
+
+        assert!("abc".parse::<Key>().is_err() && "zz".repeat(32).parse::<Key>().is_err());
+        assert_eq!(format!("{:?}", key), "Key(<redacted>)");
+    }
+}
@@ -0,0 +1,117 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::api::{ErrorResponse, ShortBufferResponse};
+use crate::capture_audio::{CaptureError, SaveError};
+use crate::wakeword_listener::WakewordError;
+
+/// The crate's errors, by what failed. Handlers can return them directly:
+/// each variant maps to a status and an [`ErrorResponse`] body. The message
+/// includes the underlying cause, which also stays reachable through
+/// [`std::error::Error::source`].
+#[derive(Debug, thiserror::Error)]
+pub enum VoiceError {
+    /// No input device could be opened, as the host reported it
+    #[error("failed to open input device: {0}")]
+    Device(String),
+    /// Building or starting the stream on an open device
+    #[error(transparent)]
+    StreamBuild(CaptureError),
+    /// Encoding or writing a recording, at the step that failed
+    #[error(transparent)]
+    Wav(#[from] SaveError),
+    /// An I/O error outside of saving a recording
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Porcupine could not be set up
+    #[error(transparent)]
+    Wakeword(#[from] WakewordError),
+    /// A setting that is invalid, unknown or fixed, as given
+    #[error("{0}")]
+    Config(String),
+    /// Less audio than a save takes, both in seconds
+    #[error("{}", short_message(*buffered, *minimum))]
+    TooShort {
+        /// Audio there is
+        buffered: f64,
+        /// Audio the save takes
+        minimum: f64,
+    },
+}
+
+fn short_message(buffered: f64, minimum: f64) -> String {
+    match buffered > 0.0 {
+        false => "Nothing is buffered to save".to_string(),
+        true => format!("Only {:.2}s is buffered; a save needs at least {:.2}s", buffered, minimum),
+    }
+}
+
+impl From<CaptureError> for VoiceError {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::Device(e) => VoiceError::Device(e),
+            e => VoiceError::StreamBuild(e),
+        }
+    }
+}
+
+impl ResponseError for VoiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            VoiceError::Device(_) | VoiceError::StreamBuild(_) => StatusCode::SERVICE_UNAVAILABLE,
+            VoiceError::Wav(e) => crate::save_error_status(e.io_error()),
+            VoiceError::Io(e) => crate::save_error_status(e),
+            VoiceError::Wakeword(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            VoiceError::Config(_) => StatusCode::BAD_REQUEST,
+            VoiceError::TooShort { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match *self {
+            VoiceError::TooShort { buffered, minimum } => response.json(ShortBufferResponse {
+                error: self.to_string(),
+                buffered_seconds: buffered,
+                minimum_seconds: minimum,
+            }),
+            _ => response.json(ErrorResponse::new(self.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use std::io::{Error, ErrorKind};
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use crate::capture_audio::{CaptureError, SaveError};
+    use crate::wakeword_listener::WakewordError;
+    use super::VoiceError;
+
+    #[test]
+    fn each_variant_has_its_status_and_keeps_its_cause() {
+        let device = VoiceError::from(CaptureError::Device("No default input device".to_string()));
+        assert!(matches!(device, VoiceError::Device(_)));
+        assert_eq!(device.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        let stream = VoiceError::from(CaptureError::BuildStream(cpal::BuildStreamError::DeviceNotAvailable));
+        assert_eq!(stream.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(stream.to_string().starts_with("failed to build input stream: "), "{}", stream);
+
+        let full = VoiceError::from(SaveError::WriteSamples(Error::from(ErrorKind::StorageFull)));
+        assert_eq!(full.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(full.source().and_then(|e| e.downcast_ref::<Error>()).map(Error::kind), Some(ErrorKind::StorageFull));
+        let denied = VoiceError::from(Error::from(ErrorKind::PermissionDenied));
+        assert_eq!(denied.status_code(), StatusCode::FORBIDDEN);
+
+        let wakeword = VoiceError::from(WakewordError::MissingEnv("PORCUPINE_MODEL_PATH"));
+        assert_eq!(wakeword.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(wakeword.to_string(), "PORCUPINE_MODEL_PATH is not set");
+        assert_eq!(VoiceError::Config("unknown field".to_string()).status_code(), StatusCode::BAD_REQUEST);
+        let short = VoiceError::TooShort { buffered: 0.25, minimum: 1.0 };
+        assert_eq!(short.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(short.to_string(), "Only 0.25s is buffered; a save needs at least 1.00s");
+        assert_eq!(VoiceError::TooShort { buffered: 0.0, minimum: 0.0 }.to_string(), "Nothing is buffered to save");
+    }
+}
@@ -0,0 +1,213 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::api::ErrorResponse;
+use crate::{AudioState, RecordingState};
+
+/// Events buffered per subscriber before it starts skipping
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+// Encoded events queued per connection while the client reads them
+const CONNECTION_QUEUE: usize = 16;
+
+// Comment sent on quiet streams so proxies keep the connection open and
+// vanished clients are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Default and longest wait of /wait-for-detection, in ms
+const DETECTION_WAIT_DEFAULT_MS: u64 = 30_000;
+const DETECTION_WAIT_MAX_MS: u64 = 300_000;
+
+/// Something that happened, pushed to /events subscribers
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Sent first on every connection, then whenever recording starts, pauses or stops
+    RecordingState {
+        /// The state recording is now in
+        state: RecordingState,
+    },
+    /// Porcupine heard a keyword
+    WakewordDetected {
+        /// Name of the keyword heard
+        keyword: String,
+        /// Samples captured before the detection, buffered or not
+        captured_sample: u64,
+    },
+    /// A sound-activated capture started; `trigger` is "level", as in the saved file's sidecar
+    CaptureTriggered {
+        /// What started the capture
+        trigger: String,
+        /// Level that crossed the threshold, in dBFS
+        level_db: f64,
+        /// Samples captured before the capture started
+        captured_sample: u64,
+    },
+    /// The --stt-url service transcribed what followed a detection
+    Transcribed {
+        /// Keyword of the detection, if the audio followed one
+        keyword: Option<String>,
+        /// What the service heard
+        text: String,
+        /// Samples captured before the detection
+        captured_sample: u64,
+    },
+    /// A /save finished writing; path is the first file of `files`
+    SaveCompleted {
+        /// Path of the first file written
+        path: String,
+        /// Files written, more than one per save when split by channel or gap
+        files: usize,
+        /// Interleaved samples written, across all files
+        samples: usize,
+        /// Length of the saved audio
+        duration_seconds: f64,
+        /// Total size on disk
+        size_bytes: u64,
+    },
+    /// The buffer filled in --buffer-mode stop and recording paused
+    BufferOverflow {
+        /// Samples the buffer held when it filled
+        buffered_samples: usize,
+    },
+}
+
+// Event with the time it was sent
+#[derive(Serialize)]
+struct Envelope<'a> {
+    at: chrono::DateTime<chrono::Local>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+// One Server-Sent Events message carrying the event as JSON
+fn encode(event: &Event) -> Bytes {
+    let envelope = Envelope { at: chrono::Local::now(), event };
+    let json = serde_json::to_string(&envelope).unwrap_or_default();
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+// Response body fed by the connection's forwarding task
+struct EventBody(mpsc::Receiver<Bytes>);
+
+impl MessageBody for EventBody {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.0.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+/// Server-Sent Events stream of detections, sound triggers, transcriptions, recording state changes, completed saves and buffer overflows
+#[utoipa::path(
+    get,
+    path = "/events",
+    responses((status = 200, description = "One JSON event per message", body = Event, content_type = "text/event-stream")),
+)]
+pub async fn stream_events(req: HttpRequest, state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    let mut events = state.events.subscribe();
+    let (sender, receiver) = mpsc::channel(CONNECTION_QUEUE);
+    let initial = Event::RecordingState { state: state.recording_state() };
+    let peer = req.peer_addr();
+    tracing::info!("Event stream client connected: {:?}", peer);
+
+    // Forward until the client goes away; dropping the body closes `sender`,
+    // which ends the task and with it the broadcast subscription
+    rt::spawn(async move {
+        if sender.send(encode(&initial)).await.is_err() {
+            return;
+        }
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        keepalive.reset();
+        loop {
+            let message = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => encode(&event),
+                    // Slow client: tell it how many it missed and carry on
+                    Err(RecvError::Lagged(skipped)) => Bytes::from(format!(": skipped {} events\n\n", skipped)),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => Bytes::from_static(b": keepalive\n\n"),
+                _ = sender.closed() => break,
+            };
+            if sender.send(message).await.is_err() {
+                break;
+            }
+        }
+        tracing::info!("Event stream client disconnected: {:?}", peer);
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        .body(EventBody(receiver))
+}
+
+/// Query of GET /wait-for-detection
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WaitQuery {
+    /// Longest wait for a detection, in ms (default: 30000, at most 300000)
+    timeout_ms: Option<u64>,
+}
+
+/// Wait for the next wakeword detection, for clients that can't read /events
+#[utoipa::path(
+    get,
+    path = "/wait-for-detection",
+    params(WaitQuery),
+    responses(
+        (status = 200, description = "The detection, as /events sends it", body = Event),
+        (status = 204, description = "No detection before the timeout"),
+        (status = 400, description = "`timeout_ms` is over 300000", body = ErrorResponse),
+        (status = 409, description = "Wakeword detection is disabled with --no-wakeword", body = ErrorResponse),
+        (status = 503, description = "The server started shutting down", body = ErrorResponse),
+    ),
+)]
+pub async fn wait_for_detection(state: web::Data<Arc<AudioState>>, query: web::Query<WaitQuery>) -> HttpResponse {
+    let timeout_ms = query.timeout_ms.unwrap_or(DETECTION_WAIT_DEFAULT_MS);
+    if timeout_ms > DETECTION_WAIT_MAX_MS {
+        return HttpResponse::BadRequest()
+            .json(ErrorResponse::new(format!("`timeout_ms` must be at most {}", DETECTION_WAIT_MAX_MS)));
+    }
+    if state.wakeword_disabled {
+        return HttpResponse::Conflict().json(ErrorResponse::new("Wakeword detection is disabled with --no-wakeword"));
+    }
+    // Subscribed before waiting, so only detections after the request count
+    let mut events = state.events.subscribe();
+    let detection = async {
+        loop {
+            match events.recv().await {
+                Ok(event @ Event::WakewordDetected { .. }) => return Some(event),
+                // Whatever was skipped, the next detection is still worth waiting for
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    };
+    // A halt ends the wait rather than waiting out the timeout
+    let detection = tokio::select! {
+        detection = tokio::time::timeout(Duration::from_millis(timeout_ms), detection) => detection,
+        () = state.halting() => {
+            return HttpResponse::ServiceUnavailable().json(ErrorResponse::new("Server is shutting down"));
+        }
+    };
+    match detection {
+        Ok(Some(event)) => HttpResponse::Ok().json(Envelope { at: chrono::Local::now(), event: &event }),
+        Ok(None) | Err(_) => HttpResponse::NoContent().finish(),
+    }
+}
@@ -0,0 +1,389 @@
+use std::path::{Path, PathBuf};
+use chrono::format::{Item, StrftimeItems};
+
+/// Reproduces the names used before templates existed
+pub const DEFAULT_TEMPLATE: &str = "recording_%Y%m%d_%H%M%S_%3f_{seq}";
+
+// Extensions stripped from a template, since the output format decides the real one
+const KNOWN_EXTENSIONS: &[&str] = &["wav", "mp3", "opus", "flac", "raw"];
+
+/// Holds the next {counter} value, in the output directory
+pub const COUNTER_FILE: &str = ".filename_counter";
+
+/// What caused a recording to be saved. Detection-triggered saves get their
+/// own variant when they land.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// An HTTP /save request
+    Manual,
+    /// --auto-save-interval
+    Auto,
+    /// A sound-activated capture, from --trigger-level-db
+    Level,
+    /// An utterance ended by --auto-stop-silence-ms
+    Silence,
+}
+
+impl Trigger {
+    /// Name used for `{trigger}` and in sidecars
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Manual => "manual",
+            Trigger::Auto => "auto",
+            Trigger::Level => "level",
+            Trigger::Silence => "silence",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    // Literal text, possibly containing strftime specifiers
+    Text(String),
+    Trigger,
+    Keyword,
+    Seq,
+    // Persisted across restarts, unlike `Seq`
+    Counter,
+    Device,
+}
+
+/// Everything a template can refer to besides the time
+pub struct NameContext<'a> {
+    /// What the save is for
+    pub trigger: Trigger,
+    /// Keyword of the detection that caused it; `none` in names when there is none
+    pub keyword: Option<&'a str>,
+    /// Saves so far this session
+    pub seq: u64,
+    /// Next value of the persistent counter; only drawn when the template uses it
+    pub counter: u64,
+    /// Name of the device the audio came from
+    pub device: &'a str,
+}
+
+/// Parsed --filename-template, rendered into a file stem for each save
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().expect("default filename template is valid")
+    }
+}
+
+// Check every strftime specifier in `text`, naming the first one chrono rejects
+fn validate_strftime(text: &str) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(index) = rest.find('%') {
+        let spec = &rest[index..];
+        if let Some(Item::Error) = StrftimeItems::new(spec).next() {
+            let shown: String = spec.chars().take(2).collect();
+            return Err(format!("invalid time specifier `{}`", shown));
+        }
+        // Skip past the percent sign and the character after it, so `%%` isn't read twice
+        rest = &spec[spec.char_indices().nth(2).map_or(spec.len(), |(i, _)| i)..];
+    }
+    Ok(())
+}
+
+impl std::str::FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let stem = match template.rsplit_once('.') {
+            Some((stem, ext)) if KNOWN_EXTENSIONS.iter().any(|known| known.eq_ignore_ascii_case(ext)) => stem,
+            _ => template,
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = stem;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+                return Err(format!("unclosed placeholder `{}`", &rest[open..]));
+            };
+            if open > 0 {
+                parts.push(Part::Text(rest[..open].to_string()));
+            }
+            parts.push(match &rest[open + 1..close] {
+                "trigger" => Part::Trigger,
+                "keyword" => Part::Keyword,
+                "seq" => Part::Seq,
+                "counter" => Part::Counter,
+                "device" => Part::Device,
+                other => return Err(format!(
+                    "unknown placeholder `{{{}}}`, expected {{trigger}}, {{keyword}}, {{seq}}, {{counter}} or {{device}}",
+                    other
+                )),
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        for part in &parts {
+            if let Part::Text(text) = part {
+                validate_strftime(text)?;
+            }
+        }
+        if parts.is_empty() {
+            return Err("template is empty".to_string());
+        }
+        Ok(FilenameTemplate { parts })
+    }
+}
+
+/// Make a rendered name safe to join onto the output directory: no path
+/// separators, no characters filesystems reject, and no leading dots
+pub fn sanitize(name: &str) -> String {
+    let cleaned: String = name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.');
+    if cleaned.is_empty() {
+        "recording".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+impl FilenameTemplate {
+    /// Whether names can repeat within a session, letting one save overwrite another
+    pub fn may_collide(&self) -> bool {
+        !self.parts.contains(&Part::Seq) && !self.uses_counter()
+    }
+
+    /// Whether the template has a `{counter}`, drawn from [`FileCounter`]
+    pub fn uses_counter(&self) -> bool {
+        self.parts.contains(&Part::Counter)
+    }
+
+    // Highest {counter} value among the file names in `names`, read after the
+    // literal text the template puts before the counter. Without any, as in
+    // `%Y%m%d{counter}`, digits before it would be taken for the counter, so
+    // there is nothing to go on unless the counter starts the name.
+    fn highest_counter<'a>(&self, names: impl Iterator<Item = &'a str>) -> Option<u64> {
+        let index = self.parts.iter().position(|part| *part == Part::Counter)?;
+        // Only what follows the last time specifier is the same in every name
+        let prefix = match index.checked_sub(1).map(|before| &self.parts[before]) {
+            Some(Part::Text(text)) => sanitize(text.rsplit_once('%').map_or(text, |(_, rest)| rest.get(1..).unwrap_or(""))),
+            Some(_) => String::new(),
+            None => return names.filter_map(leading_number).max(),
+        };
+        if prefix.is_empty() {
+            return None;
+        }
+        let prefix = prefix.as_str();
+        names
+            .flat_map(|name| name.match_indices(prefix).filter_map(move |(at, _)| leading_number(&name[at + prefix.len()..])))
+            .max()
+    }
+
+    /// File stem for a save at `now`, without extension
+    pub fn render(&self, now: chrono::DateTime<chrono::Local>, context: &NameContext) -> String {
+        let name: String = self.parts.iter()
+            .map(|part| match part {
+                Part::Text(text) => now.format(text).to_string(),
+                Part::Trigger => context.trigger.as_str().to_string(),
+                Part::Keyword => context.keyword.unwrap_or("none").to_string(),
+                Part::Seq => format!("{:04}", context.seq),
+                Part::Counter => format!("{:06}", context.counter),
+                Part::Device => context.device.to_string(),
+            })
+            .collect();
+        sanitize(&name)
+    }
+}
+
+// Whether a file called `file` belongs to a save of `name`: the recording and
+// its sidecar, or a per-channel, per-segment or per-device part of it
+fn belongs_to(file: &str, name: &str) -> bool {
+    file.strip_prefix(name).is_some_and(|rest| rest.starts_with('.') || rest.starts_with('_'))
+}
+
+/// `stem`, relative to `dir`, with `_1`, `_2`, ... appended until no file of
+/// that name is in `dir` already and `reserved` doesn't hold it, so a save
+/// never replaces an earlier one
+pub fn free_stem(dir: &Path, stem: &str, reserved: impl Fn(&str) -> bool) -> String {
+    let (parent, name) = stem.rsplit_once('/').unwrap_or(("", stem));
+    // Only files; a directory is never a recording
+    let existing: Vec<String> = std::fs::read_dir(dir.join(parent))
+        .map(|entries| entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_ok_and(|kind| !kind.is_dir()))
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect())
+        .unwrap_or_default();
+    let full = |name: &str| match parent {
+        "" => name.to_string(),
+        parent => format!("{}/{}", parent, name),
+    };
+    let mut candidate = name.to_string();
+    let mut suffix = 0;
+    while reserved(&full(&candidate)) || existing.iter().any(|file| belongs_to(file, &candidate)) {
+        suffix += 1;
+        candidate = format!("{}_{}", name, suffix);
+    }
+    full(&candidate)
+}
+
+// The number `text` starts with, if it starts with a digit
+fn leading_number(text: &str) -> Option<u64> {
+    let digits: String = text.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse::<u64>().ok()
+}
+
+/// The {counter} of saved file names, kept in [`COUNTER_FILE`] so it
+/// increases across restarts
+#[derive(Debug)]
+pub struct FileCounter {
+    path: PathBuf,
+    // Held while the value after it is written, so the file always ends up
+    // with the highest value handed out
+    next: parking_lot::Mutex<u64>,
+}
+
+impl FileCounter {
+    /// Continue from the counter file in `dir`, or when there is none from
+    /// one past the highest counter among the recordings already there
+    pub fn open(dir: &Path, template: &FilenameTemplate) -> std::io::Result<Self> {
+        let path = dir.join(COUNTER_FILE);
+        let next = match std::fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a counter: {}", path.display(), e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut found = Vec::new();
+                match crate::recordings::find_recordings(dir, &mut found) {
+                    // Nothing saved yet
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                let names = found.iter().filter_map(|(path, _)| path.file_stem().and_then(|stem| stem.to_str()));
+                template.highest_counter(names).map_or(1, |highest| highest + 1)
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(FileCounter { path, next: parking_lot::Mutex::new(next) })
+    }
+
+    /// Take the next value, recording the one after it on disk
+    pub fn next(&self) -> u64 {
+        let mut next = self.next.lock();
+        let value = *next;
+        *next += 1;
+        // Written whole and renamed, so a crash never leaves half a number
+        if let Err(e) = crate::recordings::replace_file(&self.path, format!("{}\n", *next).as_bytes()) {
+            tracing::warn!("Failed to save the filename counter to {}: {}", self.path.display(), e);
+        }
+        value
+    }
+
+    /// The value the next save will take, leaving it in place
+    pub fn peek(&self) -> u64 {
+        *self.next.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn templates_render_placeholders_and_time() {
+        let template: FilenameTemplate = "kitchen_%Y-%m-%d_%H%M%S_{trigger}_{keyword}_{seq}.wav".parse().unwrap();
+        let now = chrono::Local.with_ymd_and_hms(2024, 3, 9, 7, 5, 1).unwrap();
+        let context = NameContext { trigger: Trigger::Manual, keyword: Some("porcupine"), seq: 7, counter: 0, device: "mic" };
+        assert_eq!(template.render(now, &context), "kitchen_2024-03-09_070501_manual_porcupine_0007");
+        for name in ["rec_{seq}.raw", "rec_{seq}.FLAC"] {
+            let template: FilenameTemplate = name.parse().unwrap();
+            assert_eq!(template.render(now, &context), "rec_0007", "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_templates_name_the_problem() {
+        let error = "rec_{trigger}_{mood}".parse::<FilenameTemplate>().unwrap_err();
+        assert!(error.contains("{mood}"), "{}", error);
+        let error = "rec_%Y_%Q".parse::<FilenameTemplate>().unwrap_err();
+        assert!(error.contains("%Q"), "{}", error);
+        assert!("rec_{seq".parse::<FilenameTemplate>().is_err());
+        assert!("100%%_{seq}".parse::<FilenameTemplate>().is_ok());
+    }
+
+    #[test]
+    fn rendered_names_stay_inside_the_output_directory() {
+        let template: FilenameTemplate = "../{device}/x".parse().unwrap();
+        let now = chrono::Local::now();
+        let context = NameContext { trigger: Trigger::Manual, keyword: None, seq: 0, counter: 0, device: "hw:0,0/../../etc" };
+        let name = template.render(now, &context);
+        assert!(!name.contains('/') && !name.starts_with('.'), "{}", name);
+    }
+
+    #[test]
+    fn the_counter_continues_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let template: FilenameTemplate = "%Y%m%d_rec_{counter}".parse().unwrap();
+        for name in ["20240309_rec_000041.wav", "20240309_rec_000007.mp3", "notes.txt", "rec_999.wav_"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        // No counter file yet, so it follows the highest recording
+        let counter = FileCounter::open(dir.path(), &template).unwrap();
+        assert_eq!((counter.next(), counter.next()), (42, 43));
+        let name = template.render(chrono::Local::now(), &NameContext {
+            trigger: Trigger::Manual, keyword: None, seq: 0, counter: 44, device: "mic",
+        });
+        assert!(name.ends_with("_rec_000044"), "{}", name);
+
+        // A restart picks up where it left off, whatever the directory holds
+        std::fs::remove_file(dir.path().join("20240309_rec_000041.wav")).unwrap();
+        assert_eq!(FileCounter::open(dir.path(), &template).unwrap().next(), 44);
+        assert_eq!(FileCounter::open(tempfile::tempdir().unwrap().path(), &template).unwrap().next(), 1);
+
+        // No literal text before the counter, so the date can't be told from it
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("20240309000041.wav"), b"").unwrap();
+        let dated: FilenameTemplate = "%Y%m%d{counter}".parse().unwrap();
+        assert_eq!(FileCounter::open(dir.path(), &dated).unwrap().next(), 1);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("000041_20240309.wav"), b"").unwrap();
+        let leading: FilenameTemplate = "{counter}_%Y%m%d".parse().unwrap();
+        assert_eq!(FileCounter::open(dir.path(), &leading).unwrap().next(), 42);
+    }
+
+    #[test]
+    fn concurrent_saves_leave_the_highest_counter_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let template: FilenameTemplate = "rec_{counter}".parse().unwrap();
+        let counter = FileCounter::open(dir.path(), &template).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| (0..25).for_each(|_| { counter.next(); }));
+            }
+        });
+        assert_eq!(std::fs::read_to_string(dir.path().join(COUNTER_FILE)).unwrap(), "201\n");
+    }
+
+    #[test]
+    fn taken_stems_get_a_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("2024/03/09")).unwrap();
+        for name in ["take.wav", "take_1_ch0.wav", "take_10.wav", "2024/03/09/take.json"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let nothing_reserved = |_: &str| false;
+        assert_eq!(free_stem(dir.path(), "take", nothing_reserved), "take_2");
+        assert_eq!(free_stem(dir.path(), "take", |stem| stem == "take_2"), "take_3");
+        assert_eq!(free_stem(dir.path(), "2024/03/09/take", nothing_reserved), "2024/03/09/take_1");
+        assert_eq!(free_stem(dir.path(), "takeover", nothing_reserved), "takeover");
+        assert_eq!(free_stem(&dir.path().join("missing"), "take", nothing_reserved), "take");
+    }
+}
@@ -0,0 +1,802 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Lossless FLAC for archiving recordings. The encoder only uses fixed
+// predictors with Rice-coded residuals; the decoder reads any stream of
+// integer samples, so archived files can be checked before the WAV goes.
+
+// Frames per FLAC frame, as reference encoders use
+pub const BLOCK_SIZE: usize = 4096;
+
+const MAX_FIXED_ORDER: usize = 4;
+
+const MAX_PARTITION_ORDER: u32 = 8;
+
+// Largest 5-bit Rice parameter; 31 is the escape code
+const MAX_RICE_PARAMETER: u32 = 30;
+
+// Bytes read from a stream being decoded at a time
+const READ_CHUNK: usize = 64 * 1024;
+
+// Widest samples the encoder takes, so fixed residuals always fit 32 bits
+pub const MAX_BITS_PER_SAMPLE: u16 = 24;
+
+// The STREAMINFO block: what every sample in the stream looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    // Samples per channel
+    pub frames: u64,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// CRC-16 (polynomial 0x8005) closing every frame
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize])
+}
+
+// CRC-8 (polynomial 0x07) closing every frame header
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Fewer than 8 bits not yet written out
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    // Append the low `count` bits of `value`, at most 32
+    fn write(&mut self, value: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.pending = (self.pending << count) | (value & ((1 << count) - 1));
+        self.pending_bits += count;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // In bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn byte(&self) -> std::io::Result<u8> {
+        self.bytes.get(self.position / 8).copied()
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "FLAC stream ends mid-frame"))
+    }
+
+    // Read `count` bits, at most 64, as an unsigned number
+    fn read(&mut self, count: u32) -> std::io::Result<u64> {
+        let mut value = 0u64;
+        let mut remaining = count;
+        while remaining > 0 {
+            let available = 8 - (self.position % 8) as u32;
+            let take = available.min(remaining);
+            let bits = (self.byte()? as u64 >> (available - take)) & ((1 << take) - 1);
+            value = (value << take) | bits;
+            self.position += take as usize;
+            remaining -= take;
+        }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self, count: u32) -> std::io::Result<i64> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let value = self.read(count)?;
+        Ok(((value << (64 - count)) as i64) >> (64 - count))
+    }
+
+    // Zeros before the next one bit, which is consumed too
+    fn read_unary(&mut self) -> std::io::Result<u64> {
+        let mut zeros = 0;
+        loop {
+            let offset = self.position % 8;
+            let rest = self.byte()? << offset;
+            if rest == 0 {
+                zeros += (8 - offset) as u64;
+                self.position += 8 - offset;
+                continue;
+            }
+            let leading = rest.leading_zeros() as usize;
+            self.position += leading + 1;
+            return Ok(zeros + leading as u64);
+        }
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+// Frame numbers use the same variable-length coding as UTF-8, up to 36 bits
+fn write_coded_number(out: &mut BitWriter, number: u64) {
+    if number < 0x80 {
+        out.write(number, 8);
+        return;
+    }
+    let len = (2..=7u32).find(|&len| number < 1 << ((7 - len) + 6 * (len - 1))).unwrap_or(7);
+    out.write((0xFF00u64 >> len) & 0xFF | number >> (6 * (len - 1)), 8);
+    for index in (0..len - 1).rev() {
+        out.write(0x80 | (number >> (6 * index)) & 0x3F, 8);
+    }
+}
+
+// Residuals of the fixed predictor of `order`: repeated differences
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    let mut residual: Vec<i64> = samples.iter().map(|&s| s as i64).collect();
+    for step in 0..order {
+        for i in (step + 1..residual.len()).rev() {
+            residual[i] -= residual[i - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Rice parameter for a partition and its approximate size in bits
+fn rice_parameter(sum: u64, count: usize) -> (u32, u64) {
+    let count = count as u64;
+    if count == 0 {
+        return (0, 0);
+    }
+    let mean = sum / count;
+    let guess = if mean == 0 { 0 } else { (63 - mean.leading_zeros()).min(MAX_RICE_PARAMETER) };
+    let cost = |k: u32| count * (k as u64 + 1) + (sum >> k);
+    [guess.saturating_sub(1), guess, (guess + 1).min(MAX_RICE_PARAMETER)]
+        .into_iter()
+        .map(|k| (k, cost(k) + 5))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+// Partition order and per-partition parameters with the smallest estimated
+// size, given `block` samples of which the first `order` are warm-up
+fn plan_partitions(residual: &[i64], block: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let finest = (0..=MAX_PARTITION_ORDER)
+        .rev()
+        .find(|&p| block.is_multiple_of(1 << p) && (block >> p) > order)
+        .unwrap_or(0);
+    // Sums of zigzagged residuals for each partition at the finest order,
+    // merged pairwise for coarser ones
+    let size = block >> finest;
+    let mut sums: Vec<(u64, usize)> = (0..1usize << finest)
+        .map(|partition| {
+            let start = (partition * size).saturating_sub(order);
+            let end = (partition + 1) * size - order;
+            (residual[start..end].iter().map(|&r| zigzag(r)).sum(), end - start)
+        })
+        .collect();
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for p in (0..=finest).rev() {
+        let params: Vec<(u32, u64)> = sums.iter().map(|&(sum, count)| rice_parameter(sum, count)).collect();
+        let bits = params.iter().map(|&(_, bits)| bits).sum::<u64>();
+        if best.as_ref().is_none_or(|(_, _, best_bits)| bits < *best_bits) {
+            best = Some((p, params.iter().map(|&(k, _)| k).collect(), bits));
+        }
+        sums = sums.chunks(2).map(|pair| pair.iter().fold((0, 0), |(s, c), &(sum, count)| (s + sum, c + count))).collect();
+    }
+    best.unwrap_or((0, vec![0], 0))
+}
+
+fn encode_subframe(out: &mut BitWriter, samples: &[i32], bits: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        out.write(0, 8);
+        out.write(samples[0] as u64, bits);
+        return;
+    }
+    // Pick the order whose residuals are smallest, then plan its coding
+    let order = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .min_by_key(|&order| fixed_residual(samples, order).iter().map(|&r| r.unsigned_abs()).sum::<u64>())
+        .unwrap_or(0);
+    let residual = fixed_residual(samples, order);
+    let (partition_order, params, residual_bits) = plan_partitions(&residual, samples.len(), order);
+    if order as u64 * bits as u64 + residual_bits >= samples.len() as u64 * bits as u64 {
+        out.write(0b0000_0010, 8);
+        for &sample in samples {
+            out.write(sample as u64, bits);
+        }
+        return;
+    }
+
+    out.write((0b001000 | order as u64) << 1, 8);
+    for &sample in &samples[..order] {
+        out.write(sample as u64, bits);
+    }
+    // Rice coding with 5-bit parameters
+    out.write(0b01, 2);
+    out.write(partition_order as u64, 4);
+    let size = samples.len() >> partition_order;
+    let mut start = 0;
+    for (partition, &k) in params.iter().enumerate() {
+        let end = (partition + 1) * size - order;
+        out.write(k as u64, 5);
+        for &value in &residual[start..end] {
+            let value = zigzag(value);
+            out.write_unary(value >> k);
+            out.write(value, k);
+        }
+        start = end;
+    }
+}
+
+// Writes a FLAC stream a block at a time, so a recording never has to be in
+// memory whole. STREAMINFO goes out first, with the frame count from `info`.
+pub struct Encoder<W: Write> {
+    out: W,
+    info: StreamInfo,
+    frames: u64,
+    // Set once a block shorter than BLOCK_SIZE went out, which ends the stream
+    ended: bool,
+    channel: Vec<i32>,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(mut out: W, info: StreamInfo) -> std::io::Result<Self> {
+        let channels = info.channels as usize;
+        if !(1..=8).contains(&channels) {
+            return Err(invalid(format!("FLAC holds 1 to 8 channels, not {}", channels)));
+        }
+        if !(4..=MAX_BITS_PER_SAMPLE).contains(&info.bits_per_sample) {
+            return Err(invalid(format!("cannot encode {}-bit samples as FLAC", info.bits_per_sample)));
+        }
+        if info.sample_rate == 0 || info.sample_rate >= 1 << 20 {
+            return Err(invalid(format!("FLAC cannot hold a {} Hz sample rate", info.sample_rate)));
+        }
+
+        let mut header = BitWriter::default();
+        header.bytes.extend_from_slice(b"fLaC");
+        // The only metadata block: STREAMINFO, 34 bytes
+        header.write(0x80, 8);
+        header.write(34, 24);
+        header.write(BLOCK_SIZE as u64, 16);
+        header.write(BLOCK_SIZE as u64, 16);
+        // Frame sizes and the MD5 signature are left unknown
+        header.write(0, 24);
+        header.write(0, 24);
+        header.write(info.sample_rate as u64, 20);
+        header.write(channels as u64 - 1, 3);
+        header.write(info.bits_per_sample as u64 - 1, 5);
+        header.write(info.frames >> 32, 4);
+        header.write(info.frames, 32);
+        for _ in 0..4 {
+            header.write(0, 32);
+        }
+        out.write_all(&header.bytes)?;
+        Ok(Encoder { out, info, frames: 0, ended: false, channel: Vec::with_capacity(BLOCK_SIZE) })
+    }
+
+    // Encode up to BLOCK_SIZE interleaved frames as the next FLAC frame. Only
+    // the last block of a stream may be shorter.
+    pub fn write_block(&mut self, block: &[i32]) -> std::io::Result<()> {
+        let channels = self.info.channels as usize;
+        let block_frames = block.len() / channels;
+        if block.is_empty() || !block.len().is_multiple_of(channels) || block_frames > BLOCK_SIZE {
+            return Err(invalid(format!("a FLAC block holds 1 to {} whole frames", BLOCK_SIZE)));
+        }
+        if self.ended {
+            return Err(invalid("only the last FLAC block may be short"));
+        }
+        let bits = self.info.bits_per_sample as u32;
+        let mut frame = BitWriter::default();
+        // Sync code, fixed block size, block size as a 16-bit field and the
+        // sample rate and size from STREAMINFO
+        frame.write(0b1111_1111_1111_1000, 16);
+        frame.write(0b0111_0000, 8);
+        // Independent channels, sample size from STREAMINFO
+        frame.write((channels as u64 - 1) << 4, 8);
+        write_coded_number(&mut frame, self.frames / BLOCK_SIZE as u64);
+        frame.write(block_frames as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+        for c in 0..channels {
+            self.channel.clear();
+            self.channel.extend(block.iter().skip(c).step_by(channels));
+            encode_subframe(&mut frame, &self.channel, bits);
+        }
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+        self.out.write_all(&frame.bytes)?;
+        self.frames += block_frames as u64;
+        self.ended = block_frames < BLOCK_SIZE;
+        Ok(())
+    }
+
+    // Check every frame STREAMINFO promised was written and hand back the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.frames != self.info.frames {
+            return Err(invalid(format!("wrote {} FLAC frames, STREAMINFO promises {}", self.frames, self.info.frames)));
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Encode interleaved integer samples as a complete FLAC stream
+#[cfg(test)]
+pub fn encode(samples: &[i32], info: StreamInfo) -> std::io::Result<Vec<u8>> {
+    let channels = (info.channels as usize).max(1);
+    let frames = samples.len() / channels;
+    let mut encoder = Encoder::new(Vec::new(), StreamInfo { frames: frames as u64, ..info })?;
+    for block in samples[..frames * channels].chunks(BLOCK_SIZE * channels) {
+        encoder.write_block(block)?;
+    }
+    encoder.finish()
+}
+
+fn read_stream_info(reader: &mut BitReader) -> std::io::Result<StreamInfo> {
+    reader.read(16 + 16 + 24 + 24)?;
+    let sample_rate = reader.read(20)? as u32;
+    let channels = reader.read(3)? as u16 + 1;
+    let bits_per_sample = reader.read(5)? as u16 + 1;
+    let frames = reader.read(36)?;
+    Ok(StreamInfo { sample_rate, channels, bits_per_sample, frames })
+}
+
+// Metadata blocks, returning STREAMINFO and where the first frame starts
+fn read_metadata(bytes: &[u8]) -> std::io::Result<(StreamInfo, usize)> {
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "FLAC metadata is truncated");
+    if bytes.len() < 4 && b"fLaC".starts_with(bytes) {
+        return Err(truncated());
+    }
+    if !bytes.starts_with(b"fLaC") {
+        return Err(invalid("not a FLAC stream"));
+    }
+    let mut position = 4;
+    let mut info = None;
+    loop {
+        let header = bytes.get(position..position + 4).ok_or_else(truncated)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = bytes.get(position + 4..position + 4 + length).ok_or_else(truncated)?;
+        if header[0] & 0x7F == 0 {
+            info = Some(read_stream_info(&mut BitReader::new(body))?);
+        }
+        position += 4 + length;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    Ok((info.ok_or_else(|| invalid("FLAC stream has no STREAMINFO"))?, position))
+}
+
+// Format of the FLAC file at `path`, from its STREAMINFO
+pub fn read_info(path: &Path) -> std::io::Result<StreamInfo> {
+    // The marker and a leading STREAMINFO block, as every encoder writes it
+    let mut head = [0u8; 42];
+    std::fs::File::open(path)?.read_exact(&mut head)?;
+    if !head.starts_with(b"fLaC") || head[4] & 0x7F != 0 {
+        return Err(invalid("not a FLAC stream starting with STREAMINFO"));
+    }
+    read_stream_info(&mut BitReader::new(&head[8..]))
+}
+
+fn decode_residual(reader: &mut BitReader, block: usize, order: usize, out: &mut Vec<i64>) -> std::io::Result<()> {
+    let (parameter_bits, escape) = match reader.read(2)? {
+        0 => (4, 15),
+        1 => (5, 31),
+        _ => return Err(invalid("reserved FLAC residual coding")),
+    };
+    let partition_order = reader.read(4)? as u32;
+    let size = block >> partition_order;
+    if size << partition_order != block || size < order {
+        return Err(invalid("FLAC partition order doesn't fit the block"));
+    }
+    for partition in 0..1usize << partition_order {
+        let count = if partition == 0 { size - order } else { size };
+        let k = reader.read(parameter_bits)? as u32;
+        if k == escape {
+            let raw_bits = reader.read(5)? as u32;
+            for _ in 0..count {
+                out.push(reader.read_signed(raw_bits)?);
+            }
+            continue;
+        }
+        for _ in 0..count {
+            let value = (reader.read_unary()? << k) | reader.read(k)?;
+            out.push((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Ok(())
+}
+
+fn decode_subframe(reader: &mut BitReader, block: usize, bits: u32) -> std::io::Result<Vec<i64>> {
+    reader.read(1)?;
+    let kind = reader.read(6)? as usize;
+    let wasted = match reader.read(1)? {
+        1 => reader.read_unary()? as u32 + 1,
+        _ => 0,
+    };
+    let bits = bits.checked_sub(wasted).ok_or_else(|| invalid("FLAC subframe wastes every bit"))?;
+    let mut samples = Vec::with_capacity(block);
+    match kind {
+        0 => samples.resize(block, reader.read_signed(bits)?),
+        1 => {
+            for _ in 0..block {
+                samples.push(reader.read_signed(bits)?);
+            }
+        }
+        8..=12 => {
+            let order = kind - 8;
+            for _ in 0..order.min(block) {
+                samples.push(reader.read_signed(bits)?);
+            }
+            decode_residual(reader, block, order, &mut samples)?;
+            let coefficients: &[i64] = [&[][..], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]][order];
+            for i in order..samples.len() {
+                let prediction: i64 = coefficients.iter().enumerate().map(|(j, &c)| c * samples[i - 1 - j]).sum();
+                samples[i] += prediction;
+            }
+        }
+        32..=63 => {
+            let order = kind - 31;
+            for _ in 0..order.min(block) {
+                samples.push(reader.read_signed(bits)?);
+            }
+            let precision = reader.read(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(invalid("invalid FLAC LPC precision"));
+            }
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(invalid("negative FLAC LPC shift"));
+            }
+            let coefficients: Vec<i64> = (0..order).map(|_| reader.read_signed(precision)).collect::<Result<_, _>>()?;
+            decode_residual(reader, block, order, &mut samples)?;
+            for i in order..samples.len() {
+                let prediction: i64 = coefficients.iter().enumerate().map(|(j, &c)| c * samples[i - 1 - j]).sum();
+                samples[i] += prediction >> shift;
+            }
+        }
+        _ => return Err(invalid("reserved FLAC subframe type")),
+    }
+    if wasted > 0 {
+        samples.iter_mut().for_each(|sample| *sample <<= wasted);
+    }
+    Ok(samples)
+}
+
+// Decode one frame starting at `start`, appending its interleaved samples,
+// and return where the next frame starts
+fn decode_frame(bytes: &[u8], start: usize, info: &StreamInfo, out: &mut Vec<i32>) -> std::io::Result<usize> {
+    let mut reader = BitReader::new(&bytes[start..]);
+    if reader.read(15)? != 0x7FFC {
+        return Err(invalid("lost FLAC frame sync"));
+    }
+    reader.read(1)?;
+    let block_code = reader.read(4)?;
+    let rate_code = reader.read(4)?;
+    let assignment = reader.read(4)?;
+    let size_code = reader.read(3)?;
+    reader.read(1)?;
+    let first = reader.read(8)? as u8;
+    for _ in 1..first.leading_ones() {
+        reader.read(8)?;
+    }
+    let block = match block_code {
+        0 => return Err(invalid("reserved FLAC block size")),
+        1 => 192,
+        2..=5 => 576 << (block_code - 2),
+        6 => reader.read(8)? as usize + 1,
+        7 => reader.read(16)? as usize + 1,
+        _ => 256 << (block_code - 8),
+    };
+    match rate_code {
+        12 => {
+            reader.read(8)?;
+        }
+        13 | 14 => {
+            reader.read(16)?;
+        }
+        15 => return Err(invalid("invalid FLAC sample rate")),
+        _ => {}
+    }
+    let bits = match size_code {
+        0 => info.bits_per_sample as u32,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return Err(invalid("reserved FLAC sample size")),
+    };
+    let header_len = reader.position / 8;
+    if reader.read(8)? as u8 != crc8(&bytes[start..start + header_len]) {
+        return Err(invalid("FLAC frame header CRC mismatch"));
+    }
+
+    let (channels, side) = match assignment {
+        0..=7 => (assignment as usize + 1, None),
+        // Left/side, right/side and mid/side stereo; the side channel has an extra bit
+        8 => (2, Some(1)),
+        9 => (2, Some(0)),
+        10 => (2, Some(1)),
+        _ => return Err(invalid("reserved FLAC channel assignment")),
+    };
+    if channels != info.channels as usize {
+        return Err(invalid("FLAC frame channel count differs from STREAMINFO"));
+    }
+    let mut decoded = Vec::with_capacity(channels);
+    for channel in 0..channels {
+        let extra = (side == Some(channel)) as u32;
+        decoded.push(decode_subframe(&mut reader, block, bits + extra)?);
+    }
+    reader.align();
+    let frame_len = reader.position / 8;
+    if reader.read(16)? as u16 != crc16(&bytes[start..start + frame_len]) {
+        return Err(invalid("FLAC frame CRC mismatch"));
+    }
+
+    for i in 0..block {
+        let (a, b) = match channels {
+            2 => (decoded[0][i], decoded[1][i]),
+            _ => (0, 0),
+        };
+        match assignment {
+            8 => out.extend([a as i32, (a - b) as i32]),
+            9 => out.extend([(a + b) as i32, b as i32]),
+            10 => {
+                let mid = (a << 1) | (b & 1);
+                out.extend([((mid + b) >> 1) as i32, ((mid - b) >> 1) as i32]);
+            }
+            _ => out.extend(decoded.iter().map(|channel| channel[i] as i32)),
+        }
+    }
+    Ok(start + reader.position / 8)
+}
+
+// Read more of `input` onto the end of `buffer`; false at the end of the input
+fn fill(input: &mut impl Read, buffer: &mut Vec<u8>) -> std::io::Result<bool> {
+    let start = buffer.len();
+    buffer.resize(start + READ_CHUNK, 0);
+    let read = loop {
+        match input.read(&mut buffer[start..]) {
+            Ok(read) => break read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                buffer.truncate(start);
+                return Err(e);
+            }
+        }
+    };
+    buffer.truncate(start + read);
+    Ok(read > 0)
+}
+
+// Decodes a FLAC stream a frame at a time as it is read, checking every CRC,
+// so only about one frame of it is in memory at once
+pub struct Decoder<R: Read> {
+    input: R,
+    buffer: Vec<u8>,
+    // Where the next frame starts in `buffer`
+    position: usize,
+    info: StreamInfo,
+    // Decoded so far, across all channels
+    samples: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    // Read the metadata, up to the first frame
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut buffer = Vec::new();
+        let (info, position) = loop {
+            match read_metadata(&buffer) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    if !fill(&mut input, &mut buffer)? {
+                        return Err(invalid(e.to_string()));
+                    }
+                }
+                metadata => break metadata?,
+            }
+        };
+        Ok(Decoder { input, buffer, position, info, samples: 0 })
+    }
+
+    pub fn info(&self) -> StreamInfo {
+        self.info
+    }
+
+    // Decode the next frame, appending its interleaved samples to `out`.
+    // Returns false once the stream has ended with every sample STREAMINFO
+    // promised.
+    pub fn next_block(&mut self, out: &mut Vec<i32>) -> std::io::Result<bool> {
+        let promised = self.info.frames * self.info.channels as u64;
+        loop {
+            if self.position == self.buffer.len() {
+                self.buffer.clear();
+                self.position = 0;
+                if !fill(&mut self.input, &mut self.buffer)? {
+                    if self.samples != promised {
+                        return Err(invalid(format!("FLAC stream holds {} samples, STREAMINFO promises {}", self.samples, promised)));
+                    }
+                    return Ok(false);
+                }
+                continue;
+            }
+            let before = out.len();
+            match decode_frame(&self.buffer, self.position, &self.info, out) {
+                Ok(next) => {
+                    self.position = next;
+                    self.samples += (out.len() - before) as u64;
+                    if self.samples > promised {
+                        return Err(invalid(format!("FLAC stream holds more than the {} samples STREAMINFO promises", promised)));
+                    }
+                    return Ok(true);
+                }
+                // The frame runs past what has been read; keep it and read on
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.buffer.drain(..self.position);
+                    self.position = 0;
+                    if !fill(&mut self.input, &mut self.buffer)? {
+                        return Err(invalid(e.to_string()));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+// Decode a complete FLAC stream into interleaved samples
+#[cfg(test)]
+pub fn decode(bytes: &[u8]) -> std::io::Result<(StreamInfo, Vec<i32>)> {
+    let mut decoder = Decoder::new(bytes)?;
+    let mut samples = Vec::new();
+    while decoder.next_block(&mut samples)? {}
+    Ok((decoder.info(), samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The signal tests/fixtures/make_reference_flac.py encodes
+    fn reference_samples() -> Vec<i32> {
+        let tri = |i: i64, period: i64, amp: i64| ((i * 4 * amp / period) % (4 * amp) - 2 * amp).abs() - amp;
+        let mut seed: i64 = 12345;
+        (0..6000)
+            .flat_map(|i| {
+                seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+                let mut left = tri(i, 100, 9000) + tri(i, 37, 2000) + (seed >> 16) % 33 - 16;
+                let mut right = ((left * 3) >> 2) + tri(i, 250, 1500);
+                if i >= 4096 {
+                    (left, right) = (left & !3, right & !3);
+                }
+                [left as i32, right as i32]
+            })
+            .collect()
+    }
+
+    // Hands out a few bytes per read, so frames straddle reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(777);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn streams_written_to_the_format_spec_decode() {
+        // Built independently of the encoder, with LPC, mid/side and
+        // left/side stereo, wasted bits, escaped partitions and extra metadata
+        let bytes = include_bytes!("../tests/fixtures/reference.flac");
+        let info = StreamInfo { sample_rate: 44_100, channels: 2, bits_per_sample: 16, frames: 6000 };
+        assert_eq!(decode(bytes).unwrap(), (info, reference_samples()));
+
+        let mut decoder = Decoder::new(Trickle(bytes)).unwrap();
+        let mut samples = Vec::new();
+        while decoder.next_block(&mut samples).unwrap() {}
+        assert_eq!(samples, reference_samples());
+
+        assert!(decode(&bytes[..bytes.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn encoding_round_trips_losslessly() {
+        // A stereo tone with noise, a silent stretch and a partial last block
+        let mut seed = 1u32;
+        let samples: Vec<i32> = (0..10_000)
+            .flat_map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let noise = (seed >> 16) as i32 % 64 - 32;
+                let tone = ((i as f32 * 0.05).sin() * 12_000.0) as i32;
+                match i {
+                    4096..=8191 => [0, 0],
+                    _ => [tone + noise, -tone],
+                }
+            })
+            .collect();
+        let info = StreamInfo { sample_rate: 16_000, channels: 2, bits_per_sample: 16, frames: 10_000 };
+        let encoded = encode(&samples, info).unwrap();
+        assert!(encoded.len() < samples.len(), "{} bytes for {} samples", encoded.len(), samples.len());
+        assert_eq!(decode(&encoded).unwrap(), (info, samples));
+
+        // Full-scale 24-bit noise falls back to verbatim subframes
+        let loud: Vec<i32> = (0..5000)
+            .map(|i: i32| i.wrapping_mul(2_654_435_761u32 as i32) >> 8)
+            .collect();
+        let info = StreamInfo { sample_rate: 48_000, channels: 1, bits_per_sample: 24, frames: 5000 };
+        assert_eq!(decode(&encode(&loud, info).unwrap()).unwrap().1, loud);
+
+        // Long streams number their frames with the multi-byte form, as UTF-8 would
+        for number in [0x7F, 200, 0x7FF, 70_000] {
+            let mut out = BitWriter::default();
+            write_coded_number(&mut out, number);
+            let mut utf8 = [0; 4];
+            assert_eq!(out.bytes, char::from_u32(number as u32).unwrap().encode_utf8(&mut utf8).as_bytes());
+        }
+
+        let mut corrupt = encoded.clone();
+        corrupt[100] ^= 0x10;
+        assert!(decode(&corrupt).is_err());
+    }
+}
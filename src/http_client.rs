@@ -0,0 +1,235 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::http::Uri;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::tls;
+
+// Limit on connecting, and on waiting for the response once the body is sent
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Longest a single file may take to send
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Response bytes kept, enough for an error message
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+// A server files are sent to, parsed from an http:// or https:// URL
+#[derive(Clone)]
+pub struct Endpoint {
+    // Set for https URLs
+    tls: Option<tokio_rustls::TlsConnector>,
+    host: String,
+    port: u16,
+    // Host header, with the port when one was given
+    authority: String,
+    path: String,
+    query: Option<String>,
+}
+
+impl Endpoint {
+    // `ca_file` is trusted for https instead of the system bundle
+    pub fn parse(url: &str, ca_file: Option<&str>) -> Result<Self, String> {
+        let uri: Uri = url.parse().map_err(|e| format!("invalid URL `{}`: {}", url, e))?;
+        let tls = match uri.scheme_str() {
+            Some("https") => Some(tokio_rustls::TlsConnector::from(Arc::new(tls::load_client_config(ca_file)?))),
+            Some("http") => None,
+            _ => return Err(format!("URL `{}` must start with http:// or https://", url)),
+        };
+        let bracketed = uri.host().filter(|host| !host.is_empty())
+            .ok_or_else(|| format!("URL `{}` has no host", url))?;
+        Ok(Endpoint {
+            port: uri.port_u16().unwrap_or(if tls.is_some() { 443 } else { 80 }),
+            tls,
+            host: bracketed.trim_start_matches('[').trim_end_matches(']').to_string(),
+            authority: match uri.port_u16() {
+                Some(port) => format!("{}:{}", bracketed, port),
+                None => bracketed.to_string(),
+            },
+            path: uri.path().to_string(),
+            query: uri.query().map(str::to_string),
+        })
+    }
+
+    // Host and port, as sent in the Host header
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Path and query, as sent in the request line
+    pub fn target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+
+    // Send `file` as the body of a `method` request for `target`, streaming it
+    // from disk, and return the response's status and (possibly truncated) body.
+    // Host, Content-Length and Connection are added to `headers`.
+    pub async fn send_file(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        file: &Path,
+    ) -> Result<(u16, Vec<u8>), String> {
+        let body = tokio::fs::File::open(file).await
+            .map_err(|e| format!("unable to open {}: {}", file.display(), e))?;
+        let length = body.metadata().await
+            .map_err(|e| format!("unable to read {}: {}", file.display(), e))?
+            .len();
+        // Only send what was there when the request started, should the file change meanwhile
+        self.send(method, target, headers, length, body.take(length)).await
+    }
+
+    // Send `body`, already in memory, as for send_file
+    pub async fn send_bytes(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>), String> {
+        self.send(method, target, headers, body.len() as u64, body).await
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        length: u64,
+        body: impl AsyncRead + Unpin,
+    ) -> Result<(u16, Vec<u8>), String> {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n", method, target, self.authority, length);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        let tcp = timeout(IO_TIMEOUT, TcpStream::connect((self.host.as_str(), self.port)))
+            .await
+            .map_err(|_| format!("timed out connecting to {}", self.authority))?
+            .map_err(|e| format!("unable to connect to {}: {}", self.authority, e))?;
+        match &self.tls {
+            Some(connector) => {
+                let name = rustls::pki_types::ServerName::try_from(self.host.clone())
+                    .map_err(|e| format!("invalid TLS server name {}: {}", self.host, e))?;
+                let stream = timeout(IO_TIMEOUT, connector.connect(name, tcp))
+                    .await
+                    .map_err(|_| format!("timed out negotiating TLS with {}", self.authority))?
+                    .map_err(|e| format!("TLS handshake with {} failed: {}", self.authority, e))?;
+                exchange(stream, head, body).await
+            }
+            None => exchange(tcp, head, body).await,
+        }
+    }
+}
+
+// Send the request head and body, then read the response's status and body
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    head: String,
+    mut body: impl AsyncRead + Unpin,
+) -> Result<(u16, Vec<u8>), String> {
+    let send = async {
+        stream.write_all(head.as_bytes()).await?;
+        tokio::io::copy(&mut body, &mut stream).await?;
+        stream.flush().await
+    };
+    let sent = match timeout(TRANSFER_TIMEOUT, send).await {
+        Ok(sent) => sent.map_err(|e| format!("failed to send: {}", e)),
+        Err(_) => Err("timed out sending".to_string()),
+    };
+    let response = timeout(IO_TIMEOUT, read_response(&mut stream)).await;
+    match (sent, response) {
+        (_, Ok(Ok(response))) => Ok(response),
+        // A server refusing the request may close before taking the whole body;
+        // its response explains why better than the broken pipe
+        (Err(e), _) => Err(e),
+        (Ok(()), Ok(Err(e))) => Err(e),
+        (Ok(()), Err(_)) => Err("timed out waiting for the response".to_string()),
+    }
+}
+
+// Status and (possibly truncated) body of an HTTP/1.1 response
+async fn read_response(stream: &mut (impl AsyncRead + Unpin)) -> Result<(u16, Vec<u8>), String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = match stream.read(&mut chunk).await {
+            Ok(read) => read,
+            // Many servers close TLS without a close_notify once they have answered
+            Err(_) if !buffer.is_empty() => 0,
+            Err(e) => return Err(format!("failed to read the response: {}", e)),
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+        let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") else {
+            if read == 0 {
+                return Err("connection closed without a response".to_string());
+            }
+            if buffer.len() > MAX_RESPONSE_BYTES {
+                return Err("response headers too long".to_string());
+            }
+            continue;
+        };
+        let head = String::from_utf8_lossy(&buffer[..end]);
+        let status = head.split_whitespace().nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| "malformed response".to_string())?;
+        let header = |wanted: &str| head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim().to_ascii_lowercase());
+        let content_length = header("content-length").and_then(|value| value.parse::<usize>().ok());
+        let chunked = header("transfer-encoding").is_some_and(|value| value.ends_with("chunked"));
+        let body = &buffer[end + 4..];
+        let complete = match chunked {
+            true => dechunk(body).is_some(),
+            false => content_length.is_some_and(|length| body.len() >= length),
+        };
+        if read == 0 || complete || body.len() >= MAX_RESPONSE_BYTES {
+            if chunked {
+                return Ok((status, dechunk(body).unwrap_or_else(|| body.to_vec())));
+            }
+            let length = content_length.unwrap_or(body.len()).min(body.len());
+            return Ok((status, body[..length].to_vec()));
+        }
+    }
+}
+
+// The body of a chunked response, once the last chunk has arrived
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        // Chunk extensions follow a `;`
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dechunk;
+
+    #[test]
+    fn chunked_bodies_are_reassembled_once_complete() {
+        assert_eq!(dechunk(b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n").as_deref(), Some(&b"hello, world"[..]));
+        assert_eq!(dechunk(b"5\r\nhello\r\n"), None);
+    }
+}
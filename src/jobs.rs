@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{upload, webhook, AudioState, SaveResponse};
+use crate::api::ErrorResponse;
+
+// Finished jobs kept for polling; older ones are forgotten
+pub const JOB_HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    // Waiting for a save permit, or for an earlier push of the same files
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Save,
+    Upload,
+    Webhook,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Job {
+    id: u64,
+    kind: JobKind,
+    status: JobStatus,
+    created_at: chrono::DateTime<chrono::Local>,
+    finished_at: Option<chrono::DateTime<chrono::Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<SaveResponse>,
+    // Set on finished upload jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<upload::UploadResponse>,
+    // Set on finished webhook jobs, one per file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<Vec<webhook::Delivery>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Background save jobs started with /save?async=true, S3 uploads and webhook pushes
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    // Keyed by id, so iteration order is creation order
+    jobs: parking_lot::Mutex<BTreeMap<u64, Job>>,
+    // Notified as each job finishes
+    finished: tokio::sync::Notify,
+}
+
+impl Jobs {
+    pub fn create(&self) -> u64 {
+        self.create_kind(JobKind::Save)
+    }
+
+    pub fn create_upload(&self) -> u64 {
+        self.create_kind(JobKind::Upload)
+    }
+
+    pub fn create_webhook(&self) -> u64 {
+        self.create_kind(JobKind::Webhook)
+    }
+
+    fn create_kind(&self, kind: JobKind) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.jobs.lock().insert(id, Job {
+            id,
+            kind,
+            status: JobStatus::Pending,
+            created_at: chrono::Local::now(),
+            finished_at: None,
+            result: None,
+            upload: None,
+            webhook: None,
+            error: None,
+        });
+        id
+    }
+
+    pub fn start(&self, id: u64) {
+        if let Some(job) = self.jobs.lock().get_mut(&id) {
+            job.status = JobStatus::Running;
+        }
+    }
+
+    pub fn finish(&self, id: u64, result: Result<SaveResponse, String>) {
+        self.complete(id, |job| match result {
+            Ok(response) => job.result = Some(response),
+            Err(e) => job.error = Some(e),
+        });
+    }
+
+    pub fn finish_upload(&self, id: u64, result: Result<upload::UploadResponse, String>) {
+        self.complete(id, |job| match result {
+            Ok(response) => job.upload = Some(response),
+            Err(e) => job.error = Some(e),
+        });
+    }
+
+    // Every delivery is kept; the job fails if any of them did
+    pub fn finish_webhook(&self, id: u64, deliveries: Vec<webhook::Delivery>) {
+        self.complete(id, |job| {
+            job.error = deliveries.iter().find_map(|delivery| delivery.failure());
+            job.webhook = Some(deliveries);
+        });
+    }
+
+    // Record a job's outcome with `store`; it failed if that set an error
+    fn complete(&self, id: u64, store: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.finished_at = Some(chrono::Local::now());
+            store(job);
+            job.status = match job.error {
+                Some(_) => JobStatus::Failed,
+                None => JobStatus::Done,
+            };
+        }
+
+        // Evict the oldest finished jobs; unfinished ones are always kept
+        let finished: Vec<u64> = jobs.values()
+            .filter(|job| job.finished_at.is_some())
+            .map(|job| job.id)
+            .collect();
+        for id in finished.iter().take(finished.len().saturating_sub(JOB_HISTORY_LIMIT)) {
+            jobs.remove(id);
+        }
+        drop(jobs);
+        self.finished.notify_waiters();
+    }
+
+    // Notified each time a job finishes, for waiting until none are in flight
+    pub fn finished(&self) -> &tokio::sync::Notify {
+        &self.finished
+    }
+
+    // Jobs still pending or running
+    pub fn in_flight(&self) -> usize {
+        self.jobs.lock().values().filter(|job| job.finished_at.is_none()).count()
+    }
+
+    fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().get(&id).cloned()
+    }
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct JobAccepted {
+    job_id: u64,
+    status_url: String,
+}
+
+impl JobAccepted {
+    pub fn new(job_id: u64) -> Self {
+        JobAccepted { job_id, status_url: format!("/jobs/{}", job_id) }
+    }
+
+    pub fn status_url(&self) -> &str {
+        &self.status_url
+    }
+}
+
+/// Status of a background save or upload job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id returned by /save or /recordings/{name}/upload")),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown job, or one that has dropped out of the history", body = ErrorResponse),
+    ),
+)]
+pub async fn get_job(state: web::Data<Arc<AudioState>>, id: web::Path<u64>) -> HttpResponse {
+    match state.jobs.get(*id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json(ErrorResponse::new(format!("Job {} not found", id))),
+    }
+}
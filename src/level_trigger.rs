@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::capture_audio::SaveWindow;
+use crate::{filename, save_in_background, AudioState};
+
+// Finished captures queued for saving; the callback drops any beyond this
+const CAPTURE_QUEUE: usize = 16;
+
+/// Settings for sound-activated captures, from the --trigger-* options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelOptions {
+    /// RMS level in dBFS that starts a capture
+    pub threshold_db: f64,
+    /// Audio kept from before the level crossed the threshold
+    pub pre_roll: Duration,
+    /// How long the level must stay below the threshold to end the capture
+    pub hang: Duration,
+    /// Longest capture, not counting the pre-roll
+    pub max: Duration,
+    /// Quiet time after a capture before another can start
+    pub cooldown: Duration,
+}
+
+/// What a callback's audio did to the trigger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelEvent {
+    /// The level crossed the threshold at absolute buffer position `at`
+    Started {
+        /// Where the level first crossed the threshold
+        at: u64,
+        /// Level of the callback that crossed it, in dBFS
+        level_db: f64,
+    },
+    /// The capture ended; `from` includes the pre-roll and `to` is exclusive
+    Finished {
+        /// First position of the capture, pre-roll included
+        from: u64,
+        /// Position just past the capture
+        to: u64,
+        /// Loudest callback of the capture, in dBFS
+        peak_db: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Armed,
+    Capturing { started: u64, last_loud: u64, peak_db: f64 },
+    // Still loud when the last capture hit its maximum length; re-arms once quiet
+    Waiting,
+}
+
+/// A stretch of buffered audio to save, from `from` up to `to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    /// First absolute buffer position to save
+    pub from: u64,
+    /// Absolute buffer position just past the last
+    pub to: u64,
+    /// What the capture is named for
+    pub trigger: filename::Trigger,
+}
+
+/// Follows the level callback by callback. Everything is in interleaved samples
+/// at absolute buffer positions, so pauses don't count towards hang time.
+pub struct LevelTrigger {
+    threshold_db: f64,
+    pre_roll: u64,
+    hang: u64,
+    max: u64,
+    cooldown: u64,
+    phase: Phase,
+    // No capture starts before this position
+    quiet_until: u64,
+}
+
+impl LevelTrigger {
+    /// A trigger for audio of `channels` interleaved channels at `sample_rate`
+    pub fn new(options: LevelOptions, sample_rate: u32, channels: u16) -> Self {
+        let samples = |duration: Duration| {
+            (duration.as_secs_f64() * sample_rate as f64).round() as u64 * channels.max(1) as u64
+        };
+        LevelTrigger {
+            threshold_db: options.threshold_db,
+            pre_roll: samples(options.pre_roll),
+            hang: samples(options.hang),
+            max: samples(options.max),
+            cooldown: samples(options.cooldown),
+            phase: Phase::Armed,
+            quiet_until: 0,
+        }
+    }
+
+    /// Feed the level of `len` samples buffered from absolute position `at`
+    pub fn process(&mut self, at: u64, len: usize, level_db: f64) -> Option<LevelEvent> {
+        let loud = level_db >= self.threshold_db;
+        let end = at + len as u64;
+        match self.phase {
+            Phase::Armed if loud && at >= self.quiet_until => {
+                self.phase = Phase::Capturing { started: at, last_loud: end, peak_db: level_db };
+                Some(LevelEvent::Started { at, level_db })
+            }
+            Phase::Armed => None,
+            Phase::Waiting => {
+                if !loud {
+                    self.phase = Phase::Armed;
+                }
+                None
+            }
+            Phase::Capturing { started, last_loud, peak_db } => {
+                let (last_loud, peak_db) = if loud { (end, peak_db.max(level_db)) } else { (last_loud, peak_db) };
+                if end - last_loud < self.hang && end - started < self.max {
+                    self.phase = Phase::Capturing { started, last_loud, peak_db };
+                    return None;
+                }
+                // Ended by the hang time, or cut at the maximum while (maybe) still loud
+                self.phase = if loud { Phase::Waiting } else { Phase::Armed };
+                self.quiet_until = end + self.cooldown;
+                Some(LevelEvent::Finished { from: started.saturating_sub(self.pre_roll), to: end, peak_db })
+            }
+        }
+    }
+}
+
+/// Settings for stopping after an utterance, from the --auto-stop-* options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoStopOptions {
+    /// RMS level in dBFS that counts as speech
+    pub threshold_db: f64,
+    /// Quiet after speech that pauses recording
+    pub silence: Duration,
+    /// Save the utterance when recording pauses
+    pub save: bool,
+}
+
+/// Pauses recording once the level has stayed below the threshold for a while
+/// after rising above it, from --auto-stop-silence-ms
+pub struct SilenceStop {
+    threshold_db: f64,
+    silence: u64,
+    // Where the utterance began: the first audio buffered since the last stop
+    started: Option<u64>,
+    heard: bool,
+    quiet_since: Option<u64>,
+}
+
+impl SilenceStop {
+    /// A stop for audio of `channels` interleaved channels at `sample_rate`
+    pub fn new(options: AutoStopOptions, sample_rate: u32, channels: u16) -> Self {
+        SilenceStop {
+            threshold_db: options.threshold_db,
+            silence: (options.silence.as_secs_f64() * sample_rate as f64).round() as u64 * channels.max(1) as u64,
+            started: None,
+            heard: false,
+            quiet_since: None,
+        }
+    }
+
+    /// Feed the level of `len` samples buffered from absolute position `at`,
+    /// returning the utterance's (from, to) span when it is time to stop
+    pub fn process(&mut self, at: u64, len: usize, level_db: f64) -> Option<(u64, u64)> {
+        let started = *self.started.get_or_insert(at);
+        let end = at + len as u64;
+        if level_db >= self.threshold_db {
+            self.heard = true;
+            self.quiet_since = None;
+            return None;
+        }
+        if !self.heard {
+            return None;
+        }
+        let quiet_since = *self.quiet_since.get_or_insert(at);
+        if end - quiet_since < self.silence {
+            return None;
+        }
+        (self.started, self.heard, self.quiet_since) = (None, false, None);
+        Some((started, end))
+    }
+}
+
+/// Start the task saving finished captures and return the queue the capture
+/// callback feeds
+pub fn spawn_saver(state: Arc<AudioState>) -> (mpsc::Sender<Capture>, tokio::task::JoinHandle<()>) {
+    let (sender, mut captures) = mpsc::channel(CAPTURE_QUEUE);
+    let handle = tokio::spawn(async move {
+        while let Some(Capture { from, to, trigger }) = captures.recv().await {
+            let window = SaveWindow { since: Some(from), until: Some(to), ..SaveWindow::default() };
+            match save_in_background(&state, window, trigger).await {
+                Ok((Some(response), _)) => tracing::info!(
+                    "Saved a {:.1}s {}-triggered capture to {}",
+                    response.duration_seconds, trigger.as_str(), response.path
+                ),
+                Ok((None, _)) => tracing::warn!("{}-triggered capture left the buffer before it could be saved", trigger.as_str()),
+                Err(e) => tracing::error!("Failed to save {}-triggered capture: {}", trigger.as_str(), e),
+            }
+        }
+    });
+    (sender, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustained_noise_makes_one_capture_with_pre_roll() {
+        // 10 samples per second, so each 10-sample block is 1 s
+        let options = LevelOptions {
+            threshold_db: -20.0,
+            pre_roll: Duration::from_secs(2),
+            hang: Duration::from_secs(2),
+            max: Duration::from_secs(5),
+            cooldown: Duration::from_secs(1),
+        };
+        let mut trigger = LevelTrigger::new(options, 10, 1);
+        let levels = [-60.0, -60.0, -60.0, -10.0, -30.0, -5.0, -30.0, -30.0];
+        let events: Vec<_> = levels.iter().enumerate()
+            .filter_map(|(i, &level)| trigger.process(i as u64 * 10, 10, level))
+            .collect();
+        assert_eq!(events, vec![
+            LevelEvent::Started { at: 30, level_db: -10.0 },
+            // Quiet for the 2 s hang after the last loud second
+            LevelEvent::Finished { from: 10, to: 80, peak_db: -5.0 },
+        ]);
+        // Nothing starts within the cooldown
+        assert_eq!(trigger.process(80, 10, -10.0), None);
+        assert!(matches!(trigger.process(90, 10, -10.0), Some(LevelEvent::Started { at: 90, .. })));
+
+        // A loud stretch longer than the maximum is cut once, then waits for quiet
+        let mut trigger = LevelTrigger::new(options, 10, 1);
+        let events: Vec<_> = (0..9)
+            .filter_map(|i| trigger.process(i * 10, 10, -10.0))
+            .collect();
+        assert_eq!(events, vec![
+            LevelEvent::Started { at: 0, level_db: -10.0 },
+            LevelEvent::Finished { from: 0, to: 50, peak_db: -10.0 },
+        ]);
+        assert_eq!(trigger.process(90, 10, -60.0), None);
+        assert!(matches!(trigger.process(100, 10, -10.0), Some(LevelEvent::Started { at: 100, .. })));
+    }
+
+    #[test]
+    fn silence_stops_only_after_speech() {
+        let options = AutoStopOptions { threshold_db: -40.0, silence: Duration::from_secs(2), save: false };
+        let mut stop = SilenceStop::new(options, 10, 1);
+        // Leading silence doesn't count, nor does a short pause mid-speech
+        let levels = [-60.0, -60.0, -60.0, -20.0, -50.0, -20.0, -50.0];
+        for (i, &level) in levels.iter().enumerate() {
+            assert_eq!(stop.process(i as u64 * 10, 10, level), None);
+        }
+        assert_eq!(stop.process(70, 10, -50.0), Some((0, 80)));
+        // The next utterance starts from what is buffered after the stop
+        assert_eq!(stop.process(200, 10, -20.0), None);
+        assert_eq!(stop.process(210, 10, -50.0), None);
+        assert_eq!(stop.process(220, 10, -50.0), Some((200, 230)));
+    }
+}
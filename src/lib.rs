@@ -0,0 +1,2436 @@
+//! Wakeword-triggered audio capture: a ring buffer fed by the input device,
+//! Porcupine detection, and the HTTP API that saves, streams and manages
+//! what was captured.
+//!
+//! The `misteragent-voice-rust` binary parses its options into an
+//! [`AudioState`], starts capture with [`spawn_capture`] and serves [`app`].
+//! Embedders can do the same, or feed the buffer themselves with
+//! [`AudioState::push_samples`].
+
+#![warn(missing_docs)]
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use actix_cors::Cors;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{middleware, web, App, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use api::ErrorResponse;
+use porcupine::Porcupine;
+use encoding::{OutputFormat, OutputOptions};
+
+/// Porcupine construction and the built-in keywords it listens for
+pub mod wakeword_listener;
+/// The input device, the capture stream and encoding of saved audio
+pub mod capture_audio;
+/// Listing, serving and deleting saved recordings
+pub mod recordings;
+mod auth;
+mod live_stream;
+/// Output formats and the sample conversions behind them
+pub mod encoding;
+mod api;
+/// Settings adjustable at runtime and those fixed at startup
+pub mod config;
+mod jobs;
+mod process;
+/// TLS for the HTTP server
+pub mod tls;
+/// Serving the API on a Unix socket
+pub mod uds;
+/// Request logging
+pub mod access_log;
+/// Archiving the capture as fixed-length segments
+pub mod segments;
+/// Names of saved recordings
+pub mod filename;
+/// Limits on how many recordings are kept, and how much space they take
+pub mod retention;
+/// Storage of the ring buffer
+pub mod sample_buffer;
+/// Notifications sent to /events subscribers
+pub mod events;
+mod bwf;
+mod http_client;
+/// Uploading recordings to S3-compatible storage
+pub mod upload;
+/// Pushing saved recordings to a webhook
+pub mod webhook;
+/// Periodic saves of the buffer
+pub mod autosave;
+/// Sound-activated captures and pausing after speech
+pub mod level_trigger;
+mod flac;
+/// Background compression of old recordings to FLAC
+pub mod maintenance;
+/// Speech-to-text of the audio following a detection
+pub mod stt;
+/// Options read from a --config TOML file
+pub mod config_file;
+/// Log output for tracing events and `log` records, as text or JSON
+pub mod logging;
+/// Generated audio standing in for a microphone, via --source synthetic
+pub mod synthetic;
+/// The crate-wide error type and the HTTP responses it maps to
+pub mod error;
+/// Encryption of saved recordings at rest, with --encrypt-key
+pub mod encryption;
+use capture_audio::{
+    supervise_capture, AudioSource, BufferMode, CaptureOptions, Gap, GapMode, SaveWindow, Snapshot,
+};
+
+const CAPTURE_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+const SAVE_FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_SHUTDOWN_GRACE: Duration = Duration::from_secs(10 * 60);
+const SAVE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+const SAVE_RETRY_AFTER_SECS: u64 = 1;
+// How long /record waits past its duration for the last samples to arrive
+const RECORD_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+// Hex SHA-256 of the body of a /save download
+const SHA256_HEADER: &str = "x-content-sha256";
+// Largest /process upload unless --max-upload-mb says otherwise
+const DEFAULT_UPLOAD_LIMIT: usize = 50 * 1024 * 1024;
+// What a raw /save download holds, since the bytes don't say: e.g. `s16le`,
+// and the rate and channel count to hand ffmpeg's -f, -ar and -ac
+const RAW_ENCODING_HEADER: &str = "x-raw-encoding";
+const SAMPLE_RATE_HEADER: &str = "x-sample-rate";
+const CHANNELS_HEADER: &str = "x-channels";
+// Saves whose names are remembered beyond what the output directory shows
+const RECENT_STEMS: usize = 64;
+// Default of --max-base64-mb
+const DEFAULT_BASE64_LIMIT: usize = 16 * 1024 * 1024;
+
+// Range accepted for /save's target_rate
+const MIN_TARGET_RATE: u32 = 8_000;
+const MAX_TARGET_RATE: u32 = 192_000;
+
+/// Everything capture, detection and the HTTP handlers share: the ring
+/// buffer, recording state and the configuration given at startup.
+///
+/// Build one with [`AudioState::new`], adjust the public fields to enable
+/// optional features, then wrap it in an [`Arc`] for [`spawn_capture`] and
+/// [`app`]. The fields are read from then on, so set them before sharing.
+pub struct AudioState {
+    buffer: parking_lot::Mutex<sample_buffer::SampleBuffer>,
+    // One buffer per channel instead of interleaved, from --split-channels
+    split_channels: bool,
+    // Storage for buffered samples, from --buffer-sample-type
+    buffer_sample_type: sample_buffer::SampleType,
+    /// Session file every save appends to, from --append-to
+    pub append_to: Option<std::path::PathBuf>,
+    // Held for the whole of a /record, which owns the buffer meanwhile
+    record_lock: tokio::sync::Mutex<()>,
+    // Serializes appends, which all write the same file
+    append_lock: parking_lot::Mutex<()>,
+    is_recording: AtomicBool,
+    // Set by /stop, which also clears the buffer; cleared again by /start
+    is_stopped: AtomicBool,
+    // Unix millis when buffering was suspended, 0 while recording
+    paused_at: AtomicU64,
+    // Samples ever pushed into the buffer, giving each one an absolute position
+    samples_written: AtomicU64,
+    // Samples ever delivered by the device, including those not buffered
+    samples_captured: AtomicU64,
+    // Wakeword detections inside the buffered audio, oldest first
+    detections: parking_lot::Mutex<Vec<capture_audio::Detection>>,
+    // /mark labels inside the buffered audio, oldest first
+    markers: parking_lot::Mutex<Vec<capture_audio::Marker>>,
+    // Pauses between buffered samples, oldest first
+    gaps: parking_lot::Mutex<Vec<Gap>>,
+    is_halting: AtomicBool,
+    // Set once the capture thread has dropped its stream
+    capture_stopped: AtomicBool,
+    // Wakes graceful_shutdown once capture_stopped is set
+    capture_done: tokio::sync::Notify,
+    // Wakes /record each time samples are buffered
+    samples_arrived: tokio::sync::Notify,
+    // Wakes graceful_shutdown as each active save ends
+    saves_done: tokio::sync::Notify,
+    // Why the capture stream couldn't be started, while it is being retried
+    capture_error: parking_lot::Mutex<Option<String>>,
+    shutdown_requested: tokio::sync::Notify,
+    // Wakes every request waiting on halting at once, unlike shutdown_requested
+    halt_waiters: tokio::sync::Notify,
+    // Grace period requested by /halt?grace_ms, None for the default
+    shutdown_grace: parking_lot::Mutex<Option<Duration>>,
+    restart_stream: AtomicBool,
+    // Wakes the capture thread for a halt or a stream restart; the mutex
+    // only orders the wake against the thread going to sleep
+    capture_wake: parking_lot::Condvar,
+    capture_wake_lock: parking_lot::Mutex<()>,
+    // Times the capture stream was rebuilt after an error or a stall
+    stream_restarts: AtomicU64,
+    // Unix millis of the last capture callback, 0 before the first one
+    last_frame_at: AtomicU64,
+    // Frames per second actually delivered by the callback
+    throughput: parking_lot::Mutex<capture_audio::Throughput>,
+    // A permit is held for the duration of each save, bounding how many run at once
+    save_permits: tokio::sync::Semaphore,
+    // Saves running in the background for /save?async=true, and uploads
+    jobs: jobs::Jobs,
+    /// Bucket to upload recordings to, from --s3-endpoint and --s3-bucket
+    pub s3: Option<upload::S3Config>,
+    /// Upload every save by default, from --auto-upload; needs `s3`
+    pub auto_upload: bool,
+    /// Where saves are pushed, from --save-webhook
+    pub webhook: webhook::WebhookConfig,
+    /// Speech-to-text service for detections and /transcribe, from --stt-url
+    pub stt: Option<stt::SttConfig>,
+    // Queue of detections to the transcriber, when that is set
+    stt_queue: std::sync::OnceLock<tokio::sync::mpsc::Sender<capture_audio::Detection>>,
+    // Outcome of the most recent webhook push
+    last_webhook: parking_lot::Mutex<Option<webhook::Delivery>>,
+    // Per-session counter keeping generated file names unique
+    save_counter: AtomicU64,
+    // Stems handed out lately, newest last, so saves still being written
+    // aren't given the same name
+    recent_stems: parking_lot::Mutex<std::collections::VecDeque<String>>,
+    // File names of saves currently being written
+    active_saves: parking_lot::Mutex<HashSet<String>>,
+    buffer_mode: BufferMode,
+    output: OutputOptions,
+    /// Largest base64 body /save?download=true&base64=true returns, in bytes,
+    /// from --max-base64-mb
+    pub base64_limit: usize,
+    /// Most memory a PATCH /config of `buffer_seconds` may give the buffers,
+    /// from --max-buffer-bytes
+    pub max_buffer_bytes: u64,
+    /// Least audio /save writes without `allow_empty`, in seconds, from
+    /// --min-save-seconds; an empty buffer is refused even at 0
+    pub min_save_seconds: f64,
+    /// Names for saved recordings, from --filename-template
+    pub filename_template: filename::FilenameTemplate,
+    /// Source of {counter} in the template; without one it counts from 0 each session
+    pub file_counter: Option<filename::FileCounter>,
+    /// Save into dated subdirectories, from --organize-by-date
+    pub organize_by_date: bool,
+    /// Limits enforced after each save, from --max-recordings and --max-recordings-age
+    pub retention: retention::RetentionPolicy,
+    // Outcome of the most recent retention pass
+    last_retention: parking_lot::Mutex<Option<retention::RetentionRun>>,
+    /// Byte budget for the output directory, from --max-output-bytes
+    pub output_budget: Option<u64>,
+    output_usage: retention::OutputUsage,
+    /// Compressing old recordings to FLAC, from --compress-after; see [`start_workers`]
+    pub compression: Option<maintenance::CompressPolicy>,
+    maintenance: maintenance::Maintenance,
+    // Settings adjustable through PATCH /config
+    settings: parking_lot::RwLock<config::Settings>,
+    // The settings' gain as f32 bits, so the capture callback reads it without a lock
+    gain: AtomicU32,
+    // Wakeword engine used by the capture callback, swapped by /wakeword/reload
+    wakeword: parking_lot::Mutex<Option<Porcupine>>,
+    /// Set by --no-wakeword: no engine is created and detection never runs
+    pub wakeword_disabled: bool,
+    /// Language model for the engine, from --model-path or PORCUPINE_PV_MODEL_PATH
+    pub wakeword_model_path: Option<std::path::PathBuf>,
+    /// Cutoff of the detection high-pass filter, from --highpass-hz
+    pub highpass_hz: Option<f32>,
+    /// Filter the buffered audio as well, from --highpass-buffer
+    pub highpass_buffer: bool,
+    /// Sound-activated captures, from --trigger-level-db; see [`start_workers`]
+    pub level_trigger: Option<level_trigger::LevelOptions>,
+    /// Pausing after an utterance, from --auto-stop-silence-ms
+    pub auto_stop: Option<level_trigger::AutoStopOptions>,
+    // Queue of finished captures to the saver, when either of those saves
+    captures: std::sync::OnceLock<tokio::sync::mpsc::Sender<level_trigger::Capture>>,
+    // Unix millis of the last accepted detection, for the cooldown
+    last_detection_at: AtomicU64,
+    // Mono PCM frames for /stream listeners
+    live_audio: tokio::sync::broadcast::Sender<web::Bytes>,
+    // Notifications for /events subscribers
+    events: tokio::sync::broadcast::Sender<events::Event>,
+    // Queue to the segment archiver, when --segment-seconds is set
+    archive: std::sync::OnceLock<std::sync::mpsc::SyncSender<Vec<f32>>>,
+    // Callbacks the archiver couldn't keep up with
+    archive_dropped: AtomicU64,
+    // Callbacks the wakeword thread couldn't keep up with
+    wakeword_dropped: AtomicU64,
+    // Format the capture stream delivers: the device default read at startup,
+    // then whatever capture_audio opened the stream with
+    input_config: parking_lot::RwLock<cpal::SupportedStreamConfig>,
+    device_name: String,
+    /// Devices captured alongside this one from a repeated --input-device,
+    /// each built with [`AudioState::companion`]. Transport controls and
+    /// PATCH /config apply to all of them, and /save writes a file from each.
+    pub other_devices: Vec<Arc<AudioState>>,
+}
+
+impl AudioState {
+    /// State for capturing `input_config` audio from `device_name` into a
+    /// buffer of `capacity` interleaved samples (see [`buffer_capacity`]),
+    /// with every optional feature off. Recording starts enabled.
+    pub fn new(
+        input_config: cpal::SupportedStreamConfig,
+        device_name: String,
+        capacity: usize,
+        buffer_mode: BufferMode,
+        output: OutputOptions,
+        settings: config::Settings,
+        max_concurrent_saves: usize,
+    ) -> Self {
+        AudioState {
+            buffer: parking_lot::Mutex::new(sample_buffer::SampleBuffer::new(
+                capacity,
+                input_config.channels(),
+                false,
+                sample_buffer::SampleType::F32,
+            )),
+            split_channels: false,
+            buffer_sample_type: sample_buffer::SampleType::F32,
+            append_to: None,
+            record_lock: tokio::sync::Mutex::new(()),
+            append_lock: parking_lot::Mutex::new(()),
+            is_recording: AtomicBool::new(true),
+            is_stopped: AtomicBool::new(false),
+            paused_at: AtomicU64::new(0),
+            samples_written: AtomicU64::new(0),
+            samples_captured: AtomicU64::new(0),
+            detections: parking_lot::Mutex::new(Vec::new()),
+            markers: parking_lot::Mutex::new(Vec::new()),
+            gaps: parking_lot::Mutex::new(Vec::new()),
+            is_halting: AtomicBool::new(false),
+            capture_stopped: AtomicBool::new(false),
+            capture_done: tokio::sync::Notify::new(),
+            samples_arrived: tokio::sync::Notify::new(),
+            saves_done: tokio::sync::Notify::new(),
+            capture_error: parking_lot::Mutex::new(None),
+            shutdown_requested: tokio::sync::Notify::new(),
+            halt_waiters: tokio::sync::Notify::new(),
+            shutdown_grace: parking_lot::Mutex::new(None),
+            restart_stream: AtomicBool::new(false),
+            capture_wake: parking_lot::Condvar::new(),
+            capture_wake_lock: parking_lot::Mutex::new(()),
+            stream_restarts: AtomicU64::new(0),
+            last_frame_at: AtomicU64::new(0),
+            throughput: parking_lot::Mutex::new(capture_audio::Throughput::default()),
+            save_permits: tokio::sync::Semaphore::new(max_concurrent_saves),
+            jobs: jobs::Jobs::default(),
+            s3: None,
+            auto_upload: false,
+            webhook: webhook::WebhookConfig::default(),
+            stt: None,
+            stt_queue: std::sync::OnceLock::new(),
+            last_webhook: parking_lot::Mutex::new(None),
+            save_counter: AtomicU64::new(0),
+            recent_stems: parking_lot::Mutex::new(std::collections::VecDeque::new()),
+            active_saves: parking_lot::Mutex::new(HashSet::new()),
+            buffer_mode,
+            output,
+            base64_limit: DEFAULT_BASE64_LIMIT,
+            max_buffer_bytes: u64::MAX,
+            min_save_seconds: 0.0,
+            filename_template: filename::FilenameTemplate::default(),
+            file_counter: None,
+            organize_by_date: false,
+            retention: retention::RetentionPolicy::default(),
+            last_retention: parking_lot::Mutex::new(None),
+            output_budget: None,
+            output_usage: retention::OutputUsage::default(),
+            compression: None,
+            maintenance: maintenance::Maintenance::default(),
+            gain: AtomicU32::new(settings.gain.to_bits()),
+            settings: parking_lot::RwLock::new(settings),
+            wakeword: parking_lot::Mutex::new(None),
+            wakeword_disabled: false,
+            wakeword_model_path: None,
+            highpass_hz: None,
+            highpass_buffer: false,
+            level_trigger: None,
+            auto_stop: None,
+            captures: std::sync::OnceLock::new(),
+            last_detection_at: AtomicU64::new(0),
+            live_audio: tokio::sync::broadcast::channel(live_stream::LIVE_CHANNEL_CAPACITY).0,
+            events: tokio::sync::broadcast::channel(events::EVENT_CHANNEL_CAPACITY).0,
+            archive: std::sync::OnceLock::new(),
+            archive_dropped: AtomicU64::new(0),
+            wakeword_dropped: AtomicU64::new(0),
+            input_config: parking_lot::RwLock::new(input_config),
+            device_name,
+            other_devices: Vec::new(),
+        }
+    }
+
+    /// State for another device captured alongside this one: the same buffer
+    /// length, layout, output and runtime settings, with no wakeword engine
+    /// and no optional features. Build it once this state is configured.
+    pub fn companion(&self, input_config: cpal::SupportedStreamConfig, device_name: String) -> Self {
+        let settings = self.settings.read().clone();
+        let capacity = buffer_capacity(&input_config, settings.buffer_seconds);
+        let mut other = AudioState::new(
+            input_config,
+            device_name,
+            capacity,
+            self.buffer_mode,
+            self.output,
+            settings,
+            // Its files are written under this state's save permits
+            1,
+        );
+        other.set_buffer_layout(self.split_channels, self.buffer_sample_type);
+        other.filename_template = self.filename_template.clone();
+        other.organize_by_date = self.organize_by_date;
+        other.wakeword_disabled = true;
+        other.highpass_hz = self.highpass_hz;
+        other.highpass_buffer = self.highpass_buffer;
+        other
+    }
+
+    /// Store buffered samples per channel and/or as `sample_type` rather
+    /// than as interleaved f32, from --split-channels and
+    /// --buffer-sample-type. The buffer is reallocated empty.
+    pub fn set_buffer_layout(&mut self, split_channels: bool, sample_type: sample_buffer::SampleType) {
+        self.split_channels = split_channels;
+        self.buffer_sample_type = sample_type;
+        let (capacity, channels) = (self.buffer.get_mut().capacity(), self.input_config().channels());
+        *self.buffer.get_mut() = sample_buffer::SampleBuffer::new(capacity, channels, split_channels, sample_type);
+    }
+
+    /// Name of the device captured into this state
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    /// Hand detection an engine, e.g. from
+    /// [`wakeword_listener::get_wakeword_listener`]. Until one is set the
+    /// capture keeps buffering but nothing is detected.
+    pub fn set_wakeword(&self, porcupine: Porcupine) {
+        *self.wakeword.lock() = Some(porcupine);
+    }
+
+    /// Buffer interleaved samples in the capture's format, as the capture
+    /// callback does with what the device delivers, and pass them on to the
+    /// segment archiver. Returns the buffer position of the first sample and
+    /// how many were kept, or None while recording is suspended.
+    pub fn push_samples(&self, samples: &[f32]) -> Option<(u64, usize)> {
+        if !self.is_recording.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut buffer = self.buffer.lock();
+        let pushed = match self.buffer_mode {
+            BufferMode::Overwrite => {
+                buffer.push_slice_overwrite(samples);
+                samples.len()
+            }
+            BufferMode::Stop => {
+                let pushed = buffer.push_slice(samples);
+                if pushed < samples.len() {
+                    self.publish(events::Event::BufferOverflow { buffered_samples: buffer.occupied_len() });
+                    self.pause();
+                    tracing::info!("Buffer full, pausing recording");
+                }
+                pushed
+            }
+        };
+        // Updated under the buffer lock so snapshots see a consistent position
+        let start = self.samples_written.fetch_add(pushed as u64, Ordering::Relaxed);
+        drop(buffer);
+        self.samples_arrived.notify_waiters();
+
+        // Hand the same audio to the segment archiver without blocking
+        if let Some(archive) = self.archive.get() {
+            if archive.try_send(samples[..pushed].to_vec()).is_err()
+                && self.archive_dropped.fetch_add(1, Ordering::Relaxed) == 0
+            {
+                tracing::warn!("Segment archiver is falling behind; dropping audio");
+            }
+        }
+        Some((start, pushed))
+    }
+
+    /// Stop recording and capture, then wake [`graceful_shutdown`]
+    pub fn request_shutdown(&self) {
+        self.is_recording.store(false, Ordering::Relaxed);
+        self.is_halting.store(true, Ordering::Relaxed);
+        self.shutdown_requested.notify_one();
+        self.halt_waiters.notify_waiters();
+        self.wake_capture();
+        for other in &self.other_devices {
+            other.request_shutdown();
+        }
+    }
+
+    // Have the capture thread rebuild its stream
+    fn restart_capture(&self) {
+        self.restart_stream.store(true, Ordering::Relaxed);
+        self.wake_capture();
+    }
+
+    // Resolve once the server is halting, for requests that would otherwise
+    // hold up the shutdown
+    async fn halting(&self) {
+        let notified = self.halt_waiters.notified();
+        tokio::pin!(notified);
+        // Registered before the check, so a halt in between still wakes it
+        notified.as_mut().enable();
+        if !self.is_halting.load(Ordering::Relaxed) {
+            notified.await;
+        }
+    }
+
+    fn wake_capture(&self) {
+        let _lock = self.capture_wake_lock.lock();
+        self.capture_wake.notify_all();
+    }
+
+    // Block the capture thread until the server halts, a stream restart is
+    // asked for when `restart`, or `timeout` passes. Returns whether it is
+    // halting.
+    fn wait_for_capture(&self, timeout: Option<Duration>, restart: bool) -> bool {
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        let mut lock = self.capture_wake_lock.lock();
+        loop {
+            if self.is_halting.load(Ordering::Relaxed) {
+                return true;
+            }
+            if restart && self.restart_stream.load(Ordering::Relaxed) {
+                return false;
+            }
+            match deadline {
+                Some(deadline) if self.capture_wake.wait_until(&mut lock, deadline).timed_out() => {
+                    return self.is_halting.load(Ordering::Relaxed);
+                }
+                Some(_) => {}
+                None => self.capture_wake.wait(&mut lock),
+            }
+        }
+    }
+
+    // Tell /events subscribers; having none is fine
+    fn publish(&self, event: events::Event) {
+        let _ = self.events.send(event);
+    }
+
+    // Stop buffering, returning whether recording was running
+    fn suspend(&self) -> bool {
+        let was_recording = self.is_recording.swap(false, Ordering::Relaxed);
+        if was_recording {
+            self.paused_at.store(capture_audio::now_millis(), Ordering::Relaxed);
+        }
+        was_recording
+    }
+
+    // Suspend buffering, keeping what is buffered
+    fn pause(&self) {
+        if self.suspend() {
+            self.publish(events::Event::RecordingState { state: RecordingState::Paused });
+        }
+        for other in &self.other_devices {
+            other.pause();
+        }
+    }
+
+    // Resume buffering, recording how long we were paused so saves can account for it
+    fn resume(&self, sample_rate: u32) {
+        for other in &self.other_devices {
+            other.resume(other.input_config().sample_rate().0);
+        }
+        if self.is_recording.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.publish(events::Event::RecordingState { state: RecordingState::Recording });
+        let paused_at = self.paused_at.swap(0, Ordering::Relaxed);
+        if self.is_stopped.swap(false, Ordering::Relaxed) || paused_at == 0 {
+            return;
+        }
+        let paused_ms = capture_audio::now_millis().saturating_sub(paused_at);
+        let (capacity, at) = {
+            let buffer = self.buffer.lock();
+            (buffer.capacity() as u64, self.samples_written.load(Ordering::Relaxed))
+        };
+        let mut gaps = self.gaps.lock();
+        // Forget pauses whose audio has been overwritten
+        gaps.retain(|gap| gap.at + capacity > at);
+        gaps.push(Gap { at, silent_frames: paused_ms * sample_rate as u64 / 1000 });
+    }
+
+    // Suspend buffering and discard the buffer, returning how many samples were dropped
+    fn stop(&self) -> usize {
+        let suspended = self.suspend();
+        let was_stopped = self.is_stopped.swap(true, Ordering::Relaxed);
+        let cleared = self.buffer.lock().clear();
+        self.gaps.lock().clear();
+        self.detections.lock().clear();
+        self.markers.lock().clear();
+        if suspended || !was_stopped {
+            self.publish(events::Event::RecordingState { state: RecordingState::Stopped });
+        }
+        for other in &self.other_devices {
+            other.stop();
+        }
+        cleared
+    }
+
+    fn recording_state(&self) -> RecordingState {
+        if self.is_recording.load(Ordering::Relaxed) {
+            RecordingState::Recording
+        } else if self.is_stopped.load(Ordering::Relaxed) {
+            RecordingState::Stopped
+        } else {
+            RecordingState::Paused
+        }
+    }
+
+    fn input_config(&self) -> cpal::SupportedStreamConfig {
+        self.input_config.read().clone()
+    }
+
+    // Adopt the format the capture stream was opened with. When the channel
+    // count or rate differs from what saves assumed so far, the buffer is
+    // reallocated empty, since its contents are in the old layout.
+    fn set_input_config(&self, config: cpal::SupportedStreamConfig) {
+        let previous = std::mem::replace(&mut *self.input_config.write(), config.clone());
+        if previous.channels() == config.channels() && previous.sample_rate() == config.sample_rate() {
+            return;
+        }
+        tracing::warn!(
+            "Capture stream delivers {} ch at {} Hz rather than the {} ch at {} Hz read at startup; saving with the stream's format",
+            config.channels(), config.sample_rate().0, previous.channels(), previous.sample_rate().0
+        );
+        let capacity = buffer_capacity(&config, self.settings.read().buffer_seconds);
+        let resized = sample_buffer::SampleBuffer::new(capacity, config.channels(), self.split_channels, self.buffer_sample_type);
+        *self.buffer.lock() = resized;
+        self.gaps.lock().clear();
+        self.detections.lock().clear();
+        self.markers.lock().clear();
+    }
+
+    // Reallocate the ring buffer for `seconds` of audio, keeping the newest samples
+    fn resize_buffer(&self, seconds: u32) {
+        let input_config = self.input_config();
+        let capacity = buffer_capacity(&input_config, seconds);
+        // Allocate before locking so the capture callback only waits for the copy
+        let mut resized = sample_buffer::SampleBuffer::new(
+            capacity,
+            input_config.channels(),
+            self.split_channels,
+            self.buffer_sample_type,
+        );
+        let mut buffer = self.buffer.lock();
+        resized.keep_newest(&buffer);
+        tracing::info!(
+            "Resized buffer from {} to {} samples, keeping {}",
+            buffer.capacity(), capacity, resized.occupied_len()
+        );
+        *buffer = resized;
+    }
+
+    // Length of `samples` interleaved samples in the capture's format
+    fn samples_to_seconds(&self, samples: usize) -> f64 {
+        let config = self.input_config();
+        samples as f64 / config.channels().max(1) as f64 / config.sample_rate().0 as f64
+    }
+
+    fn seconds_since_last_frame(&self) -> Option<f64> {
+        match self.last_frame_at.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(capture_audio::now_millis().saturating_sub(last) as f64 / 1000.0),
+        }
+    }
+}
+
+/// Ring buffer length in samples for `seconds` of audio. The buffer holds
+/// interleaved samples, one per channel for each frame. Saturates rather
+/// than wrap; [`buffer_bytes`] tells whether a length can be allocated.
+pub fn buffer_capacity(config: &cpal::SupportedStreamConfig, seconds: u32) -> usize {
+    checked_capacity(config, seconds).unwrap_or(usize::MAX)
+}
+
+fn checked_capacity(config: &cpal::SupportedStreamConfig, seconds: u32) -> Option<usize> {
+    (config.sample_rate().0 as usize)
+        .checked_mul(config.channels().max(1) as usize)?
+        .checked_mul(seconds as usize)
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1 << 20) as f64
+}
+
+/// Why a buffer of the requested length can't be allocated
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BufferSizeError {
+    /// No seconds were asked for
+    #[error("a buffer of 0 seconds holds no audio")]
+    Empty,
+    /// The size doesn't fit in this platform's address space
+    #[error("{seconds} seconds of buffer is more memory than this platform can address")]
+    Overflow {
+        /// Buffer length asked for
+        seconds: u32,
+    },
+    /// The buffers would take more than the limit allows
+    #[error(
+        "{seconds} seconds of buffer needs {needed} bytes ({:.1} MiB), over the limit of {limit} bytes ({:.1} MiB)",
+        mib(*.needed), mib(*.limit)
+    )]
+    TooLarge {
+        /// Buffer length asked for
+        seconds: u32,
+        /// Bytes the buffers would take
+        needed: u64,
+        /// Bytes they may take
+        limit: u64,
+    },
+}
+
+/// Memory the buffers for `seconds` of each of `configs` take together with
+/// samples stored as `sample_type`, refusing an empty buffer and one over
+/// `limit` bytes
+pub fn buffer_bytes(
+    configs: &[&cpal::SupportedStreamConfig],
+    seconds: u32,
+    sample_type: sample_buffer::SampleType,
+    limit: u64,
+) -> Result<u64, BufferSizeError> {
+    if seconds == 0 {
+        return Err(BufferSizeError::Empty);
+    }
+    let needed = configs.iter().try_fold(0u64, |total, config| {
+        let bytes = checked_capacity(config, seconds)?
+            .checked_mul(sample_type.size())
+            // The most a single allocation may take
+            .filter(|&bytes| bytes <= isize::MAX as usize)?;
+        total.checked_add(bytes as u64)
+    });
+    match needed {
+        None => Err(BufferSizeError::Overflow { seconds }),
+        Some(needed) if needed > limit => Err(BufferSizeError::TooLarge { seconds, needed, limit }),
+        Some(needed) => Ok(needed),
+    }
+}
+
+/// Whether captured audio is being buffered, as /status and /events report it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingState {
+    /// Buffering what is captured
+    Recording,
+    /// Not buffering; the buffer is kept and /start resumes after a gap
+    Paused,
+    /// Not buffering and the buffer was cleared
+    Stopped,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TransportResponse {
+    state: RecordingState,
+    // Whether the buffer was kept
+    buffer_kept: bool,
+    buffered_samples: usize,
+    cleared_samples: usize,
+}
+
+// Settings a /start body can switch for the session
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct StartOptions {
+    // Directory for recordings saved from now on
+    output_dir: Option<String>,
+    // New buffer length; the newest audio is kept
+    seconds: Option<u32>,
+}
+
+// HTTP endpoint handlers
+/// Resume buffering audio; after a pause the gap is remembered for /save.
+/// An optional JSON body switches the output directory and buffer length first.
+#[utoipa::path(
+    post,
+    path = "/start",
+    request_body(content = Option<StartOptions>, content_type = "application/json"),
+    responses(
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid options; nothing was changed", body = ErrorResponse),
+    ),
+)]
+async fn start_recording(state: web::Data<Arc<AudioState>>, body: web::Bytes) -> HttpResponse {
+    if !body.is_empty() {
+        let options: StartOptions = match serde_json::from_slice(&body) {
+            Ok(options) => options,
+            Err(e) => return HttpResponse::BadRequest().json(ErrorResponse::new(format!("Invalid start options: {}", e))),
+        };
+        let patch = config::ConfigPatch::for_session(options.output_dir, options.seconds);
+        if let Err(e) = config::apply_patch(&state, &patch) {
+            return HttpResponse::BadRequest().json(ErrorResponse::new(e));
+        }
+    }
+    tracing::info!("Starting recording");
+    state.resume(state.input_config().sample_rate().0);
+    HttpResponse::Ok().body("Recording started")
+}
+
+/// Pause if recording, otherwise start
+#[utoipa::path(post, path = "/toggle", responses((status = 200, body = TransportResponse)))]
+async fn toggle_recording(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    if state.is_recording.load(Ordering::Relaxed) {
+        tracing::info!("Toggling recording off");
+        state.pause();
+    } else {
+        tracing::info!("Toggling recording on");
+        state.resume(state.input_config().sample_rate().0);
+    }
+    HttpResponse::Ok().json(TransportResponse {
+        state: state.recording_state(),
+        buffer_kept: true,
+        buffered_samples: state.buffer.lock().occupied_len(),
+        cleared_samples: 0,
+    })
+}
+
+// Body of /mark
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct MarkOptions {
+    label: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct MarkResponse {
+    label: Option<String>,
+    // Absolute position of the mark among buffered samples
+    buffered_sample: u64,
+    // Samples captured since startup when it was placed
+    captured_sample: u64,
+}
+
+/// Place a cue point at the newest buffered audio, listed with its label in
+/// the response and sidecar of every save that covers it. The JSON body is optional.
+#[utoipa::path(
+    post,
+    path = "/mark",
+    request_body(content = Option<MarkOptions>, content_type = "application/json"),
+    responses(
+        (status = 200, body = MarkResponse),
+        (status = 400, description = "Invalid body", body = ErrorResponse),
+        (status = 409, description = "Recording is stopped, so there is no audio to mark", body = ErrorResponse),
+    ),
+)]
+async fn mark_recording(state: web::Data<Arc<AudioState>>, body: web::Bytes) -> HttpResponse {
+    let options = match body.is_empty() {
+        true => MarkOptions { label: None },
+        false => match serde_json::from_slice(&body) {
+            Ok(options) => options,
+            Err(e) => return HttpResponse::BadRequest().json(ErrorResponse::new(format!("Invalid mark: {}", e))),
+        },
+    };
+    if state.recording_state() == RecordingState::Stopped {
+        return HttpResponse::Conflict().json(ErrorResponse::new("Recording is stopped"));
+    }
+    let marker = capture_audio::record_marker(&state, options.label.filter(|label| !label.is_empty()));
+    tracing::info!("Marked {} at captured sample {}", marker.label.as_deref().unwrap_or("(unlabeled)"), marker.captured);
+    HttpResponse::Ok().json(MarkResponse { label: marker.label, buffered_sample: marker.at, captured_sample: marker.captured })
+}
+
+/// Suspend buffering, keeping what is buffered
+#[utoipa::path(post, path = "/pause", responses((status = 200, body = TransportResponse)))]
+async fn pause_recording(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    tracing::info!("Pausing recording");
+    state.pause();
+    HttpResponse::Ok().json(TransportResponse {
+        state: state.recording_state(),
+        buffer_kept: true,
+        buffered_samples: state.buffer.lock().occupied_len(),
+        cleared_samples: 0,
+    })
+}
+
+/// Stop buffering and clear the buffer, so the next recording starts fresh
+#[utoipa::path(post, path = "/stop", responses((status = 200, body = TransportResponse)))]
+async fn stop_recording(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    tracing::info!("Stopping recording");
+    let cleared_samples = state.stop();
+    tracing::info!("Cleared {} buffered samples", cleared_samples);
+    HttpResponse::Ok().json(TransportResponse {
+        state: state.recording_state(),
+        buffer_kept: false,
+        buffered_samples: 0,
+        cleared_samples,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+struct StatusResponse {
+    state: RecordingState,
+    recording: bool,
+    buffered_samples: usize,
+    buffer_capacity: usize,
+    // The two above as lengths of audio; samples count every channel
+    buffered_seconds: f64,
+    buffer_seconds: f64,
+    // Everything the device has delivered, buffered or not
+    samples_captured: u64,
+    seconds_since_last_frame: Option<f64>,
+    // Frames per second delivered over the last few seconds; well below
+    // sample_rate means the device is dropping audio
+    effective_sample_rate: Option<f64>,
+    sample_rate: u32,
+    // Why capture couldn't be started, while it is being retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_error: Option<String>,
+    // Times the capture stream was rebuilt; a climbing count points at flaky hardware
+    stream_restarts: u64,
+    // Callbacks of audio the wakeword thread fell too far behind to hear
+    wakeword_frames_dropped: u64,
+    // Most recent retention pass, once one has run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retention: Option<retention::RetentionRun>,
+    // Output directory usage against --max-output-bytes, when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_usage: Option<OutputUsageResponse>,
+    // Most recent webhook push, once one has finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<webhook::Delivery>,
+    // Capture from the other --input-device devices; the fields above describe the first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<DeviceStatus>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeviceStatus {
+    device: String,
+    buffered_samples: usize,
+    samples_captured: u64,
+    seconds_since_last_frame: Option<f64>,
+    sample_rate: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OutputUsageResponse {
+    // Unknown until the first save scans the directory
+    used_bytes: Option<u64>,
+    budget_bytes: u64,
+}
+
+/// Recording state and buffer usage
+#[utoipa::path(get, path = "/status", responses((status = 200, body = StatusResponse)))]
+async fn status(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    let (buffered_samples, buffer_capacity) = {
+        let buffer = state.buffer.lock();
+        (buffer.occupied_len(), buffer.capacity())
+    };
+    HttpResponse::Ok().json(StatusResponse {
+        state: state.recording_state(),
+        recording: state.is_recording.load(Ordering::Relaxed),
+        buffered_samples,
+        buffer_capacity,
+        buffered_seconds: state.samples_to_seconds(buffered_samples),
+        buffer_seconds: state.samples_to_seconds(buffer_capacity),
+        samples_captured: state.samples_captured.load(Ordering::Relaxed),
+        seconds_since_last_frame: state.seconds_since_last_frame(),
+        effective_sample_rate: state.throughput.lock().frames_per_second(capture_audio::now_millis()),
+        sample_rate: state.input_config().sample_rate().0,
+        capture_error: state.capture_error.lock().clone(),
+        stream_restarts: state.stream_restarts.load(Ordering::Relaxed),
+        wakeword_frames_dropped: state.wakeword_dropped.load(Ordering::Relaxed),
+        retention: state.last_retention.lock().clone(),
+        output_usage: state.output_budget.map(|budget_bytes| OutputUsageResponse {
+            used_bytes: state.output_usage.cached(),
+            budget_bytes,
+        }),
+        webhook: state.last_webhook.lock().clone(),
+        devices: state.other_devices.iter()
+            .map(|other| DeviceStatus {
+                device: other.device_name.clone(),
+                buffered_samples: other.buffer.lock().occupied_len(),
+                samples_captured: other.samples_captured.load(Ordering::Relaxed),
+                seconds_since_last_frame: other.seconds_since_last_frame(),
+                sample_rate: other.input_config().sample_rate().0,
+                capture_error: other.capture_error.lock().clone(),
+            })
+            .collect(),
+    })
+}
+
+// Check the output directory accepts new files by creating a probe file
+fn output_dir_writable(dir: &str) -> Result<(), std::io::Error> {
+    let probe = std::path::Path::new(dir).join(format!(".health_{}", std::process::id()));
+    std::fs::OpenOptions::new().write(true).create_new(true).open(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+#[derive(Serialize, ToSchema)]
+struct CaptureHealth {
+    ok: bool,
+    seconds_since_last_frame: Option<f64>,
+    max_age_seconds: f64,
+    error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct HealthResponse {
+    healthy: bool,
+    capture: CaptureHealth,
+    wakeword_initialized: bool,
+    output_dir_writable: bool,
+    output_dir_error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReadinessResponse {
+    healthy: bool,
+    seconds_since_last_frame: Option<f64>,
+    // Set while capture failed to start and is being retried
+    capture_failed: bool,
+}
+
+/// Readiness probe: 200 only while the capture callback keeps delivering audio.
+/// Only reads the frame heartbeat and capture state, so it is safe to poll every second.
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, body = ReadinessResponse),
+        (status = 503, description = "Capture is not delivering audio", body = ReadinessResponse),
+    ),
+)]
+async fn health(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    let since_last_frame = state.seconds_since_last_frame();
+    let max_age = state.settings.read().health_timeout_secs as f64;
+    let capture_failed = state.capture_error.lock().is_some();
+    let healthy = !capture_failed && since_last_frame.is_some_and(|age| age <= max_age);
+    let body = ReadinessResponse { healthy, seconds_since_last_frame: since_last_frame, capture_failed };
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Detailed health: capture heartbeat, wakeword engine and output directory
+#[utoipa::path(
+    get,
+    path = "/health/detail",
+    responses(
+        (status = 200, body = HealthResponse),
+        (status = 503, description = "Capture is not delivering audio", body = HealthResponse),
+    ),
+)]
+async fn health_detail(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    let since_last_frame = state.seconds_since_last_frame();
+    let (max_age, output_dir) = {
+        let settings = state.settings.read();
+        (settings.health_timeout_secs as f64, settings.output_dir.clone())
+    };
+    let capture_error = state.capture_error.lock().clone();
+    let capture_ok = capture_error.is_none() && since_last_frame.is_some_and(|age| age <= max_age);
+    let output_dir = output_dir_writable(&output_dir);
+
+    let body = HealthResponse {
+        healthy: capture_ok,
+        capture: CaptureHealth {
+            ok: capture_ok,
+            seconds_since_last_frame: since_last_frame,
+            max_age_seconds: max_age,
+            error: capture_error.or_else(|| (!capture_ok).then(|| match since_last_frame {
+                None => "no audio frames received yet".to_string(),
+                Some(age) => format!("no audio frames for {:.1}s", age),
+            })),
+        },
+        wakeword_initialized: state.wakeword.lock().is_some(),
+        output_dir_writable: output_dir.is_ok(),
+        output_dir_error: output_dir.err().map(|e| e.to_string()),
+    };
+    if capture_ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SaveQuery {
+    /// Save only the most recent N seconds
+    seconds: Option<f64>,
+    /// Start of the window, in seconds before now
+    from: Option<f64>,
+    /// End of the window, in seconds before now
+    to: Option<f64>,
+    /// Return the WAV in the response instead of writing it to disk
+    #[serde(default)]
+    download: bool,
+    /// With `download`, return the file base64-encoded in a JSON body instead,
+    /// up to the --max-base64-mb limit
+    #[serde(default)]
+    base64: bool,
+    /// Queue when the concurrent save limit is reached instead of failing with 429
+    #[serde(default = "default_true")]
+    #[param(default = true)]
+    wait: bool,
+    /// How pauses inside the window are handled (default: ignore)
+    #[serde(default)]
+    #[param(inline)]
+    gaps: GapMode,
+    /// Write in the background and return 202 with a job to poll at /jobs/{id}
+    #[serde(default, rename = "async")]
+    #[param(rename = "async")]
+    run_async: bool,
+    /// Encode this save as wav, ulaw, alaw, mp3, opus or raw instead of --output-format
+    #[param(inline)]
+    format: Option<OutputFormat>,
+    /// Write one mono file per channel, named `_ch0`, `_ch1`, ...; all of them are
+    /// written or none are. Mono captures save normally. (default: on with --split-channels)
+    #[serde(alias = "per_channel")]
+    split_channels: Option<bool>,
+    /// Scale the saved copy so its peak reaches `normalize_target`; the buffer is untouched
+    #[serde(default)]
+    normalize: bool,
+    /// Peak level for `normalize`, in dBFS (default: -1)
+    normalize_target: Option<f64>,
+    /// Resample to this rate in Hz before writing; wav and mp3 only (default: the capture rate)
+    target_rate: Option<u32>,
+    /// Upload the saved files and their sidecars to the --s3-bucket in the background;
+    /// the response links the upload job (default: on with --auto-upload)
+    upload: Option<bool>,
+    /// Push the saved files to this URL instead of --save-webhook, without its
+    /// Authorization header; `none` skips the push
+    webhook: Option<String>,
+    /// Save even when the window holds less than --min-save-seconds, or nothing
+    #[serde(default)]
+    allow_empty: bool,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+struct SavedFile {
+    path: String,
+    samples: usize,
+    duration_seconds: f64,
+    size_bytes: u64,
+    // Hex SHA-256 of the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    detections: Vec<recordings::DetectionMark>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    markers: Vec<recordings::CuePoint>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+struct SaveResponse {
+    // First file written; totals below cover every segment
+    path: String,
+    samples: usize,
+    duration_seconds: f64,
+    size_bytes: u64,
+    // Hex SHA-256 of the file; with several segments each lists its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    // Wakeword detections in the file; with several segments each lists its own
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    detections: Vec<recordings::DetectionMark>,
+    // /mark labels in the file, listed per segment like the detections
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    markers: Vec<recordings::CuePoint>,
+    // One entry per file with gaps=split or split_channels
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<SavedFile>,
+    // Present with normalize=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalization: Option<encoding::Normalization>,
+    // Length of the whole --append-to file after this save; the other fields
+    // describe only the appended audio, except size_bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_seconds: Option<f64>,
+    // Job uploading the files with upload=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<jobs::JobAccepted>,
+    // Job pushing the files to the webhook
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<jobs::JobAccepted>,
+    // Files from the other --input-device devices; the fields above describe the first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<SavedDevice>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+struct SavedDevice {
+    device: String,
+    // One per segment or channel, like `segments`, otherwise just the one
+    files: Vec<SavedFile>,
+}
+
+impl SaveResponse {
+    // Every file written, for uploading
+    fn files(&self) -> Vec<std::path::PathBuf> {
+        let own: Vec<std::path::PathBuf> = match self.segments.is_empty() {
+            true => vec![self.path.clone().into()],
+            false => self.segments.iter().map(|file| file.path.clone().into()).collect(),
+        };
+        let others = self.devices.iter().flat_map(|device| &device.files).map(|file| file.path.clone().into());
+        own.into_iter().chain(others).collect()
+    }
+
+    // The files written, as `segments` lists them
+    fn saved_files(&self) -> Vec<SavedFile> {
+        match self.segments.is_empty() {
+            true => vec![SavedFile {
+                path: self.path.clone(),
+                samples: self.samples,
+                duration_seconds: self.duration_seconds,
+                size_bytes: self.size_bytes,
+                sha256: self.sha256.clone(),
+                detections: self.detections.clone(),
+                markers: self.markers.clone(),
+            }],
+            false => self.segments.clone(),
+        }
+    }
+
+    fn completed_event(&self, files: usize) -> events::Event {
+        events::Event::SaveCompleted {
+            path: self.path.clone(),
+            files,
+            samples: self.samples,
+            duration_seconds: self.duration_seconds,
+            size_bytes: self.size_bytes,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl SaveQuery {
+    fn window(&self) -> SaveWindow {
+        match self.seconds {
+            Some(seconds) => SaveWindow::last_seconds(seconds),
+            None => SaveWindow { from: self.from, to: self.to, ..SaveWindow::default() },
+        }
+    }
+
+    // The configured output options, with the requested format and rate if any
+    fn output(&self, configured: OutputOptions) -> OutputOptions {
+        OutputOptions {
+            format: self.format.unwrap_or(configured.format),
+            sample_rate: self.target_rate.or(configured.sample_rate),
+            ..configured
+        }
+    }
+}
+
+// Output-relative stem for the next save. Drawing the
+// counter and looking for a free stem both touch the disk, so they run on the
+// blocking pool.
+async fn next_save_name(state: &Arc<AudioState>, trigger: filename::Trigger) -> String {
+    let blocking = Arc::clone(state);
+    match web::block(move || pick_save_name(&blocking, trigger)).await {
+        Ok(name) => name,
+        // The pool only turns work away while shutting down
+        Err(_) => pick_save_name(state, trigger),
+    }
+}
+
+fn pick_save_name(state: &AudioState, trigger: filename::Trigger) -> String {
+    let seq = state.save_counter.fetch_add(1, Ordering::Relaxed);
+    // Drawn only when used, so the persisted count has no holes
+    let counter = match (&state.file_counter, state.filename_template.uses_counter()) {
+        (Some(counter), true) => counter.next(),
+        _ => seq,
+    };
+    let now = chrono::Local::now();
+    let name = render_save_name(state, now, trigger, seq, counter);
+    // Relative to the output directory; the date matches the local time in the name
+    let stem = if state.organize_by_date {
+        format!("{}/{}", now.format("%Y/%m/%d"), name)
+    } else {
+        name
+    };
+    // Held while looking, so concurrent saves can't both settle on one name
+    let mut recent = state.recent_stems.lock();
+    let output_dir = std::path::PathBuf::from(&state.settings.read().output_dir);
+    let stem = filename::free_stem(&output_dir, &stem, |candidate| recent.iter().any(|taken| taken == candidate));
+    if recent.len() == RECENT_STEMS {
+        recent.pop_front();
+    }
+    recent.push_back(stem.clone());
+    stem
+}
+
+fn render_save_name(
+    state: &AudioState,
+    now: chrono::DateTime<chrono::Local>,
+    trigger: filename::Trigger,
+    seq: u64,
+    counter: u64,
+) -> String {
+    state.filename_template.render(now, &filename::NameContext {
+        trigger,
+        keyword: None,
+        seq,
+        counter,
+        device: &state.device_name,
+    })
+}
+
+// The name a save would get now, for a download's Content-Disposition. Nothing
+// is written, so neither counter advances and no stem is reserved.
+fn download_name(state: &AudioState, output: OutputOptions, trigger: filename::Trigger) -> String {
+    let seq = state.save_counter.load(Ordering::Relaxed);
+    let counter = match (&state.file_counter, state.filename_template.uses_counter()) {
+        (Some(counter), true) => counter.peek(),
+        _ => seq,
+    };
+    let name = render_save_name(state, chrono::Local::now(), trigger, seq, counter);
+    format!("{}.{}", name.rsplit('/').next().unwrap_or(&name), output.format.extension())
+}
+
+// Options that can't apply when every save appends to the --append-to file
+fn validate_append(query: &SaveQuery, per_channel: bool, configured: OutputOptions) -> Result<(), String> {
+    if query.gaps == GapMode::Split {
+        return Err("`gaps=split` cannot be used with --append-to".to_string());
+    }
+    if query.target_rate.is_some() {
+        return Err("`target_rate` cannot be used with --append-to".to_string());
+    }
+    if per_channel {
+        return Err("`split_channels` cannot be used with --append-to".to_string());
+    }
+    if query.upload == Some(true) {
+        return Err("`upload` cannot be used with --append-to".to_string());
+    }
+    if query.webhook.as_deref().is_some_and(|url| url != "none") {
+        return Err("`webhook` cannot be used with --append-to".to_string());
+    }
+    if query.output(configured).format != OutputFormat::Wav {
+        return Err("only WAV can be appended to with --append-to".to_string());
+    }
+    Ok(())
+}
+
+async fn acquire_save_permit(state: &AudioState, wait: bool) -> Option<tokio::sync::SemaphorePermit<'_>> {
+    if !wait {
+        return state.save_permits.try_acquire().ok();
+    }
+    match tokio::time::timeout(SAVE_WAIT_TIMEOUT, state.save_permits.acquire()).await {
+        Ok(Ok(permit)) => Some(permit),
+        // Timed out, or the semaphore was closed
+        _ => None,
+    }
+}
+
+/// Save the buffered audio to a WAV file, or return it with `download=true`
+#[utoipa::path(
+    post,
+    path = "/save",
+    params(SaveQuery),
+    responses(
+        (status = 200, description = "Saved file, or the WAV itself with download=true and its SHA-256 in X-Content-SHA256; \
+            with base64=true as well, JSON holding the file in `wav_base64`", body = SaveResponse),
+        (status = 202, description = "Save queued with async=true", body = jobs::JobAccepted),
+        (status = 400, description = "Invalid window, or base64 over the size limit", body = ErrorResponse),
+        (status = 403, description = "The output directory is not writable", body = ErrorResponse),
+        (status = 429, description = "Too many saves in progress", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+        (status = 503, description = "Server is shutting down", body = ErrorResponse),
+        (status = 409, description = "The --append-to file has a different format", body = ErrorResponse),
+        (status = 422, description = "The window is empty or shorter than --min-save-seconds", body = api::ShortBufferResponse),
+        (status = 507, description = "The disk is full, or the recording would not fit in --max-output-bytes", body = ErrorResponse),
+    ),
+)]
+async fn save_audio(state: web::Data<Arc<AudioState>>, query: web::Query<SaveQuery>) -> HttpResponse {
+    if state.is_halting.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().json(ErrorResponse::new("Server is shutting down"));
+    }
+    let window = query.window();
+    if let Err(e) = window.validate() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(e));
+    }
+    if query.download && query.gaps == GapMode::Split {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`gaps=split` cannot be combined with `download`"));
+    }
+    if query.download && query.run_async {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`async` cannot be combined with `download`"));
+    }
+    if query.base64 && !query.download {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`base64` needs `download=true`"));
+    }
+    let per_channel = query.split_channels.unwrap_or(state.split_channels && state.append_to.is_none());
+    if state.append_to.is_some() && !query.download {
+        if let Err(e) = validate_append(&query, per_channel, state.output) {
+            return HttpResponse::BadRequest().json(ErrorResponse::new(e));
+        }
+    }
+    let normalize_target = query.normalize_target.unwrap_or(encoding::DEFAULT_NORMALIZE_TARGET_DBFS);
+    if !normalize_target.is_finite() || !(-60.0..=0.0).contains(&normalize_target) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`normalize_target` must be between -60 and 0 dBFS"));
+    }
+    if let Some(rate) = query.target_rate {
+        if !(MIN_TARGET_RATE..=MAX_TARGET_RATE).contains(&rate) {
+            return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+                "`target_rate` must be between {} and {} Hz", MIN_TARGET_RATE, MAX_TARGET_RATE
+            )));
+        }
+        if !matches!(query.output(state.output).format, OutputFormat::Wav | OutputFormat::Mp3 | OutputFormat::Raw) {
+            return HttpResponse::BadRequest().json(ErrorResponse::new(
+                "`target_rate` only applies to wav, mp3 and raw; ulaw, alaw and opus have fixed rates"
+            ));
+        }
+    }
+    if query.download && query.split_channels == Some(true) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`split_channels` cannot be combined with `download`"));
+    }
+    let upload = !query.download && query.upload.unwrap_or(state.auto_upload);
+    if query.download && query.upload == Some(true) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`upload` cannot be combined with `download`"));
+    }
+    if upload && state.s3.is_none() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(upload::NOT_CONFIGURED));
+    }
+    if query.download && query.webhook.as_deref().is_some_and(|url| url != "none") {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("`webhook` cannot be combined with `download`"));
+    }
+    let webhook = match state.webhook.target(query.webhook.as_deref()) {
+        Ok(target) => target.filter(|_| !query.download),
+        Err(e) => return HttpResponse::BadRequest().json(ErrorResponse::new(format!("Invalid `webhook`: {}", e))),
+    };
+
+    // At most --max-concurrent-saves at once; the permit is held until the response is built.
+    // Async jobs take theirs in the background instead.
+    let _save_permit = if query.run_async {
+        None
+    } else {
+        match acquire_save_permit(&state, query.wait).await {
+            Some(permit) => Some(permit),
+            None => {
+                return HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, SAVE_RETRY_AFTER_SECS.to_string()))
+                    .json(ErrorResponse::new("Too many saves in progress"));
+            }
+        }
+    };
+
+    let output = query.output(state.output);
+    let config = state.input_config();
+    tracing::debug!("Using input config: {:?}", config);
+
+    // Snapshot under the lock, then encode on the blocking pool so workers stay free
+    let normalize = query.normalize.then_some(normalize_target);
+    let (snapshot, normalization) = prepare_snapshot(&state, &config, window, query.gaps, normalize);
+    // Checked here so async jobs are refused up front rather than failing later
+    if !query.allow_empty {
+        if let Err(e) = capture_audio::check_length(snapshot.samples.len(), &config, state.min_save_seconds) {
+            return e.error_response();
+        }
+    }
+    if query.download {
+        let base64_limit = query.base64.then_some(state.base64_limit);
+        let filename = download_name(&state, output, filename::Trigger::Manual);
+        return download_audio(filename, snapshot.samples, config, output, base64_limit).await;
+    }
+    let stem = next_save_name(&state, filename::Trigger::Manual).await;
+    let snapshots = device_snapshots(&state, snapshot, config, window, query.gaps, normalize);
+
+    if query.run_async {
+        let job_id = state.jobs.create();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            // Queue behind other saves without holding up the request
+            let Ok(_permit) = state.save_permits.acquire().await else {
+                state.jobs.finish(job_id, Err("Save queue closed".to_string()));
+                return;
+            };
+            state.jobs.start(job_id);
+            let result = write_device_snapshots(snapshots, stem, output, per_channel, filename::Trigger::Manual)
+                .await
+                .map(|response| SaveResponse { normalization, ..response })
+                .map(|response| start_pushes(&state, response, upload, webhook))
+                .map_err(|e| e.to_string());
+            if let Err(e) = &result {
+                tracing::error!("Save job {} failed: {}", job_id, e);
+            }
+            state.jobs.finish(job_id, result);
+        });
+        tracing::info!("Queued save job {}", job_id);
+        let accepted = jobs::JobAccepted::new(job_id);
+        return HttpResponse::Accepted()
+            .insert_header((header::LOCATION, accepted.status_url()))
+            .json(accepted);
+    }
+
+    match write_device_snapshots(snapshots, stem, output, per_channel, filename::Trigger::Manual).await {
+        Ok(response) => {
+            let response = start_pushes(&state, SaveResponse { normalization, ..response }, upload, webhook);
+            let outcome = access_log::SaveOutcome { file: response.path.clone(), bytes: response.size_bytes };
+            let mut http_response = HttpResponse::Ok().json(response);
+            http_response.extensions_mut().insert(outcome);
+            http_response
+        }
+        // The --append-to file doesn't match the recording format
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            tracing::error!("Failed to save audio: {}", e);
+            HttpResponse::Conflict().json(ErrorResponse::new(format!("Failed to save audio: {}", e)))
+        }
+        Err(e) => {
+            tracing::error!("Failed to save audio: {}", e);
+            HttpResponse::build(save_error_status(&e)).json(ErrorResponse::new(format!("Failed to save audio: {}", e)))
+        }
+    }
+}
+
+// Status of a save that failed to write: 507 when the disk, the quota or
+// --max-output-bytes is full and 403 when the output directory isn't
+// writable, so clients can tell whether retrying later may help
+fn save_error_status(e: &std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Queue the webhook push and upload of the saved files, as requested, linking
+// their jobs in the response. The upload waits for the push, so deleting the
+// local copy can't pull the files out from under it.
+fn start_pushes(
+    state: &Arc<AudioState>,
+    response: SaveResponse,
+    upload: bool,
+    webhook: Option<webhook::Target>,
+) -> SaveResponse {
+    let (webhook, pushed) = match webhook {
+        Some(target) => {
+            let (job_id, handle) = webhook::spawn_push(Arc::clone(state), response.files(), target);
+            (Some(jobs::JobAccepted::new(job_id)), Some(handle))
+        }
+        None => (None, None),
+    };
+    let upload = upload.then(|| jobs::JobAccepted::new(upload::spawn_upload(Arc::clone(state), response.files(), pushed)));
+    SaveResponse { upload, webhook, ..response }
+}
+
+// Save the part of the buffer `window` selects outside of any request, as a
+// /save with the configured options would. Pauses become silence, so the file
+// keeps real time. Returns the save, or None when the window holds no audio,
+// with the absolute buffer positions the snapshot covered.
+async fn save_in_background(
+    state: &Arc<AudioState>,
+    window: SaveWindow,
+    trigger: filename::Trigger,
+) -> std::io::Result<(Option<SaveResponse>, std::ops::Range<u64>)> {
+    let Some(_save_permit) = acquire_save_permit(state, true).await else {
+        return Err(std::io::Error::other("too many saves in progress"));
+    };
+    let config = state.input_config();
+    let snapshot = capture_audio::snapshot_buffer(state, &config, window);
+    let span = snapshot.end - snapshot.samples.len() as u64..snapshot.end;
+    if snapshot.samples.is_empty() {
+        return Ok((None, span));
+    }
+    let snapshot = snapshot.with_silence(config.channels(), state.buffer.lock().capacity());
+    let snapshots = device_snapshots(state, snapshot, config, window, GapMode::Silence, None);
+
+    let stem = next_save_name(state, trigger).await;
+    let per_channel = state.split_channels && state.append_to.is_none();
+    let response = write_device_snapshots(snapshots, stem, state.output, per_channel, trigger).await?;
+    let webhook = state.webhook.target(None).ok().flatten();
+    Ok((Some(start_pushes(state, response, state.auto_upload, webhook)), span))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RecordQuery {
+    /// Length of the recording; at most the buffer length
+    seconds: f64,
+}
+
+// Puts recording back as /record found it when dropped, which also covers a
+// client disconnecting and the handler's future being dropped mid-recording
+struct RestoreRecording<'a> {
+    state: &'a AudioState,
+    previous: RecordingState,
+}
+
+impl Drop for RestoreRecording<'_> {
+    fn drop(&mut self) {
+        match self.previous {
+            RecordingState::Recording => {}
+            RecordingState::Paused => self.state.pause(),
+            RecordingState::Stopped => {
+                self.state.stop();
+            }
+        }
+    }
+}
+
+/// Clear the buffer, record for `seconds`, save and return the file, then go back to the previous state
+#[utoipa::path(
+    post,
+    path = "/record",
+    params(RecordQuery),
+    responses(
+        (status = 200, body = SaveResponse),
+        (status = 400, description = "Invalid or too long duration", body = ErrorResponse),
+        (status = 403, description = "The output directory is not writable", body = ErrorResponse),
+        (status = 409, description = "Another /record is in progress", body = ErrorResponse),
+        (status = 422, description = "The duration rounds to no samples or is shorter than --min-save-seconds", body = api::ShortBufferResponse),
+        (status = 500, body = ErrorResponse),
+        (status = 503, description = "Server is shutting down or no audio arrived", body = ErrorResponse),
+        (status = 507, description = "The disk is full, or the recording would not fit in --max-output-bytes", body = ErrorResponse),
+    ),
+)]
+async fn record_once(state: web::Data<Arc<AudioState>>, query: web::Query<RecordQuery>) -> HttpResponse {
+    if state.is_halting.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().json(ErrorResponse::new("Server is shutting down"));
+    }
+    let config = state.input_config();
+    let (rate, channels) = (config.sample_rate().0, config.channels().max(1) as usize);
+    let buffer_seconds = state.samples_to_seconds(state.buffer.lock().capacity());
+    if !query.seconds.is_finite() || query.seconds <= 0.0 || query.seconds > buffer_seconds {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "`seconds` must be greater than 0 and at most the {:.1}s buffer", buffer_seconds
+        )));
+    }
+    // Checked before recording; fewer delivered samples are refused below
+    let wanted = (query.seconds * rate as f64).round() as usize * channels;
+    if let Err(e) = capture_audio::check_length(wanted, &config, state.min_save_seconds) {
+        return e.error_response();
+    }
+    let Ok(_recording) = state.record_lock.try_lock() else {
+        return HttpResponse::Conflict().json(ErrorResponse::new("Another /record is in progress"));
+    };
+
+    // Start from an empty buffer so the file holds only this recording
+    let restore = RestoreRecording { state: &state, previous: state.recording_state() };
+    state.stop();
+    let start = state.samples_written.load(Ordering::Relaxed);
+    tracing::info!("Recording {:.2}s", query.seconds);
+    state.resume(rate);
+
+    // Sleep for the duration, then wait out callbacks still in flight
+    tokio::time::sleep(Duration::from_secs_f64(query.seconds)).await;
+    let deadline = tokio::time::Instant::now() + RECORD_STALL_TIMEOUT;
+    let recorded = || (state.samples_written.load(Ordering::Relaxed) - start) as usize;
+    let done = || recorded() >= wanted || state.is_halting.load(Ordering::Relaxed);
+    wait_until(deadline, &[&state.samples_arrived, &state.halt_waiters], done).await;
+    let snapshot = capture_audio::snapshot_buffer(&state, &config, SaveWindow::default());
+    // The other devices too, before going back to a stop clears them
+    let window = SaveWindow::last_seconds(query.seconds);
+    let mut snapshots = device_snapshots(&state, snapshot, config, window, GapMode::Ignore, None);
+    drop(restore);
+    let snapshot = &mut snapshots[0].snapshot;
+    if snapshot.samples.len() < wanted {
+        tracing::error!("Recording got {} of {} samples", snapshot.samples.len(), wanted);
+        return HttpResponse::ServiceUnavailable().json(ErrorResponse::new(format!(
+            "Capture delivered {:.2}s of the requested {:.2}s",
+            snapshot.samples.len() as f64 / channels as f64 / rate as f64, query.seconds
+        )));
+    }
+    snapshot.samples.truncate(wanted);
+    snapshot.detections.retain(|&(offset, _)| offset <= wanted);
+    snapshot.markers.retain(|&(offset, _)| offset <= wanted);
+    snapshot.gaps.clear();
+
+    let Some(_save_permit) = acquire_save_permit(&state, true).await else {
+        return HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, SAVE_RETRY_AFTER_SECS.to_string()))
+            .json(ErrorResponse::new("Too many saves in progress"));
+    };
+    let stem = next_save_name(&state, filename::Trigger::Manual).await;
+    let per_channel = state.split_channels && state.append_to.is_none();
+    match write_device_snapshots(snapshots, stem, state.output, per_channel, filename::Trigger::Manual).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            tracing::error!("Failed to save recording: {}", e);
+            HttpResponse::build(save_error_status(&e)).json(ErrorResponse::new(format!("Failed to save recording: {}", e)))
+        }
+    }
+}
+
+// Snapshot `window` with pauses handled as `gaps` and, with `normalize`,
+// the copy scaled to that peak in dBFS; the buffer keeps the audio as captured
+fn prepare_snapshot(
+    state: &AudioState,
+    config: &cpal::SupportedStreamConfig,
+    window: SaveWindow,
+    gaps: GapMode,
+    normalize: Option<f64>,
+) -> (Snapshot, Option<encoding::Normalization>) {
+    let snapshot = capture_audio::snapshot_buffer(state, config, window);
+    if !snapshot.gaps.is_empty() {
+        tracing::info!("Saved window spans {} pauses, handling them as {:?}", snapshot.gaps.len(), gaps);
+    }
+    let mut snapshot = match gaps {
+        GapMode::Ignore => Snapshot { gaps: Vec::new(), ..snapshot },
+        // A pause never adds more silence than the buffer could hold
+        GapMode::Silence => snapshot.with_silence(config.channels(), state.buffer.lock().capacity()),
+        GapMode::Split => snapshot,
+    };
+    let normalization = normalize.map(|target| {
+        let normalization = encoding::normalize_peak(&mut snapshot.samples, target);
+        tracing::info!("Normalization: {:?}", normalization);
+        normalization
+    });
+    (snapshot, normalization)
+}
+
+// One device's part of a save
+struct DeviceSnapshot {
+    state: Arc<AudioState>,
+    snapshot: Snapshot,
+    config: cpal::SupportedStreamConfig,
+}
+
+// The primary's snapshot, then the same window from each other device, taken
+// straight after it so every file covers the same moment
+fn device_snapshots(
+    state: &Arc<AudioState>,
+    snapshot: Snapshot,
+    config: cpal::SupportedStreamConfig,
+    window: SaveWindow,
+    gaps: GapMode,
+    normalize: Option<f64>,
+) -> Vec<DeviceSnapshot> {
+    // Absolute positions in `window` are the primary's, so each device gets
+    // them moved onto its own buffer
+    let written = state.samples_written.load(Ordering::Relaxed);
+    let others = state.other_devices.iter().map(|other| {
+        let other_config = other.input_config();
+        let other_written = other.samples_written.load(Ordering::Relaxed);
+        let window = window.on_timeline(written, &config, other_written, &other_config);
+        let (snapshot, _) = prepare_snapshot(other, &other_config, window, gaps, normalize);
+        DeviceSnapshot { state: Arc::clone(other), snapshot, config: other_config }
+    }).collect::<Vec<_>>();
+    std::iter::once(DeviceSnapshot { state: Arc::clone(state), snapshot, config }).chain(others).collect()
+}
+
+// Write the primary's snapshot with write_snapshot, then the other devices'
+// beside it, with every stem suffixed by its device's name. A device failing
+// fails the save, though the files of the devices before it stay written.
+async fn write_device_snapshots(
+    snapshots: Vec<DeviceSnapshot>,
+    stem: String,
+    output: OutputOptions,
+    per_channel: bool,
+    trigger: filename::Trigger,
+) -> std::io::Result<SaveResponse> {
+    let mut snapshots = snapshots.into_iter();
+    let Some(primary) = snapshots.next() else {
+        return Err(std::io::Error::other("no device to save from"));
+    };
+    let state = &primary.state;
+    if state.other_devices.is_empty() {
+        return write_snapshot(state, primary.snapshot, stem, primary.config, output, per_channel, trigger).await;
+    }
+    let device_stem = |device: &str| format!("{}_{}", stem, filename::sanitize(device));
+    let primary_stem = device_stem(&state.device_name);
+    let response = write_snapshot(state, primary.snapshot, primary_stem, primary.config, output, per_channel, trigger).await?;
+    let mut devices = Vec::with_capacity(state.other_devices.len());
+    for other in snapshots {
+        if other.snapshot.samples.is_empty() {
+            tracing::warn!("Nothing buffered from {}; saving without it", other.state.device_name);
+            continue;
+        }
+        let stem = device_stem(&other.state.device_name);
+        let saved = write_snapshot(&other.state, other.snapshot, stem, other.config, output, per_channel, trigger).await?;
+        devices.push(SavedDevice { device: other.state.device_name.clone(), files: saved.saved_files() });
+    }
+    Ok(SaveResponse { devices, ..response })
+}
+
+// Write the snapshot as `<stem>.<ext>`, or one `<stem>_NN.<ext>` per segment,
+// encoding on the blocking pool. With `per_channel` each file is split further
+// into `<stem>_chN.<ext>`, counting channels from 0. Either every file is
+// written or none are. `stem` is relative to the output directory and may
+// include subdirectories.
+async fn write_snapshot(
+    state: &Arc<AudioState>,
+    snapshot: Snapshot,
+    stem: String,
+    config: cpal::SupportedStreamConfig,
+    output: OutputOptions,
+    per_channel: bool,
+    trigger: filename::Trigger,
+) -> std::io::Result<SaveResponse> {
+    let started = std::time::Instant::now();
+    if let Some(target) = &state.append_to {
+        return append_snapshot(state, snapshot, target.clone(), config, output)
+            .await
+            .inspect(|response| state.publish(response.completed_event(1)));
+    }
+    let extension = output.format.extension();
+    let stems: Vec<String> = match snapshot.gaps.len() {
+        0 => vec![stem],
+        gaps => (1..=gaps + 1).map(|part| format!("{}_{:02}", stem, part)).collect(),
+    };
+    let per_channel = per_channel && config.channels() > 1;
+    let filenames: Vec<String> = stems.iter()
+        .flat_map(|stem| match per_channel {
+            true => (0..config.channels()).map(|channel| format!("{}_ch{}.{}", stem, channel, extension)).collect(),
+            false => vec![format!("{}.{}", stem, extension)],
+        })
+        .collect();
+    let output_dir = std::path::PathBuf::from(&state.settings.read().output_dir);
+    let filepaths: Vec<_> = filenames.iter().map(|name| output_dir.join(name)).collect();
+    let _active: Vec<_> = filenames.iter().map(|name| recordings::ActiveSave::begin(state, name)).collect();
+    for filepath in &filepaths {
+        tracing::info!("Saving audio to {}", filepath.display());
+    }
+
+    // Make room under --max-output-bytes before writing anything
+    if let Some(budget) = state.output_budget {
+        let needed = output.estimated_size(snapshot.samples.len(), config.channels(), config.sample_rate().0);
+        let cleanup_state = Arc::clone(state);
+        web::block(move || retention::make_room(&cleanup_state, budget, needed))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))?;
+    }
+
+    let write_paths = filepaths.clone();
+    let device = state.device_name.clone();
+    let saved = web::block(move || {
+        let (channels, rate) = (config.channels().max(1) as usize, config.sample_rate().0 as f64);
+        let file_config = if per_channel { capture_audio::channel_config(&config) } else { config.clone() };
+        let segments = snapshot.segments(config.channels(), config.sample_rate().0);
+        let files: Vec<(std::borrow::Cow<[f32]>, &capture_audio::Segment)> = segments.iter()
+            .flat_map(|segment| {
+                let files: Vec<std::borrow::Cow<[f32]>> = if per_channel {
+                    encoding::split_channels(segment.samples, config.channels()).into_iter().map(Into::into).collect()
+                } else {
+                    vec![segment.samples.into()]
+                };
+                files.into_iter().map(move |samples| (samples, segment))
+            })
+            .collect();
+        let targets: Vec<(&[f32], &std::path::Path)> = files.iter().zip(&write_paths)
+            .map(|((samples, _), path)| (samples.as_ref(), path.as_path()))
+            .collect();
+
+        // Build each sidecar as its file is started, so WAV files can carry
+        // the same metadata ahead of their audio
+        let mut sidecars = Vec::with_capacity(files.len());
+        let saved = capture_audio::save_audio_to_files(&targets, &file_config, output, |index, saved| {
+            let (_, segment) = &files[index];
+            let path = &write_paths[index];
+            // Offsets are in captured samples; the file may be resampled or downmixed
+            let place = |offset: usize| {
+                let seconds = (offset / channels) as f64 / rate;
+                ((seconds * saved.sample_rate as f64).round() as u64, seconds)
+            };
+            let detections: Vec<_> = segment.detections.iter()
+                .map(|&(offset, detection)| {
+                    let (sample_offset, seconds) = place(offset);
+                    recordings::DetectionMark {
+                        keyword: detection.keyword.to_string(),
+                        sample_offset,
+                        seconds,
+                        captured_sample: detection.captured,
+                    }
+                })
+                .collect();
+            let markers: Vec<_> = segment.markers.iter()
+                .map(|(offset, marker)| {
+                    let (sample_offset, seconds) = place(*offset);
+                    recordings::CuePoint { label: marker.label.clone(), sample_offset, seconds, captured_sample: marker.captured }
+                })
+                .collect();
+            let sidecar = recordings::Sidecar {
+                recording: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                format: format!("{:?}", output.format).to_lowercase(),
+                sample_rate: saved.sample_rate,
+                channels: saved.channels,
+                bits_per_sample: saved.bits_per_sample,
+                samples: saved.samples,
+                duration_seconds: saved.duration_seconds,
+                started_at: segment.started_at,
+                saved_at: chrono::Local::now(),
+                trigger: trigger.as_str().to_string(),
+                detections,
+                markers,
+                audio_host: capture_audio::host_name().to_string(),
+                device: device.clone(),
+                sha256: None,
+            };
+            let chunks = match output.format.is_riff() {
+                true => bwf::BroadcastInfo::from_sidecar(&sidecar).chunks(),
+                false => Vec::new(),
+            };
+            sidecars.push(sidecar);
+            Ok(chunks)
+        })?;
+
+        saved.into_iter().zip(sidecars).zip(&write_paths)
+            .map(|((saved, mut sidecar), path)| {
+                sidecar.sha256 = saved.sha256.clone();
+                // The recording itself is intact, so a missing sidecar only warrants a warning
+                if let Err(e) = recordings::write_sidecar(path, &sidecar) {
+                    tracing::warn!("Failed to write sidecar for {}: {}", path.display(), e);
+                }
+                let size = std::fs::metadata(path)?.len();
+                Ok((saved, size, sidecar.detections, sidecar.markers))
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+    })
+    .await
+    .unwrap_or_else(|e| Err(std::io::Error::other(e)))?;
+    state.output_usage.add(saved.iter().map(|(_, size, _, _)| size).sum());
+
+    // Apply the retention limits now that the new files are safely written
+    let policy = state.retention;
+    if policy.is_enabled() {
+        let cleanup_state = Arc::clone(state);
+        let written = filepaths.clone();
+        if let Err(e) = web::block(move || retention::enforce(&cleanup_state, policy, &written)).await {
+            tracing::warn!("Retention pass failed: {}", e);
+        }
+    }
+
+    let mut files: Vec<SavedFile> = saved.into_iter().zip(&filepaths)
+        .map(|((saved, size, detections, markers), path)| {
+            tracing::info!(
+                path = %path.display(),
+                samples = saved.samples,
+                duration_seconds = saved.duration_seconds,
+                trigger = trigger.as_str(),
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "Saved recording"
+            );
+            SavedFile {
+                path: path.display().to_string(),
+                samples: saved.samples,
+                duration_seconds: saved.duration_seconds,
+                size_bytes: size,
+                sha256: saved.sha256,
+                detections,
+                markers,
+            }
+        })
+        .collect();
+    let count = files.len();
+    let single = count == 1;
+    let response = SaveResponse {
+        path: files[0].path.clone(),
+        samples: files.iter().map(|f| f.samples).sum(),
+        duration_seconds: files.iter().map(|f| f.duration_seconds).sum(),
+        size_bytes: files.iter().map(|f| f.size_bytes).sum(),
+        sha256: if single { files[0].sha256.clone() } else { None },
+        detections: if single { std::mem::take(&mut files[0].detections) } else { Vec::new() },
+        markers: if single { std::mem::take(&mut files[0].markers) } else { Vec::new() },
+        segments: if single { Vec::new() } else { files },
+        normalization: None,
+        session_seconds: None,
+        upload: None,
+        webhook: None,
+        devices: Vec::new(),
+    };
+    state.publish(response.completed_event(count));
+    Ok(response)
+}
+
+// Append the snapshot to the --append-to session file, with detections and markers placed
+// relative to the start of the whole file
+async fn append_snapshot(
+    state: &Arc<AudioState>,
+    snapshot: Snapshot,
+    target: std::path::PathBuf,
+    config: cpal::SupportedStreamConfig,
+    output: OutputOptions,
+) -> std::io::Result<SaveResponse> {
+    let started = std::time::Instant::now();
+    if let Some(budget) = state.output_budget {
+        let needed = output.estimated_size(snapshot.samples.len(), config.channels(), config.sample_rate().0);
+        let cleanup_state = Arc::clone(state);
+        web::block(move || retention::make_room(&cleanup_state, budget, needed))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))?;
+    }
+
+    let append_state = Arc::clone(state);
+    let path = target.clone();
+    let (saved, detections, markers, frames_before, size_before, size) = web::block(move || {
+        let _appending = append_state.append_lock.lock();
+        let size_before = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let (saved, frames_before) = capture_audio::append_audio_to_file(&snapshot.samples, &path, &config, output)?;
+        let channels = config.channels().max(1) as usize;
+        let frame = |offset: usize| frames_before + (offset / channels) as u64;
+        let detections: Vec<_> = snapshot.detections.iter()
+            .map(|&(offset, detection)| recordings::DetectionMark {
+                keyword: detection.keyword.to_string(),
+                sample_offset: frame(offset),
+                seconds: frame(offset) as f64 / saved.sample_rate as f64,
+                captured_sample: detection.captured,
+            })
+            .collect();
+        let markers: Vec<_> = snapshot.markers.iter()
+            .map(|(offset, marker)| recordings::CuePoint {
+                label: marker.label.clone(),
+                sample_offset: frame(*offset),
+                seconds: frame(*offset) as f64 / saved.sample_rate as f64,
+                captured_sample: marker.captured,
+            })
+            .collect();
+        let size = std::fs::metadata(&path)?.len();
+        Ok::<_, std::io::Error>((saved, detections, markers, frames_before, size_before, size))
+    })
+    .await
+    .unwrap_or_else(|e| Err(std::io::Error::other(e)))?;
+    state.output_usage.add(size.saturating_sub(size_before));
+
+    tracing::info!(
+        path = %target.display(),
+        samples = saved.samples,
+        duration_seconds = saved.duration_seconds,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "Appended recording"
+    );
+    Ok(SaveResponse {
+        path: target.display().to_string(),
+        samples: saved.samples,
+        duration_seconds: saved.duration_seconds,
+        size_bytes: size,
+        // Hashing would mean reading back the whole session file
+        sha256: None,
+        detections,
+        markers,
+        segments: Vec::new(),
+        normalization: None,
+        session_seconds: Some(frames_before as f64 / saved.sample_rate as f64 + saved.duration_seconds),
+        upload: None,
+        webhook: None,
+        devices: Vec::new(),
+    })
+}
+
+// Body of /save?download=true&base64=true
+#[derive(Serialize, ToSchema)]
+struct Base64Download {
+    filename: String,
+    content_type: String,
+    // Hex SHA-256 of the decoded file
+    sha256: String,
+    // Named for the usual format; holds whichever `format` was asked for
+    wav_base64: String,
+}
+
+// Respond with the encoded WAV as an attachment, or with `base64_limit` as
+// JSON carrying it base64-encoded, if that comes to at most the limit in bytes
+async fn download_audio(
+    filename: String,
+    samples: Vec<f32>,
+    config: cpal::SupportedStreamConfig,
+    output: OutputOptions,
+    base64_limit: Option<usize>,
+) -> HttpResponse {
+    let result = web::block(move || capture_audio::encode_recording(&samples, &config, output))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    match (result, base64_limit) {
+        (Ok((bytes, saved)), Some(limit)) => {
+            let encoded_len = bytes.len().div_ceil(3) * 4;
+            if encoded_len > limit {
+                return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+                    "The recording is {:.1} MB base64-encoded, over the {:.1} MB limit; save a shorter window with `seconds`",
+                    encoded_len as f64 / 1e6, limit as f64 / 1e6
+                )));
+            }
+            use base64::Engine;
+            tracing::info!("Returning {} samples ({} bytes) base64-encoded as {}", saved.samples, bytes.len(), filename);
+            let outcome = access_log::SaveOutcome { file: filename.clone(), bytes: bytes.len() as u64 };
+            let mut response = HttpResponse::Ok().json(Base64Download {
+                filename,
+                content_type: output.format.content_type().to_string(),
+                sha256: capture_audio::sha256(&bytes),
+                wav_base64: base64::engine::general_purpose::STANDARD.encode(&bytes),
+            });
+            response.extensions_mut().insert(outcome);
+            response
+        }
+        (Ok((bytes, saved)), None) => {
+            tracing::info!("Returning {} samples ({} bytes) as {}", saved.samples, bytes.len(), filename);
+            let outcome = access_log::SaveOutcome { file: filename.clone(), bytes: bytes.len() as u64 };
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(output.format.content_type())
+                .insert_header(header::ContentDisposition::attachment(filename))
+                .insert_header((SHA256_HEADER, capture_audio::sha256(&bytes)));
+            if output.format == OutputFormat::Raw {
+                response
+                    .insert_header((RAW_ENCODING_HEADER, output.raw.name()))
+                    .insert_header((SAMPLE_RATE_HEADER, saved.sample_rate.to_string()))
+                    .insert_header((CHANNELS_HEADER, saved.channels.to_string()));
+            }
+            let mut response = response.body(bytes);
+            response.extensions_mut().insert(outcome);
+            response
+        }
+        (Err(e), _) => {
+            tracing::error!("Failed to encode audio: {}", e);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(format!("Failed to encode audio: {}", e)))
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReloadResponse {
+    frame_length: u32,
+    sample_rate: u32,
+    // Set when the engine's rate doesn't match the capture rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+/// Rebuild the wakeword engine from the environment, keeping the old one on failure
+#[utoipa::path(
+    post,
+    path = "/wakeword/reload",
+    responses(
+        (status = 200, body = ReloadResponse),
+        (status = 409, description = "Wakeword detection is disabled with --no-wakeword", body = ErrorResponse),
+        (status = 500, description = "Rebuild failed; the previous engine stays active", body = ErrorResponse),
+    ),
+)]
+async fn reload_wakeword(state: web::Data<Arc<AudioState>>) -> Result<HttpResponse, error::VoiceError> {
+    if state.wakeword_disabled {
+        return Ok(HttpResponse::Conflict().json(ErrorResponse::new("Wakeword detection is disabled with --no-wakeword")));
+    }
+    tracing::info!("Reloading wakeword engine");
+    let model_path = state.wakeword_model_path.clone();
+    let porcupine = web::block(move || wakeword_listener::get_wakeword_listener(model_path.as_deref()))
+        .await
+        .unwrap_or_else(|e| Err(wakeword_listener::WakewordError::Init(e.to_string()).into()))
+        .inspect_err(|e| tracing::error!("Failed to reload wakeword engine, keeping the previous one: {}", e))?;
+    let frame_length = porcupine.frame_length();
+    let sample_rate = porcupine.sample_rate();
+    let warning = wakeword_listener::rate_mismatch(state.input_config().sample_rate().0, sample_rate);
+    if let Some(warning) = &warning {
+        tracing::warn!("{}", warning);
+    }
+    *state.wakeword.lock() = Some(porcupine);
+    tracing::info!("Wakeword engine reloaded with frame length {}", frame_length);
+    Ok(HttpResponse::Ok().json(ReloadResponse { frame_length, sample_rate, warning }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HaltQuery {
+    /// Longest wait for capture to stop and saves to finish before exiting, in ms; 0 exits without waiting (default: 30000)
+    grace_ms: Option<u64>,
+    /// Save the whole buffer first; if that fails the server keeps running
+    #[serde(default)]
+    save: bool,
+}
+
+/// Stop capture, finish in-flight saves and shut the server down
+#[utoipa::path(
+    post,
+    path = "/halt",
+    params(HaltQuery),
+    responses(
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 400, description = "grace_ms is too long", body = ErrorResponse),
+        (status = 422, description = "save=true found the buffer empty or shorter than --min-save-seconds; the server keeps running", body = api::ShortBufferResponse),
+        (status = 429, description = "save=true found too many saves in progress; the server keeps running", body = ErrorResponse),
+        (status = 403, description = "save=true found the output directory not writable; the server keeps running", body = ErrorResponse),
+        (status = 500, description = "save=true failed; the server keeps running", body = ErrorResponse),
+        (status = 507, description = "save=true found the disk full; the server keeps running", body = ErrorResponse),
+    ),
+)]
+async fn halt_server(state: web::Data<Arc<AudioState>>, query: web::Query<HaltQuery>) -> HttpResponse {
+    if let Some(grace_ms) = query.grace_ms {
+        if grace_ms > MAX_SHUTDOWN_GRACE.as_millis() as u64 {
+            return HttpResponse::BadRequest()
+                .json(ErrorResponse::new(format!("`grace_ms` must be at most {}", MAX_SHUTDOWN_GRACE.as_millis())));
+        }
+    }
+
+    let mut body = "Server halting".to_string();
+    if query.save && !state.is_halting.load(Ordering::Relaxed) {
+        let Some(_save_permit) = acquire_save_permit(&state, true).await else {
+            return HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, SAVE_RETRY_AFTER_SECS.to_string()))
+                .json(ErrorResponse::new("Too many saves in progress, not halting"));
+        };
+        let config = state.input_config();
+        let (snapshot, _) = prepare_snapshot(&state, &config, SaveWindow::default(), GapMode::Ignore, None);
+        if let Err(e) = capture_audio::check_length(snapshot.samples.len(), &config, state.min_save_seconds) {
+            tracing::error!("Not halting, the buffer is too short to save: {}", e);
+            return e.error_response();
+        }
+        let snapshots = device_snapshots(&state, snapshot, config, SaveWindow::default(), GapMode::Ignore, None);
+        let stem = next_save_name(&state, filename::Trigger::Manual).await;
+        let per_channel = state.split_channels && state.append_to.is_none();
+        match write_device_snapshots(snapshots, stem, state.output, per_channel, filename::Trigger::Manual).await {
+            Ok(saved) => {
+                let files: Vec<String> = saved.files().iter().map(|file| file.display().to_string()).collect();
+                body = format!("Server halting, buffer saved to {}", files.join(", "));
+            }
+            Err(e) => {
+                tracing::error!("Not halting, saving the buffer failed: {}", e);
+                return HttpResponse::build(save_error_status(&e))
+                    .json(ErrorResponse::new(format!("Failed to save audio, not halting: {}", e)));
+            }
+        }
+    }
+
+    tracing::info!("Halting server");
+    if let Some(grace_ms) = query.grace_ms {
+        *state.shutdown_grace.lock() = Some(Duration::from_millis(grace_ms));
+    }
+    // The shutdown task stops the server gracefully, so this response is delivered first
+    state.request_shutdown();
+    HttpResponse::Ok().body(body)
+}
+
+// Wait until `done` returns true, checking again whenever one of `changed`
+// is notified, or until `deadline`. Returns whether it is done.
+async fn wait_until(deadline: tokio::time::Instant, changed: &[&tokio::sync::Notify], done: impl Fn() -> bool) -> bool {
+    loop {
+        let mut notified: Vec<_> = changed.iter().map(|notify| Box::pin(notify.notified())).collect();
+        // Registered before the check, so a change in between still wakes it
+        for notified in &mut notified {
+            notified.as_mut().enable();
+        }
+        if done() {
+            return true;
+        }
+        let any = std::future::poll_fn(|cx| {
+            match notified.iter_mut().any(|notified| std::future::Future::poll(notified.as_mut(), cx).is_ready()) {
+                true => std::task::Poll::Ready(()),
+                false => std::task::Poll::Pending,
+            }
+        });
+        if tokio::time::timeout_at(deadline, any).await.is_err() {
+            return done();
+        }
+    }
+}
+
+/// Once [`AudioState::request_shutdown`] is called, wait for capture and
+/// in-flight saves to finish, within the grace period /halt asked for, then
+/// stop the HTTP server
+pub async fn graceful_shutdown(state: Arc<AudioState>, server: actix_web::dev::ServerHandle) {
+    state.shutdown_requested.notified().await;
+    tracing::info!("Shutting down");
+
+    // Capture and saves share one grace period, from /halt?grace_ms or the default
+    let grace = state.shutdown_grace.lock().unwrap_or(SAVE_FINISH_TIMEOUT);
+    let started = tokio::time::Instant::now();
+    let capture_timeout = CAPTURE_STOP_TIMEOUT.min(grace);
+    let mut captures_stopped = true;
+    for device in std::iter::once(&state).chain(&state.other_devices) {
+        let stopped = || device.capture_stopped.load(Ordering::Relaxed);
+        captures_stopped &= wait_until(started + capture_timeout, &[&device.capture_done], stopped).await;
+    }
+    if !captures_stopped {
+        tracing::warn!("Capture thread did not stop within {:?}", capture_timeout);
+    }
+    let saves_done = || state.active_saves.lock().is_empty() && state.jobs.in_flight() == 0;
+    if !wait_until(started + grace, &[&state.saves_done, state.jobs.finished()], saves_done).await {
+        tracing::warn!("In-progress saves did not finish within the {:?} grace period", grace);
+    }
+
+    // Graceful stop lets in-flight responses (including /halt) complete
+    server.stop(true).await;
+}
+
+/// Every route the server exposes. The handlers expect the
+/// `web::Data<Arc<AudioState>>` to be registered already; [`app`] does that
+/// and adds the middleware. /process takes uploads of up to 50 MB here, and
+/// of up to [`AppOptions::upload_limit`] through [`app`].
+// New handlers also need listing in api::ApiDoc to appear in /openapi.json.
+pub fn configure_app(cfg: &mut web::ServiceConfig) {
+    configure_routes(cfg, DEFAULT_UPLOAD_LIMIT);
+}
+
+// Every route, with `upload_limit` on the one resource that reads a raw body
+fn configure_routes(cfg: &mut web::ServiceConfig, upload_limit: usize) {
+    cfg.route("/stop", web::post().to(stop_recording))
+        .route("/save", web::post().to(save_audio))
+        .route("/record", web::post().to(record_once))
+        .route("/halt", web::post().to(halt_server))
+        .route("/start", web::post().to(start_recording))
+        .route("/pause", web::post().to(pause_recording))
+        .route("/toggle", web::post().to(toggle_recording))
+        .route("/mark", web::post().to(mark_recording))
+        .route("/status", web::get().to(status))
+        .route("/health", web::get().to(health))
+        .route("/health/detail", web::get().to(health_detail))
+        .route("/stream", web::get().to(live_stream::stream_audio))
+        .route("/events", web::get().to(events::stream_events))
+        .route("/wait-for-detection", web::get().to(events::wait_for_detection))
+        .route("/wakeword/reload", web::post().to(reload_wakeword))
+        .route("/jobs/{id}", web::get().to(jobs::get_job))
+        .route("/maintenance", web::get().to(maintenance::maintenance_status))
+        .service(
+            web::resource("/process")
+                // Larger bodies are rejected with 413 before reaching the handler
+                .app_data(web::PayloadConfig::new(upload_limit))
+                .route(web::post().to(process::process_audio)),
+        )
+        .route("/transcribe", web::post().to(stt::transcribe_audio))
+        .service(
+            web::resource("/config")
+                .get(config::get_config)
+                .patch(config::patch_config),
+        )
+        .route("/recordings", web::get().to(recordings::list_recordings))
+        // Ahead of the catch-all below, which would otherwise take the `/upload`
+        .route("/recordings/{name:.*}/upload", web::post().to(upload::upload_recording))
+        .service(
+            // Nested paths for --organize-by-date; resolve_recording guards against traversal
+            web::resource("/recordings/{name:.*}")
+                .get(recordings::download_recording)
+                .delete(recordings::delete_recording),
+        )
+        .route("/openapi.json", web::get().to(api::openapi_json))
+        .configure(swagger_ui);
+}
+
+#[cfg(feature = "swagger-ui")]
+fn swagger_ui(cfg: &mut web::ServiceConfig) {
+    use utoipa_swagger_ui::{Config, SwaggerUi};
+    cfg.service(SwaggerUi::new("/docs/{_:.*}").config(Config::from("/openapi.json")));
+}
+
+#[cfg(not(feature = "swagger-ui"))]
+fn swagger_ui(_cfg: &mut web::ServiceConfig) {}
+
+fn build_cors(origins: &[String]) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(["GET", "POST", "PUT", "PATCH", "DELETE"])
+        .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::RANGE])
+        .max_age(3600);
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin))
+}
+
+/// How [`app`] serves the API, beyond what [`AudioState`] holds
+#[derive(Clone)]
+pub struct AppOptions {
+    /// Bearer token every request must carry, or None to allow all
+    pub api_token: Option<String>,
+    /// Origins allowed to make cross-origin requests; `*` allows any
+    pub cors_origins: Vec<String>,
+    /// How each request is logged
+    pub access_log_format: access_log::AccessLogFormat,
+    /// Largest WAV accepted by /process, in bytes; no other route reads a raw body
+    pub upload_limit: usize,
+    /// Reported by GET /config
+    pub fixed_settings: config::FixedSettings,
+}
+
+impl Default for AppOptions {
+    fn default() -> Self {
+        AppOptions {
+            api_token: None,
+            cors_origins: Vec::new(),
+            access_log_format: access_log::AccessLogFormat::default(),
+            upload_limit: DEFAULT_UPLOAD_LIMIT,
+            fixed_settings: config::FixedSettings::default(),
+        }
+    }
+}
+
+/// The HTTP app: every route of [`configure_app`] over `state`, behind
+/// authentication, CORS and access logging. Call it once per worker from
+/// `HttpServer::new`, or hand it to `actix_web::test::init_service`.
+pub fn app(
+    state: Arc<AudioState>,
+    options: &AppOptions,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let cors_origins = &options.cors_origins;
+    App::new()
+        .app_data(web::Data::new(state))
+        .app_data(web::Data::new(auth::ApiToken(options.api_token.clone())))
+        .app_data(web::Data::new(options.fixed_settings.clone()))
+        .app_data(web::Data::new(options.access_log_format))
+        .wrap(middleware::from_fn(auth::require_token))
+        // Outermost so preflight requests are answered before authentication
+        .wrap(middleware::Condition::new(!cors_origins.is_empty(), build_cors(cors_origins)))
+        // Outermost of all so rejected and preflight requests are logged too
+        .wrap(middleware::from_fn(access_log::log_requests))
+        .configure(|cfg| configure_routes(cfg, options.upload_limit))
+}
+
+/// Open `source` and run capture on a thread of its own until the server
+/// halts, retrying with backoff whenever the source can't be opened or the
+/// stream fails. `capture_error` reports the failure meanwhile.
+pub fn spawn_capture(
+    state: Arc<AudioState>,
+    options: CaptureOptions,
+    source: Box<dyn AudioSource>,
+) -> CaptureThread {
+    // Carried by everything the capture logs, on whichever thread; the device
+    // fields are filled in once it is open
+    let span = tracing::info_span!("capture", device = tracing::field::Empty, sample_rate = tracing::field::Empty);
+    let (finished, done) = std::sync::mpsc::channel();
+    let handle = std::thread::spawn(move || {
+        span.in_scope(|| supervise_capture(&state, options, source));
+        state.capture_stopped.store(true, Ordering::Relaxed);
+        state.capture_done.notify_waiters();
+        let _ = finished.send(());
+    });
+    CaptureThread { handle, done }
+}
+
+/// A capture thread from [`spawn_capture`]
+pub struct CaptureThread {
+    handle: std::thread::JoinHandle<()>,
+    // Sent to as the thread ends; a panic drops the sender instead
+    done: std::sync::mpsc::Receiver<()>,
+}
+
+impl CaptureThread {
+    /// Wait for the thread to finish, however long that takes
+    pub fn join(self) -> std::thread::Result<()> {
+        self.handle.join()
+    }
+}
+
+/// Wait up to `timeout` for a thread from [`spawn_capture`] to finish once
+/// [`AudioState::request_shutdown`] was called, so the device is released
+/// before the process exits. Returns false if it is still running.
+pub fn join_capture(capture: CaptureThread, timeout: Duration) -> bool {
+    if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = capture.done.recv_timeout(timeout) {
+        return false;
+    }
+    if capture.join().is_err() {
+        tracing::error!("Capture thread panicked");
+    }
+    true
+}
+
+/// Start the background tasks the configuration in `state` calls for: the
+/// saver of sound-activated captures, the transcriber and FLAC compression.
+/// Must run inside a Tokio runtime, after the state is shared.
+pub fn start_workers(state: &Arc<AudioState>) {
+    if state.level_trigger.is_some() || state.auto_stop.is_some_and(|options| options.save) {
+        let (sender, _) = level_trigger::spawn_saver(Arc::clone(state));
+        let _ = state.captures.set(sender);
+    }
+    if state.stt.is_some() {
+        let (sender, _) = stt::spawn(Arc::clone(state));
+        let _ = state.stt_queue.set(sender);
+    }
+    if let Some(policy) = state.compression {
+        tracing::info!("Compressing WAV recordings older than {:?} to FLAC every {:?}", policy.after, policy.interval);
+        maintenance::spawn(Arc::clone(state), policy);
+    }
+}
+
+/// Archive everything captured as segments, from --segment-seconds. The
+/// archiver stops once the server halts, after finalizing its last segment.
+pub fn start_segment_archiver(state: &Arc<AudioState>, options: segments::SegmentOptions) -> std::thread::JoinHandle<()> {
+    let (sender, handle) = segments::spawn_archiver(Arc::clone(state), options);
+    let _ = state.archive.set(sender);
+    handle
+}
+
+#[cfg(test)]
+mod tests;
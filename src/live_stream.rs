@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use actix_web::web::Bytes;
+use actix_web::{rt, web, Error, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::AudioState;
+
+// Frames buffered per listener before it starts skipping
+pub const LIVE_CHANNEL_CAPACITY: usize = 64;
+
+// Downmix interleaved f32 samples to mono 16-bit little-endian PCM
+pub fn encode_frame(data: &[f32], channels: u16) -> Bytes {
+    let channels = channels.max(1) as usize;
+    let mut out = Vec::with_capacity(data.len() / channels * 2);
+    for frame in data.chunks_exact(channels) {
+        let mono = frame.iter().sum::<f32>() / channels as f32;
+        let sample = (mono.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    Bytes::from(out)
+}
+
+/// Upgrade to a WebSocket relaying live mono PCM; the first message is a JSON format description
+#[utoipa::path(
+    get,
+    path = "/stream",
+    responses((status = 101, description = "Switching to the WebSocket protocol")),
+)]
+pub async fn stream_audio(
+    req: HttpRequest,
+    body: web::Payload,
+    state: web::Data<Arc<AudioState>>,
+) -> Result<HttpResponse, Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut frames = state.live_audio.subscribe();
+    let sample_rate = state.input_config().sample_rate().0;
+    let peer = req.peer_addr();
+    tracing::info!("Live stream client connected: {:?}", peer);
+
+    rt::spawn(async move {
+        let handshake = serde_json::json!({
+            "format": "pcm_s16le",
+            "sample_rate": sample_rate,
+            "channels": 1,
+        });
+        if session.text(handshake.to_string()).await.is_err() {
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Ok(frame) => {
+                        if session.binary(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Slow client: skip what it missed rather than back-pressure capture
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::debug!("Live stream client {:?} skipped {} frames", peer, skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+
+        let _ = session.close(None).await;
+        tracing::info!("Live stream client disconnected: {:?}", peer);
+    });
+
+    Ok(response)
+}
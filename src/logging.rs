@@ -0,0 +1,376 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use serde_json::Value;
+use tracing::field::{Field, Visit};
+use tracing::span::{self, Attributes, Id};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// How log lines are written, from --log-format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[time LEVEL target] span{field=value}: message field=value`
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields and enclosing spans
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format `{}`, expected `text` or `json`", other)),
+        }
+    }
+}
+
+type Fields = Vec<(&'static str, Value)>;
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: Option<String>,
+    fields: Fields,
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = Some(format!("{:?}", value)),
+            name => self.fields.push((name, format!("{:?}", value).into())),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = Some(value.to_string()),
+            name => self.fields.push((name, value.into())),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.push((field.name(), value.into()));
+    }
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    parent: Option<u64>,
+    fields: Fields,
+    // Handles to the span still alive; it is forgotten at zero
+    refs: usize,
+}
+
+thread_local! {
+    // Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+/// Formats tracing events and log records alike, each inside the spans
+/// entered on its thread, after filtering both with RUST_LOG directives
+pub struct Logger {
+    filter: env_filter::Filter,
+    format: LogFormat,
+    spans: parking_lot::Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+    output: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl Logger {
+    /// `directives` as in RUST_LOG, e.g. `info,actix_web=warn`
+    pub fn new(directives: &str, format: LogFormat, output: Box<dyn Fn(&str) + Send + Sync>) -> Self {
+        Logger {
+            filter: env_filter::Builder::new().parse(directives).build(),
+            format,
+            spans: parking_lot::Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            output,
+        }
+    }
+
+    fn target_enabled(&self, level: log::Level, target: &str) -> bool {
+        self.filter.enabled(&log::Metadata::builder().level(level).target(target).build())
+    }
+
+    // The span `id` and its parents, outermost first
+    fn scope(&self, id: Option<u64>) -> Vec<(&'static str, Fields)> {
+        let spans = self.spans.lock();
+        let mut scope = Vec::new();
+        let mut next = id;
+        while let Some(span) = next.and_then(|id| spans.get(&id)) {
+            scope.push((span.metadata.name(), span.fields.clone()));
+            next = span.parent;
+        }
+        scope.reverse();
+        scope
+    }
+
+    fn current(&self) -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    fn write(&self, level: log::Level, target: &str, message: &str, fields: &Fields, scope: &[(&'static str, Fields)]) {
+        (self.output)(&format_line(self.format, chrono::Utc::now(), level, target, message, fields, scope));
+    }
+}
+
+fn format_line(
+    format: LogFormat,
+    time: chrono::DateTime<chrono::Utc>,
+    level: log::Level,
+    target: &str,
+    message: &str,
+    fields: &Fields,
+    scope: &[(&'static str, Fields)],
+) -> String {
+    let time = time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    match format {
+        LogFormat::Text => {
+            let pairs = |fields: &Fields| {
+                fields.iter()
+                    .map(|(name, value)| match value {
+                        Value::String(s) => format!("{}={}", name, s),
+                        value => format!("{}={}", name, value),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            let mut line = format!("[{} {:<5} {}] ", time, level, target);
+            for (name, fields) in scope {
+                let _ = write!(line, "{}{{{}}}:", name, pairs(fields));
+            }
+            if !scope.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(message);
+            if !fields.is_empty() {
+                let _ = write!(line, " {}", pairs(fields));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let object = |fields: &Fields| Value::Object(fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect());
+            let mut line = serde_json::json!({
+                "timestamp": time,
+                "level": level.as_str(),
+                "target": target,
+                "message": message,
+            });
+            if !fields.is_empty() {
+                line["fields"] = object(fields);
+            }
+            if !scope.is_empty() {
+                line["spans"] = scope.iter()
+                    .map(|(name, fields)| {
+                        let mut span = object(fields);
+                        span["name"] = (*name).into();
+                        span
+                    })
+                    .collect();
+            }
+            line.to_string()
+        }
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.target_enabled(log_level(metadata.level()), metadata.target())
+    }
+
+    fn max_level_hint(&self) -> Option<tracing::level_filters::LevelFilter> {
+        use tracing::level_filters::LevelFilter;
+        Some(match self.filter.filter() {
+            log::LevelFilter::Off => LevelFilter::OFF,
+            log::LevelFilter::Error => LevelFilter::ERROR,
+            log::LevelFilter::Warn => LevelFilter::WARN,
+            log::LevelFilter::Info => LevelFilter::INFO,
+            log::LevelFilter::Debug => LevelFilter::DEBUG,
+            log::LevelFilter::Trace => LevelFilter::TRACE,
+        })
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut visitor = FieldVisitor::default();
+        attributes.record(&mut visitor);
+        let parent = match (attributes.parent(), attributes.is_contextual()) {
+            (Some(parent), _) => Some(parent.into_u64()),
+            (None, true) => self.current(),
+            (None, false) => None,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let span = SpanData { metadata: attributes.metadata(), parent, fields: visitor.fields, refs: 1 };
+        self.spans.lock().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, id: &Id, values: &span::Record<'_>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = self.spans.lock().get_mut(&id.into_u64()) {
+            // A field recorded again, e.g. once the device is reopened, replaces its value
+            span.fields.retain(|(name, _)| !visitor.fields.iter().any(|(recorded, _)| recorded == name));
+            span.fields.extend(visitor.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let parent = match (event.parent(), event.is_contextual()) {
+            (Some(parent), _) => Some(parent.into_u64()),
+            (None, true) => self.current(),
+            (None, false) => None,
+        };
+        let metadata = event.metadata();
+        let message = visitor.message.unwrap_or_default();
+        self.write(log_level(metadata.level()), metadata.target(), &message, &visitor.fields, &self.scope(parent));
+    }
+
+    fn enter(&self, id: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(id.into_u64()));
+    }
+
+    fn exit(&self, id: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(index) = entered.iter().rposition(|&entered| entered == id.into_u64()) {
+                entered.remove(index);
+            }
+        });
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(span) = self.spans.lock().get_mut(&id.into_u64()) {
+            span.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.spans.lock();
+        let Some(span) = spans.get_mut(&id.into_u64()) else {
+            return false;
+        };
+        span.refs -= 1;
+        let closed = span.refs == 0;
+        if closed {
+            spans.remove(&id.into_u64());
+        }
+        closed
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        let spans = self.spans.lock();
+        match self.current().and_then(|id| spans.get(&id).map(|span| (id, span.metadata))) {
+            Some((id, metadata)) => tracing_core::span::Current::new(Id::from_u64(id), metadata),
+            None => tracing_core::span::Current::none(),
+        }
+    }
+}
+
+// Hands records from the `log` crate, as used by dependencies, to the logger
+struct LogBridge(Arc<Logger>);
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.0.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.0.filter.matches(record) {
+            let scope = self.0.scope(self.0.current());
+            self.0.write(record.level(), record.target(), &record.args().to_string(), &Vec::new(), &scope);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Filter without --log-level or $RUST_LOG: errors only
+pub const DEFAULT_DIRECTIVES: &str = "error";
+
+/// Send tracing events and `log` records to stderr in `format`, filtered by
+/// `directives` as env_logger would read them from $RUST_LOG
+pub fn init(directives: &str, format: LogFormat) {
+    let logger = Arc::new(Logger::new(directives, format, Box::new(|line| {
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    })));
+    log::set_max_level(logger.filter.filter());
+    if log::set_boxed_logger(Box::new(LogBridge(Arc::clone(&logger)))).is_err()
+        || tracing::subscriber::set_global_default(logger).is_err()
+    {
+        eprintln!("A logger is already installed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::{LogFormat, Logger};
+
+    fn capture(format: LogFormat, f: impl FnOnce()) -> Vec<String> {
+        let lines = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let sink = Arc::clone(&lines);
+        let logger = Logger::new("info", format, Box::new(move |line| sink.lock().push(line.to_string())));
+        tracing::subscriber::with_default(logger, f);
+        let lines = lines.lock().clone();
+        lines
+    }
+
+    #[test]
+    fn events_carry_the_fields_of_their_spans() {
+        let emit = || {
+            let capture = tracing::info_span!("capture", device = "mic", sample_rate = 16000u32);
+            let _capture = capture.enter();
+            tracing::info_span!("request", method = "POST").in_scope(|| {
+                tracing::info!(keyword = "porcupine", "Wakeword detected");
+            });
+            tracing::debug!("filtered out");
+        };
+
+        let text = capture(LogFormat::Text, emit);
+        assert_eq!(text.len(), 1);
+        assert!(
+            text[0].ends_with(" INFO  misteragent_voice_rust::logging::tests] capture{device=mic sample_rate=16000}:request{method=POST}: Wakeword detected keyword=porcupine"),
+            "{}", text[0]
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&capture(LogFormat::Json, emit)[0]).unwrap();
+        assert_eq!(json["message"], "Wakeword detected");
+        assert_eq!(json["fields"]["keyword"], "porcupine");
+        assert_eq!(json["spans"][0]["name"], "capture");
+        assert_eq!(json["spans"][0]["sample_rate"], 16000);
+        assert_eq!(json["spans"][1]["method"], "POST");
+    }
+}
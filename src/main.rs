@@ -1,101 +1,522 @@
+use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use ringbuf::HeapRb;
-use tokio;
-use actix_web::{web, App, HttpServer, HttpResponse};
-use log;
-use env_logger;
-use parking_lot;
+use actix_web::HttpServer;
 use argh::FromArgs;
-use chrono;
 use dotenv::dotenv;
+use misteragent_voice_rust::capture_audio::{self, watch_capture, AudioSource, BufferMode, CaptureOptions, SourceKind};
+use misteragent_voice_rust::encoding::{self, OutputFormat, OutputOptions, SampleKind, WavEncoding};
+use misteragent_voice_rust::{
+    access_log, autosave, config, config_file, encryption, filename, logging, level_trigger, maintenance, recordings, retention, sample_buffer,
+    segments, stt, synthetic, tls, uds, upload, wakeword_listener, webhook, AppOptions, AudioState,
+};
 
-mod wakeword_listener;
-mod capture_audio;
-use capture_audio::{capture_audio, get_input_config};
+const DEFAULT_BIND: &str = "127.0.0.1:8000";
+// How long exit waits for each capture thread after the server has stopped
+const CAPTURE_JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Everything a --config file may set: the options below except --list-devices,
+// --decrypt and --config, plus the secrets otherwise only read from the environment
+const CONFIG_KEYS: &[config_file::Key] = {
+    use config_file::Kind::{Float, Integer, List, String, Switch};
+    use config_file::Key;
+    &[
+        Key::option("source", String),
+        Key::option("synthetic_signal", String),
+        Key::option("input_file", String),
+        Key::option("input_device", List),
+        Key::option("seconds", Integer),
+        Key::option("warn_buffer_seconds", Integer),
+        Key::option("max_buffer_bytes", String),
+        Key::option("output_dir", String),
+        Key::option("segment_seconds", Integer),
+        Key::option("filename_template", String),
+        Key::option("max_recordings", Integer),
+        Key::option("max_recordings_age", Integer),
+        Key::option("max_output_bytes", String),
+        Key::option("compress_after", String),
+        Key::option("compress_interval", String),
+        Key::option("split_channels", Switch),
+        Key::option("force_mono", Switch),
+        Key::option("buffer_sample_type", String),
+        Key::option("append_to", String),
+        Key::option("encrypt_key", String),
+        Key::option("organize_by_date", Switch),
+        Key::option("segment_keep", Integer),
+        Key::option("buffer_mode", String),
+        Key::option("output_format", String),
+        Key::option("mp3_bitrate", Integer),
+        Key::option("opus_bitrate", Integer),
+        Key::option("raw_encoding", String),
+        Key::option("wav_sample_format", String),
+        Key::option("wav_bits", Integer),
+        Key::option("stall_timeout", Integer),
+        Key::option("health_timeout", Integer),
+        Key::option("auto_save_interval", String),
+        Key::option("max_concurrent_saves", Integer),
+        Key::option("max_upload_mb", Integer),
+        Key::option("max_base64_mb", Integer),
+        Key::option("min_save_seconds", Float),
+        Key::option("gain", Float),
+        Key::option("highpass_hz", Float),
+        Key::option("highpass_buffer", Switch),
+        Key::option("trigger_level_db", Float),
+        Key::option("trigger_pre_roll_seconds", Float),
+        Key::option("trigger_hang_seconds", Float),
+        Key::option("trigger_max_seconds", Float),
+        Key::option("trigger_cooldown_ms", Integer),
+        Key::option("auto_stop_silence_ms", Integer),
+        Key::option("auto_stop_level_db", Float),
+        Key::option("auto_stop_save", Switch),
+        Key::option("wakeword_cooldown_ms", Integer),
+        Key::option("no_wakeword", Switch),
+        Key::option("model_path", String).env(&["PORCUPINE_PV_MODEL_PATH"]),
+        Key::option("restart_on_stall", Switch),
+        Key::option("device_timeout", Integer),
+        Key::option("capture_latency_ms", Integer),
+        Key::option("wakeword_queue_ms", Integer),
+        Key::option("bind", String).env(&["BIND_ADDRESS"]),
+        Key::option("uds", String),
+        Key::option("uds_mode", String),
+        Key::option("token", String).env(&["API_TOKEN"]).secret(),
+        Key::option("require_token", Switch),
+        Key::option("access_log_format", String),
+        Key::option("log_format", String),
+        Key::option("log_level", String).env(&["RUST_LOG"]),
+        Key::option("tls_cert", String),
+        Key::option("tls_key", String),
+        Key::option("cors_origin", List),
+        Key::option("s3_endpoint", String),
+        Key::option("s3_bucket", String),
+        Key::option("s3_prefix", String),
+        Key::option("s3_region", String),
+        Key::option("s3_ca_file", String),
+        Key::option("s3_delete_local", Switch),
+        Key::option("auto_upload", Switch),
+        Key::option("save_webhook", String),
+        Key::option("save_webhook_method", String),
+        Key::option("save_webhook_auth", String).env(&["SAVE_WEBHOOK_AUTH"]).secret(),
+        Key::option("save_webhook_retries", Integer),
+        Key::option("stt_url", String),
+        Key::option("stt_seconds", Float),
+        Key::variable("picovoice_access_key", &["PICOVOICE_ACCESS_KEY"]).secret(),
+        Key::variable("picovoice_access_key_file", &["PICOVOICE_ACCESS_KEY_FILE"]),
+        Key::variable("porcupine_model_path", &["PORCUPINE_MODEL_PATH"]),
+        Key::variable("s3_access_key_id", &["S3_ACCESS_KEY_ID", "AWS_ACCESS_KEY_ID"]).secret(),
+        Key::variable("s3_secret_access_key", &["S3_SECRET_ACCESS_KEY", "AWS_SECRET_ACCESS_KEY"]).secret(),
+    ]
+};
+
+// Memory the system can still hand out, where the platform reports it
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+// The command line with the --config file applied underneath, parsed the way
+// argh::from_env would
+fn parse_args() -> (Args, Option<config_file::ConfigFile>, Vec<config_file::Setting>) {
+    let mut command_line: Vec<String> = std::env::args().collect();
+    let cmd = command_line.remove(0);
+    let file = config_file::flag_value(&command_line, "config").map(|path| {
+        // Logging waits for --log-format, so this goes straight to stderr like argh's errors
+        config_file::ConfigFile::load(std::path::Path::new(path), CONFIG_KEYS).unwrap_or_else(|e| {
+            eprintln!("Invalid --config file: {}", e);
+            std::process::exit(2);
+        })
+    });
+    let merged = config_file::merge(file.as_ref(), CONFIG_KEYS, &command_line);
+    // Before any other thread starts
+    for (name, value) in &merged.env {
+        std::env::set_var(name, value);
+    }
+    let strs: Vec<&str> = merged.args.iter().map(String::as_str).collect();
+    match Args::from_args(&[&cmd], &strs) {
+        Ok(args) => (args, file, merged.sources),
+        Err(argh::EarlyExit { output, status: Ok(()) }) => {
+            println!("{}", output);
+            std::process::exit(0);
+        }
+        Err(argh::EarlyExit { output, status: Err(()) }) => {
+            eprintln!("{}\nRun {} --help for more information.", output, cmd);
+            std::process::exit(1);
+        }
+    }
+}
 
 /// Audio recording application
 #[derive(FromArgs)]
 struct Args {
+    /// print the input devices and the formats they support, then exit
+    #[argh(switch)]
+    list_devices: bool,
+
+    /// TOML file of options, named like the long options with underscores, e.g.
+    /// `seconds = 120` or `[s3] bucket = "b"`; the command line and environment
+    /// take precedence over it
+    #[argh(option)]
+    config: Option<String>,
+
+    /// where audio comes from: `cpal`, an input device, or `synthetic`, a generated
+    /// signal for development and CI without audio hardware (default: cpal)
+    #[argh(option, default = "SourceKind::Cpal")]
+    source: SourceKind,
+
+    /// signal played by --source synthetic: `sine`, `sine:<hz>`, `noise` or
+    /// `file:<path>` for a looped WAV (default: sine at 440 Hz)
+    #[argh(option, default = "synthetic::Signal::default()")]
+    synthetic_signal: synthetic::Signal,
+
+    /// capture from this WAV file instead of a device, looped in real time at its
+    /// own rate and channel count, e.g. recorded system output where no loopback
+    /// or monitor device exists; short for --source synthetic --synthetic-signal
+    /// file:<path>
+    #[argh(option)]
+    input_file: Option<String>,
+
+    /// input device to capture from, by exact name or case-insensitive substring,
+    /// e.g. a PulseAudio/PipeWire monitor (default: the host's default input);
+    /// repeat to capture several at once, each into its own buffer, with /save
+    /// writing a file per device and wakeword detection on the first
+    #[argh(option)]
+    input_device: Vec<String>,
+
     /// number of seconds of audio to buffer (default: 60)
     #[argh(option, default = "60")]
     seconds: u32,
 
+    /// warn when --seconds is above this, as a likely typo; only
+    /// --max-buffer-bytes refuses to start (default: 3600)
+    #[argh(option, default = "3600")]
+    warn_buffer_seconds: u32,
+
+    /// most memory the buffers may take, e.g. 512M or 2G, also for a PATCH /config
+    /// of buffer_seconds; startup fails above it (default: the memory available)
+    #[argh(option)]
+    max_buffer_bytes: Option<retention::ByteSize>,
+
     /// directory to store output WAV files (default: ".")
     #[argh(option, default = "String::from(\"captures\")")]
     output_dir: String,
-}
 
-// Structure to hold our audio data and state
-struct AudioState {
-    buffer: parking_lot::Mutex<HeapRb<f32>>,
-    is_recording: AtomicBool,
-    is_halting: AtomicBool,
-    output_dir: String,
-}
+    /// also record continuously to disk in WAV segments of this many seconds (default: off)
+    #[argh(option)]
+    segment_seconds: Option<u32>,
 
-impl AudioState {
-    fn new(capacity: usize, output_dir: String) -> Self {
-        AudioState {
-            buffer: parking_lot::Mutex::new(HeapRb::new(capacity)),
-            is_recording: AtomicBool::new(true),
-            is_halting: AtomicBool::new(false),
-            output_dir,
-        }
-    }
-}
+    /// name for saved recordings: strftime specifiers plus {trigger}, {keyword}, {seq},
+    /// {counter}, which keeps increasing across restarts, and {device}; the extension
+    /// follows the output format
+    /// (default: recording_%Y%m%d_%H%M%S_%3f_{seq})
+    #[argh(option, default = "filename::FilenameTemplate::default()")]
+    filename_template: filename::FilenameTemplate,
 
-// HTTP endpoint handlers
-async fn start_recording(state: web::Data<Arc<AudioState>>) -> HttpResponse {
-    log::info!("Starting recording");
-    state.is_recording.store(true, Ordering::Relaxed);
-    HttpResponse::Ok().body("Recording started")
-}
+    /// delete the oldest recordings after each save so at most this many remain (default: no limit)
+    #[argh(option)]
+    max_recordings: Option<usize>,
 
-async fn stop_recording(state: web::Data<Arc<AudioState>>) -> HttpResponse {
-    log::info!("Stopping recording");
-    state.is_recording.store(false, Ordering::Relaxed);
-    HttpResponse::Ok().body("Recording stopped")
-}
+    /// delete recordings older than this many days after each save (default: no limit)
+    #[argh(option)]
+    max_recordings_age: Option<u32>,
 
-async fn save_audio(state: web::Data<Arc<AudioState>>) -> HttpResponse {
-    // Generate timestamp for unique filename
-    let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
-    let filename = format!("recording_{}.wav", timestamp);
-    let filepath = std::path::Path::new(&state.output_dir).join(filename);
-    
-    log::info!("Saving audio to {}", filepath.display());
-    
-    let config = get_input_config();
-    log::debug!("Using input config: {:?}", config);
+    /// byte budget for the output directory, e.g. 500M or 2G; the oldest recordings
+    /// are deleted to make room before each save (default: no limit)
+    #[argh(option)]
+    max_output_bytes: Option<retention::ByteSize>,
 
-    match capture_audio::save_audio_to_file(&state, &filepath, &config) {
-        Ok(sample_count) => {
-            log::info!("Successfully saved {} samples to {}", sample_count, filepath.display());
-            HttpResponse::Ok().body(format!("Audio saved to {}", filepath.display()))
-        }
-        Err(e) => {
-            log::error!("Failed to save audio: {}", e);
-            HttpResponse::InternalServerError().body(format!("Failed to save audio: {}", e))
-        }
-    }
-}
+    /// transcode WAV recordings older than this, e.g. `7d` or `12h`, to FLAC in the
+    /// background, keeping them only once the FLAC verifies (default: off)
+    #[argh(option)]
+    compress_after: Option<autosave::Interval>,
 
-async fn halt_server(state: web::Data<Arc<AudioState>>) -> HttpResponse {
-    log::info!("Halting server");
-    // First stop recording
-    state.is_recording.store(false, Ordering::Relaxed);
-    // Signal the capture thread to stop
-    state.is_halting.store(true, Ordering::Relaxed);
-    
-    // Give a brief moment for the recording thread to clean up
-    tokio::time::sleep(Duration::from_millis(200)).await;
-    HttpResponse::Ok().body("Server halted");
-    // wait for response
-    tokio::time::sleep(Duration::from_millis(500)).await;
-    // Exit process cleanly
-    std::process::exit(0);
+    /// how often to look for recordings to compress with --compress-after (default: 1h)
+    #[argh(option, default = "autosave::Interval(Duration::from_secs(60 * 60))")]
+    compress_interval: autosave::Interval,
+
+    /// keep one ring buffer per channel instead of interleaving them, and save one
+    /// mono file per channel unless /save is given split_channels=false
+    #[argh(switch)]
+    split_channels: bool,
+
+    /// average every input's channels into one as it is captured, so buffers hold
+    /// a sample per frame and the wakeword engine hears a mix of all channels
+    #[argh(switch)]
+    force_mono: bool,
+
+    /// how buffered samples are stored: f32 (default) or i16, which halves the
+    /// buffer's memory at the cost of rounding to 16 bits on capture
+    #[argh(option, default = "sample_buffer::SampleType::F32")]
+    buffer_sample_type: sample_buffer::SampleType,
+
+    /// append every save to this WAV file (created if missing), keeping one continuous
+    /// file per session instead of a new file per save
+    #[argh(option)]
+    append_to: Option<String>,
+
+    /// file holding an AES-256 key as 64 hex digits, e.g. from `openssl rand -hex 32`;
+    /// saved recordings are encrypted with it (AES-256-GCM), read them back with --decrypt
+    #[argh(option)]
+    encrypt_key: Option<String>,
+
+    /// write the plaintext of this recording, encrypted with --encrypt-key, to
+    /// standard output, then exit
+    #[argh(option)]
+    decrypt: Option<String>,
+
+    /// save into YYYY/MM/DD subdirectories of the output directory, by local date
+    #[argh(switch)]
+    organize_by_date: bool,
+
+    /// number of segment files to keep with --segment-seconds, 0 for all (default: 0)
+    #[argh(option, default = "0")]
+    segment_keep: usize,
+
+    /// what to do when the buffer is full: overwrite (default) or stop recording
+    #[argh(option, default = "BufferMode::Overwrite")]
+    buffer_mode: BufferMode,
+
+    /// output format: wav (default), ulaw or alaw (8 kHz mono G.711 WAV), mp3, opus
+    /// (48 kHz Ogg Opus), or raw (headerless PCM in --raw-encoding, saved as .raw)
+    #[argh(option, default = "OutputFormat::Wav")]
+    output_format: OutputFormat,
+
+    /// MP3 bitrate in kbps when --output-format is mp3 (default: 128)
+    #[argh(option, default = "128")]
+    mp3_bitrate: u16,
+
+    /// bitrate in kbps, 6 to 510, when --output-format or format= is opus (default: 24)
+    #[argh(option, default = "24")]
+    opus_bitrate: u16,
+
+    /// sample layout when --output-format or format= is raw: s16le (default) or
+    /// f32le, interleaved at the capture rate
+    #[argh(option, default = "encoding::RawEncoding::S16Le")]
+    raw_encoding: encoding::RawEncoding,
+
+    /// WAV sample format, int or float (default: matches the device)
+    #[argh(option)]
+    wav_sample_format: Option<SampleKind>,
+
+    /// WAV bits per sample: 8, 16, 24 or 32 for int, 32 for float (default: matches the device)
+    #[argh(option)]
+    wav_bits: Option<u16>,
+
+    /// seconds without audio frames before the capture is reported stalled (default: 5)
+    #[argh(option, default = "5")]
+    stall_timeout: u64,
+
+    /// seconds without audio frames before /health reports unhealthy (default: 5)
+    #[argh(option, default = "5")]
+    health_timeout: u64,
+
+    /// save whatever was captured since the previous auto-save this often, e.g. `30s` or
+    /// `10m`, with the same naming, retention and pushes as /save (default: off)
+    #[argh(option)]
+    auto_save_interval: Option<autosave::Interval>,
+
+    /// number of saves allowed to run at once; further requests queue or get 429 (default: 1)
+    #[argh(option, default = "1")]
+    max_concurrent_saves: usize,
+
+    /// largest WAV accepted by /process, in megabytes (default: 50)
+    #[argh(option, default = "50")]
+    max_upload_mb: usize,
+
+    /// largest JSON body /save?download=true&base64=true returns, in megabytes of
+    /// base64 (default: 16)
+    #[argh(option, default = "16")]
+    max_base64_mb: usize,
+
+    /// refuse a /save, /record or /halt?save=true of no audio or less than this
+    /// many seconds with 422; /save can pass allow_empty=true to save anyway (default: 0)
+    #[argh(option, default = "0.0")]
+    min_save_seconds: f64,
+
+    /// linear gain applied to captured audio (default: 1.0)
+    #[argh(option, default = "1.0")]
+    gain: f32,
+
+    /// cutoff in Hz of a high-pass filter applied to the audio the wakeword engine
+    /// hears, to cut low-frequency rumble such as HVAC or traffic (default: off)
+    #[argh(option)]
+    highpass_hz: Option<f32>,
+
+    /// also apply --highpass-hz to the buffered audio, so saves are filtered too
+    #[argh(switch)]
+    highpass_buffer: bool,
+
+    /// RMS level in dBFS (e.g. -30) that starts a sound-activated capture, saved once
+    /// the level stays below it for --trigger-hang-seconds (default: off)
+    #[argh(option)]
+    trigger_level_db: Option<f64>,
+
+    /// seconds of audio from before the trigger kept in each sound-activated capture (default: 2)
+    #[argh(option, default = "2.0")]
+    trigger_pre_roll_seconds: f64,
+
+    /// seconds the level must stay below --trigger-level-db to end a capture (default: 2)
+    #[argh(option, default = "2.0")]
+    trigger_hang_seconds: f64,
+
+    /// longest sound-activated capture in seconds, not counting the pre-roll (default: 30)
+    #[argh(option, default = "30.0")]
+    trigger_max_seconds: f64,
+
+    /// milliseconds after a sound-activated capture before another can start (default: 1000)
+    #[argh(option, default = "1000")]
+    trigger_cooldown_ms: u64,
+
+    /// pause recording once the level stays below --auto-stop-level-db for this many
+    /// milliseconds after rising above it, for hands-free single utterances (default: off)
+    #[argh(option)]
+    auto_stop_silence_ms: Option<u64>,
+
+    /// RMS level in dBFS that counts as speech for --auto-stop-silence-ms (default: -40)
+    #[argh(option, default = "-40.0")]
+    auto_stop_level_db: f64,
+
+    /// save each utterance when --auto-stop-silence-ms pauses recording
+    #[argh(switch)]
+    auto_stop_save: bool,
+
+    /// milliseconds after a detection during which further detections are ignored (default: 0)
+    #[argh(option, default = "0")]
+    wakeword_cooldown_ms: u64,
+
+    /// run as a plain recorder: skip the wakeword engine, so no Picovoice access key is needed
+    #[argh(switch)]
+    no_wakeword: bool,
+
+    /// path to the Porcupine language model (.pv) matching the keyword files, for wake words in
+    /// languages other than English (default: PORCUPINE_PV_MODEL_PATH, else the bundled model)
+    #[argh(option)]
+    model_path: Option<String>,
+
+    /// rebuild the audio stream when the capture stalls
+    #[argh(switch)]
+    restart_on_stall: bool,
+
+    /// seconds to keep retrying, with backoff, when the input device isn't available
+    /// yet at startup (default: 30)
+    #[argh(option, default = "30")]
+    device_timeout: u64,
+
+    /// capture buffer length in milliseconds (default: 100); too-low values may cause xruns on some hardware
+    #[argh(option, default = "100")]
+    capture_latency_ms: u64,
+
+    /// milliseconds of audio queued for wakeword detection when it falls behind the
+    /// capture callback (default: 1000); audio beyond that goes unheard
+    #[argh(option, default = "1000")]
+    wakeword_queue_ms: u64,
+
+    /// address to listen on, e.g. 0.0.0.0:9000 (default: $BIND_ADDRESS or 127.0.0.1:8000)
+    #[argh(option)]
+    bind: Option<String>,
+
+    /// also listen on this Unix socket; without --bind or $BIND_ADDRESS, listen only there
+    #[argh(option)]
+    uds: Option<String>,
+
+    /// permissions for the --uds socket file, in octal (default: 0660)
+    #[argh(option, default = "uds::SocketMode(0o660)")]
+    uds_mode: uds::SocketMode,
+
+    /// bearer token required on every request (default: $API_TOKEN, open access if unset)
+    #[argh(option)]
+    token: Option<String>,
+
+    /// refuse to listen on a non-loopback address without a token
+    #[argh(switch)]
+    require_token: bool,
+
+    /// access log line format: text (default) or json
+    #[argh(option, default = "access_log::AccessLogFormat::Text")]
+    access_log_format: access_log::AccessLogFormat,
+
+    /// log line format: text (default) or json, with each event's fields and
+    /// enclosing spans
+    #[argh(option, default = "logging::LogFormat::Text")]
+    log_format: logging::LogFormat,
+
+    /// what to log, as RUST_LOG directives, e.g. `info` or `warn,access=info`;
+    /// the access log, a line per request with its status and duration, is the
+    /// `access` target (default: $RUST_LOG, else errors only)
+    #[argh(option)]
+    log_level: Option<String>,
+
+    /// PEM certificate chain; with --tls-key, serve HTTPS instead of plain HTTP
+    #[argh(option)]
+    tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert
+    #[argh(option)]
+    tls_key: Option<String>,
+
+    /// origin allowed to make cross-origin requests, repeatable, or `*` for any (default: no CORS)
+    #[argh(option)]
+    cors_origin: Vec<String>,
+
+    /// S3-compatible endpoint to upload recordings to, e.g. https://s3.us-east-1.amazonaws.com;
+    /// credentials come from $S3_ACCESS_KEY_ID and $S3_SECRET_ACCESS_KEY (or the AWS_ equivalents)
+    #[argh(option)]
+    s3_endpoint: Option<String>,
+
+    /// bucket for --s3-endpoint uploads
+    #[argh(option)]
+    s3_bucket: Option<String>,
+
+    /// key prefix for uploaded recordings, e.g. `kitchen/`; keys then follow the
+    /// recording's path in the output directory (default: none)
+    #[argh(option, default = "String::new()")]
+    s3_prefix: String,
+
+    /// region used to sign S3 requests (default: us-east-1)
+    #[argh(option, default = "String::from(\"us-east-1\")")]
+    s3_region: String,
+
+    /// PEM CA bundle trusted for an https --s3-endpoint (default: the system bundle)
+    #[argh(option)]
+    s3_ca_file: Option<String>,
+
+    /// delete the local recording and its sidecar once uploaded
+    #[argh(switch)]
+    s3_delete_local: bool,
+
+    /// upload every save unless /save is given upload=false
+    #[argh(switch)]
+    auto_upload: bool,
+
+    /// URL each saved file is sent to as the request body, with its metadata in
+    /// X-Recording-* headers (default: off)
+    #[argh(option)]
+    save_webhook: Option<String>,
+
+    /// HTTP method for --save-webhook: post (default) or put
+    #[argh(option, default = "webhook::Method::Post")]
+    save_webhook_method: webhook::Method,
+
+    /// value of the Authorization header sent to --save-webhook, e.g. `Bearer abc123`
+    /// (default: $SAVE_WEBHOOK_AUTH, none if unset)
+    #[argh(option)]
+    save_webhook_auth: Option<String>,
+
+    /// times to retry a push not answered with 2xx, waiting 1s, 2s, 4s, ... in
+    /// between (default: 3)
+    #[argh(option, default = "3")]
+    save_webhook_retries: u32,
+
+    /// URL of a speech-to-text service: after each wakeword detection, and on
+    /// /transcribe, the audio is POSTed to it as WAV and the text it returns is logged
+    /// (default: off)
+    #[argh(option)]
+    stt_url: Option<String>,
+
+    /// seconds of audio after a detection sent to --stt-url, and the default
+    /// length for /transcribe (default: 5)
+    #[argh(option, default = "5.0")]
+    stt_seconds: f64,
 }
 
 #[actix_web::main]
@@ -103,50 +524,691 @@ async fn main() -> std::io::Result<()> {
     // Load environment variables from .env file
     dotenv().ok();
 
-    // Get command line arguments
-    let args: Args = argh::from_env();
-    
+    // Get command line arguments, over any --config file
+    let (args, config_file, sources) = parse_args();
+
     // Initialize logger
-    env_logger::init();
-    log::info!("Starting audio recording application");
+    let directives = args.log_level.clone().unwrap_or_else(|| logging::DEFAULT_DIRECTIVES.to_string());
+    logging::init(&directives, args.log_format);
+
+    // Answered before touching anything else, so it works whatever the other options say
+    if args.list_devices {
+        match capture_audio::describe_input_devices() {
+            Ok(devices) => {
+                print!("{}", devices);
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let encryption = match args.encrypt_key.as_deref().map(|path| encryption::Key::load(std::path::Path::new(path))) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            tracing::error!("Invalid --encrypt-key: {}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    if let Some(path) = &args.decrypt {
+        let Some(key) = encryption else {
+            tracing::error!("--decrypt needs --encrypt-key");
+            std::process::exit(2);
+        };
+        match std::fs::read(path).and_then(|sealed| key.decrypt(&sealed)) {
+            Ok(plaintext) => {
+                std::io::Write::write_all(&mut std::io::stdout().lock(), &plaintext)?;
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Cannot decrypt {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    tracing::info!("Starting audio recording application");
+    if let Some(file) = &config_file {
+        tracing::info!("Reading options from {}", file.path.display());
+        for warning in &file.warnings {
+            tracing::warn!("{}", warning);
+        }
+    }
+    for setting in &sources {
+        let source = match setting.source {
+            config_file::Source::CommandLine => "command line".to_string(),
+            config_file::Source::Environment(var) => format!("${}", var),
+            config_file::Source::File => "config file".to_string(),
+        };
+        tracing::info!("{} = {} (from {})", setting.name, setting.value, source);
+    }
+    if args.seconds > args.warn_buffer_seconds {
+        tracing::warn!(
+            "--seconds {} is above --warn-buffer-seconds {}; the whole buffer is held in memory",
+            args.seconds, args.warn_buffer_seconds
+        );
+    }
 
     // Calculate buffer size using the input config and CLI argument
-    let config = get_input_config();
-    let buffer_size: usize = config.sample_rate().0 as usize * args.seconds as usize;
-    log::info!("Initializing buffer for {} seconds ({} samples)", args.seconds, buffer_size);
+    let device_timeout = Duration::from_secs(args.device_timeout);
+    if args.force_mono && args.split_channels {
+        tracing::error!("--split-channels needs more than one channel, which --force-mono mixes away");
+        std::process::exit(2);
+    }
+    // Mixed down when --force-mono is given
+    let mono = |source: Box<dyn AudioSource>| -> Box<dyn AudioSource> {
+        match args.force_mono {
+            true => Box::new(capture_audio::MonoSource::new(source)),
+            false => source,
+        }
+    };
+    let signal = match &args.input_file {
+        Some(_) if !args.input_device.is_empty() => {
+            tracing::error!("--input-file can't be combined with --input-device");
+            std::process::exit(2);
+        }
+        Some(path) => Some(synthetic::Signal::File(path.into())),
+        None if matches!(args.source, SourceKind::Synthetic) => Some(args.synthetic_signal.clone()),
+        None => None,
+    };
+    let mut source: Box<dyn AudioSource> = match &signal {
+        None => Box::new(capture_audio::CpalSource::new(args.input_device.first().cloned())),
+        Some(_) if !args.input_device.is_empty() => {
+            tracing::error!("--input-device needs --source cpal");
+            std::process::exit(2);
+        }
+        Some(signal) => Box::new(synthetic::SyntheticSource::new(signal.clone())),
+    };
+    source = mono(source);
+    let config = match capture_audio::wait_for_source(source.as_mut(), device_timeout).await {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Failed to open input device: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let device_name = source.name();
+    match signal {
+        None => tracing::info!("Capturing from {} via {}", device_name, capture_audio::host_name()),
+        Some(_) => tracing::info!("Capturing from {}", device_name),
+    }
+    // The other devices, each into a buffer of its own
+    let mut other_sources = Vec::new();
+    for wanted in args.input_device.iter().skip(1) {
+        let mut source = mono(Box::new(capture_audio::CpalSource::new(Some(wanted.clone()))));
+        match capture_audio::wait_for_source(source.as_mut(), device_timeout).await {
+            Ok(config) => {
+                tracing::info!("Also capturing from {}", source.name());
+                other_sources.push((source, config));
+            }
+            Err(e) => {
+                tracing::error!("Failed to open input device {}: {}", wanted, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    let buffer_size = misteragent_voice_rust::buffer_capacity(&config, args.seconds);
+    tracing::info!("Initializing buffer for {} seconds ({} samples)", args.seconds, buffer_size);
+    // Refuse a buffer that can't be allocated rather than abort on it
+    let (limit, limited_by) = match (args.max_buffer_bytes.map(|size| size.0), available_memory()) {
+        (Some(cap), Some(available)) if available < cap => (available, "the memory available"),
+        (Some(cap), _) => (cap, "--max-buffer-bytes"),
+        (None, Some(available)) => (available, "the memory available"),
+        (None, None) => (u64::MAX, ""),
+    };
+    let configs: Vec<_> = std::iter::once(&config).chain(other_sources.iter().map(|(_, config)| config)).collect();
+    let buffer_bytes = match misteragent_voice_rust::buffer_bytes(&configs, args.seconds, args.buffer_sample_type, limit) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let smaller = match args.buffer_sample_type {
+                sample_buffer::SampleType::F32 => "; lower --seconds or use --buffer-sample-type i16",
+                sample_buffer::SampleType::I16 => "; lower --seconds",
+            };
+            let advice = match e {
+                misteragent_voice_rust::BufferSizeError::Empty => "; --seconds must be at least 1".to_string(),
+                misteragent_voice_rust::BufferSizeError::Overflow { .. } => smaller.to_string(),
+                misteragent_voice_rust::BufferSizeError::TooLarge { .. } => format!(", set by {}{}", limited_by, smaller),
+            };
+            tracing::error!("Invalid --seconds: {}{}", e, advice);
+            std::process::exit(2);
+        }
+    };
+    tracing::info!("Buffers take {} bytes ({:.1} MiB)", buffer_bytes, buffer_bytes as f64 / (1 << 20) as f64);
     
-    // Create output directory if it doesn't exist
-    std::fs::create_dir_all(&args.output_dir)
-        .expect("Failed to create output directory");
-    log::info!("Using output directory: {}", args.output_dir);
+    // Create the output directory up front when we can. Saves create it again
+    // as needed, so an unwritable directory only stops startup if the
+    // segment archiver needs it straight away.
+    match std::fs::create_dir_all(&args.output_dir) {
+        Ok(()) => {
+            tracing::info!("Using output directory: {}", args.output_dir);
+            let removed = recordings::remove_stale_temp_files(std::path::Path::new(&args.output_dir), recordings::STALE_TEMP_AGE);
+            if removed > 0 {
+                tracing::info!("Removed {} temp files left by unfinished saves", removed);
+            }
+        }
+        Err(e) if args.segment_seconds.is_some() => {
+            tracing::error!("Cannot create output directory {} for --segment-seconds: {}", args.output_dir, e);
+            std::process::exit(2);
+        }
+        Err(e) => tracing::warn!(
+            "Cannot create output directory {}: {}; detection keeps running, but saves will fail until it is writable",
+            args.output_dir, e
+        ),
+    }
 
-    let state = Arc::new(AudioState::new(buffer_size, args.output_dir));
-    let state_clone = Arc::clone(&state);
+    if !(1..=2000).contains(&args.capture_latency_ms) {
+        tracing::error!("--capture-latency-ms must be between 1 and 2000, got {}", args.capture_latency_ms);
+        std::process::exit(2);
+    }
+    if !(10..=60_000).contains(&args.wakeword_queue_ms) {
+        tracing::error!("--wakeword-queue-ms must be between 10 and 60000, got {}", args.wakeword_queue_ms);
+        std::process::exit(2);
+    }
+    if args.capture_latency_ms < 10 {
+        tracing::warn!("Capture latency of {} ms may cause xruns on some hardware", args.capture_latency_ms);
+    }
+    let wav_encoding = match WavEncoding::resolve(config.sample_format(), args.wav_sample_format, args.wav_bits) {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            tracing::error!("Invalid WAV output format: {}", e);
+            std::process::exit(2);
+        }
+    };
+    match args.output_format {
+        OutputFormat::Wav => tracing::info!(
+            "Saving {}-bit {:?} WAV (device delivers {:?})",
+            wav_encoding.bits_per_sample, wav_encoding.kind, config.sample_format()
+        ),
+        OutputFormat::Mp3 => tracing::info!("Saving MP3 at {} kbps", args.mp3_bitrate),
+        OutputFormat::Opus => tracing::info!("Saving Ogg Opus at {} kbps", args.opus_bitrate),
+        OutputFormat::Raw => tracing::info!("Saving raw {} PCM at {} Hz", args.raw_encoding.name(), config.sample_rate().0),
+        format => tracing::info!("Saving {:?} WAV at 8 kHz mono", format),
+    }
+    if let Err(e) = encoding::mp3_bitrate(args.mp3_bitrate) {
+        tracing::error!("Invalid --mp3-bitrate: {}", e);
+        std::process::exit(2);
+    }
+    if !encoding::OPUS_BITRATE_RANGE.contains(&args.opus_bitrate) {
+        tracing::error!("Invalid --opus-bitrate {}: expected 6 to 510 kbps", args.opus_bitrate);
+        std::process::exit(2);
+    }
 
-    // Spawn audio capture task in a dedicated thread
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(capture_audio(state_clone));
+    if args.filename_template.may_collide() {
+        tracing::warn!("--filename-template has no {{seq}} or {{counter}}; saves rendering the same name get _1, _2, ... appended");
+    }
+    let file_counter = match args.filename_template.uses_counter() {
+        true => match filename::FileCounter::open(std::path::Path::new(&args.output_dir), &args.filename_template) {
+            Ok(counter) => Some(counter),
+            Err(e) => {
+                tracing::error!("Cannot read the filename counter in {}: {}", args.output_dir, e);
+                std::process::exit(2);
+            }
+        },
+        false => None,
+    };
+
+    if args.append_to.is_some() && args.output_format != OutputFormat::Wav {
+        tracing::error!("--append-to only supports --output-format wav");
+        std::process::exit(2);
+    }
+    if args.append_to.is_some() && !other_sources.is_empty() {
+        tracing::error!("--append-to takes a single --input-device");
+        std::process::exit(2);
+    }
+    // Each of these rewrites or extends files in place, which an encrypted file can't take
+    if encryption.is_some() && (args.append_to.is_some() || args.segment_seconds.is_some() || args.compress_after.is_some()) {
+        tracing::error!("--encrypt-key can't be combined with --append-to, --segment-seconds or --compress-after");
+        std::process::exit(2);
+    }
+    if args.max_output_bytes.is_some_and(|size| size.0 == 0) {
+        tracing::error!("--max-output-bytes must be greater than 0");
+        std::process::exit(2);
+    }
+    if args.max_recordings == Some(0) || args.max_recordings_age == Some(0) {
+        tracing::error!("--max-recordings and --max-recordings-age must be at least 1");
+        std::process::exit(2);
+    }
+
+    if args.max_concurrent_saves == 0 {
+        tracing::error!("--max-concurrent-saves must be at least 1");
+        std::process::exit(2);
+    }
+    if args.auto_save_interval.is_some_and(|interval| interval.0 < autosave::MIN_INTERVAL) {
+        tracing::error!("--auto-save-interval must be at least {:?}", autosave::MIN_INTERVAL);
+        std::process::exit(2);
+    }
+    if args.compress_interval.0 < maintenance::MIN_INTERVAL {
+        tracing::error!("--compress-interval must be at least {:?}", maintenance::MIN_INTERVAL);
+        std::process::exit(2);
+    }
+    if !args.min_save_seconds.is_finite() || args.min_save_seconds < 0.0 || args.min_save_seconds > args.seconds as f64 {
+        tracing::error!("--min-save-seconds must be between 0 and --seconds {}, got {}", args.seconds, args.min_save_seconds);
+        std::process::exit(2);
+    }
+    if !args.gain.is_finite() || args.gain <= 0.0 {
+        tracing::error!("--gain must be a positive number, got {}", args.gain);
+        std::process::exit(2);
+    }
+    let nyquist = config.sample_rate().0 as f32 / 2.0;
+    if let Some(hz) = args.highpass_hz.filter(|&hz| !hz.is_finite() || hz <= 0.0 || hz >= nyquist) {
+        tracing::error!("--highpass-hz must be between 0 and {} Hz, got {}", nyquist, hz);
+        std::process::exit(2);
+    }
+    if args.highpass_buffer && args.highpass_hz.is_none() {
+        tracing::error!("--highpass-buffer needs --highpass-hz");
+        std::process::exit(2);
+    }
+    let level_trigger = match args.trigger_level_db {
+        Some(db) if !db.is_finite() || !(-120.0..=0.0).contains(&db) => {
+            tracing::error!("--trigger-level-db must be between -120 and 0 dBFS, got {}", db);
+            std::process::exit(2);
+        }
+        Some(threshold_db) => {
+            let seconds = [args.trigger_pre_roll_seconds, args.trigger_hang_seconds, args.trigger_max_seconds];
+            if seconds.iter().any(|s| !s.is_finite() || *s < 0.0) || args.trigger_hang_seconds == 0.0 || args.trigger_max_seconds == 0.0 {
+                tracing::error!("--trigger-hang-seconds and --trigger-max-seconds must be positive and --trigger-pre-roll-seconds not negative");
+                std::process::exit(2);
+            }
+            // The whole capture is saved from the buffer once it ends
+            let longest = args.trigger_pre_roll_seconds + args.trigger_max_seconds;
+            if longest > args.seconds as f64 {
+                tracing::error!(
+                    "--trigger-pre-roll-seconds plus --trigger-max-seconds ({}s) must fit in the {}s buffer",
+                    longest, args.seconds
+                );
+                std::process::exit(2);
+            }
+            tracing::info!("Saving sound-activated captures above {} dBFS", threshold_db);
+            Some(level_trigger::LevelOptions {
+                threshold_db,
+                pre_roll: Duration::from_secs_f64(args.trigger_pre_roll_seconds),
+                hang: Duration::from_secs_f64(args.trigger_hang_seconds),
+                max: Duration::from_secs_f64(args.trigger_max_seconds),
+                cooldown: Duration::from_millis(args.trigger_cooldown_ms),
+            })
+        }
+        None => None,
+    };
+    let auto_stop = match args.auto_stop_silence_ms {
+        Some(0) => {
+            tracing::error!("--auto-stop-silence-ms must be at least 1");
+            std::process::exit(2);
+        }
+        _ if !args.auto_stop_level_db.is_finite() || !(-120.0..=0.0).contains(&args.auto_stop_level_db) => {
+            tracing::error!("--auto-stop-level-db must be between -120 and 0 dBFS, got {}", args.auto_stop_level_db);
+            std::process::exit(2);
+        }
+        Some(ms) => {
+            tracing::info!("Pausing after {} ms below {} dBFS following speech", ms, args.auto_stop_level_db);
+            Some(level_trigger::AutoStopOptions {
+                threshold_db: args.auto_stop_level_db,
+                silence: Duration::from_millis(ms),
+                save: args.auto_stop_save,
+            })
+        }
+        None if args.auto_stop_save => {
+            tracing::error!("--auto-stop-save needs --auto-stop-silence-ms");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    if let Some(hz) = args.highpass_hz {
+        let filtered = if args.highpass_buffer { "detection and recorded audio" } else { "detection audio only" };
+        tracing::info!("High-pass filtering below {} Hz: {}", hz, filtered);
+    }
+
+    let capture_options = CaptureOptions {
+        latency: Duration::from_millis(args.capture_latency_ms),
+        wakeword_queue: Duration::from_millis(args.wakeword_queue_ms),
+    };
+
+    let mut state = AudioState::new(
+        config.clone(),
+        device_name.clone(),
+        buffer_size,
+        args.buffer_mode,
+        OutputOptions {
+            format: args.output_format,
+            wav: wav_encoding,
+            mp3_bitrate_kbps: args.mp3_bitrate,
+            opus_bitrate_kbps: args.opus_bitrate,
+            raw: args.raw_encoding,
+            sample_rate: None,
+            encryption,
+        },
+        config::Settings {
+            output_dir: args.output_dir,
+            gain: args.gain,
+            wakeword_cooldown_ms: args.wakeword_cooldown_ms,
+            health_timeout_secs: args.health_timeout,
+            buffer_seconds: args.seconds,
+        },
+        args.max_concurrent_saves,
+    );
+    if args.split_channels || args.buffer_sample_type != sample_buffer::SampleType::F32 {
+        state.set_buffer_layout(args.split_channels, args.buffer_sample_type);
+    }
+    state.filename_template = args.filename_template;
+    state.file_counter = file_counter;
+    state.base64_limit = args.max_base64_mb.saturating_mul(1024 * 1024);
+    state.min_save_seconds = args.min_save_seconds;
+    state.max_buffer_bytes = args.max_buffer_bytes.map_or(u64::MAX, |size| size.0);
+    state.organize_by_date = args.organize_by_date;
+    state.retention = retention::RetentionPolicy {
+        max_count: args.max_recordings,
+        max_age: args.max_recordings_age.map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60)),
+    };
+    state.output_budget = args.max_output_bytes.map(|size| size.0);
+    state.compression = args.compress_after.map(|after| maintenance::CompressPolicy {
+        after: after.0,
+        interval: args.compress_interval.0,
     });
+    state.wakeword_disabled = args.no_wakeword;
+    state.wakeword_model_path = args.model_path
+        .filter(|path| !path.is_empty())
+        .map(std::path::PathBuf::from);
+    state.highpass_hz = args.highpass_hz;
+    state.level_trigger = level_trigger;
+    state.auto_stop = auto_stop;
+    state.highpass_buffer = args.highpass_buffer;
+    state.append_to = args.append_to.map(std::path::PathBuf::from);
+    state.s3 = match (args.s3_endpoint, args.s3_bucket) {
+        (Some(endpoint), Some(bucket)) => {
+            let options = upload::S3Options {
+                endpoint,
+                bucket,
+                prefix: args.s3_prefix,
+                region: args.s3_region,
+                ca_file: args.s3_ca_file,
+                delete_local: args.s3_delete_local,
+            };
+            match upload::Credentials::from_env().and_then(|credentials| upload::S3Config::new(options, credentials)) {
+                Ok(s3) => {
+                    tracing::info!("Uploading to bucket {} at {}", s3.bucket(), s3.endpoint());
+                    Some(s3)
+                }
+                Err(e) => {
+                    tracing::error!("Invalid S3 upload configuration: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        (None, None) => None,
+        _ => {
+            tracing::error!("--s3-endpoint and --s3-bucket must be given together");
+            std::process::exit(2);
+        }
+    };
+    if (args.auto_upload || args.s3_delete_local) && state.s3.is_none() {
+        tracing::error!("--auto-upload and --s3-delete-local need --s3-endpoint and --s3-bucket");
+        std::process::exit(2);
+    }
+    if args.auto_upload && state.append_to.is_some() {
+        tracing::error!("--auto-upload cannot be used with --append-to");
+        std::process::exit(2);
+    }
+    state.auto_upload = args.auto_upload;
+    if args.save_webhook.is_some() && state.append_to.is_some() {
+        tracing::error!("--save-webhook cannot be used with --append-to");
+        std::process::exit(2);
+    }
+    let webhook_auth = args.save_webhook_auth
+        .filter(|auth| !auth.is_empty());
+    state.webhook = match webhook::WebhookConfig::new(
+        args.save_webhook.as_deref(),
+        webhook_auth,
+        args.save_webhook_method,
+        args.save_webhook_retries,
+        Duration::from_secs(1),
+    ) {
+        Ok(webhook) => webhook,
+        Err(e) => {
+            tracing::error!("Invalid --save-webhook: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Some(url) = &args.save_webhook {
+        tracing::info!("Pushing saved recordings to {}", url);
+    }
+    if !args.stt_seconds.is_finite() || args.stt_seconds <= 0.0 || args.stt_seconds > args.seconds as f64 {
+        tracing::error!("--stt-seconds must be positive and at most the {}s buffer, got {}", args.seconds, args.stt_seconds);
+        std::process::exit(2);
+    }
+    state.stt = match args.stt_url.as_deref().map(|url| stt::SttConfig::new(url, Duration::from_secs_f64(args.stt_seconds))) {
+        Some(Ok(config)) => Some(config),
+        Some(Err(e)) => {
+            tracing::error!("Invalid --stt-url: {}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    if let Some(url) = &args.stt_url {
+        tracing::info!("Transcribing {}s after each detection with {}", args.stt_seconds, url);
+    }
+    state.other_devices = other_sources.iter()
+        .map(|(source, config)| Arc::new(state.companion(config.clone(), source.name())))
+        .collect();
+    let state = Arc::new(state);
+
+    // Initialize Porcupine before capture starts, so misconfiguration stops startup
+    if state.wakeword_disabled {
+        tracing::info!("Wakeword detection disabled, running as a recorder only");
+    } else {
+        match wakeword_listener::get_wakeword_listener(state.wakeword_model_path.as_deref()) {
+            Ok(porcupine) => {
+                tracing::info!("Porcupine initialized with frame length: {}", porcupine.frame_length());
+                if let Some(warning) = wakeword_listener::rate_mismatch(config.sample_rate().0, porcupine.sample_rate()) {
+                    tracing::warn!("{}", warning);
+                }
+                state.set_wakeword(porcupine);
+            }
+            Err(e) => {
+                tracing::error!("Failed to initialize the wakeword engine: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let archiver = match args.segment_seconds {
+        Some(0) => {
+            tracing::error!("--segment-seconds must be at least 1");
+            std::process::exit(2);
+        }
+        Some(seconds) => {
+            let options = segments::SegmentOptions { seconds, keep: args.segment_keep, encoding: wav_encoding };
+            Some(misteragent_voice_rust::start_segment_archiver(&state, options))
+        }
+        None => None,
+    };
+    misteragent_voice_rust::start_workers(&state);
+    let mut captures = vec![misteragent_voice_rust::spawn_capture(Arc::clone(&state), capture_options, source)];
+    for (other, (source, _)) in state.other_devices.iter().zip(other_sources) {
+        captures.push(misteragent_voice_rust::spawn_capture(Arc::clone(other), capture_options, source));
+    }
 
-    // Set up ctrl-c handler
+    // Warn when the audio callback stops delivering frames
+    if args.stall_timeout > 0 {
+        tokio::spawn(watch_capture(
+            Arc::clone(&state),
+            Duration::from_secs(args.stall_timeout),
+            args.restart_on_stall,
+        ));
+    }
+
+    if let Some(interval) = args.auto_save_interval {
+        let buffer_seconds = buffer_size as f64
+            / config.channels().max(1) as f64 / config.sample_rate().0 as f64;
+        if interval.0.as_secs_f64() > buffer_seconds {
+            tracing::warn!(
+                "--auto-save-interval {:?} is longer than the {:.0}s buffer; audio between auto-saves will be lost",
+                interval.0, buffer_seconds
+            );
+        }
+        tracing::info!("Auto-saving every {:?}", interval.0);
+        autosave::spawn(Arc::clone(&state), interval.0);
+    }
+    // Set up the SIGINT/SIGTERM handler so orchestrators get the same shutdown as Ctrl-C
     let state_clone = Arc::clone(&state);
     ctrlc::set_handler(move || {
-        state_clone.is_recording.store(false, Ordering::Relaxed);
-        std::process::exit(0);
-    }).expect("Failed to set Ctrl-C handler");
+        tracing::info!("Received termination signal, shutting down");
+        state_clone.request_shutdown();
+    }).expect("Failed to set signal handler");
+
+    // A Unix socket on its own replaces the default TCP address
+    let bind = args.bind
+        .or_else(|| args.uds.is_none().then(|| DEFAULT_BIND.to_string()));
+    if cfg!(not(unix)) && args.uds.is_some() {
+        tracing::error!("--uds is only supported on Unix platforms");
+        std::process::exit(2);
+    }
+    let is_loopback = bind.as_ref().is_none_or(|bind| {
+        bind.to_socket_addrs()
+            .map(|mut addrs| addrs.all(|addr| addr.ip().is_loopback()))
+            .unwrap_or(false)
+    });
+    let token = args.token
+        .filter(|token| !token.is_empty());
+    if let (Some(bind), false, None) = (&bind, is_loopback, &token) {
+        if args.require_token {
+            tracing::error!("Refusing to listen on non-loopback address {} without an API token", bind);
+            std::process::exit(2);
+        }
+        tracing::warn!("Listening on non-loopback address {}: the control API is reachable from the network without authentication", bind);
+    }
+    let cors_origins = args.cors_origin;
+    if let Some(bad) = cors_origins.iter().find(|o| *o != "*" && !o.starts_with("http://") && !o.starts_with("https://")) {
+        tracing::error!("Invalid --cors-origin `{}`: expected `*` or an origin like https://example.com", bad);
+        std::process::exit(2);
+    }
+    if !cors_origins.is_empty() {
+        tracing::info!("Allowing cross-origin requests from: {}", cors_origins.join(", "));
+    }
+
+    let fixed_settings = config::FixedSettings {
+        bind: bind.clone(),
+        uds: args.uds.clone(),
+        device: device_name,
+        other_devices: state.other_devices.iter().map(|other| other.device_name().to_string()).collect(),
+        sample_rate: config.sample_rate().0,
+        channels: config.channels(),
+        buffer_mode: format!("{:?}", args.buffer_mode).to_lowercase(),
+        split_channels: args.split_channels,
+        buffer_sample_type: args.buffer_sample_type.name().to_string(),
+        output_format: format!("{:?}", args.output_format).to_lowercase(),
+        capture_latency_ms: args.capture_latency_ms,
+        wakeword_queue_ms: args.wakeword_queue_ms,
+        wakewords: match args.no_wakeword {
+            true => Vec::new(),
+            false => wakeword_listener::keyword_names().into_iter().map(String::from).collect(),
+        },
+        wakeword_sensitivity: wakeword_listener::SENSITIVITY,
+        highpass_hz: args.highpass_hz,
+        highpass_buffer: args.highpass_buffer,
+        trigger_level_db: args.trigger_level_db,
+        auto_stop_silence_ms: args.auto_stop_silence_ms,
+        auth_enabled: token.is_some(),
+        tls_enabled: args.tls_cert.is_some() && args.tls_key.is_some(),
+        config_file: args.config.clone(),
+    };
+
+    let upload_limit = args.max_upload_mb.saturating_mul(1024 * 1024);
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_server_config(cert, key) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                tracing::error!("Failed to load TLS configuration: {}", e);
+                std::process::exit(2);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            tracing::error!("--tls-cert and --tls-key must be given together");
+            std::process::exit(2);
+        }
+    };
+
+    let app_options = AppOptions {
+        api_token: token,
+        cors_origins,
+        access_log_format: args.access_log_format,
+        upload_limit,
+        fixed_settings,
+    };
 
-    log::info!("Starting HTTP server on port 8000");
     // Start HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(Arc::clone(&state)))
-            .route("/stop", web::post().to(stop_recording))
-            .route("/save", web::post().to(save_audio))
-            .route("/halt", web::post().to(halt_server))
-            .route("/start", web::post().to(start_recording))
-    })
-    .bind("127.0.0.1:8000")?
-    .run()
-    .await
-}
\ No newline at end of file
+    let shutdown_state = Arc::clone(&state);
+    let server = HttpServer::new(move || misteragent_voice_rust::app(Arc::clone(&state), &app_options))
+    // Signals are handled by the ctrlc handler so every exit goes through graceful_shutdown
+    .disable_signals();
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    let server = match (&bind, tls_config) {
+        (Some(bind), Some(tls_config)) => server.bind_rustls_0_23(bind, tls_config)?,
+        (Some(bind), None) => server.bind(bind)?,
+        (None, _) => server,
+    };
+
+    // Report the bound addresses, which resolves port 0 to the real port
+    for addr in server.addrs() {
+        tracing::info!("Starting HTTP server on {}://{}", scheme, addr);
+    }
+    #[cfg(unix)]
+    let server = match &args.uds {
+        Some(path) => {
+            let path = std::path::Path::new(path);
+            uds::remove_stale_socket(path)?;
+            // The socket carries plain HTTP; TLS only applies to the TCP listener
+            let server = server.bind_uds(path)?;
+            uds::set_socket_mode(path, args.uds_mode)?;
+            tracing::info!("Starting HTTP server on unix:{} (mode {:o})", path.display(), args.uds_mode.0);
+            server
+        }
+        None => server,
+    };
+    let server = server.run();
+    tokio::spawn(misteragent_voice_rust::graceful_shutdown(shutdown_state, server.handle()));
+    server.await?;
+
+    // graceful_shutdown already waited for capture to stop; this makes sure
+    // the streams are closed before exiting, so the next launch can open the device
+    for capture in captures {
+        if !misteragent_voice_rust::join_capture(capture, CAPTURE_JOIN_TIMEOUT) {
+            tracing::warn!("Capture thread still running after {:?}; exiting anyway", CAPTURE_JOIN_TIMEOUT);
+        }
+    }
+
+    // The archiver notices the halt within a second and finalizes its segment
+    if let Some(archiver) = archiver {
+        let _ = archiver.join();
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.uds {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("Failed to remove socket {}: {}", path, e);
+        }
+    }
+    tracing::info!("Server stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use argh::FromArgs;
+    use super::{Args, CONFIG_KEYS};
+
+    #[test]
+    fn config_keys_cover_every_option() {
+        let help = Args::from_args(&["recorder"], &["--help"]).err().unwrap().output;
+        let mut options: Vec<String> = help.lines()
+            // Option lines, not the descriptions continued below them
+            .filter_map(|line| line.strip_prefix("  --"))
+            .filter_map(|line| line.split([' ', ',']).next())
+            .filter(|name| !["list-devices", "decrypt", "config", "help"].contains(name))
+            .map(|name| name.replace('-', "_"))
+            .collect();
+        let mut keys: Vec<String> = CONFIG_KEYS.iter().filter(|key| key.flag).map(|key| key.name.to_string()).collect();
+        options.sort();
+        keys.sort();
+        assert_eq!(options, keys);
+    }
+}
@@ -0,0 +1,368 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{capture_audio, flac, recordings, segments, AudioState};
+
+/// Shortest --compress-interval, so passes don't rescan the directory back to back
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Archiving of old recordings, from --compress-after and --compress-interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressPolicy {
+    /// WAV recordings last modified longer ago than this are transcoded to FLAC
+    pub after: Duration,
+    /// Time between passes
+    pub interval: Duration,
+}
+
+/// One recording archived by a pass
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Compressed {
+    // The FLAC that replaced it, relative to the output directory
+    recording: String,
+    wav_bytes: u64,
+    flac_bytes: u64,
+}
+
+/// A recording left as it was, and why
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Skipped {
+    recording: String,
+    reason: String,
+}
+
+/// What one pass did
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MaintenanceRun {
+    started_at: chrono::DateTime<chrono::Local>,
+    finished_at: Option<chrono::DateTime<chrono::Local>>,
+    compressed: Vec<Compressed>,
+    // Transcodes that failed or didn't verify; the WAV is kept
+    failed: Vec<Skipped>,
+    skipped: Vec<Skipped>,
+    // Set when the pass stopped early, e.g. because a save started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stopped: Option<String>,
+}
+
+#[derive(Default)]
+struct Progress {
+    running: bool,
+    // Recording being transcoded right now
+    current: Option<String>,
+    last_run: Option<MaintenanceRun>,
+    files_compressed: u64,
+    bytes_saved: u64,
+}
+
+/// Shared progress of the maintenance task, reported by /maintenance
+#[derive(Default)]
+pub struct Maintenance {
+    progress: parking_lot::Mutex<Progress>,
+}
+
+/// Body of GET /maintenance
+#[derive(Serialize, ToSchema)]
+pub struct MaintenanceStatus {
+    // Whether --compress-after is set
+    enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compress_after_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval_seconds: Option<u64>,
+    running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+    // The pass in progress, else the most recent one
+    last_run: Option<MaintenanceRun>,
+    // Totals since startup
+    files_compressed: u64,
+    bytes_saved: u64,
+}
+
+// Why no new file may be started: a save is writing, or shutdown began
+fn busy(state: &AudioState) -> Option<&'static str> {
+    if state.is_halting.load(Ordering::Relaxed) {
+        Some("shutting down")
+    } else if !state.active_saves.lock().is_empty() || state.jobs.in_flight() > 0 {
+        Some("a save was in progress")
+    } else {
+        None
+    }
+}
+
+// WAV recordings old enough to compress, oldest first. Archive segments and
+// the --append-to session file are left alone; `.tmp` files are never
+// listed, as they don't count as recordings.
+fn candidates(state: &AudioState, root: &Path, after: Duration) -> Vec<(PathBuf, SystemTime, u64)> {
+    let mut found = Vec::new();
+    if let Err(e) = recordings::find_recordings(root, &mut found) {
+        tracing::warn!("Maintenance: unable to list {}: {}", root.display(), e);
+    }
+    let now = SystemTime::now();
+    let append_to = state.append_to.as_deref().and_then(|path| path.canonicalize().ok());
+    let mut candidates: Vec<_> = found.into_iter()
+        .filter(|(path, _)| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav")))
+        .filter(|(path, _)| !segments::is_segment(path))
+        .filter(|(path, _)| append_to.is_none() || path.canonicalize().ok() != append_to)
+        .filter_map(|(path, metadata)| {
+            let modified = metadata.modified().ok()?;
+            now.duration_since(modified).is_ok_and(|age| age > after).then_some((path, modified, metadata.len()))
+        })
+        .collect();
+    candidates.sort_by_key(|&(_, modified, _)| modified);
+    candidates
+}
+
+// Why a recording wasn't compressed
+enum NotCompressed {
+    // Left as WAV on purpose, e.g. float samples FLAC can't hold
+    Skipped(String),
+    Failed(String),
+}
+
+impl<E: std::fmt::Display> From<E> for NotCompressed {
+    fn from(e: E) -> Self {
+        NotCompressed::Failed(e.to_string())
+    }
+}
+
+type WavSamples = hound::WavIntoSamples<std::io::BufReader<std::fs::File>, i32>;
+
+// Format and samples, as they are read, of a WAV FLAC can hold losslessly
+fn open_wav(path: &Path) -> Result<(flac::StreamInfo, WavSamples), NotCompressed> {
+    let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample > flac::MAX_BITS_PER_SAMPLE {
+        return Err(NotCompressed::Skipped(format!(
+            "{}-bit {:?} samples can't be stored as FLAC", spec.bits_per_sample, spec.sample_format
+        )));
+    }
+    let info = flac::StreamInfo {
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits_per_sample: spec.bits_per_sample,
+        frames: reader.duration() as u64,
+    };
+    Ok((info, reader.into_samples::<i32>()))
+}
+
+// The next block of up to `len` samples, empty at the end
+fn read_block(samples: &mut WavSamples, len: usize, block: &mut Vec<i32>) -> hound::Result<()> {
+    block.clear();
+    for sample in samples.take(len) {
+        block.push(sample?);
+    }
+    Ok(())
+}
+
+// Hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    digest: ring::digest::Context,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.digest.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// Point the sidecar, if there is one, at the FLAC that replaced its WAV.
+// Both share the `<basename>.json` sidecar, so only its fields change.
+fn update_sidecar(flac_path: &Path, sha256: String) -> std::io::Result<()> {
+    let sidecar_path = recordings::sidecar_path(flac_path);
+    let Ok(bytes) = std::fs::read(&sidecar_path) else {
+        return Ok(());
+    };
+    let mut sidecar: serde_json::Value = serde_json::from_slice(&bytes).map_err(std::io::Error::other)?;
+    sidecar["recording"] = flac_path.file_name().unwrap_or_default().to_string_lossy().into_owned().into();
+    sidecar["format"] = "flac".into();
+    sidecar["sha256"] = sha256.into();
+    let json = serde_json::to_vec_pretty(&sidecar).map_err(std::io::Error::other)?;
+    recordings::replace_file(&sidecar_path, &json)
+}
+
+// Transcode one WAV, check the FLAC decodes to the very same samples, then
+// swap it in. Returns the FLAC's path and size. Both passes go a block at a
+// time, so memory stays at a few blocks whatever the recording's length.
+fn compress(wav: &Path, modified: SystemTime) -> Result<(PathBuf, u64), NotCompressed> {
+    let (info, mut samples) = open_wav(wav)?;
+    let flac_path = wav.with_extension("flac");
+    if flac_path.exists() {
+        return Err(NotCompressed::Skipped(format!("{} already exists", flac_path.display())));
+    }
+    let block_len = flac::BLOCK_SIZE * info.channels as usize;
+
+    let temp = recordings::temp_path(&flac_path);
+    let verified = (|| {
+        let file = std::fs::File::create(&temp)?;
+        let mut encoder = flac::Encoder::new(std::io::BufWriter::new(file), info)?;
+        let mut block = Vec::with_capacity(block_len);
+        loop {
+            read_block(&mut samples, block_len, &mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            encoder.write_block(&block)?;
+        }
+        let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        // Keep the recording's age, so retention treats it as before
+        file.set_modified(modified)?;
+        file.sync_all()?;
+
+        // Verify what reached the disk, not what was meant to, against a
+        // second read of the WAV
+        let (_, mut original) = open_wav(wav)?;
+        let flac_file = std::fs::File::open(&temp)?;
+        let size = flac_file.metadata()?.len();
+        let reader = HashingReader {
+            inner: std::io::BufReader::new(flac_file),
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        };
+        let mut decoder = flac::Decoder::new(reader)?;
+        let mut decoded = Vec::with_capacity(block_len);
+        let mut matches = decoder.info() == info;
+        while matches {
+            decoded.clear();
+            if !decoder.next_block(&mut decoded)? {
+                break;
+            }
+            read_block(&mut original, decoded.len(), &mut block)?;
+            matches = decoded == block;
+        }
+        // Nothing of the WAV may be left over either
+        read_block(&mut original, 1, &mut block)?;
+        if !matches || !block.is_empty() {
+            return Err(NotCompressed::Failed("FLAC did not decode to the original samples".to_string()));
+        }
+        let sha256 = capture_audio::hex(decoder.into_inner().digest.finish().as_ref());
+        std::fs::rename(&temp, &flac_path)?;
+        Ok((size, sha256))
+    })();
+    let (size, sha256) = match verified {
+        Ok(verified) => verified,
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+    };
+    if let Err(e) = update_sidecar(&flac_path, sha256) {
+        tracing::warn!("Maintenance: failed to update the sidecar of {}: {}", flac_path.display(), e);
+    }
+    if let Err(e) = std::fs::remove_file(wav) {
+        tracing::warn!("Maintenance: compressed {} but failed to delete it: {}", wav.display(), e);
+    }
+    Ok((flac_path, size))
+}
+
+/// One pass over the output directory, skipped entirely while a save is
+/// writing and stopped early if one starts
+pub fn run(state: &AudioState, policy: CompressPolicy) {
+    let root = PathBuf::from(&state.settings.read().output_dir);
+    let mut run = MaintenanceRun {
+        started_at: chrono::Local::now(),
+        finished_at: None,
+        compressed: Vec::new(),
+        failed: Vec::new(),
+        skipped: Vec::new(),
+        stopped: None,
+    };
+    {
+        let mut progress = state.maintenance.progress.lock();
+        progress.running = true;
+        progress.last_run = Some(run.clone());
+    }
+
+    for (path, modified, size) in candidates(state, &root, policy.after) {
+        if let Some(reason) = busy(state) {
+            tracing::info!("Maintenance: stopping, {}", reason);
+            run.stopped = Some(reason.to_string());
+            break;
+        }
+        let name = recordings::relative_name(&root, &path);
+        state.maintenance.progress.lock().current = Some(name.clone());
+        // Keeps retention and DELETE away from the WAV meanwhile
+        let active = recordings::ActiveSave::begin(state, &name);
+        match compress(&path, modified) {
+            Ok((flac_path, flac_bytes)) => {
+                let recording = recordings::relative_name(&root, &flac_path);
+                tracing::info!("Maintenance: compressed {} to {} ({} -> {} bytes)", name, recording, size, flac_bytes);
+                state.output_usage.add(flac_bytes);
+                state.output_usage.remove(size);
+                run.compressed.push(Compressed { recording, wav_bytes: size, flac_bytes });
+            }
+            Err(NotCompressed::Skipped(reason)) => {
+                tracing::debug!("Maintenance: skipping {}: {}", name, reason);
+                run.skipped.push(Skipped { recording: name, reason });
+            }
+            Err(NotCompressed::Failed(reason)) => {
+                tracing::warn!("Maintenance: failed to compress {}: {}", name, reason);
+                run.failed.push(Skipped { recording: name, reason });
+            }
+        }
+        drop(active);
+        state.maintenance.progress.lock().last_run = Some(run.clone());
+    }
+
+    run.finished_at = Some(chrono::Local::now());
+    let mut progress = state.maintenance.progress.lock();
+    progress.files_compressed += run.compressed.len() as u64;
+    progress.bytes_saved += run.compressed.iter()
+        .map(|file| file.wav_bytes.saturating_sub(file.flac_bytes))
+        .sum::<u64>();
+    progress.running = false;
+    progress.current = None;
+    progress.last_run = Some(run);
+}
+
+/// Run a pass every `policy.interval` until shutdown, starting right away
+pub fn spawn(state: Arc<AudioState>, policy: CompressPolicy) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(policy.interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if state.is_halting.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some(reason) = busy(&state) {
+                tracing::info!("Maintenance: skipping this pass, {}", reason);
+                continue;
+            }
+            let pass_state = Arc::clone(&state);
+            if let Err(e) = tokio::task::spawn_blocking(move || run(&pass_state, policy)).await {
+                tracing::error!("Maintenance pass failed: {}", e);
+            }
+        }
+    })
+}
+
+/// Progress and results of background maintenance, such as compressing old recordings to FLAC
+#[utoipa::path(
+    get,
+    path = "/maintenance",
+    responses((status = 200, body = MaintenanceStatus)),
+)]
+pub async fn maintenance_status(state: web::Data<Arc<AudioState>>) -> HttpResponse {
+    let progress = state.maintenance.progress.lock();
+    let policy = state.compression;
+    HttpResponse::Ok().json(MaintenanceStatus {
+        enabled: policy.is_some(),
+        compress_after_seconds: policy.map(|policy| policy.after.as_secs()),
+        interval_seconds: policy.map(|policy| policy.interval.as_secs()),
+        running: progress.running,
+        current: progress.current.clone(),
+        last_run: progress.last_run.clone(),
+        files_compressed: progress.files_compressed,
+        bytes_saved: progress.bytes_saved,
+    })
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::sync::Arc;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::AudioState;
+use crate::api::ErrorResponse;
+use crate::encoding::{downmix, read_wav, resample, to_i16};
+use crate::wakeword_listener::{keyword_name, get_wakeword_listener};
+
+// Content types accepted by /process
+const ACCEPTED_TYPES: &[&str] = &["audio/wav", "audio/x-wav", "audio/wave", "application/octet-stream"];
+
+#[derive(Serialize, ToSchema)]
+pub struct Detection {
+    keyword: String,
+    keyword_index: i32,
+    // End of the frame the keyword was detected in, from the start of the file
+    offset_seconds: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProcessResponse {
+    duration_seconds: f64,
+    sample_rate: u32,
+    channels: u16,
+    detections: Vec<Detection>,
+}
+
+enum ProcessError {
+    BadAudio(String),
+    Engine(String),
+}
+
+// Decode the upload and run it through a dedicated engine, so the live
+// capture's engine state is never touched
+fn detect(body: &[u8], model_path: Option<&Path>) -> Result<ProcessResponse, ProcessError> {
+    let (spec, samples) = read_wav(body).map_err(|e| ProcessError::BadAudio(format!("Invalid WAV: {}", e)))?;
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        return Err(ProcessError::BadAudio("WAV has no channels or a zero sample rate".to_string()));
+    }
+    let porcupine = get_wakeword_listener(model_path).map_err(|e| ProcessError::Engine(e.to_string()))?;
+    let engine_rate = porcupine.sample_rate();
+    let frame_length = porcupine.frame_length() as usize;
+
+    let mono = downmix(&samples, spec.channels);
+    let pcm: Vec<i16> = resample(&mono, spec.sample_rate, engine_rate).iter().map(|&s| to_i16(s)).collect();
+
+    let mut detections = Vec::new();
+    for (i, frame) in pcm.chunks_exact(frame_length).enumerate() {
+        let keyword_index = porcupine.process(frame)
+            .map_err(|e| ProcessError::Engine(format!("Error processing audio: {}", e)))?;
+        if keyword_index >= 0 {
+            detections.push(Detection {
+                keyword: keyword_name(keyword_index).to_string(),
+                keyword_index,
+                offset_seconds: ((i + 1) * frame_length) as f64 / engine_rate as f64,
+            });
+        }
+    }
+
+    Ok(ProcessResponse {
+        duration_seconds: mono.len() as f64 / spec.sample_rate as f64,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        detections,
+    })
+}
+
+/// Run an uploaded WAV through the wakeword engine and list the detections
+#[utoipa::path(
+    post,
+    path = "/process",
+    request_body(content = Vec<u8>, content_type = "audio/wav"),
+    responses(
+        (status = 200, body = ProcessResponse),
+        (status = 400, description = "Body is not a readable WAV file", body = ErrorResponse),
+        (status = 413, description = "Upload exceeds --max-upload-mb"),
+        (status = 415, description = "Unsupported content type", body = ErrorResponse),
+        (status = 500, description = "The wakeword engine failed", body = ErrorResponse),
+    ),
+)]
+pub async fn process_audio(state: web::Data<Arc<AudioState>>, req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let content_type = req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+    if let Some(content_type) = content_type {
+        if !ACCEPTED_TYPES.contains(&content_type.as_str()) {
+            return HttpResponse::UnsupportedMediaType()
+                .json(ErrorResponse::new(format!("Expected audio/wav, got {}", content_type)));
+        }
+    }
+
+    tracing::info!("Processing uploaded audio ({} bytes)", body.len());
+    let model_path = state.wakeword_model_path.clone();
+    let result = web::block(move || detect(&body, model_path.as_deref()))
+        .await
+        .unwrap_or_else(|e| Err(ProcessError::Engine(e.to_string())));
+    match result {
+        Ok(response) => {
+            tracing::info!(
+                "Processed {:.1}s of uploaded audio: {} detections",
+                response.duration_seconds, response.detections.len()
+            );
+            HttpResponse::Ok().json(response)
+        }
+        Err(ProcessError::BadAudio(e)) => HttpResponse::BadRequest().json(ErrorResponse::new(e)),
+        Err(ProcessError::Engine(e)) => {
+            tracing::error!("Failed to process uploaded audio: {}", e);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(e))
+        }
+    }
+}
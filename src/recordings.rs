@@ -0,0 +1,482 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::AudioState;
+use crate::api::ErrorResponse;
+
+/// File extensions we treat as recordings
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["wav", "mp3", "opus", "flac", "raw"];
+
+/// Order of GET /recordings
+#[derive(Deserialize, ToSchema, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Most recently modified first
+    #[default]
+    Newest,
+    /// Least recently modified first
+    Oldest,
+    /// Largest first
+    Size,
+}
+
+/// Query of GET /recordings
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// Maximum number of entries to return
+    limit: Option<usize>,
+    /// Sort order (default: newest)
+    #[serde(default)]
+    #[param(inline)]
+    sort: SortOrder,
+}
+
+/// One recording listed by GET /recordings
+#[derive(Serialize, ToSchema)]
+pub struct RecordingEntry {
+    // Path relative to the output directory, `/`-separated
+    filename: String,
+    size_bytes: u64,
+    modified: Option<chrono::DateTime<chrono::Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channels: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parse_error: Option<String>,
+    // Encrypted with --encrypt-key, so the audio details can't be read
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    encrypted: bool,
+}
+
+/// Suffix of files still being written; they are renamed into place once complete
+pub const TEMP_EXTENSION: &str = "tmp";
+
+/// Temp files left behind longer than this are from a save that never finished
+pub const STALE_TEMP_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Unfinished saves end in `.tmp`, so they never count as recordings
+pub fn is_recording(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| SUPPORTED_EXTENSIONS.iter().any(|s| s.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// `path` relative to `root` with `/` separators, as used in URLs and listings
+pub fn relative_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Build a listing entry, reading the WAV or FLAC header for audio details
+fn describe_recording(root: &Path, path: &Path, metadata: &std::fs::Metadata) -> RecordingEntry {
+    let mut entry = RecordingEntry {
+        filename: relative_name(root, path),
+        size_bytes: metadata.len(),
+        modified: metadata.modified().ok().map(chrono::DateTime::from),
+        duration_seconds: None,
+        sample_rate: None,
+        channels: None,
+        parse_error: None,
+        encrypted: false,
+    };
+    if crate::encryption::is_encrypted_file(path) {
+        entry.encrypted = true;
+        return entry;
+    }
+
+    // Only WAV and FLAC headers carry the details we report
+    let is_wav = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("flac")) {
+        match crate::flac::read_info(path) {
+            Ok(info) => {
+                entry.duration_seconds = Some(info.frames as f64 / info.sample_rate as f64);
+                entry.sample_rate = Some(info.sample_rate);
+                entry.channels = Some(info.channels);
+            }
+            Err(e) => entry.parse_error = Some(e.to_string()),
+        }
+        return entry;
+    }
+    if !is_wav {
+        return entry;
+    }
+    match hound::WavReader::open(path) {
+        Ok(reader) => {
+            let spec = reader.spec();
+            // duration() counts frames, i.e. samples per channel
+            entry.duration_seconds = Some(reader.duration() as f64 / spec.sample_rate as f64);
+            entry.sample_rate = Some(spec.sample_rate);
+            entry.channels = Some(spec.channels);
+        }
+        Err(e) => {
+            entry.parse_error = Some(e.to_string());
+        }
+    }
+    entry
+}
+
+/// Find recording files under `dir`, descending into subdirectories such as
+/// the --organize-by-date layout. Symlinks are never followed.
+pub fn find_recordings(dir: &Path, found: &mut Vec<(PathBuf, std::fs::Metadata)>) -> std::io::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        // Neither file_type nor metadata on a DirEntry follow symlinks
+        let metadata = match dir_entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                tracing::warn!("Unable to read metadata for {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            if let Err(e) = find_recordings(&path, found) {
+                tracing::warn!("Unable to list {}: {}", path.display(), e);
+            }
+        } else if metadata.is_file() && is_recording(&path) {
+            found.push((path, metadata));
+        }
+    }
+    Ok(())
+}
+
+/// Recordings under `dir`, sorted by `sort` and cut to `limit`
+pub fn list_recordings_in(dir: &Path, sort: SortOrder, limit: Option<usize>) -> std::io::Result<Vec<RecordingEntry>> {
+    let mut found = Vec::new();
+    find_recordings(dir, &mut found)?;
+    let mut entries: Vec<RecordingEntry> = found.iter()
+        .map(|(path, metadata)| describe_recording(dir, path, metadata))
+        .collect();
+
+    match sort {
+        SortOrder::Newest => entries.sort_by_key(|e| std::cmp::Reverse(e.modified)),
+        SortOrder::Oldest => entries.sort_by_key(|e| e.modified),
+        SortOrder::Size => entries.sort_by_key(|e| std::cmp::Reverse(e.size_bytes)),
+    }
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+    Ok(entries)
+}
+
+/// List saved recordings with their WAV or FLAC details
+#[utoipa::path(
+    get,
+    path = "/recordings",
+    params(ListQuery),
+    responses((status = 200, body = Vec<RecordingEntry>), (status = 500, body = ErrorResponse)),
+)]
+pub async fn list_recordings(
+    state: web::Data<Arc<AudioState>>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let output_dir = state.settings.read().output_dir.clone();
+    let dir = Path::new(&output_dir);
+    match list_recordings_in(dir, query.sort, query.limit) {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            tracing::error!("Failed to list recordings in {}: {}", dir.display(), e);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(format!("Failed to list recordings: {}", e)))
+        }
+    }
+}
+
+/// Where a wakeword detection falls in a saved file
+#[derive(Clone, Serialize, ToSchema)]
+pub struct DetectionMark {
+    /// Name of the keyword heard
+    pub keyword: String,
+    /// Frame index (samples per channel) at the file's sample rate
+    pub sample_offset: u64,
+    /// `sample_offset` in seconds
+    pub seconds: f64,
+    /// Samples captured since startup, across all channels, when it fired
+    pub captured_sample: u64,
+}
+
+/// Where a /mark label falls in a saved file, placed like a DetectionMark
+#[derive(Clone, Serialize, ToSchema)]
+pub struct CuePoint {
+    /// Text given to /mark, if any
+    pub label: Option<String>,
+    /// Frame index (samples per channel) at the file's sample rate
+    pub sample_offset: u64,
+    /// `sample_offset` in seconds
+    pub seconds: f64,
+    /// Samples captured since startup, across all channels, when it was placed
+    pub captured_sample: u64,
+}
+
+/// Metadata written next to each saved recording as `<basename>.json`. A
+/// webhook push later adds its outcome under `webhook`.
+#[derive(Serialize)]
+pub struct Sidecar {
+    /// File name of the recording, without directories
+    pub recording: String,
+    /// Format it was saved in, as for --output-format
+    pub format: String,
+    /// Frames per second in the file
+    pub sample_rate: u32,
+    /// Channels per frame in the file
+    pub channels: u16,
+    /// None for compressed formats
+    pub bits_per_sample: Option<u16>,
+    /// Interleaved samples in the file
+    pub samples: usize,
+    /// Length of the audio
+    pub duration_seconds: f64,
+    /// Wall-clock time of the first sample
+    pub started_at: chrono::DateTime<chrono::Local>,
+    /// When the file was written
+    pub saved_at: chrono::DateTime<chrono::Local>,
+    /// The filename::Trigger that caused the save
+    pub trigger: String,
+    /// Wakeword detections inside the audio
+    pub detections: Vec<DetectionMark>,
+    /// Labels dropped with /mark while the audio was recorded
+    pub markers: Vec<CuePoint>,
+    /// Audio host the device belongs to
+    pub audio_host: String,
+    /// Name of the device the audio came from
+    pub device: String,
+    /// Hex SHA-256 of the recording
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Where `path` is written before being renamed into place: `<name>.<ext>.tmp`
+/// in the same directory, so the rename is atomic
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(TEMP_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Swap `bytes` in for the contents of `path` through its temp path, so a
+/// reader or a crash sees the old file or the new one, never half of it
+pub fn replace_file(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let temp = temp_path(path);
+    let written = (|| {
+        let mut file = std::fs::File::create(&temp)?;
+        std::io::Write::write_all(&mut file, bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Delete temp files under `dir` last modified more than `max_age` ago, left
+/// by saves interrupted by a crash. Returns how many were removed.
+pub fn remove_stale_temp_files(dir: &Path, max_age: std::time::Duration) -> usize {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            removed += remove_stale_temp_files(&path, max_age);
+            continue;
+        }
+        let is_temp = path.extension().is_some_and(|ext| ext == TEMP_EXTENSION);
+        let stale = metadata.modified().ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > max_age);
+        if metadata.is_file() && is_temp && stale {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    tracing::info!("Removed stale temp file {}", path.display());
+                    removed += 1;
+                }
+                Err(e) => tracing::warn!("Failed to remove stale temp file {}: {}", path.display(), e),
+            }
+        }
+    }
+    removed
+}
+
+/// Where the sidecar of `recording` goes
+pub fn sidecar_path(recording: &Path) -> PathBuf {
+    recording.with_extension("json")
+}
+
+/// Write the sidecar of `recording`
+pub fn write_sidecar(recording: &Path, sidecar: &Sidecar) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(sidecar).map_err(std::io::Error::other)?;
+    std::fs::write(sidecar_path(recording), json)
+}
+
+/// Marks a recording as being written so it can't be deleted mid-save.
+/// Names are relative to the output directory, as relative_name gives them.
+pub struct ActiveSave<'a> {
+    state: &'a AudioState,
+    name: String,
+}
+
+impl<'a> ActiveSave<'a> {
+    /// Mark `name` as being written until the returned value is dropped
+    pub fn begin(state: &'a AudioState, name: &str) -> Self {
+        state.active_saves.lock().insert(name.to_string());
+        ActiveSave { state, name: name.to_string() }
+    }
+}
+
+impl Drop for ActiveSave<'_> {
+    fn drop(&mut self) {
+        self.state.active_saves.lock().remove(&self.name);
+        self.state.saves_done.notify_waiters();
+    }
+}
+
+/// Why a requested recording can't be served
+pub enum ResolveError {
+    /// No such recording
+    NotFound,
+    /// The name leads outside the output directory
+    Forbidden,
+    /// Looking it up failed
+    Io(std::io::Error),
+}
+
+impl ResolveError {
+    /// The error reply for the recording `name`
+    pub fn into_response(self, name: &str) -> HttpResponse {
+        match self {
+            ResolveError::NotFound => HttpResponse::NotFound()
+                .json(ErrorResponse::new(format!("Recording {} not found", name))),
+            ResolveError::Forbidden => HttpResponse::Forbidden()
+                .json(ErrorResponse::new("Recording path is outside the output directory")),
+            ResolveError::Io(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse::new(format!("Failed to resolve recording: {}", e))),
+        }
+    }
+}
+
+/// Resolve a client-supplied relative path to a file, making sure it stays
+/// inside `dir`. Returns the file and its canonical relative name.
+pub fn resolve_recording(dir: &Path, name: &str) -> Result<(PathBuf, String), ResolveError> {
+    let not_found_or = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::NotFound => ResolveError::NotFound,
+        _ => ResolveError::Io(e),
+    };
+    let root = dir.canonicalize().map_err(ResolveError::Io)?;
+    let path = root.join(name).canonicalize().map_err(not_found_or)?;
+    if !path.starts_with(&root) || path == root {
+        tracing::warn!("Rejected recording path outside output directory: {}", name);
+        return Err(ResolveError::Forbidden);
+    }
+    if !path.is_file() {
+        return Err(ResolveError::NotFound);
+    }
+    let relative = relative_name(&root, &path);
+    Ok((path, relative))
+}
+
+/// Download a recording; supports Range requests for seeking
+#[utoipa::path(
+    get,
+    path = "/recordings/{name}",
+    params(("name" = String, Path, description = "Recording path relative to the output directory")),
+    responses(
+        (status = 200, description = "Recording contents", content_type = "audio/wav"),
+        (status = 206, description = "Requested byte range", content_type = "audio/wav"),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    ),
+)]
+pub async fn download_recording(
+    req: HttpRequest,
+    state: web::Data<Arc<AudioState>>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let (path, _) = match resolve_recording(Path::new(&state.settings.read().output_dir), &name) {
+        Ok(resolved) => resolved,
+        Err(e) => return e.into_response(&name),
+    };
+    // NamedFile handles Content-Type, Range and conditional requests
+    match NamedFile::open_async(&path).await {
+        Ok(file) => file.into_response(&req),
+        Err(e) => ResolveError::Io(e).into_response(&name),
+    }
+}
+
+/// Reply to DELETE /recordings/{name}
+#[derive(Serialize, ToSchema)]
+pub struct DeleteResponse {
+    deleted: String,
+    bytes_reclaimed: u64,
+}
+
+/// Delete a recording
+#[utoipa::path(
+    delete,
+    path = "/recordings/{name}",
+    params(("name" = String, Path, description = "Recording path relative to the output directory")),
+    responses(
+        (status = 200, body = DeleteResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, description = "The recording is still being saved", body = ErrorResponse),
+    ),
+)]
+pub async fn delete_recording(
+    state: web::Data<Arc<AudioState>>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let (path, relative) = match resolve_recording(Path::new(&state.settings.read().output_dir), &name) {
+        Ok(resolved) => resolved,
+        Err(e) => return e.into_response(&name),
+    };
+
+    // Hold the active-save set while deleting so a save can't start in between
+    let active_saves = state.active_saves.lock();
+    if active_saves.contains(&relative) {
+        return HttpResponse::Conflict()
+            .json(ErrorResponse::new(format!("Recording {} is currently being saved", name)));
+    }
+
+    let mut size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            // The sidecar goes with its recording
+            let sidecar = sidecar_path(&path);
+            if is_recording(&path) && sidecar.is_file() {
+                let sidecar_size = std::fs::metadata(&sidecar).map(|m| m.len()).unwrap_or(0);
+                match std::fs::remove_file(&sidecar) {
+                    Ok(()) => size += sidecar_size,
+                    Err(e) => tracing::warn!("Failed to delete sidecar {}: {}", sidecar.display(), e),
+                }
+            }
+            tracing::info!("Deleted recording {} ({} bytes reclaimed)", path.display(), size);
+            state.output_usage.remove(size);
+            HttpResponse::Ok().json(DeleteResponse {
+                deleted: name.into_inner(),
+                bytes_reclaimed: size,
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => ResolveError::NotFound.into_response(&name),
+        Err(e) => {
+            tracing::error!("Failed to delete {}: {}", path.display(), e);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(format!("Failed to delete recording: {}", e)))
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::AudioState;
+use crate::{recordings, segments};
+
+// How long the cached output directory size is trusted before rescanning,
+// to pick up files other processes add or remove
+const USAGE_RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits applied to saved recordings after every save
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep at most this many recordings
+    pub max_count: Option<usize>,
+    /// Delete recordings last modified longer ago than this
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Whether any limit is set
+    pub fn is_enabled(&self) -> bool {
+        self.max_count.is_some() || self.max_age.is_some()
+    }
+}
+
+/// A byte count parsed from the CLI, e.g. `500M` or `2G` (binary multiples)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let upper = trimmed.to_ascii_uppercase();
+        let unit_start = upper.find(|c: char| !c.is_ascii_digit()).unwrap_or(upper.len());
+        let (digits, unit) = upper.split_at(unit_start);
+        let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            "T" => 1 << 40,
+            _ => return Err(format!("invalid size `{}`, expected a number with an optional K, M, G or T suffix", s)),
+        };
+        digits.parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(ByteSize)
+            .ok_or_else(|| format!("invalid size `{}`, expected a number with an optional K, M, G or T suffix", s))
+    }
+}
+
+/// Cached total size of the output directory, for --max-output-bytes
+#[derive(Default)]
+pub struct OutputUsage {
+    // (directory scanned, bytes, when it was scanned)
+    cached: parking_lot::Mutex<Option<(PathBuf, u64, Instant)>>,
+}
+
+// Bytes in regular files under `dir`, without following symlinks
+fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries.filter_map(Result::ok)
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+        .map(|(path, metadata)| {
+            if metadata.is_dir() {
+                directory_size(&path)
+            } else if metadata.is_file() {
+                metadata.len()
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+impl OutputUsage {
+    /// Current usage of `dir`, rescanning when the cache is stale or for another directory
+    pub fn current(&self, dir: &Path) -> u64 {
+        let mut cached = self.cached.lock();
+        match &*cached {
+            Some((scanned, bytes, at)) if scanned == dir && at.elapsed() < USAGE_RESYNC_INTERVAL => *bytes,
+            _ => {
+                let bytes = directory_size(dir);
+                tracing::debug!("Output directory {} holds {} bytes", dir.display(), bytes);
+                *cached = Some((dir.to_path_buf(), bytes, Instant::now()));
+                bytes
+            }
+        }
+    }
+
+    /// Last known usage, without scanning
+    pub fn cached(&self) -> Option<u64> {
+        self.cached.lock().as_ref().map(|&(_, bytes, _)| bytes)
+    }
+
+    /// Count `bytes` just written, if usage is cached
+    pub fn add(&self, bytes: u64) {
+        if let Some((_, total, _)) = &mut *self.cached.lock() {
+            *total += bytes;
+        }
+    }
+
+    /// Count `bytes` just deleted, if usage is cached
+    pub fn remove(&self, bytes: u64) {
+        if let Some((_, total, _)) = &mut *self.cached.lock() {
+            *total = total.saturating_sub(bytes);
+        }
+    }
+}
+
+/// What the most recent cleanup pass did, reported by /status
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RetentionRun {
+    files_deleted: usize,
+    bytes_freed: u64,
+    ran_at: chrono::DateTime<chrono::Local>,
+}
+
+impl RetentionRun {
+    fn new() -> Self {
+        RetentionRun { files_deleted: 0, bytes_freed: 0, ran_at: chrono::Local::now() }
+    }
+}
+
+/// Remove a recording and its sidecar, returning the bytes reclaimed
+pub fn delete_recording(path: &Path, size: u64) -> std::io::Result<u64> {
+    std::fs::remove_file(path)?;
+    let mut freed = size;
+    let sidecar = recordings::sidecar_path(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(&sidecar) {
+        if metadata.is_file() {
+            match std::fs::remove_file(&sidecar) {
+                Ok(()) => freed += metadata.len(),
+                Err(e) => tracing::warn!("Failed to delete sidecar {}: {}", sidecar.display(), e),
+            }
+        }
+    }
+    Ok(freed)
+}
+
+// Recordings the policies may delete, newest first. Archive segments are
+// left to --segment-keep.
+fn candidates(root: &Path) -> Vec<(PathBuf, SystemTime, u64)> {
+    let mut found = Vec::new();
+    if let Err(e) = recordings::find_recordings(root, &mut found) {
+        tracing::warn!("Retention: unable to list {}: {}", root.display(), e);
+    }
+    let mut candidates: Vec<_> = found.into_iter()
+        .filter(|(path, _)| !segments::is_segment(path))
+        .map(|(path, metadata)| {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            (path, modified, metadata.len())
+        })
+        .collect();
+    candidates.sort_by_key(|&(_, modified, _)| std::cmp::Reverse(modified));
+    candidates
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Delete one recording unless a save is still writing it, recording the outcome
+fn delete_unless_active(state: &AudioState, root: &Path, path: &Path, size: u64, reason: &str, run: &mut RetentionRun) {
+    // Hold the active-save set so a save of the same name can't start meanwhile
+    let active_saves = state.active_saves.lock();
+    if active_saves.contains(&recordings::relative_name(root, path)) {
+        return;
+    }
+    // The --append-to session file is still growing
+    if state.append_to.as_deref().is_some_and(|target| same_file(target, path)) {
+        return;
+    }
+    match delete_recording(path, size) {
+        Ok(freed) => {
+            tracing::info!("Retention: deleted {} ({} bytes, {})", path.display(), freed, reason);
+            state.output_usage.remove(freed);
+            run.files_deleted += 1;
+            run.bytes_freed += freed;
+        }
+        Err(e) => tracing::warn!("Retention: failed to delete {}: {}", path.display(), e),
+    }
+}
+
+/// Delete the oldest recordings beyond the policy's limits. Files in `keep`
+/// (the save that just finished), saves still being written and archive
+/// segments are never touched.
+pub fn enforce(state: &AudioState, policy: RetentionPolicy, keep: &[PathBuf]) {
+    let root = PathBuf::from(&state.settings.read().output_dir);
+    let now = SystemTime::now();
+    let mut run = RetentionRun::new();
+    for (index, (path, modified, size)) in candidates(&root).iter().enumerate() {
+        let over_count = policy.max_count.is_some_and(|max| index >= max);
+        let too_old = policy.max_age
+            .is_some_and(|max| now.duration_since(*modified).is_ok_and(|age| age > max));
+        if !(over_count || too_old) || keep.contains(path) {
+            continue;
+        }
+        let reason = if over_count { "over the count limit" } else { "past the age limit" };
+        delete_unless_active(state, &root, path, *size, reason, &mut run);
+    }
+    if run.files_deleted > 0 {
+        tracing::info!("Retention: deleted {} recordings, {} bytes reclaimed", run.files_deleted, run.bytes_freed);
+    }
+    *state.last_retention.lock() = Some(run);
+}
+
+/// Before a save of about `needed` bytes, delete the oldest recordings until
+/// the output directory fits in `budget`. Fails with StorageFull when the
+/// save can't fit even after every deletable recording is gone.
+pub fn make_room(state: &AudioState, budget: u64, needed: u64) -> std::io::Result<()> {
+    let storage_full = |message: String| std::io::Error::new(std::io::ErrorKind::StorageFull, message);
+    if needed > budget {
+        return Err(storage_full(format!(
+            "recording of about {} bytes is larger than the {} byte output budget", needed, budget
+        )));
+    }
+    let root = PathBuf::from(&state.settings.read().output_dir);
+    if state.output_usage.current(&root) + needed <= budget {
+        return Ok(());
+    }
+
+    let mut run = RetentionRun::new();
+    for (path, _, size) in candidates(&root).iter().rev() {
+        delete_unless_active(state, &root, path, *size, "over the output budget", &mut run);
+        if state.output_usage.current(&root) + needed <= budget {
+            break;
+        }
+    }
+    if run.files_deleted > 0 {
+        tracing::info!(
+            "Retention: deleted {} recordings, {} bytes reclaimed to fit the output budget",
+            run.files_deleted, run.bytes_freed
+        );
+        *state.last_retention.lock() = Some(run);
+    }
+    let usage = state.output_usage.current(&root);
+    if usage + needed > budget {
+        return Err(storage_full(format!(
+            "output directory holds {} bytes that can't be freed; a {} byte recording would exceed the {} byte budget",
+            usage, needed, budget
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_accept_binary_suffixes() {
+        assert_eq!("2G".parse::<ByteSize>(), Ok(ByteSize(2 << 30)));
+        assert_eq!("500mb".parse::<ByteSize>(), Ok(ByteSize(500 << 20)));
+        assert_eq!("64KiB".parse::<ByteSize>(), Ok(ByteSize(64 << 10)));
+        assert_eq!("4096".parse::<ByteSize>(), Ok(ByteSize(4096)));
+        assert!("2X".parse::<ByteSize>().is_err());
+        assert!("G".parse::<ByteSize>().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use ringbuf::HeapRb;
+use ringbuf::traits::{Consumer, Observer, Producer, RingBuffer};
+
+use crate::encoding::to_i16;
+
+/// How buffered samples are stored, from --buffer-sample-type. i16 halves the
+/// memory for a long buffer at the cost of rounding to 16 bits on capture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SampleType {
+    /// 32-bit float, as captured
+    #[default]
+    F32,
+    /// 16-bit integer
+    I16,
+}
+
+impl std::str::FromStr for SampleType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(SampleType::F32),
+            "i16" => Ok(SampleType::I16),
+            other => Err(format!("unknown buffer sample type `{}`, expected `f32` or `i16`", other)),
+        }
+    }
+}
+
+impl SampleType {
+    /// Name as given to --buffer-sample-type
+    pub fn name(&self) -> &'static str {
+        match self {
+            SampleType::F32 => "f32",
+            SampleType::I16 => "i16",
+        }
+    }
+
+    /// Memory one buffered sample takes
+    pub fn size(&self) -> usize {
+        match self {
+            SampleType::F32 => std::mem::size_of::<f32>(),
+            SampleType::I16 => std::mem::size_of::<i16>(),
+        }
+    }
+}
+
+/// A sample as held in the buffer; everything outside it works in f32
+pub trait Stored: Copy + Sized {
+    /// Convert a captured sample for storage
+    fn from_f32(sample: f32) -> Self;
+    /// Convert a stored sample back for the rest of the pipeline
+    fn to_f32(self) -> f32;
+
+    /// Bulk conversions, which f32 replaces with plain copies
+    fn push_overwrite_all(ring: &mut HeapRb<Self>, samples: &[f32]) {
+        ring.push_iter_overwrite(samples.iter().map(|&s| Self::from_f32(s)));
+    }
+
+    /// Append `stored` to `out` as f32
+    fn extend_f32(out: &mut Vec<f32>, stored: &[Self]) {
+        out.extend(stored.iter().map(|&s| s.to_f32()));
+    }
+}
+
+impl Stored for f32 {
+    fn from_f32(sample: f32) -> Self {
+        sample
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn push_overwrite_all(ring: &mut HeapRb<Self>, samples: &[f32]) {
+        ring.push_slice_overwrite(samples);
+    }
+
+    fn extend_f32(out: &mut Vec<f32>, stored: &[Self]) {
+        out.extend_from_slice(stored);
+    }
+}
+
+impl Stored for i16 {
+    fn from_f32(sample: f32) -> Self {
+        to_i16(sample)
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32 / i16::MAX as f32
+    }
+}
+
+/// Interleaved by default, or one ring per channel with --split-channels
+#[allow(clippy::large_enum_variant)]
+pub enum Rings<T> {
+    /// All channels in one ring, frame by frame
+    Interleaved(HeapRb<T>),
+    /// One ring per channel, in channel order
+    Split(Vec<HeapRb<T>>),
+}
+
+/// The capture ring buffer. Lengths and offsets are always in interleaved
+/// samples, so positions mean the same thing in every layout.
+/// There is only ever one, so the size difference between layouts doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum SampleBuffer {
+    /// Samples kept as f32
+    F32(Rings<f32>),
+    /// Samples kept as i16
+    I16(Rings<i16>),
+}
+
+// Run the same generic code on whichever sample type the buffer holds
+macro_rules! with_rings {
+    ($buffer:expr, $rings:ident => $body:expr) => {
+        match $buffer {
+            SampleBuffer::F32($rings) => $body,
+            SampleBuffer::I16($rings) => $body,
+        }
+    };
+}
+
+impl<T: Stored> Rings<T> {
+    fn new(capacity: usize, channels: u16, split: bool) -> Self {
+        let channels = channels.max(1) as usize;
+        if split {
+            let per_channel = (capacity / channels).max(1);
+            Rings::Split((0..channels).map(|_| HeapRb::new(per_channel)).collect())
+        } else {
+            Rings::Interleaved(HeapRb::new(capacity))
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Rings::Interleaved(buffer) => buffer.capacity().get(),
+            Rings::Split(buffers) => buffers.iter().map(|b| b.capacity().get()).sum(),
+        }
+    }
+
+    fn occupied_len(&self) -> usize {
+        match self {
+            Rings::Interleaved(buffer) => buffer.occupied_len(),
+            Rings::Split(buffers) => buffers.iter().map(|b| b.occupied_len()).sum(),
+        }
+    }
+
+    fn clear(&mut self) -> usize {
+        match self {
+            Rings::Interleaved(buffer) => buffer.clear(),
+            Rings::Split(buffers) => buffers.iter_mut().map(|b| b.clear()).sum(),
+        }
+    }
+
+    fn push_slice_overwrite(&mut self, samples: &[f32]) {
+        match self {
+            Rings::Interleaved(buffer) => T::push_overwrite_all(buffer, samples),
+            Rings::Split(buffers) => {
+                for frame in samples.chunks_exact(buffers.len()) {
+                    for (buffer, &sample) in buffers.iter_mut().zip(frame) {
+                        buffer.push_overwrite(T::from_f32(sample));
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_slice(&mut self, samples: &[f32]) -> usize {
+        match self {
+            Rings::Interleaved(buffer) => buffer.push_iter(samples.iter().map(|&s| T::from_f32(s))),
+            Rings::Split(buffers) => {
+                let channels = buffers.len();
+                let room = buffers.iter().map(|b| b.vacant_len()).min().unwrap_or(0);
+                let frames = (samples.len() / channels).min(room);
+                for frame in samples.chunks_exact(channels).take(frames) {
+                    for (buffer, &sample) in buffers.iter_mut().zip(frame) {
+                        let _ = buffer.try_push(T::from_f32(sample));
+                    }
+                }
+                frames * channels
+            }
+        }
+    }
+
+    fn copy_range(&self, out: &mut Vec<f32>, skip: usize, take: usize) {
+        match self {
+            Rings::Interleaved(buffer) => {
+                let (head, tail) = buffer.as_slices();
+                let end = skip + take;
+                if skip < head.len() {
+                    T::extend_f32(out, &head[skip..end.min(head.len())]);
+                }
+                if end > head.len() {
+                    let start = skip.saturating_sub(head.len());
+                    T::extend_f32(out, &tail[start..end - head.len()]);
+                }
+            }
+            Rings::Split(buffers) => {
+                let channels = buffers.len();
+                let base = out.len();
+                out.resize(base + take / channels * channels, 0.0);
+                for (channel, buffer) in buffers.iter().enumerate() {
+                    let samples = buffer.iter().skip(skip / channels).take(take / channels);
+                    for (frame, &sample) in samples.enumerate() {
+                        out[base + frame * channels + channel] = sample.to_f32();
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn latest(&self) -> Option<f32> {
+        match self {
+            Rings::Interleaved(buffer) => buffer.iter().last().map(|&s| s.to_f32()),
+            Rings::Split(buffers) => buffers.last()?.iter().last().map(|&s| s.to_f32()),
+        }
+    }
+}
+
+impl SampleBuffer {
+    /// Room for `capacity` interleaved samples, split evenly across channels when `split`
+    pub fn new(capacity: usize, channels: u16, split: bool, sample_type: SampleType) -> Self {
+        match sample_type {
+            SampleType::F32 => SampleBuffer::F32(Rings::new(capacity, channels, split)),
+            SampleType::I16 => SampleBuffer::I16(Rings::new(capacity, channels, split)),
+        }
+    }
+
+    /// Interleaved samples the buffer holds when full
+    pub fn capacity(&self) -> usize {
+        with_rings!(self, rings => rings.capacity())
+    }
+
+    /// Interleaved samples it holds now
+    pub fn occupied_len(&self) -> usize {
+        with_rings!(self, rings => rings.occupied_len())
+    }
+
+    /// Discard everything, returning how many samples were dropped
+    pub fn clear(&mut self) -> usize {
+        with_rings!(self, rings => rings.clear())
+    }
+
+    /// Append interleaved samples, overwriting the oldest once full
+    pub fn push_slice_overwrite(&mut self, samples: &[f32]) {
+        with_rings!(self, rings => rings.push_slice_overwrite(samples))
+    }
+
+    /// Append as many interleaved samples as fit, returning how many were taken.
+    /// Split buffers only take whole frames.
+    pub fn push_slice(&mut self, samples: &[f32]) -> usize {
+        with_rings!(self, rings => rings.push_slice(samples))
+    }
+
+    /// Append `take` interleaved samples starting `skip` samples in. For split
+    /// buffers both must be whole frames, as SaveWindow::sample_range produces.
+    pub fn copy_range(&self, out: &mut Vec<f32>, skip: usize, take: usize) {
+        with_rings!(self, rings => rings.copy_range(out, skip, take))
+    }
+
+    /// Copy the contents of `other` into this buffer, keeping the newest samples when it is smaller
+    pub fn keep_newest(&mut self, other: &SampleBuffer) {
+        let mut samples = Vec::with_capacity(other.occupied_len());
+        other.copy_range(&mut samples, 0, other.occupied_len());
+        self.push_slice_overwrite(&samples);
+    }
+
+    /// Most recent sample, of the last channel when split
+    #[cfg(test)]
+    pub fn latest(&self) -> Option<f32> {
+        with_rings!(self, rings => rings.latest())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_buffers_read_back_interleaved() {
+        let mut interleaved = SampleBuffer::new(8, 2, false, SampleType::F32);
+        let mut split = SampleBuffer::new(8, 2, true, SampleType::F32);
+        let samples: Vec<f32> = (0..12).map(|i| i as f32).collect();
+        interleaved.push_slice_overwrite(&samples);
+        split.push_slice_overwrite(&samples);
+        assert_eq!(split.occupied_len(), 8);
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        interleaved.copy_range(&mut a, 2, 4);
+        split.copy_range(&mut b, 2, 4);
+        assert_eq!(a, b);
+        assert_eq!(b, vec![6.0, 7.0, 8.0, 9.0]);
+
+        let mut full = SampleBuffer::new(4, 2, true, SampleType::F32);
+        assert_eq!(full.push_slice(&samples[..7]), 4);
+        assert_eq!(full.latest(), Some(3.0));
+    }
+
+    #[test]
+    fn i16_buffers_round_to_16_bits() {
+        let mut buffer = SampleBuffer::new(4, 1, false, SampleType::I16);
+        let samples = [0.5, -0.25, 1.5, 0.1234567];
+        buffer.push_slice_overwrite(&samples);
+        let mut out = Vec::new();
+        buffer.copy_range(&mut out, 0, 4);
+        // Out-of-range samples clip; the rest are within one 16-bit step
+        for (read, expected) in out.iter().zip([0.5, -0.25, 1.0, 0.1234567]) {
+            assert!((read - expected).abs() <= 1.0 / i16::MAX as f32, "{} vs {}", read, expected);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::time::Duration;
+
+use crate::AudioState;
+use crate::encoding::{write_samples, WavEncoding};
+
+// Capture callbacks queued for the archiver before new ones are dropped
+const ARCHIVE_QUEUE_FRAMES: usize = 256;
+// How often the open segment's header is rewritten, so a crash leaves a playable file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const SEGMENT_PREFIX: &str = "segment_";
+
+/// Continuous archival of captured audio into rotating WAV files
+pub struct SegmentOptions {
+    /// Length of each segment file
+    pub seconds: u32,
+    /// Number of segment files to keep, 0 for no limit
+    pub keep: usize,
+    /// Sample encoding of the segment files
+    pub encoding: WavEncoding,
+}
+
+type SegmentWriter = hound::WavWriter<BufWriter<File>>;
+
+struct Archiver {
+    state: Arc<AudioState>,
+    options: SegmentOptions,
+    spec: hound::WavSpec,
+    samples_per_segment: u64,
+    current: Option<(SegmentWriter, PathBuf, u64)>,
+}
+
+impl Archiver {
+    fn open_segment(&mut self) -> std::io::Result<()> {
+        let dir = PathBuf::from(&self.state.settings.read().output_dir);
+        let name = format!("{}{}.wav", SEGMENT_PREFIX, chrono::Local::now().format("%Y%m%d_%H%M%S_%3f"));
+        let path = dir.join(name);
+        std::fs::create_dir_all(&dir)?;
+        let writer = hound::WavWriter::create(&path, self.spec).map_err(std::io::Error::other)?;
+        tracing::info!("Archiving to {}", path.display());
+        self.current = Some((writer, path, 0));
+        prune_segments(&dir, self.options.keep);
+        Ok(())
+    }
+
+    fn close_segment(&mut self) {
+        if let Some((writer, path, _)) = self.current.take() {
+            if let Err(e) = writer.finalize() {
+                tracing::error!("Failed to finalize segment {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn write(&mut self, mut samples: &[f32]) -> std::io::Result<()> {
+        while !samples.is_empty() {
+            if self.current.is_none() {
+                self.open_segment()?;
+            }
+            let (writer, _, written) = self.current.as_mut().expect("segment just opened");
+            // Split at the boundary, keeping whole frames in each file
+            let room = (self.samples_per_segment - *written) as usize;
+            let (now, rest) = samples.split_at(room.min(samples.len()));
+            write_samples(writer, now, self.options.encoding).map_err(std::io::Error::other)?;
+            *written += now.len() as u64;
+            if *written >= self.samples_per_segment {
+                self.close_segment();
+            }
+            samples = rest;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some((writer, path, _)) = self.current.as_mut() {
+            if let Err(e) = writer.flush() {
+                tracing::warn!("Failed to flush segment {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn run(mut self, frames: Receiver<Vec<f32>>) {
+        let mut last_flush = std::time::Instant::now();
+        while !self.state.is_halting.load(Ordering::Relaxed) {
+            match frames.recv_timeout(FLUSH_INTERVAL) {
+                Ok(frame) => {
+                    if let Err(e) = self.write(&frame) {
+                        tracing::error!("Failed to archive audio: {}", e);
+                        // Start a fresh file rather than keep writing to a broken one
+                        self.close_segment();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+                last_flush = std::time::Instant::now();
+            }
+        }
+        self.close_segment();
+        tracing::info!("Segment archiver stopped");
+    }
+}
+
+/// Whether `path` is an archive segment, which --segment-keep manages on its own
+pub fn is_segment(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with(SEGMENT_PREFIX) && name.ends_with(".wav")
+}
+
+// Delete the oldest segments so at most `keep` remain; names sort by time
+fn prune_segments(dir: &Path, keep: usize) {
+    if keep == 0 {
+        return;
+    }
+    let mut segments: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_segment(path))
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Unable to list segments in {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    segments.sort();
+    for path in &segments[..segments.len().saturating_sub(keep)] {
+        match std::fs::remove_file(path) {
+            Ok(()) => tracing::info!("Pruned old segment {}", path.display()),
+            Err(e) => tracing::warn!("Failed to prune segment {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Start the archiver thread and return the queue the capture callback feeds
+pub fn spawn_archiver(
+    state: Arc<AudioState>,
+    options: SegmentOptions,
+) -> (SyncSender<Vec<f32>>, std::thread::JoinHandle<()>) {
+    let (sender, frames) = std::sync::mpsc::sync_channel(ARCHIVE_QUEUE_FRAMES);
+    let (channels, rate) = (state.input_config().channels(), state.input_config().sample_rate().0);
+    let archiver = Archiver {
+        spec: options.encoding.spec(channels, rate),
+        samples_per_segment: options.seconds as u64 * rate as u64 * channels as u64,
+        state,
+        options,
+        current: None,
+    };
+    tracing::info!(
+        "Archiving continuously in {}s segments{}",
+        archiver.options.seconds,
+        match archiver.options.keep {
+            0 => String::new(),
+            keep => format!(", keeping the newest {}", keep),
+        }
+    );
+    let handle = std::thread::spawn(move || archiver.run(frames));
+    (sender, handle)
+}
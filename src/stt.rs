@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::api::ErrorResponse;
+use crate::capture_audio::{self, Detection, SaveWindow};
+use crate::encoding::{OutputFormat, OutputOptions, SampleKind, WavEncoding};
+use crate::{events, http_client, AudioState};
+
+// Detections queued for transcription; the callback drops any beyond this
+const DETECTION_QUEUE: usize = 8;
+
+/// Where audio is sent to be transcribed, from --stt-url
+pub struct SttConfig {
+    url: String,
+    endpoint: http_client::Endpoint,
+    // Audio sent after each detection, and by /transcribe unless told otherwise
+    window: Duration,
+}
+
+impl SttConfig {
+    /// Send audio to `url`, `window` of it after each detection
+    pub fn new(url: &str, window: Duration) -> Result<Self, String> {
+        Ok(SttConfig { url: url.to_string(), endpoint: http_client::Endpoint::parse(url, None)?, window })
+    }
+}
+
+/// What the STT service made of a window of audio
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Transcription {
+    text: String,
+    // The wakeword that triggered it, for transcriptions after a detection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyword: Option<String>,
+    duration_seconds: f64,
+    // Wall-clock time of the first sample sent
+    started_at: chrono::DateTime<chrono::Local>,
+}
+
+// The text of an STT response: the `text` field of a JSON body, as
+// Whisper-style servers return it, else the whole body
+fn response_text(body: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(json)) if json.get("text").is_some_and(|text| text.is_string()) => {
+            json["text"].as_str().unwrap_or_default().trim().to_string()
+        }
+        _ => String::from_utf8_lossy(body).trim().to_string(),
+    }
+}
+
+/// Send `window` of the buffer to the STT service as a 16-bit WAV at the
+/// capture's rate and channels. None when the window holds no audio.
+pub async fn transcribe(
+    state: &AudioState,
+    config: &SttConfig,
+    window: SaveWindow,
+    keyword: Option<&str>,
+) -> Result<Option<Transcription>, String> {
+    let input = state.input_config();
+    let snapshot = capture_audio::snapshot_buffer(state, &input, window);
+    if snapshot.samples.is_empty() {
+        return Ok(None);
+    }
+    let snapshot = snapshot.with_silence(input.channels(), state.buffer.lock().capacity());
+    let output = OutputOptions {
+        format: OutputFormat::Wav,
+        wav: WavEncoding::new(SampleKind::Int, 16)?,
+        sample_rate: None,
+        ..state.output
+    };
+    let (wav, saved) = capture_audio::encode_recording(&snapshot.samples, &input, output)
+        .map_err(|e| format!("failed to encode audio: {}", e))?;
+
+    let headers = [("Content-Type", "audio/wav")];
+    let (status, body) = config.endpoint.send_bytes("POST", &config.endpoint.target(), &headers, &wav).await
+        .map_err(|e| format!("{}: {}", config.url, e))?;
+    if !(200..300).contains(&status) {
+        let body = String::from_utf8_lossy(&body);
+        let detail = body.trim().chars().take(200).collect::<String>();
+        return Err(format!("{} responded {} {}", config.url, status, detail).trim_end().to_string());
+    }
+    Ok(Some(Transcription {
+        text: response_text(&body),
+        keyword: keyword.map(str::to_string),
+        duration_seconds: saved.duration_seconds,
+        started_at: snapshot.started_at,
+    }))
+}
+
+/// Start the task transcribing what is said after each detection and return
+/// the queue the capture callback feeds
+pub fn spawn(state: Arc<AudioState>) -> (mpsc::Sender<Detection>, tokio::task::JoinHandle<()>) {
+    let (sender, mut detections) = mpsc::channel::<Detection>(DETECTION_QUEUE);
+    let handle = tokio::spawn(async move {
+        while let Some(detection) = detections.recv().await {
+            let Some(config) = &state.stt else {
+                break;
+            };
+            // Give the command following the wakeword time to be spoken
+            tokio::time::sleep(config.window).await;
+            let input = state.input_config();
+            let samples = (config.window.as_secs_f64() * input.sample_rate().0 as f64).round() as u64
+                * input.channels().max(1) as u64;
+            let window = SaveWindow {
+                since: Some(detection.at),
+                until: Some(detection.at + samples),
+                ..SaveWindow::default()
+            };
+            match transcribe(&state, config, window, Some(detection.keyword)).await {
+                Ok(Some(transcription)) => {
+                    tracing::info!("Transcribed after {}: {}", detection.keyword, transcription.text);
+                    state.publish(events::Event::Transcribed {
+                        keyword: transcription.keyword,
+                        text: transcription.text,
+                        captured_sample: detection.captured,
+                    });
+                }
+                Ok(None) => tracing::warn!("Audio after the {} detection left the buffer before it was transcribed", detection.keyword),
+                Err(e) => tracing::error!("Transcription after {} failed: {}", detection.keyword, e),
+            }
+        }
+    });
+    (sender, handle)
+}
+
+/// Hand a detection to the transcriber, if --stt-url is set
+pub fn queue_detection(state: &AudioState, detection: Detection) {
+    let Some(queue) = state.stt_queue.get() else {
+        return;
+    };
+    if queue.try_send(detection).is_err() {
+        tracing::warn!("Transcription queue is full; skipping the {} detection", detection.keyword);
+    }
+}
+
+/// Query of POST /transcribe
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TranscribeQuery {
+    /// Transcribe the most recent N seconds (default: --stt-seconds)
+    seconds: Option<f64>,
+}
+
+/// Send the most recent audio to the --stt-url service and return its transcription
+#[utoipa::path(
+    post,
+    path = "/transcribe",
+    params(TranscribeQuery),
+    responses(
+        (status = 200, body = Transcription),
+        (status = 400, description = "Invalid duration", body = ErrorResponse),
+        (status = 404, description = "The buffer holds no audio", body = ErrorResponse),
+        (status = 409, description = "No --stt-url is configured", body = ErrorResponse),
+        (status = 502, description = "The STT service failed", body = ErrorResponse),
+    ),
+)]
+pub async fn transcribe_audio(state: web::Data<Arc<AudioState>>, query: web::Query<TranscribeQuery>) -> HttpResponse {
+    let Some(config) = &state.stt else {
+        return HttpResponse::Conflict().json(ErrorResponse::new("Transcription needs --stt-url"));
+    };
+    let seconds = query.seconds.unwrap_or(config.window.as_secs_f64());
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("seconds must be a positive number"));
+    }
+    match transcribe(&state, config, SaveWindow::last_seconds(seconds), None).await {
+        Ok(Some(transcription)) => {
+            tracing::info!("Transcribed {:.1}s: {}", transcription.duration_seconds, transcription.text);
+            HttpResponse::Ok().json(transcription)
+        }
+        Ok(None) => HttpResponse::NotFound().json(ErrorResponse::new("No audio to transcribe")),
+        Err(e) => {
+            tracing::error!("Transcription failed: {}", e);
+            HttpResponse::BadGateway().json(ErrorResponse::new(format!("Transcription failed: {}", e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::response_text;
+
+    #[test]
+    fn text_comes_from_json_or_the_plain_body() {
+        assert_eq!(response_text(br#"{"text": " turn on the lights ", "language": "en"}"#), "turn on the lights");
+        assert_eq!(response_text(b"turn off the lights\n"), "turn off the lights");
+        assert_eq!(response_text(br#"["not", "an object"]"#), r#"["not", "an object"]"#);
+    }
+}
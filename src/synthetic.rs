@@ -0,0 +1,190 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capture_audio::{AudioSource, BlockCallback, CaptureError, ErrorCallback, SourceStream};
+use crate::encoding;
+
+// Format of the generated signals, Porcupine's own so detection runs unresampled
+const SIGNAL_SAMPLE_RATE: u32 = 16_000;
+const SINE_AMPLITUDE: f32 = 0.5;
+const NOISE_AMPLITUDE: f32 = 0.25;
+
+/// What --source synthetic plays, from --synthetic-signal
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// A tone of this many Hz, at half of full scale
+    Sine(f32),
+    /// Uniform white noise at a quarter of full scale
+    Noise,
+    /// A WAV file looped at its own rate and channel count
+    File(PathBuf),
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Signal::Sine(440.0)
+    }
+}
+
+impl std::str::FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "sine" => Ok(Signal::default()),
+            None if s == "noise" => Ok(Signal::Noise),
+            Some(("sine", hz)) => match hz.parse::<f32>() {
+                Ok(hz) if hz.is_finite() && hz > 0.0 && hz < SIGNAL_SAMPLE_RATE as f32 / 2.0 => Ok(Signal::Sine(hz)),
+                _ => Err(format!("sine frequency must be between 0 and {} Hz, got `{}`", SIGNAL_SAMPLE_RATE / 2, hz)),
+            },
+            Some(("file", path)) if !path.is_empty() => Ok(Signal::File(PathBuf::from(path))),
+            _ => Err(format!("unknown signal `{}`, expected sine, sine:<hz>, noise or file:<path>", s)),
+        }
+    }
+}
+
+/// Generated audio in place of a microphone, paced in real time, so the
+/// whole pipeline can run without audio hardware
+pub struct SyntheticSource {
+    signal: Signal,
+    sample_rate: u32,
+    channels: u16,
+    // The decoded file, interleaved
+    samples: Arc<Vec<f32>>,
+}
+
+impl SyntheticSource {
+    /// A source generating `signal`, mono at 16 kHz unless it is a file
+    pub fn new(signal: Signal) -> Self {
+        SyntheticSource { signal, sample_rate: SIGNAL_SAMPLE_RATE, channels: 1, samples: Arc::new(Vec::new()) }
+    }
+}
+
+// `frames` frames of the signal starting at frame `start`. Noise comes from
+// `seed`, which advances so consecutive blocks continue the sequence.
+fn generate(signal: &Signal, samples: &[f32], sample_rate: u32, channels: u16, start: u64, frames: usize, seed: &mut u64) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    match signal {
+        Signal::Sine(hz) => (0..frames as u64)
+            .flat_map(|frame| {
+                // Phase from the frame index modulo the period keeps long runs precise
+                let period = sample_rate as f64 / *hz as f64;
+                let phase = ((start + frame) as f64 % period) / period;
+                let value = SINE_AMPLITUDE * (std::f64::consts::TAU * phase).sin() as f32;
+                std::iter::repeat_n(value, channels)
+            })
+            .collect(),
+        Signal::Noise => (0..frames * channels)
+            .map(|_| {
+                // xorshift64, plenty for test noise
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                NOISE_AMPLITUDE * ((*seed >> 40) as f32 / (1u64 << 23) as f32 - 1.0)
+            })
+            .collect(),
+        Signal::File(_) if samples.is_empty() => vec![0.0; frames * channels],
+        Signal::File(_) => {
+            let offset = (start as usize * channels) % samples.len();
+            samples.iter().cycle().skip(offset).take(frames * channels).copied().collect()
+        }
+    }
+}
+
+// Stops the generator thread when the stream is dropped, waiting for it so
+// no block arrives afterwards
+struct Running {
+    stopped: Arc<AtomicBool>,
+    generator: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(generator) = self.generator.take() {
+            let _ = generator.join();
+        }
+    }
+}
+
+impl AudioSource for SyntheticSource {
+    fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String> {
+        if let Signal::File(path) = &self.signal {
+            let bytes = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            let (spec, samples) = encoding::read_wav(&bytes).map_err(|e| format!("cannot decode {}: {}", path.display(), e))?;
+            if samples.is_empty() {
+                return Err(format!("{} holds no audio", path.display()));
+            }
+            (self.sample_rate, self.channels, self.samples) = (spec.sample_rate, spec.channels, Arc::new(samples));
+        }
+        Ok(cpal::SupportedStreamConfig::new(
+            self.channels,
+            cpal::SampleRate(self.sample_rate),
+            cpal::SupportedBufferSize::Unknown,
+            cpal::SampleFormat::F32,
+        ))
+    }
+
+    fn name(&self) -> String {
+        match &self.signal {
+            Signal::Sine(hz) => format!("synthetic sine {} Hz", hz),
+            Signal::Noise => "synthetic noise".to_string(),
+            Signal::File(path) => format!("synthetic {}", path.display()),
+        }
+    }
+
+    fn start(
+        &mut self,
+        config: &cpal::StreamConfig,
+        mut on_block: BlockCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<SourceStream, CaptureError> {
+        let frames = match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames.max(1) as usize,
+            cpal::BufferSize::Default => (self.sample_rate / 10) as usize,
+        };
+        let block = Duration::from_secs_f64(frames as f64 / self.sample_rate as f64);
+        let (signal, samples, sample_rate, channels) = (self.signal.clone(), Arc::clone(&self.samples), self.sample_rate, self.channels);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let generator = std::thread::Builder::new().name("synthetic source".to_string()).spawn(move || {
+            let (started, mut seed, mut position) = (Instant::now(), 0x2545_f491_4f6c_dd1d_u64, 0u64);
+            while !stop.load(Ordering::Relaxed) {
+                // Blocks are due on a fixed schedule, so sleeping late never drifts the rate
+                let due = started + block.mul_f64(position as f64 / frames as f64);
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                on_block(&generate(&signal, &samples, sample_rate, channels, position, frames, &mut seed));
+                position += frames as u64;
+            }
+        })?;
+        Ok(SourceStream::new(Running { stopped, generator: Some(generator) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, Signal};
+
+    #[test]
+    fn signals_parse_and_generate() {
+        assert_eq!("sine".parse(), Ok(Signal::Sine(440.0)));
+        assert_eq!("sine:1000".parse(), Ok(Signal::Sine(1000.0)));
+        assert_eq!("file:clip.wav".parse(), Ok(Signal::File("clip.wav".into())));
+        assert!("sine:9000".parse::<Signal>().is_err() && "square".parse::<Signal>().is_err());
+
+        // A 1 kHz tone at 16 kHz repeats every 16 frames, on both channels
+        let mut seed = 1;
+        let tone = generate(&Signal::Sine(1000.0), &[], 16_000, 2, 4, 32, &mut seed);
+        assert_eq!(tone.len(), 64);
+        assert!((tone[0] - 0.5).abs() < 1e-6 && tone[0] == tone[1]);
+        assert!((tone[0] - tone[32]).abs() < 1e-6);
+
+        let noise = generate(&Signal::Noise, &[], 16_000, 1, 0, 1000, &mut seed);
+        assert!(noise.iter().all(|s| s.abs() <= 0.25) && noise.iter().any(|&s| s != noise[0]));
+
+        let file = generate(&Signal::File("x".into()), &[0.1, 0.2, 0.3], 16_000, 1, 2, 4, &mut seed);
+        assert_eq!(file, [0.3, 0.1, 0.2, 0.3]);
+    }
+}
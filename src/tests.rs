@@ -0,0 +1,1796 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use actix_web::{test, web, App};
+
+use crate::config::Settings;
+use crate::encoding::{OutputFormat, OutputOptions, RawEncoding, SampleKind, WavEncoding};
+use crate::{configure_app, AudioState, BufferMode};
+
+const SAMPLE_RATE: u32 = 16_000;
+
+fn test_state(output_dir: &Path) -> Arc<AudioState> {
+    let input_config = cpal::SupportedStreamConfig::new(
+        1,
+        cpal::SampleRate(SAMPLE_RATE),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    );
+    Arc::new(AudioState::new(
+        input_config,
+        "test device".to_string(),
+        SAMPLE_RATE as usize,
+        BufferMode::Overwrite,
+        OutputOptions {
+            format: OutputFormat::Wav,
+            wav: WavEncoding::new(SampleKind::Int, 16).unwrap(),
+            mp3_bitrate_kbps: 128,
+            opus_bitrate_kbps: 24,
+            raw: RawEncoding::S16Le,
+            sample_rate: None,
+            encryption: None,
+        },
+        Settings {
+            output_dir: output_dir.display().to_string(),
+            gain: 1.0,
+            wakeword_cooldown_ms: 0,
+            health_timeout_secs: 5,
+            buffer_seconds: 1,
+        },
+        1,
+    ))
+}
+
+macro_rules! test_app {
+    ($state:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(&$state)))
+                .configure(configure_app),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn start_stop_and_toggle_flip_recording() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.5; 100]);
+    let post = |path: &str| test::TestRequest::post().uri(path).to_request();
+
+    assert!(test::call_service(&app, post("/stop")).await.status().is_success());
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+    assert!(state.is_stopped.load(Ordering::Relaxed));
+    assert_eq!(state.buffer.lock().occupied_len(), 0);
+
+    assert!(test::call_service(&app, post("/start")).await.status().is_success());
+    assert!(state.is_recording.load(Ordering::Relaxed));
+    assert!(!state.is_stopped.load(Ordering::Relaxed));
+
+    assert!(test::call_service(&app, post("/toggle")).await.status().is_success());
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+    assert!(!state.is_stopped.load(Ordering::Relaxed));
+
+    assert!(test::call_service(&app, post("/toggle")).await.status().is_success());
+    assert!(state.is_recording.load(Ordering::Relaxed));
+}
+
+#[actix_web::test]
+async fn start_body_switches_output_dir_and_buffer_length() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    let samples: Vec<f32> = (0..SAMPLE_RATE).map(|i| i as f32).collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.pause();
+
+    let session_dir = dir.path().join("session");
+    let request = test::TestRequest::post()
+        .uri("/start")
+        .set_json(serde_json::json!({ "output_dir": session_dir, "seconds": 3 }))
+        .to_request();
+    assert!(test::call_service(&app, request).await.status().is_success());
+    assert!(state.is_recording.load(Ordering::Relaxed));
+    assert!(session_dir.is_dir());
+    assert_eq!(state.settings.read().output_dir, session_dir.display().to_string());
+    {
+        let buffer = state.buffer.lock();
+        assert_eq!(buffer.capacity(), 3 * SAMPLE_RATE as usize);
+        assert_eq!(buffer.occupied_len(), SAMPLE_RATE as usize);
+    }
+
+    // Shrinking keeps the newest audio
+    let request = test::TestRequest::post().uri("/start").set_json(serde_json::json!({ "seconds": 1 })).to_request();
+    assert!(test::call_service(&app, request).await.status().is_success());
+    assert_eq!(state.buffer.lock().latest(), Some((SAMPLE_RATE - 1) as f32));
+
+    let request = test::TestRequest::post().uri("/start").set_json(serde_json::json!({ "seconds": 0 })).to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    let request = test::TestRequest::post().uri("/start").set_json(serde_json::json!({ "gain": 2.0 })).to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn pause_keeps_the_buffer() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.5; 100]);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/pause").to_request()).await;
+    assert!(response.status().is_success());
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+    assert_eq!(state.buffer.lock().occupied_len(), 100);
+}
+
+#[actix_web::test]
+async fn save_writes_a_readable_wav() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    let samples: Vec<f32> = (0..4000).map(|i| (i as f32 / 4000.0) - 0.5).collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.samples_written.store(samples.len() as u64, Ordering::Relaxed);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["samples"], 4000);
+
+    let reader = hound::WavReader::open(body["path"].as_str().unwrap()).unwrap();
+    assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+    assert_eq!(reader.spec().channels, 1);
+    assert_eq!(reader.spec().bits_per_sample, 16);
+    assert_eq!(reader.len(), 4000);
+}
+
+#[actix_web::test]
+async fn save_reports_detections_relative_to_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.1; 4000]);
+    state.samples_written.store(4000, Ordering::Relaxed);
+    let detection = |at, captured| crate::capture_audio::Detection { at, captured, keyword: "porcupine" };
+    // The first falls before the saved window
+    state.detections.lock().extend([detection(500, 900), detection(1000, 1400)]);
+
+    // The last 0.2 s, i.e. buffer positions 800..4000
+    let request = test::TestRequest::post().uri("/save?seconds=0.2").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let detections = body["detections"].as_array().unwrap();
+    assert_eq!(detections.len(), 1);
+    assert_eq!(detections[0]["keyword"], "porcupine");
+    assert_eq!(detections[0]["sample_offset"], 200);
+    assert_eq!(detections[0]["seconds"], 200.0 / SAMPLE_RATE as f64);
+    assert_eq!(detections[0]["captured_sample"], 1400);
+
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &std::fs::read(Path::new(body["path"].as_str().unwrap()).with_extension("json")).unwrap(),
+    ).unwrap();
+    assert_eq!(sidecar["detections"][0]["sample_offset"], 200);
+}
+
+#[actix_web::test]
+async fn marks_are_saved_at_their_place_in_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.1; 1000]);
+    state.samples_written.store(1000, Ordering::Relaxed);
+    let request = test::TestRequest::post().uri("/mark").set_json(serde_json::json!({ "label": "interesting" })).to_request();
+    let mark: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    assert_eq!(mark["label"], "interesting");
+    assert_eq!(mark["buffered_sample"], 1000);
+    state.buffer.lock().push_slice_overwrite(&[0.1; 600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let request = test::TestRequest::post().uri("/mark").to_request();
+    assert!(test::call_service(&app, request).await.status().is_success());
+
+    let request = test::TestRequest::post().uri("/save").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let markers = body["markers"].as_array().unwrap();
+    assert_eq!(markers.len(), 2);
+    assert_eq!(markers[0]["label"], "interesting");
+    assert_eq!(markers[0]["sample_offset"], 1000);
+    assert_eq!(markers[1]["label"], serde_json::Value::Null);
+    assert_eq!(markers[1]["sample_offset"], 1600);
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &std::fs::read(Path::new(body["path"].as_str().unwrap()).with_extension("json")).unwrap(),
+    ).unwrap();
+    assert_eq!(sidecar["markers"][0]["seconds"], 1000.0 / SAMPLE_RATE as f64);
+
+    // Stopping clears the buffer along with its marks
+    test::call_service(&app, test::TestRequest::post().uri("/stop").to_request()).await;
+    let request = test::TestRequest::post().uri("/mark").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), actix_web::http::StatusCode::CONFLICT);
+    assert!(state.markers.lock().is_empty());
+}
+
+#[actix_web::test]
+async fn saves_of_too_little_audio_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().min_save_seconds = 0.5;
+    let app = test_app!(state);
+    let save = |query: &str| test::TestRequest::post().uri(&format!("/save{}", query)).to_request();
+
+    for query in ["", "?async=true", "?download=true"] {
+        let response = test::call_service(&app, save(query)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNPROCESSABLE_ENTITY, "{}", query);
+    }
+    state.buffer.lock().push_slice_overwrite(&[0.25; 4000]);
+    state.samples_written.store(4000, Ordering::Relaxed);
+    let response = test::call_service(&app, save("")).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!((body["buffered_seconds"].as_f64(), body["minimum_seconds"].as_f64()), (Some(0.25), Some(0.5)));
+    assert_eq!(body["error"], "Only 0.25s is buffered; a save needs at least 0.50s");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    assert_eq!(state.jobs.in_flight(), 0);
+
+    // /record and /halt?save=true hold to the same minimum, and the server keeps running
+    for uri in ["/record?seconds=0.2", "/record?seconds=0.00001", "/halt?save=true"] {
+        let response = test::call_service(&app, test::TestRequest::post().uri(uri).to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNPROCESSABLE_ENTITY, "{}", uri);
+    }
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    assert!(!state.is_halting.load(Ordering::Relaxed));
+
+    assert!(test::call_service(&app, save("?allow_empty=true")).await.status().is_success());
+    state.buffer.lock().push_slice_overwrite(&[0.25; 4000]);
+    state.samples_written.store(8000, Ordering::Relaxed);
+    assert!(test::call_service(&app, save("")).await.status().is_success());
+}
+
+#[actix_web::test]
+async fn concurrent_saves_write_distinct_valid_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = std::rc::Rc::new(test_app!(state));
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+
+    // Spawned on the test's local runtime, so all ten are in flight at once
+    let saves: Vec<_> = (0..10)
+        .map(|_| {
+            let app = std::rc::Rc::clone(&app);
+            actix_web::rt::spawn(async move {
+                let response = test::call_service(&*app, test::TestRequest::post().uri("/save").to_request()).await;
+                assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+                let saved: serde_json::Value = test::read_body_json(response).await;
+                saved["path"].as_str().unwrap().to_string()
+            })
+        })
+        .collect();
+    let mut paths = std::collections::HashSet::new();
+    for save in saves {
+        let path = save.await.unwrap();
+        assert_eq!(hound::WavReader::open(&path).unwrap().len(), 1600, "{}", path);
+        assert!(paths.insert(path));
+    }
+    assert_eq!(paths.len(), 10);
+    let wavs = std::fs::read_dir(dir.path()).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "wav"))
+        .count();
+    assert_eq!(wavs, 10);
+}
+
+#[actix_web::test]
+async fn raw_pcm_is_saved_and_downloaded_without_a_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.5; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let save = |query: &str| test::TestRequest::post().uri(&format!("/save?format=raw{}", query)).to_request();
+
+    let response = test::call_service(&app, save("&download=true")).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    let header = |name: &str| response.headers().get(name).unwrap().to_str().unwrap().to_string();
+    assert_eq!((header("x-raw-encoding"), header("x-sample-rate"), header("x-channels")), ("s16le".into(), "16000".into(), "1".into()));
+    assert!(header("content-disposition").contains(".raw"));
+    let body = test::read_body(response).await;
+    assert_eq!(body.len(), 3200);
+    assert_eq!(body[..2], 16383i16.to_le_bytes());
+
+    let response = test::call_service(&app, save("")).await;
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    let path = saved["path"].as_str().unwrap();
+    assert!(path.ends_with(".raw"), "{}", path);
+    assert_eq!(std::fs::read(path).unwrap(), body);
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    assert_eq!(listing.as_array().unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn dated_recordings_are_listed_served_and_deleted_by_relative_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().organize_by_date = true;
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert!(response.status().is_success());
+
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    let name = listing[0]["filename"].as_str().unwrap().to_string();
+    // Checked against the date in the file's own name rather than a second
+    // clock read, which midnight could fall between
+    let (dated, file) = name.rsplit_once('/').unwrap();
+    let stamp = file.strip_prefix("recording_").unwrap();
+    let date = chrono::NaiveDate::parse_from_str(&stamp[..8], "%Y%m%d").unwrap();
+    assert_eq!(dated, date.format("%Y/%m/%d").to_string(), "{}", name);
+    assert!(dir.path().join(&name).is_file());
+
+    let get = test::TestRequest::get().uri(&format!("/recordings/{}", name)).to_request();
+    assert!(test::call_service(&app, get).await.status().is_success());
+    let escape = test::TestRequest::get().uri("/recordings/../../etc/passwd").to_request();
+    assert!(test::call_service(&app, escape).await.status().is_client_error());
+
+    let delete = test::TestRequest::delete().uri(&format!("/recordings/{}", name)).to_request();
+    assert!(test::call_service(&app, delete).await.status().is_success());
+    assert!(!dir.path().join(&name).exists());
+}
+
+#[actix_web::test]
+async fn save_names_an_output_dir_it_cannot_create() {
+    let dir = tempfile::tempdir().unwrap();
+    // A directory can't be created beneath a regular file
+    let blocker = dir.path().join("not-a-dir");
+    std::fs::write(&blocker, b"").unwrap();
+    let output_dir = blocker.join("captures");
+    let state = test_state(&output_dir);
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 160]);
+    state.samples_written.store(160, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let error = body["error"].as_str().unwrap();
+    assert!(error.contains(&output_dir.display().to_string()), "{}", error);
+}
+
+#[actix_web::test]
+async fn retention_deletes_the_oldest_recordings_after_a_save() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().retention = crate::retention::RetentionPolicy {
+        max_count: Some(2),
+        max_age: Some(std::time::Duration::from_secs(7 * 24 * 60 * 60)),
+    };
+    let app = test_app!(state);
+
+    let hours_ago = |name: &str, hours: u64| {
+        let path = dir.path().join(name);
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(hours * 60 * 60)).unwrap();
+        path
+    };
+    let ancient = hours_ago("ancient.wav", 30 * 24);
+    let sidecar = hours_ago("ancient.json", 30 * 24);
+    let notes = hours_ago("notes.txt", 30 * 24);
+    let recent = hours_ago("recent.wav", 1);
+    let older = hours_ago("older.mp3", 2);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 160]);
+    state.samples_written.store(160, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+
+    // The new save and the newest existing one survive; the age limit also takes ancient.wav
+    assert!(Path::new(body["path"].as_str().unwrap()).is_file());
+    assert!(recent.exists());
+    assert!(!older.exists());
+    assert!(!ancient.exists() && !sidecar.exists());
+    assert!(notes.exists());
+
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!(status["retention"]["files_deleted"], 2);
+}
+
+#[actix_web::test]
+async fn maintenance_compresses_old_recordings_to_flac() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let policy = crate::maintenance::CompressPolicy {
+        after: std::time::Duration::from_secs(24 * 60 * 60),
+        interval: std::time::Duration::from_secs(60 * 60),
+    };
+    Arc::get_mut(&mut state).unwrap().compression = Some(policy);
+    let app = test_app!(state);
+
+    let samples: Vec<f32> = (0..1600).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let wav = std::path::PathBuf::from(body["path"].as_str().unwrap());
+    let original: Vec<i32> = hound::WavReader::open(&wav).unwrap().into_samples().map(Result::unwrap).collect();
+    let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 60 * 60);
+    std::fs::File::options().write(true).open(&wav).unwrap().set_modified(two_days_ago).unwrap();
+    // Too recent, and never a recording
+    let recent = dir.path().join("recent.wav");
+    std::fs::copy(&wav, &recent).unwrap();
+    let temp = dir.path().join("partial.wav.tmp");
+    std::fs::copy(&wav, &temp).unwrap();
+    std::fs::File::options().write(true).open(&temp).unwrap().set_modified(two_days_ago).unwrap();
+
+    // Nothing is touched while a save is writing
+    state.active_saves.lock().insert("other.wav".to_string());
+    crate::maintenance::run(&state, policy);
+    assert!(wav.exists());
+    state.active_saves.lock().clear();
+
+    crate::maintenance::run(&state, policy);
+    let flac_path = wav.with_extension("flac");
+    assert!(!wav.exists());
+    assert!(recent.exists() && temp.exists());
+    let (info, decoded) = crate::flac::decode(&std::fs::read(&flac_path).unwrap()).unwrap();
+    assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (SAMPLE_RATE, 1, 16));
+    assert_eq!(decoded, original);
+    assert_eq!(std::fs::metadata(&flac_path).unwrap().modified().unwrap(), two_days_ago);
+
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(wav.with_extension("json")).unwrap()).unwrap();
+    assert_eq!(sidecar["format"], "flac");
+    assert_eq!(sidecar["recording"], flac_path.file_name().unwrap().to_str().unwrap());
+    assert_eq!(sidecar["sha256"], sha256_of(&std::fs::read(&flac_path).unwrap()));
+
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings?sort=oldest").to_request()).await,
+    ).await;
+    assert_eq!(listing[0]["filename"], flac_path.file_name().unwrap().to_str().unwrap());
+    assert_eq!(listing[0]["duration_seconds"], 0.1);
+
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/maintenance").to_request()).await,
+    ).await;
+    assert_eq!(status["enabled"], true);
+    assert_eq!(status["compress_after_seconds"], 24 * 60 * 60);
+    assert_eq!(status["running"], false);
+    assert_eq!(status["files_compressed"], 1);
+    assert_eq!(status["last_run"]["compressed"][0]["recording"], listing[0]["filename"]);
+    assert!(status["bytes_saved"].as_u64().unwrap() > 0);
+}
+
+#[actix_web::test]
+async fn output_budget_deletes_the_oldest_recordings_or_refuses_the_save() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().output_budget = Some(3000);
+    let app = test_app!(state);
+
+    let hours_ago = |name: &str, bytes: usize, hours: u64| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, vec![0u8; bytes]).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(hours * 60 * 60)).unwrap();
+        path
+    };
+    let oldest = hours_ago("oldest.wav", 1000, 2);
+    let newer = hours_ago("newer.wav", 700, 1);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 160]);
+    state.samples_written.store(160, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert!(!oldest.exists());
+    assert!(newer.exists());
+
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!(status["output_usage"]["budget_bytes"], 3000);
+    assert!(status["output_usage"]["used_bytes"].as_u64().unwrap() <= 3000);
+
+    // Nothing can make room for a save larger than the whole budget
+    let small_dir = tempfile::tempdir().unwrap();
+    let mut small = test_state(small_dir.path());
+    Arc::get_mut(&mut small).unwrap().output_budget = Some(100);
+    let app = test_app!(small);
+    small.buffer.lock().push_slice_overwrite(&[0.25; 160]);
+    small.samples_written.store(160, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), 507);
+    assert_eq!(std::fs::read_dir(small_dir.path()).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn split_channels_save_one_mono_file_per_channel() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        *state.input_config.get_mut() = cpal::SupportedStreamConfig::new(
+            2,
+            cpal::SampleRate(SAMPLE_RATE),
+            cpal::SupportedBufferSize::Unknown,
+            cpal::SampleFormat::F32,
+        );
+        state.split_channels = true;
+        state.buffer = parking_lot::Mutex::new(crate::sample_buffer::SampleBuffer::new(SAMPLE_RATE as usize, 2, true, Default::default()));
+    }
+    let app = test_app!(state);
+
+    // Left channel at 0.25, right at -0.5
+    let frames: Vec<f32> = std::iter::repeat_n([0.25, -0.5], 400).flatten().collect();
+    state.buffer.lock().push_slice_overwrite(&frames);
+    state.samples_written.store(frames.len() as u64, Ordering::Relaxed);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let first = body["path"].as_str().unwrap();
+    assert!(first.ends_with("_ch0.wav"), "{}", first);
+    let second = first.replace("_ch0.wav", "_ch1.wav");
+    for (path, expected) in [(first, 0.25), (second.as_str(), -0.5)] {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        assert_eq!(reader.spec().channels, 1);
+        let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(samples.len(), 400);
+        assert!(samples.iter().all(|&s| (s as f32 / i16::MAX as f32 - expected).abs() < 0.001));
+    }
+    assert_eq!(body["segments"].as_array().unwrap().len(), 2);
+
+    // The interleaved layout is still one stereo file
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?per_channel=false").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(hound::WavReader::open(body["path"].as_str().unwrap()).unwrap().spec().channels, 2);
+}
+
+#[actix_web::test]
+async fn split_channel_saves_write_every_file_or_none() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        *state.input_config.get_mut() = cpal::SupportedStreamConfig::new(
+            2,
+            cpal::SampleRate(SAMPLE_RATE),
+            cpal::SupportedBufferSize::Unknown,
+            cpal::SampleFormat::F32,
+        );
+        state.filename_template = "take".parse().unwrap();
+    }
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 800]);
+    state.samples_written.store(800, Ordering::Relaxed);
+
+    // A directory in the way of the second channel fails the save after the first is written
+    std::fs::create_dir(dir.path().join("take_ch1.wav")).unwrap();
+    let request = test::TestRequest::post().uri("/save?split_channels=true").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 500);
+    let mut left: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    left.sort();
+    assert_eq!(left, ["take_ch1.wav"]);
+
+    std::fs::remove_dir(dir.path().join("take_ch1.wav")).unwrap();
+    let request = test::TestRequest::post().uri("/save?split_channels=true").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let segments = body["segments"].as_array().unwrap();
+    assert_eq!(segments.len(), 2);
+    assert!(segments.iter().all(|file| file["samples"] == 400));
+}
+
+#[actix_web::test]
+async fn split_channels_on_a_mono_capture_saves_one_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    let request = test::TestRequest::post().uri("/save?split_channels=true").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let path = body["path"].as_str().unwrap();
+    assert!(!path.contains("_ch"), "{}", path);
+    assert!(body["segments"].is_null());
+    assert_eq!(hound::WavReader::open(path).unwrap().len(), 400);
+}
+
+#[actix_web::test]
+async fn target_rate_resamples_without_shifting_pitch() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    *Arc::get_mut(&mut state).unwrap().input_config.get_mut() = cpal::SupportedStreamConfig::new(
+        1,
+        cpal::SampleRate(48_000),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    );
+    let app = test_app!(state);
+
+    // 0.3 s of a 1 kHz tone at 48 kHz
+    let tone: Vec<f32> = (0..14_400)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 48_000.0).sin())
+        .collect();
+    state.buffer.lock().push_slice_overwrite(&tone);
+    state.samples_written.store(tone.len() as u64, Ordering::Relaxed);
+
+    let request = test::TestRequest::post().uri("/save?target_rate=16000").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let mut reader = hound::WavReader::open(body["path"].as_str().unwrap()).unwrap();
+    assert_eq!(reader.spec().sample_rate, 16_000);
+    let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+    assert_eq!(samples.len(), 4_800);
+    // Rising zero crossings count the cycles
+    let cycles = samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+    let frequency = cycles as f64 / 0.3;
+    assert!((frequency - 1000.0).abs() <= 10.0, "{} Hz", frequency);
+
+    let request = test::TestRequest::post().uri("/save?target_rate=16000&format=opus").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 400);
+}
+
+// Next message of an /events response, as JSON
+async fn next_event<B: actix_web::body::MessageBody>(body: &mut std::pin::Pin<Box<B>>) -> serde_json::Value {
+    let read = std::future::poll_fn(|cx| body.as_mut().poll_next(cx));
+    let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), read).await
+        .expect("no event within 5s")
+        .and_then(Result::ok)
+        .expect("event stream ended");
+    let text = std::str::from_utf8(&chunk).unwrap();
+    let json = text.strip_prefix("data: ").and_then(|rest| rest.strip_suffix("\n\n")).unwrap();
+    serde_json::from_str(json).unwrap()
+}
+
+#[actix_web::test]
+async fn events_stream_state_changes_and_saves() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/events").to_request()).await;
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/event-stream");
+    let mut body = Box::pin(response.into_body());
+    let event = next_event(&mut body).await;
+    assert_eq!((event["type"].as_str(), event["state"].as_str()), (Some("recording_state"), Some("recording")));
+    assert!(event["at"].is_string());
+
+    test::call_service(&app, test::TestRequest::post().uri("/pause").to_request()).await;
+    let event = next_event(&mut body).await;
+    assert_eq!(event["state"], "paused");
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    let event = next_event(&mut body).await;
+    assert_eq!(event["type"], "save_completed");
+    assert_eq!(event["path"], saved["path"]);
+    assert_eq!(event["samples"], 400);
+
+    // Disconnecting ends the subscription
+    assert_eq!(state.events.receiver_count(), 1);
+    drop(body);
+    for _ in 0..100 {
+        if state.events.receiver_count() == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(state.events.receiver_count(), 0);
+}
+
+#[actix_web::test]
+async fn saves_with_the_same_name_never_overwrite() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().filename_template = "take".parse().unwrap();
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    let mut paths = Vec::new();
+    for _ in 0..2 {
+        let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        paths.push(body["path"].as_str().unwrap().to_string());
+    }
+    // Auto-saves and sound-triggered captures take names the same way
+    let (saved, _) = crate::save_in_background(&state, crate::SaveWindow::default(), crate::filename::Trigger::Auto).await.unwrap();
+    paths.push(saved.unwrap().path);
+    let names: Vec<_> = paths.iter().map(|path| Path::new(path).file_name().unwrap().to_str().unwrap()).collect();
+    assert_eq!(names, ["take.wav", "take_1.wav", "take_2.wav"]);
+    assert!(paths.iter().all(|path| Path::new(path).exists()));
+}
+
+#[actix_web::test]
+async fn a_long_poll_returns_the_next_detection() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    // Other events don't end the wait
+    let detect = async {
+        while state.events.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        state.publish(crate::events::Event::RecordingState { state: crate::RecordingState::Paused });
+        state.publish(crate::events::Event::WakewordDetected { keyword: "porcupine".to_string(), captured_sample: 4800 });
+    };
+    let request = test::TestRequest::get().uri("/wait-for-detection?timeout_ms=5000").to_request();
+    let (response, ()) = tokio::join!(test::call_service(&app, request), detect);
+    assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!((body["type"].as_str(), body["keyword"].as_str()), (Some("wakeword_detected"), Some("porcupine")));
+    assert_eq!(body["captured_sample"], 4800);
+
+    let request = test::TestRequest::get().uri("/wait-for-detection?timeout_ms=20").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), actix_web::http::StatusCode::NO_CONTENT);
+    let request = test::TestRequest::get().uri("/wait-for-detection?timeout_ms=300001").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+    // A halt doesn't wait for the poll to time out
+    let halt = async {
+        while state.events.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        state.request_shutdown();
+    };
+    let started = std::time::Instant::now();
+    let request = test::TestRequest::get().uri("/wait-for-detection?timeout_ms=60000").to_request();
+    let (response, ()) = tokio::join!(test::call_service(&app, request), halt);
+    assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[actix_web::test]
+async fn saved_wavs_carry_broadcast_metadata_for_the_capture_window() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    // A full second of audio ending now
+    state.buffer.lock().push_slice_overwrite(&[0.25; SAMPLE_RATE as usize]);
+    state.samples_written.store(SAMPLE_RATE as u64, Ordering::Relaxed);
+
+    let before = chrono::Local::now();
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let after = chrono::Local::now();
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = Path::new(body["path"].as_str().unwrap());
+
+    let bytes = std::fs::read(path).unwrap();
+    let chunks = crate::bwf::chunks(&bytes).unwrap();
+    let ids: Vec<&[u8]> = chunks.iter().map(|(id, _, _)| &id[..]).collect();
+    assert_eq!(ids, [&b"fmt "[..], b"bext", b"LIST", b"data"]);
+    let (_, _, bext) = chunks.iter().find(|(id, _, _)| id == b"bext").unwrap();
+    let (description, date, time, time_reference) = crate::bwf::read_bext(bext).unwrap();
+    assert!(description.contains("test device"), "{}", description);
+
+    // The first sample was captured a second before the save
+    let origination = chrono::NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S").unwrap();
+    let earliest = (before - chrono::Duration::seconds(1)).naive_local() - chrono::Duration::seconds(1);
+    let latest = (after - chrono::Duration::seconds(1)).naive_local();
+    assert!(origination >= earliest && origination <= latest, "{} not in {}..{}", origination, earliest, latest);
+    let seconds = time_reference / SAMPLE_RATE as u64;
+    assert_eq!(seconds, chrono::Timelike::num_seconds_from_midnight(&origination) as u64);
+
+    // The sidecar holds the same start time, and ordinary readers still see the audio
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(crate::recordings::sidecar_path(path)).unwrap()).unwrap();
+    let started_at = chrono::DateTime::parse_from_rfc3339(sidecar["started_at"].as_str().unwrap()).unwrap();
+    assert_eq!(started_at.naive_local().format("%H:%M:%S").to_string(), time);
+    assert_eq!(hound::WavReader::open(path).unwrap().len(), SAMPLE_RATE);
+}
+
+// Hashed here rather than with capture_audio::sha256, so a bug there can't hide
+fn sha256_of(bytes: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, bytes).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[actix_web::test]
+async fn saves_report_the_sha256_of_what_was_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = Path::new(body["path"].as_str().unwrap());
+    let expected = sha256_of(&std::fs::read(path).unwrap());
+    assert_eq!(body["sha256"], expected.as_str());
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(crate::recordings::sidecar_path(path)).unwrap()).unwrap();
+    assert_eq!(sidecar["sha256"], expected.as_str());
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?download=true").to_request()).await;
+    let header = response.headers().get("x-content-sha256").unwrap().to_str().unwrap().to_string();
+    let bytes = test::read_body(response).await;
+    assert_eq!(header, sha256_of(&bytes));
+}
+
+#[actix_web::test]
+async fn downloads_can_come_base64_encoded_in_json() {
+    use base64::Engine;
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    // 1600 samples of 16-bit WAV take 3244 bytes, 4328 as base64
+    Arc::get_mut(&mut state).unwrap().base64_limit = 5000;
+    let app = test_app!(state);
+    state.push_samples(&[0.25; 1600]);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?download=true&base64=true").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert!(body["filename"].as_str().unwrap().ends_with(".wav"));
+    assert_eq!(body["content_type"], "audio/wav");
+    let wav = base64::engine::general_purpose::STANDARD.decode(body["wav_base64"].as_str().unwrap()).unwrap();
+    assert_eq!(body["sha256"], sha256_of(&wav).as_str());
+    assert_eq!(hound::WavReader::new(std::io::Cursor::new(wav)).unwrap().len(), 1600);
+    // Nothing is written to disk
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    state.push_samples(&[0.25; 1600]);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?download=true&base64=true").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = test::read_body_json(response).await;
+    assert!(error["error"].as_str().unwrap().contains("over the"), "{}", error);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?base64=true").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+}
+
+// Stand in for the capture callback: feed `value` in 10 ms blocks while recording
+fn feed_while_recording(state: &Arc<AudioState>, value: f32) -> tokio::task::JoinHandle<()> {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let block = vec![value; SAMPLE_RATE as usize / 100];
+        while !state.is_halting.load(Ordering::Relaxed) {
+            state.push_samples(&block);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+}
+
+#[actix_web::test]
+async fn record_captures_exactly_the_requested_duration() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    // Audio from before the request must not end up in the file
+    state.buffer.lock().push_slice_overwrite(&[0.9; 800]);
+    state.samples_written.store(800, Ordering::Relaxed);
+    state.pause();
+    let feeder = feed_while_recording(&state, 0.25);
+
+    let request = test::TestRequest::post().uri("/record?seconds=0.2").to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["samples"], 3200);
+    let mut reader = hound::WavReader::open(body["path"].as_str().unwrap()).unwrap();
+    let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+    assert_eq!(samples.len(), 3200);
+    assert!(samples.iter().all(|&s| (s as f32 / i16::MAX as f32 - 0.25).abs() < 0.001));
+    // Back to paused, as before the request
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+
+    let request = test::TestRequest::post().uri("/record?seconds=5").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 400);
+
+    // A client that gives up mid-recording leaves it paused too
+    let request = test::TestRequest::post().uri("/record?seconds=0.5").to_request();
+    let abandoned = tokio::time::timeout(std::time::Duration::from_millis(50), test::call_service(&app, request)).await;
+    assert!(abandoned.is_err());
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+    assert_eq!(state.recording_state(), crate::RecordingState::Paused);
+    state.is_halting.store(true, Ordering::Relaxed);
+    feeder.await.unwrap();
+}
+
+// (head, body) of each request to a fake server
+type ReceivedRequests = Arc<parking_lot::Mutex<Vec<(String, Vec<u8>)>>>;
+
+// Value of `name` in a request head
+fn header(head: &str, name: &str) -> String {
+    head.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)).map(str::to_string))
+        .unwrap_or_default()
+}
+
+// Stand in for an S3 service or webhook receiver, answering each request with
+// the next of `responses` and 200 once they run out
+async fn fake_server(responses: Vec<(u16, &'static str)>) -> (String, ReceivedRequests) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let requests = ReceivedRequests::default();
+    let recorded = Arc::clone(&requests);
+    tokio::spawn(async move {
+        let mut responses = responses.into_iter();
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let head_end = loop {
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..read]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+            let length: usize = header(&head, "Content-Length").parse().unwrap();
+            while request.len() < head_end + length {
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..read]);
+            }
+            recorded.lock().push((head, request[head_end..].to_vec()));
+            let (status, body) = responses.next().unwrap_or((200, ""));
+            let response = format!("HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (endpoint, requests)
+}
+
+// Poll the job at `status_url` until it is no longer pending or running
+macro_rules! finished_job {
+    ($app:expr, $status_url:expr) => {{
+        let mut job = serde_json::Value::Null;
+        for _ in 0..500 {
+            let response = test::call_service(&$app, test::TestRequest::get().uri($status_url).to_request()).await;
+            job = test::read_body_json(response).await;
+            if job["finished_at"].is_string() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        job
+    }};
+}
+
+#[actix_web::test]
+async fn transcribe_posts_the_recent_audio_as_wav() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let (endpoint, requests) = fake_server(vec![(200, r#"{"text": "lights on"}"#), (500, "model not loaded")]).await;
+    let url = format!("{}/v1/transcribe?lang=en", endpoint);
+    Arc::get_mut(&mut state).unwrap().stt =
+        Some(crate::stt::SttConfig::new(&url, std::time::Duration::from_millis(500)).unwrap());
+    let app = test_app!(state);
+    let post = |uri: &str| test::TestRequest::post().uri(uri).to_request();
+
+    let response = test::call_service(&app, post("/transcribe")).await;
+    assert_eq!(response.status(), 404);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; SAMPLE_RATE as usize]);
+    state.samples_written.store(SAMPLE_RATE as u64, Ordering::Relaxed);
+    let response = test::call_service(&app, post("/transcribe")).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["text"], "lights on");
+    assert_eq!(body["duration_seconds"], 0.5);
+
+    let (head, wav) = requests.lock()[0].clone();
+    assert!(head.starts_with("POST /v1/transcribe?lang=en HTTP/1.1"), "{}", head);
+    assert_eq!(header(&head, "Content-Type"), "audio/wav");
+    let reader = hound::WavReader::new(std::io::Cursor::new(wav)).unwrap();
+    assert_eq!((reader.spec().bits_per_sample, reader.duration()), (16, SAMPLE_RATE / 2));
+
+    let response = test::call_service(&app, post("/transcribe?seconds=0.25")).await;
+    assert_eq!(response.status(), 502);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert!(body["error"].as_str().unwrap().contains("500 model not loaded"), "{}", body);
+}
+
+#[actix_web::test]
+async fn uploads_stream_to_s3_and_keep_the_local_copy_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let busy = "<Error><Code>SlowDown</Code><Message>Reduce your request rate</Message></Error>";
+    let (endpoint, requests) = fake_server(vec![(503, busy)]).await;
+    let options = crate::upload::S3Options {
+        endpoint,
+        bucket: "archive".to_string(),
+        prefix: "kitchen/".to_string(),
+        region: "us-east-1".to_string(),
+        ca_file: None,
+        delete_local: true,
+    };
+    let credentials = crate::upload::Credentials {
+        access_key_id: "TESTKEY".to_string(),
+        secret_access_key: "secret".to_string(),
+    };
+    Arc::get_mut(&mut state).unwrap().s3 = Some(crate::upload::S3Config::new(options, credentials).unwrap());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    // A failed upload leaves the recording in place and explains why on the job
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?upload=true").to_request()).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = Path::new(body["path"].as_str().unwrap()).to_path_buf();
+    let job = finished_job!(app, body["upload"]["status_url"].as_str().unwrap());
+    assert_eq!(job["kind"], "upload");
+    assert_eq!(job["status"], "failed");
+    let error = job["error"].as_str().unwrap();
+    assert!(error.contains("503") && error.contains("SlowDown"), "{}", error);
+    assert!(path.is_file());
+
+    // Retrying sends the recording as saved, then its sidecar
+    let wav = std::fs::read(&path).unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap().to_string();
+    let request = test::TestRequest::post().uri(&format!("/recordings/{}/upload", name)).to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 202);
+    let accepted: serde_json::Value = test::read_body_json(response).await;
+    let job = finished_job!(app, accepted["status_url"].as_str().unwrap());
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(job["upload"]["objects"][0]["key"], format!("kitchen/{}", name));
+    assert_eq!(job["upload"]["local_deleted"], true);
+
+    let requests = requests.lock().clone();
+    assert_eq!(requests.len(), 3);
+    let (head, sent) = &requests[1];
+    assert!(head.starts_with(&format!("PUT /archive/kitchen/{} HTTP/1.1\r\n", name)), "{}", head);
+    let authorization = header(head, "Authorization");
+    assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=TESTKEY/"), "{}", authorization);
+    assert!(authorization.contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date"));
+    assert_eq!(sent, &wav);
+    assert!(requests[2].0.lines().next().unwrap().contains(".json "));
+    // --s3-delete-local removes both once they are safely uploaded
+    assert!(!path.exists());
+    assert!(!crate::recordings::sidecar_path(&path).exists());
+
+    let request = test::TestRequest::post().uri("/save?upload=true&download=true").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn snapshots_hold_the_buffer_lock_only_briefly() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    // Ten minutes at 48 kHz stereo
+    let capacity = 48_000 * 2 * 600;
+    Arc::get_mut(&mut state).unwrap().buffer =
+        parking_lot::Mutex::new(crate::sample_buffer::SampleBuffer::new(capacity, 1, false, Default::default()));
+    let samples: Vec<f32> = (0..capacity).map(|i| (i % 1000) as f32).collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.samples_written.store(capacity as u64, Ordering::Relaxed);
+
+    // Stand in for the capture callback: push 10 ms of audio at a time and
+    // record the longest wait for the lock
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let callback = {
+        let (state, done) = (Arc::clone(&state), Arc::clone(&done));
+        std::thread::spawn(move || {
+            let frame = [0.0f32; 960];
+            let (mut longest, mut pushes) = (std::time::Duration::ZERO, 0);
+            while !done.load(Ordering::Relaxed) {
+                let asked = std::time::Instant::now();
+                let mut buffer = state.buffer.lock();
+                longest = longest.max(asked.elapsed());
+                buffer.push_slice_overwrite(&frame);
+                state.samples_written.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                drop(buffer);
+                pushes += 1;
+                std::thread::sleep(std::time::Duration::from_micros(200));
+            }
+            (longest, pushes)
+        })
+    };
+
+    let config = state.input_config();
+    let copied = std::time::Instant::now();
+    let snapshot = crate::capture_audio::snapshot_buffer(&state, &config, crate::capture_audio::SaveWindow::default());
+    let copied = copied.elapsed();
+    done.store(true, Ordering::Relaxed);
+    let (longest, pushes) = callback.join().unwrap();
+
+    assert_eq!(snapshot.samples.len(), capacity);
+    // Every sample is the one that was buffered at that position, except where
+    // the callback overwrote it before the copy got there: never in the first
+    // chunk, which is copied under the lock that fixed the window, and never
+    // more than the callback pushed
+    let zeroed: Vec<usize> = snapshot.samples.iter().enumerate()
+        .filter(|&(i, &s)| s != (i % 1000) as f32)
+        .map(|(i, &s)| {
+            assert_eq!(s, 0.0, "sample {}", i);
+            i
+        })
+        .collect();
+    assert!(zeroed.first().is_none_or(|&i| i >= 16 * 1024), "sample {} was lost", zeroed[0]);
+    assert!(zeroed.len() <= pushes * 960, "{} samples lost to {} pushes", zeroed.len(), pushes);
+    assert!(pushes > 1, "the callback never ran during the copy");
+    // With a single core the callback also waits for the scheduler, so only
+    // check that the lock wasn't held for the whole copy
+    let limit = match std::thread::available_parallelism().map_or(1, |n| n.get()) {
+        1 => copied / 4,
+        _ => std::time::Duration::from_millis(1),
+    };
+    assert!(longest < limit, "callback waited {:?} (whole copy took {:?})", longest, copied);
+}
+
+#[actix_web::test]
+async fn saves_are_renamed_into_place_and_temp_files_are_hidden() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    let stale = dir.path().join("crashed.wav.tmp");
+    std::fs::write(&stale, b"RIFF").unwrap();
+    std::fs::File::options().write(true).open(&stale).unwrap()
+        .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60))
+        .unwrap();
+    let fresh = dir.path().join("writing.wav.tmp");
+    std::fs::write(&fresh, b"RIFF").unwrap();
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 160]);
+    state.samples_written.store(160, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let saved = Path::new(body["path"].as_str().unwrap());
+    assert!(hound::WavReader::open(saved).is_ok());
+    assert!(!crate::recordings::temp_path(saved).exists());
+
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    let names: Vec<&str> = listing.as_array().unwrap().iter().map(|e| e["filename"].as_str().unwrap()).collect();
+    assert_eq!(names, vec![saved.file_name().unwrap().to_str().unwrap()]);
+
+    // Only temp files older than the cutoff are from saves that died
+    assert_eq!(crate::recordings::remove_stale_temp_files(dir.path(), crate::recordings::STALE_TEMP_AGE), 1);
+    assert!(!stale.exists() && fresh.exists());
+}
+
+#[actix_web::test]
+async fn wakeword_reload_is_refused_when_detection_is_disabled() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().wakeword_disabled = true;
+    let app = test_app!(state);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/wakeword/reload").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::CONFLICT);
+    assert!(state.wakeword.lock().is_none());
+}
+
+// Peak of a saved 16-bit WAV, as a fraction of full scale
+fn wav_peak(path: &str) -> f32 {
+    hound::WavReader::open(path).unwrap()
+        .samples::<i16>()
+        .map(|s| (s.unwrap() as f32 / i16::MAX as f32).abs())
+        .fold(0.0, f32::max)
+}
+
+#[actix_web::test]
+async fn normalize_scales_the_saved_copy_to_the_target_peak() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    let save = |uri: &'static str| test::TestRequest::post().uri(uri).to_request();
+
+    // A -40 dBFS sine comes up by 39 dB to the default -1 dBFS
+    let amplitude = 10f32.powf(-40.0 / 20.0);
+    let sine: Vec<f32> = (0..1600)
+        .map(|i| amplitude * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / SAMPLE_RATE as f32).sin())
+        .collect();
+    state.buffer.lock().push_slice_overwrite(&sine);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, save("/save?normalize=true&seconds=0.1")).await).await;
+    assert_eq!(body["normalization"]["applied"], true);
+    assert!((body["normalization"]["gain_db"].as_f64().unwrap() - 39.0).abs() < 0.1, "{}", body);
+    let peak = wav_peak(body["path"].as_str().unwrap());
+    assert!((peak - 10f32.powf(-1.0 / 20.0)).abs() < 0.005, "peak {}", peak);
+    // The buffer itself keeps the quiet original
+    assert!(state.buffer.lock().latest().unwrap().abs() <= amplitude);
+
+    // Clipped audio is never turned up, only down to the target
+    state.buffer.lock().push_slice_overwrite(&[1.0, -1.0].repeat(800));
+    state.samples_written.fetch_add(1600, Ordering::Relaxed);
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, save("/save?normalize=true&normalize_target=-6&seconds=0.1")).await).await;
+    assert!(body["normalization"]["gain_db"].as_f64().unwrap() <= 0.0);
+    let peak = wav_peak(body["path"].as_str().unwrap());
+    assert!((peak - 10f32.powf(-6.0 / 20.0)).abs() < 0.005, "peak {}", peak);
+
+    // Near-silence is left alone rather than amplified to full scale
+    state.buffer.lock().push_slice_overwrite(&[0.0001; 1600]);
+    state.samples_written.fetch_add(1600, Ordering::Relaxed);
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, save("/save?normalize=true&seconds=0.1")).await).await;
+    assert_eq!(body["normalization"]["applied"], false);
+    assert!(body["normalization"]["skipped"].is_string());
+
+    let response = test::call_service(&app, save("/save?normalize=true&normalize_target=3")).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn append_to_grows_one_session_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("session.wav");
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().append_to = Some(session.clone());
+    let app = test_app!(state);
+
+    for (value, seconds) in [(0.25f32, 0.01), (-0.5, 0.02)] {
+        state.buffer.lock().push_slice_overwrite(&[value; 1600]);
+        state.samples_written.fetch_add(1600, Ordering::Relaxed);
+        let uri = format!("/save?seconds={}", seconds);
+        let response = test::call_service(&app, test::TestRequest::post().uri(&uri).to_request()).await;
+        assert!(response.status().is_success());
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["path"], session.display().to_string());
+    }
+
+    // One file holding both saves back to back, with a header covering all of it
+    let mut reader = hound::WavReader::open(&session).unwrap();
+    assert_eq!(reader.duration(), 160 + 320);
+    let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+    assert!(samples[..160].iter().all(|&s| s > 0) && samples[160..].iter().all(|&s| s < 0));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?format=mp3").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+    // A session file in another format is refused rather than corrupted
+    let stereo = hound::WavSpec { channels: 2, sample_rate: 8000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    hound::WavWriter::create(&session, stereo).unwrap().finalize().unwrap();
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::CONFLICT);
+    assert_eq!(hound::WavReader::open(&session).unwrap().duration(), 0);
+}
+
+#[actix_web::test]
+async fn halt_can_save_the_buffer_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/halt?grace_ms=999999999").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    assert!(!state.is_halting.load(Ordering::Relaxed));
+    // Nothing to save is refused like an empty /save
+    let response = test::call_service(&app, test::TestRequest::post().uri("/halt?save=true").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!state.is_halting.load(Ordering::Relaxed));
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/halt?save=true&grace_ms=500").to_request()).await;
+    assert!(response.status().is_success());
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let path = body.rsplit(' ').next().unwrap();
+    assert_eq!(hound::WavReader::open(path).unwrap().duration(), 1600);
+    assert!(state.is_halting.load(Ordering::Relaxed));
+    assert_eq!(*state.shutdown_grace.lock(), Some(std::time::Duration::from_millis(500)));
+}
+
+#[actix_web::test]
+async fn shutdown_wakes_as_the_last_save_ends() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let saves_done = || state.active_saves.lock().is_empty() && state.jobs.in_flight() == 0;
+    let changed = [&state.saves_done, state.jobs.finished()];
+    let soon = || tokio::time::Instant::now() + std::time::Duration::from_millis(50);
+
+    let save = crate::recordings::ActiveSave::begin(&state, "a.wav");
+    let job = state.jobs.create();
+    assert!(!crate::wait_until(soon(), &changed, saves_done).await);
+    let finish = async {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        drop(save);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        state.jobs.finish(job, Err("gave up".to_string()));
+    };
+    let started = std::time::Instant::now();
+    let later = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+    let (drained, ()) = tokio::join!(crate::wait_until(later, &changed, saves_done), finish);
+    assert!(drained && started.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[actix_web::test]
+async fn halt_waits_its_turn_to_save() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+
+    // Another save holds the only permit for a while
+    let held = state.save_permits.try_acquire().unwrap();
+    let halt = test::call_service(&app, test::TestRequest::post().uri("/halt?save=true&grace_ms=0").to_request());
+    let release = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!state.is_halting.load(Ordering::Relaxed));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        drop(held);
+    };
+    let (response, ()) = tokio::join!(halt, release);
+    assert!(response.status().is_success());
+    assert!(state.is_halting.load(Ordering::Relaxed));
+    // No grace at all, rather than the default
+    assert_eq!(*state.shutdown_grace.lock(), Some(std::time::Duration::ZERO));
+}
+
+#[actix_web::test]
+async fn save_returns_the_wav_with_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+
+    let request = test::TestRequest::post().uri("/save?download=true").to_request();
+    let response = test::call_service(&app, request).await;
+    assert!(response.status().is_success());
+    let bytes = test::read_body(response).await;
+
+    let reader = hound::WavReader::new(std::io::Cursor::new(bytes.to_vec())).unwrap();
+    assert_eq!(reader.len(), 1600);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn downloads_leave_the_file_counter_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let template: crate::filename::FilenameTemplate = "rec_{counter}".parse().unwrap();
+    let counter = crate::filename::FileCounter::open(dir.path(), &template).unwrap();
+    let inner = Arc::get_mut(&mut state).unwrap();
+    (inner.filename_template, inner.file_counter) = (template, Some(counter));
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.samples_written.store(1600, Ordering::Relaxed);
+
+    let mut saved = Vec::new();
+    for query in ["", "?download=true", "?download=true&base64=true", ""] {
+        let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/save{}", query)).to_request()).await;
+        assert!(response.status().is_success(), "{}", query);
+        if query.is_empty() {
+            let body: serde_json::Value = test::read_body_json(response).await;
+            saved.push(body["path"].as_str().unwrap().rsplit('/').next().unwrap().to_string());
+        } else if query.contains("base64") {
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(body["filename"], "rec_000002.wav");
+        } else {
+            // Named after the save that would come next
+            let disposition = response.headers().get(actix_web::http::header::CONTENT_DISPOSITION).unwrap().to_str().unwrap();
+            assert!(disposition.contains("rec_000002.wav"), "{}", disposition);
+        }
+    }
+    assert_eq!(saved, ["rec_000001.wav", "rec_000002.wav"]);
+}
+
+// Decode an Ogg Opus file, returning its header fields and the 48 kHz samples after pre-skip
+fn decode_ogg_opus(bytes: &[u8]) -> (u16, usize, Vec<f32>) {
+    let mut reader = ogg::reading::PacketReader::new(std::io::Cursor::new(bytes));
+    let head = reader.read_packet_expected().unwrap();
+    assert_eq!(&head.data[..8], b"OpusHead");
+    let channels = head.data[9] as u16;
+    let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+    let tags = reader.read_packet_expected().unwrap();
+    assert_eq!(&tags.data[..8], b"OpusTags");
+
+    let mut error = 0;
+    let decoder = unsafe { unsafe_libopus::opus_decoder_create(48000, channels as i32, &mut error) };
+    assert_eq!(error, 0);
+    let mut decoded = Vec::new();
+    let mut last_granule = 0;
+    let mut frame = vec![0.0f32; 5760 * channels as usize];
+    while let Some(packet) = reader.read_packet().unwrap() {
+        let frames = unsafe {
+            unsafe_libopus::opus_decode_float(
+                decoder, packet.data.as_ptr(), packet.data.len() as i32, frame.as_mut_ptr(), 5760, 0,
+            )
+        };
+        assert!(frames > 0, "decode failed with {}", frames);
+        decoded.extend_from_slice(&frame[..frames as usize * channels as usize]);
+        last_granule = packet.absgp_page();
+    }
+    unsafe { unsafe_libopus::opus_decoder_destroy(decoder) };
+
+    // The final granule position marks where the real audio ends
+    let end = last_granule as usize * channels as usize;
+    assert!(decoded.len() >= end);
+    decoded.truncate(end);
+    (channels, pre_skip, decoded.split_off(pre_skip * channels as usize))
+}
+
+#[actix_web::test]
+async fn save_encodes_playable_ogg_opus() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    // One second of a 440 Hz tone at half scale
+    let samples: Vec<f32> = (0..SAMPLE_RATE)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / SAMPLE_RATE as f32).sin())
+        .collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.samples_written.store(samples.len() as u64, Ordering::Relaxed);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?format=opus").to_request()).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = body["path"].as_str().unwrap();
+    assert!(path.ends_with(".opus"));
+    assert!((body["duration_seconds"].as_f64().unwrap() - 1.0).abs() < 0.01);
+
+    let bytes = std::fs::read(path).unwrap();
+    assert_eq!(body["size_bytes"].as_u64().unwrap(), bytes.len() as u64);
+    // 24 kbps for one second, plus headers and container overhead
+    assert!(bytes.len() < 6000, "{} bytes for one second", bytes.len());
+
+    let (channels, pre_skip, decoded) = decode_ogg_opus(&bytes);
+    assert_eq!(channels, 1);
+    assert!(pre_skip > 0);
+    assert_eq!(decoded.len(), 48000);
+
+    // Same loudness and pitch as the input: RMS of a half-scale sine is about 0.354,
+    // and 440 Hz crosses zero 880 times a second
+    let rms = (decoded.iter().map(|s| s * s).sum::<f32>() / decoded.len() as f32).sqrt();
+    assert!((rms - 0.354).abs() < 0.05, "rms {}", rms);
+    let crossings = decoded.windows(2).filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0)).count();
+    assert!((860..=900).contains(&crossings), "{} zero crossings", crossings);
+}
+
+#[cfg(unix)]
+#[actix_web::test]
+async fn routes_are_served_over_a_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let socket = dir.path().join("control.sock");
+
+    let app_state = Arc::clone(&state);
+    let server = actix_web::HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(Arc::clone(&app_state)))
+            .configure(configure_app)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind_uds(&socket)
+    .unwrap();
+    crate::uds::set_socket_mode(&socket, crate::uds::SocketMode(0o600)).unwrap();
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    stream.write_all(b"POST /pause HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+    assert!(response.contains(r#""state":"paused""#));
+    assert!(!state.is_recording.load(Ordering::Relaxed));
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode, 0o600);
+
+    handle.stop(true).await;
+}
+
+#[actix_web::test]
+async fn health_follows_the_frame_heartbeat() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    let get = || test::TestRequest::get().uri("/health").to_request();
+
+    let response = test::call_service(&app, get()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+    state.last_frame_at.store(crate::capture_audio::now_millis(), Ordering::Relaxed);
+    let response = test::call_service(&app, get()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+
+    state.last_frame_at.store(crate::capture_audio::now_millis() - 10_000, Ordering::Relaxed);
+    let response = test::call_service(&app, get()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn capture_init_failures_are_reported_and_retried() {
+    static ATTEMPTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    struct Unplugged;
+    impl crate::capture_audio::AudioSource for Unplugged {
+        fn open(&mut self) -> Result<cpal::SupportedStreamConfig, String> {
+            ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+            Err("No default input device".to_string())
+        }
+        fn name(&self) -> String {
+            String::new()
+        }
+        fn start(
+            &mut self,
+            _: &cpal::StreamConfig,
+            _: crate::capture_audio::BlockCallback,
+            _: crate::capture_audio::ErrorCallback,
+        ) -> Result<crate::capture_audio::SourceStream, crate::capture_audio::CaptureError> {
+            unreachable!("never opened")
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    let options = crate::capture_audio::CaptureOptions {
+        latency: std::time::Duration::from_millis(10),
+        wakeword_queue: std::time::Duration::from_secs(1),
+    };
+    let capture = crate::spawn_capture(Arc::clone(&state), options, Box::new(Unplugged));
+
+    // The first retry follows a short backoff, and the server keeps running
+    let started = std::time::Instant::now();
+    while ATTEMPTS.load(Ordering::Relaxed) < 2 {
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "capture was not retried");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    // Frames arriving from elsewhere don't hide the failure
+    state.last_frame_at.store(crate::capture_audio::now_millis(), Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+    let health: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(health["capture_failed"], true);
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!(status["capture_error"], "failed to open input device: No default input device");
+
+    // Halting ends the retries at once, without waiting out the backoff
+    state.request_shutdown();
+    assert!(crate::join_capture(capture, std::time::Duration::from_millis(50)), "capture outlived the halt");
+    assert!(state.capture_stopped.load(Ordering::Relaxed));
+}
+
+#[actix_web::test]
+async fn webhooks_push_saves_with_metadata_and_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let (endpoint, requests) = fake_server(vec![(500, "try later")]).await;
+    let backoff = std::time::Duration::from_millis(5);
+    let url = format!("{}/ingest?source=kitchen", endpoint);
+    let webhook = crate::webhook::WebhookConfig::new(
+        Some(&url), Some("Bearer hook-token".to_string()), crate::webhook::Method::Post, 2, backoff,
+    );
+    Arc::get_mut(&mut state).unwrap().webhook = webhook.unwrap();
+    let app = test_app!(state);
+    state.buffer.lock().push_slice_overwrite(&[0.25; 400]);
+    state.samples_written.store(400, Ordering::Relaxed);
+
+    // The first attempt gets a 500 and the retry goes through
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = Path::new(body["path"].as_str().unwrap()).to_path_buf();
+    let job = finished_job!(app, body["webhook"]["status_url"].as_str().unwrap());
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(job["webhook"][0]["attempts"], 2);
+
+    let wav = std::fs::read(&path).unwrap();
+    let received = requests.lock().clone();
+    assert_eq!(received.len(), 2);
+    let (head, sent) = &received[1];
+    assert!(head.starts_with("POST /ingest?source=kitchen HTTP/1.1\r\n"), "{}", head);
+    assert_eq!(header(head, "Content-Type"), "audio/wav");
+    assert_eq!(header(head, "Authorization"), "Bearer hook-token");
+    assert_eq!(header(head, "X-Recording-Trigger"), "manual");
+    assert_eq!(header(head, "X-Recording-Name"), path.file_name().unwrap().to_str().unwrap());
+    assert_eq!(header(head, "X-Content-SHA256"), sha256_of(&wav));
+    assert!(!header(head, "X-Recording-Started-At").is_empty());
+    assert_eq!(sent, &wav);
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(crate::recordings::sidecar_path(&path)).unwrap()).unwrap();
+    assert_eq!(sidecar["webhook"]["delivered"], true);
+    let response = test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await;
+    let status: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(status["webhook"]["attempts"], 2);
+
+    // A per-request URL never gets the configured credentials; giving up fails the job
+    let (other, other_requests) = fake_server(vec![(503, ""), (503, ""), (503, "")]).await;
+    let request = test::TestRequest::post().uri(&format!("/save?webhook={}/other", other)).to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, request).await).await;
+    let job = finished_job!(app, body["webhook"]["status_url"].as_str().unwrap());
+    assert_eq!(job["status"], "failed");
+    assert!(job["error"].as_str().unwrap().contains("after 3 attempts"), "{}", job);
+    let received = other_requests.lock().clone();
+    assert_eq!(received.len(), 3);
+    assert!(received.iter().all(|(head, _)| header(head, "Authorization").is_empty()));
+    assert!(Path::new(body["path"].as_str().unwrap()).is_file());
+
+    let request = test::TestRequest::post().uri("/save?webhook=ftp://example.com").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn auto_saves_only_write_new_audio_and_pad_pauses() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+    assert_eq!("10m".parse::<crate::autosave::Interval>().unwrap().0, std::time::Duration::from_secs(600));
+    assert!("10 minutes".parse::<crate::autosave::Interval>().is_err());
+
+    let push = |value: f32, len: usize| {
+        state.buffer.lock().push_slice_overwrite(&vec![value; len]);
+        state.samples_written.fetch_add(len as u64, Ordering::Relaxed);
+    };
+    push(0.25, 4000);
+    let (first, since) = crate::autosave::save_new_audio(&state, 0).await.unwrap();
+    assert_eq!(first.unwrap().samples, 4000);
+    assert_eq!(since, 4000);
+    let (nothing, since) = crate::autosave::save_new_audio(&state, since).await.unwrap();
+    assert!(nothing.is_none());
+
+    // A pause between the saves is filled with silence, and nothing from the first file repeats
+    push(0.5, 1000);
+    state.gaps.lock().push(crate::capture_audio::Gap { at: 5000, silent_frames: 500 });
+    push(0.5, 1000);
+    let (second, since) = crate::autosave::save_new_audio(&state, since).await.unwrap();
+    let second = second.unwrap();
+    assert_eq!(since, 6000);
+    let samples: Vec<i16> = hound::WavReader::open(&second.path).unwrap().samples().map(Result::unwrap).collect();
+    assert_eq!(samples.len(), 2500);
+    assert_eq!(samples.iter().filter(|&&s| s == 0).count(), 500);
+    assert!(samples.iter().all(|&s| s == 0 || s > i16::MAX / 3));
+
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    assert_eq!(listing.as_array().unwrap().len(), 2);
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &std::fs::read(crate::recordings::sidecar_path(Path::new(&second.path))).unwrap(),
+    ).unwrap();
+    assert_eq!(sidecar["trigger"], "auto");
+}
+
+#[actix_web::test]
+async fn saves_use_the_format_the_stream_was_opened_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path());
+    let app = test_app!(state);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 1600]);
+    state.set_input_config(cpal::SupportedStreamConfig::new(
+        2,
+        cpal::SampleRate(48_000),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    ));
+    // The mono audio can't be read back as stereo, so the buffer starts over
+    assert_eq!(state.buffer.lock().occupied_len(), 0);
+    // Still a second of audio, now of two channels
+    assert_eq!(state.buffer.lock().capacity(), 96_000);
+
+    state.buffer.lock().push_slice_overwrite(&[0.25; 9600]);
+    state.samples_written.store(9600, Ordering::Relaxed);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let spec = hound::WavReader::open(body["path"].as_str().unwrap()).unwrap().spec();
+    assert_eq!((spec.channels, spec.sample_rate), (2, 48_000));
+    assert_eq!(body["duration_seconds"], 0.1);
+}
+
+#[actix_web::test]
+async fn sound_triggered_captures_save_just_their_span() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    Arc::get_mut(&mut state).unwrap().filename_template = "{trigger}_{seq}".parse().unwrap();
+    let app = test_app!(state);
+
+    let samples: Vec<f32> = (0..3000).map(|i| if (1000..2000).contains(&i) { 0.5 } else { 0.0 }).collect();
+    state.buffer.lock().push_slice_overwrite(&samples);
+    state.samples_written.store(3000, Ordering::Relaxed);
+    let (captures, _) = crate::level_trigger::spawn_saver(Arc::clone(&state));
+    captures.send(crate::level_trigger::Capture { from: 1000, to: 2000, trigger: crate::filename::Trigger::Level }).await.unwrap();
+
+    let path = dir.path().join("level_0000.wav");
+    for _ in 0..200 {
+        if path.is_file() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let saved: Vec<i16> = hound::WavReader::open(&path).unwrap().samples().map(Result::unwrap).collect();
+    assert_eq!(saved.len(), 1000);
+    assert!(saved.iter().all(|&s| s > i16::MAX / 3));
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    assert_eq!(listing[0]["filename"], "level_0000.wav");
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &std::fs::read(crate::recordings::sidecar_path(&path)).unwrap(),
+    ).unwrap();
+    assert_eq!(sidecar["trigger"], "level");
+}
+
+#[actix_web::test]
+async fn save_errors_map_to_statuses_clients_can_act_on() {
+    use std::io::{Error, ErrorKind};
+    use actix_web::http::StatusCode;
+    use crate::capture_audio::SaveError;
+
+    let status = |error: SaveError| crate::save_error_status(&error.into());
+    assert_eq!(status(SaveError::WriteSamples(Error::from(ErrorKind::StorageFull))), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(status(SaveError::Finalize(Error::from(ErrorKind::QuotaExceeded))), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(status(SaveError::CreateWriter(Error::from(ErrorKind::PermissionDenied))), StatusCode::FORBIDDEN);
+    assert_eq!(status(SaveError::WriteSamples(Error::other("encoder failed"))), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_web::test]
+async fn every_device_is_saved_to_a_file_of_its_own() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let stereo = cpal::SupportedStreamConfig::new(2, cpal::SampleRate(48_000), cpal::SupportedBufferSize::Unknown, cpal::SampleFormat::F32);
+    let other = Arc::new(state.companion(stereo, "USB mic: front".to_string()));
+    Arc::get_mut(&mut state).unwrap().other_devices = vec![Arc::clone(&other)];
+    let app = test_app!(state);
+
+    state.push_samples(&[0.25; 1600]);
+    other.push_samples(&[0.5; 9600]);
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    let path = saved["path"].as_str().unwrap();
+    assert!(path.ends_with("_test device.wav"), "{}", path);
+    assert_eq!(saved["samples"], 1600);
+    assert_eq!(saved["devices"][0]["device"], "USB mic: front");
+    let other_path = saved["devices"][0]["files"][0]["path"].as_str().unwrap();
+    assert_eq!(other_path, path.replace("_test device.wav", "_USB mic_ front.wav"));
+    let reader = hound::WavReader::open(other_path).unwrap();
+    assert_eq!((reader.spec().channels, reader.spec().sample_rate, reader.len()), (2, 48_000, 9600));
+
+    // Transport controls reach every device
+    test::call_service(&app, test::TestRequest::post().uri("/pause").to_request()).await;
+    assert_eq!(other.push_samples(&[0.5; 2]), None);
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!(status["devices"][0]["buffered_samples"], 9600);
+
+    // So does the save before a halt
+    let response = test::call_service(&app, test::TestRequest::post().uri("/halt?save=true&grace_ms=0").to_request()).await;
+    assert!(response.status().is_success());
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let (_, files) = body.split_once(" saved to ").unwrap();
+    let files: Vec<&str> = files.split(", ").collect();
+    assert_eq!(files.len(), 2, "{}", body);
+    assert!(files[0].ends_with("_test device.wav") && files[1].ends_with("_USB mic_ front.wav"), "{}", body);
+    assert_eq!(hound::WavReader::open(files[1]).unwrap().len(), 9600);
+}
+
+#[actix_web::test]
+async fn companion_devices_save_the_same_stretch_of_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = test_state(dir.path());
+    let stereo = cpal::SupportedStreamConfig::new(2, cpal::SampleRate(48_000), cpal::SupportedBufferSize::Unknown, cpal::SampleFormat::F32);
+    let other = Arc::new(state.companion(stereo, "USB mic".to_string()));
+    Arc::get_mut(&mut state).unwrap().other_devices = vec![Arc::clone(&other)];
+
+    // 100 ms on each, then a save of the last 50 ms by the primary's position
+    state.push_samples(&[0.25; 1600]);
+    other.push_samples(&[0.5; 9600]);
+    let (saved, _) = crate::autosave::save_new_audio(&state, 800).await.unwrap();
+    let saved = saved.unwrap();
+    assert_eq!(saved.samples, 800);
+    let path = &saved.devices[0].files[0].path;
+    let reader = hound::WavReader::open(path).unwrap();
+    assert_eq!((reader.spec().channels, reader.spec().sample_rate, reader.len()), (2, 48_000, 4800));
+}
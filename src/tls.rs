@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::BufReader;
+
+/// Build a rustls server config from PEM certificate chain and private key files
+pub fn load_server_config(cert_path: &str, key_path: &str) -> Result<rustls::ServerConfig, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Unable to open {}: {}", path, e))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Unable to parse certificates in {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No PEM certificates found in {}", cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| format!("Unable to parse private key in {}: {}", key_path, e))?
+        .ok_or_else(|| format!("No PEM private key found in {}", key_path))?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Certificate and key in {} and {} don't form a usable pair: {}", cert_path, key_path, e))
+}
+
+// CA bundles of common distributions, tried in order when none is given
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// Build a rustls client config trusting the PEM certificates in `ca_file`,
+/// or the system bundle
+pub fn load_client_config(ca_file: Option<&str>) -> Result<rustls::ClientConfig, String> {
+    let path = match ca_file {
+        Some(path) => path,
+        None => SYSTEM_CA_BUNDLES.iter()
+            .copied()
+            .find(|path| std::path::Path::new(path).is_file())
+            .ok_or("No system CA bundle found; pass one with --s3-ca-file")?,
+    };
+    let mut reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Unable to parse certificates in {}: {}", path, e))?;
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(format!("No usable CA certificates found in {}", path));
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
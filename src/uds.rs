@@ -0,0 +1,38 @@
+/// Unix file permission bits given in octal on the command line, e.g. 0660
+#[derive(Debug, Clone, Copy)]
+pub struct SocketMode(pub u32);
+
+impl std::str::FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("invalid socket mode `{}`, expected octal permissions like 0660", s)),
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous run so binding doesn't fail,
+/// refusing to touch anything that isn't a socket
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Give the socket at `path` the permissions `mode`
+#[cfg(unix)]
+pub fn set_socket_mode(path: &std::path::Path, mode: SocketMode) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode.0))
+}
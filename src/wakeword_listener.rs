@@ -1,25 +1,123 @@
-use porcupine::{Porcupine, PorcupineBuilder, BuiltinKeywords};
-use std::env;
-use std::path::Path;
-
-pub fn get_wakeword_listener() -> Porcupine {
-    let access_key = env::var("PICOVOICE_ACCESS_KEY").unwrap_or_else(|_| {
-        panic!("PICOVOICE_ACCESS_KEY is not set");
-    });
-    let dir = env!("CARGO_MANIFEST_DIR");
-    let ppn_file = env::var("PORCUPINE_MODEL_PATH").unwrap_or_else(|_| {
-        panic!("PORCUPINE_MODEL_PATH is not set");
-    });
-    let full_path = Path::new(dir).join(ppn_file);
-    log::info!("Porcupine model path: {}", full_path.display());
-    
-    PorcupineBuilder::new_with_keywords(
-        access_key, 
-        &[BuiltinKeywords::Porcupine]
-    ).init().expect("Unable to create Porcupine")
-
-    // PorcupineBuilder::new_with_keyword_paths(
-    //     &access_key,
-    //     &[full_path],
-    // ).init().expect("Failed to create Porcupine instance")
-}
+use porcupine::{Porcupine, PorcupineBuilder, BuiltinKeywords};
+use std::env;
+use std::path::Path;
+
+use crate::error::VoiceError;
+
+/// Why the wakeword engine could not be created
+#[derive(Debug)]
+pub enum WakewordError {
+    /// A required environment variable is not set
+    MissingEnv(&'static str),
+    /// The access key file could not be read
+    AccessKeyFile(String),
+    /// Porcupine refused to start
+    Init(String),
+}
+
+impl std::fmt::Display for WakewordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WakewordError::MissingEnv(name) => write!(f, "{} is not set", name),
+            WakewordError::AccessKeyFile(e) => write!(f, "{}", e),
+            WakewordError::Init(e) => write!(f, "Unable to create Porcupine: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WakewordError {}
+
+// Keywords the engine listens for, in the order Porcupine reports their index
+const KEYWORDS: &[BuiltinKeywords] = &[BuiltinKeywords::Porcupine];
+
+/// Detection threshold for every keyword, from 0 (fewest misses) to 1 (fewest false alarms)
+pub const SENSITIVITY: f32 = 0.5;
+
+/// Names of the keywords the engine listens for
+pub fn keyword_names() -> Vec<&'static str> {
+    KEYWORDS.iter().map(BuiltinKeywords::to_str).collect()
+}
+
+/// Name of the keyword behind a detection index
+pub fn keyword_name(index: i32) -> &'static str {
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| KEYWORDS.get(i))
+        .map(BuiltinKeywords::to_str)
+        .unwrap_or("unknown")
+}
+
+/// Explain why detection will be unreliable when the device rate differs from
+/// the engine's. Samples reach Porcupine at the device rate, unresampled.
+pub fn rate_mismatch(capture_rate: u32, engine_rate: u32) -> Option<String> {
+    (capture_rate != engine_rate).then(|| format!(
+        "The input device captures at {} Hz but Porcupine expects {} Hz; wakewords will rarely if ever be \
+         detected. Pick a device or configuration running at {} Hz.",
+        capture_rate, engine_rate, engine_rate
+    ))
+}
+
+// Read the access key from PICOVOICE_ACCESS_KEY_FILE (e.g. a mounted secret),
+// falling back to the inline PICOVOICE_ACCESS_KEY
+fn access_key() -> Result<String, WakewordError> {
+    let Ok(path) = env::var("PICOVOICE_ACCESS_KEY_FILE") else {
+        return env::var("PICOVOICE_ACCESS_KEY")
+            .map_err(|_| WakewordError::MissingEnv("PICOVOICE_ACCESS_KEY (or PICOVOICE_ACCESS_KEY_FILE)"));
+    };
+    if env::var_os("PICOVOICE_ACCESS_KEY").is_some() {
+        tracing::warn!("Both PICOVOICE_ACCESS_KEY and PICOVOICE_ACCESS_KEY_FILE are set, using the file");
+    }
+    let contents = std::fs::read_to_string(&path).map_err(|e| WakewordError::AccessKeyFile(match e.kind() {
+        std::io::ErrorKind::NotFound => format!("Access key file {} not found", path),
+        _ => format!("Unable to read access key file {}: {}", path, e),
+    }))?;
+    let key = contents.trim();
+    if key.is_empty() {
+        return Err(WakewordError::AccessKeyFile(format!("Access key file {} is empty", path)));
+    }
+    Ok(key.to_string())
+}
+
+/// Build a Porcupine instance from the environment: the access key comes
+/// from PICOVOICE_ACCESS_KEY_FILE or PICOVOICE_ACCESS_KEY. `model_path` is
+/// the language model (.pv) to load instead of the bundled English one, from
+/// --model-path or PORCUPINE_PV_MODEL_PATH.
+pub fn get_wakeword_listener(model_path: Option<&Path>) -> Result<Porcupine, VoiceError> {
+    let access_key = access_key()?;
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let ppn_file = env::var("PORCUPINE_MODEL_PATH")
+        .map_err(|_| WakewordError::MissingEnv("PORCUPINE_MODEL_PATH"))?;
+    let full_path = Path::new(dir).join(ppn_file);
+    tracing::info!("Porcupine model path: {}", full_path.display());
+    
+    let mut porcupine_builder = PorcupineBuilder::new_with_keywords(
+        access_key, 
+        KEYWORDS
+    );
+    porcupine_builder.sensitivities(&[SENSITIVITY; KEYWORDS.len()]);
+    if let Some(model_path) = model_path {
+        tracing::info!("Porcupine language model: {}", model_path.display());
+        porcupine_builder.model_path(model_path);
+    }
+    let porcupine = porcupine_builder
+        .init()
+        .map_err(|e| WakewordError::Init(e.to_string()))?;
+    Ok(porcupine)
+
+    // PorcupineBuilder::new_with_keyword_paths(
+    //     &access_key,
+    //     &[full_path],
+    // ).init().expect("Failed to create Porcupine instance")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_mismatched_rates_warn() {
+        assert_eq!(rate_mismatch(16_000, 16_000), None);
+        let warning = rate_mismatch(48_000, 16_000).unwrap();
+        assert!(warning.contains("48000 Hz") && warning.contains("16000 Hz"), "{}", warning);
+    }
+}
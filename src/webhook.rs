@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::web;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{http_client, recordings, AudioState};
+
+// Longest wait between attempts, however many retries are configured
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// HTTP method a push uses, from --save-webhook-method
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Method {
+    /// POST the recording
+    #[default]
+    Post,
+    /// PUT the recording
+    Put,
+}
+
+impl std::str::FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "post" => Ok(Method::Post),
+            "put" => Ok(Method::Put),
+            other => Err(format!("unknown webhook method `{}`, expected `post` or `put`", other)),
+        }
+    }
+}
+
+impl Method {
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Post => "POST",
+            Method::Put => "PUT",
+        }
+    }
+}
+
+/// Where one push goes
+#[derive(Clone)]
+pub struct Target {
+    url: String,
+    endpoint: http_client::Endpoint,
+    // Value of the Authorization header, if any
+    authorization: Option<String>,
+}
+
+/// How saved recordings are pushed, from the --save-webhook options
+pub struct WebhookConfig {
+    // --save-webhook, parsed at startup
+    default: Option<Target>,
+    method: Method,
+    // Attempts after the first before giving up
+    retries: u32,
+    // Wait before the first retry, doubling for each one after
+    backoff: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig { default: None, method: Method::Post, retries: 3, backoff: Duration::from_secs(1) }
+    }
+}
+
+impl WebhookConfig {
+    /// Push to `url`, when set, retrying `retries` times starting `backoff` apart
+    pub fn new(
+        url: Option<&str>,
+        authorization: Option<String>,
+        method: Method,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<Self, String> {
+        let default = match url {
+            Some(url) => Some(Target {
+                url: url.to_string(),
+                endpoint: http_client::Endpoint::parse(url, None)?,
+                authorization,
+            }),
+            None => None,
+        };
+        Ok(WebhookConfig { default, method, retries, backoff })
+    }
+
+    /// Where a save's files go: `requested` from the request when given, else
+    /// --save-webhook. `none` skips the push. Only --save-webhook gets the
+    /// Authorization header, so a request can't send it elsewhere.
+    pub fn target(&self, requested: Option<&str>) -> Result<Option<Target>, String> {
+        match requested {
+            Some("none") => Ok(None),
+            Some(url) => Ok(Some(Target {
+                url: url.to_string(),
+                endpoint: http_client::Endpoint::parse(url, None)?,
+                authorization: None,
+            })),
+            None => Ok(self.default.clone()),
+        }
+    }
+}
+
+/// Outcome of pushing one file, kept in its sidecar, on the job and in /status
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Delivery {
+    // Relative to the output directory
+    recording: String,
+    url: String,
+    delivered: bool,
+    attempts: u32,
+    // Status of the last response, when one arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    finished_at: chrono::DateTime<chrono::Local>,
+}
+
+impl Delivery {
+    /// Why the push failed, if it did
+    pub fn failure(&self) -> Option<String> {
+        match (self.delivered, &self.error) {
+            (true, _) => None,
+            (false, error) => Some(format!(
+                "Failed to push {} after {} attempts: {}",
+                self.recording, self.attempts, error.as_deref().unwrap_or("unknown error")
+            )),
+        }
+    }
+}
+
+// Text made safe for a header value: anything outside printable ASCII is percent-encoded
+fn header_value(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Request headers describing the recording, from its sidecar when it has one
+fn metadata_headers(name: &str, path: &Path, sidecar: Option<&serde_json::Value>) -> Vec<(String, String)> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let mut headers = vec![
+        ("Content-Type".to_string(), actix_files::file_extension_to_mime(extension).to_string()),
+        ("X-Recording-Name".to_string(), header_value(name)),
+    ];
+    let Some(sidecar) = sidecar else {
+        return headers;
+    };
+    let text = |field: &str| sidecar[field].as_str().map(header_value);
+    // The first keyword heard in the recording
+    let keyword = sidecar["detections"][0]["keyword"].as_str().map(header_value);
+    let trigger = text("trigger").unwrap_or_else(|| "manual".to_string());
+    headers.push(("X-Recording-Trigger".to_string(), trigger));
+    let fields = [
+        ("X-Recording-Keyword", keyword),
+        ("X-Recording-Started-At", text("started_at")),
+        ("X-Recording-Saved-At", text("saved_at")),
+        ("X-Recording-Duration-Seconds", sidecar["duration_seconds"].as_f64().map(|s| s.to_string())),
+        ("X-Recording-Device", text("device")),
+        ("X-Content-SHA256", text("sha256")),
+    ];
+    headers.extend(fields.into_iter().filter_map(|(header, value)| Some((header.to_string(), value?))));
+    headers
+}
+
+// Send one file, retrying with exponential backoff until a 2xx response or
+// the retries run out
+async fn push_file(config: &WebhookConfig, target: &Target, name: String, path: &Path) -> Delivery {
+    let sidecar_path = recordings::sidecar_path(path);
+    let read = sidecar_path.clone();
+    let sidecar: Option<serde_json::Value> = web::block(move || std::fs::read(read)).await.ok()
+        .and_then(Result::ok)
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    let mut headers = metadata_headers(&name, path, sidecar.as_ref());
+    if let Some(authorization) = &target.authorization {
+        headers.push(("Authorization".to_string(), authorization.clone()));
+    }
+    let headers: Vec<(&str, &str)> = headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+
+    let mut backoff = config.backoff;
+    let mut attempts = 0;
+    let (mut status, mut error) = (None, None);
+    while attempts <= config.retries {
+        attempts += 1;
+        match target.endpoint.send_file(config.method.name(), &target.endpoint.target(), &headers, path).await {
+            Ok((code, _)) if (200..300).contains(&code) => {
+                (status, error) = (Some(code), None);
+                break;
+            }
+            Ok((code, body)) => {
+                let body = String::from_utf8_lossy(&body);
+                let detail = body.trim().chars().take(200).collect::<String>();
+                (status, error) = (Some(code), Some(format!("responded {} {}", code, detail).trim_end().to_string()));
+            }
+            Err(e) => (status, error) = (None, Some(e)),
+        }
+        if attempts <= config.retries {
+            tracing::warn!(
+                "Webhook push of {} failed ({}), retrying in {:?}",
+                name, error.as_deref().unwrap_or_default(), backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    let delivery = Delivery {
+        recording: name,
+        url: target.url.clone(),
+        delivered: error.is_none(),
+        attempts,
+        status,
+        error,
+        finished_at: chrono::Local::now(),
+    };
+    match delivery.failure() {
+        None => tracing::info!("Pushed {} to {} ({} attempts)", path.display(), target.url, attempts),
+        Some(e) => tracing::error!("{}", e),
+    }
+
+    // Note the outcome next to the recording's other metadata, replacing the
+    // sidecar whole so a reader never sees it half-written
+    if let Some(mut sidecar) = sidecar {
+        sidecar["webhook"] = serde_json::to_value(&delivery).unwrap_or_default();
+        let target = sidecar_path.clone();
+        let written = match serde_json::to_vec_pretty(&sidecar) {
+            Ok(json) => web::block(move || recordings::replace_file(&target, &json)).await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        if let Err(e) = written {
+            tracing::warn!("Failed to record the webhook outcome in {}: {}", sidecar_path.display(), e);
+        }
+    }
+    delivery
+}
+
+/// Push `files` to `target` in the background, returning the job to poll at
+/// /jobs/{id} and a handle that completes with it
+pub fn spawn_push(state: Arc<AudioState>, files: Vec<PathBuf>, target: Target) -> (u64, tokio::task::JoinHandle<()>) {
+    let job_id = state.jobs.create_webhook();
+    let handle = tokio::spawn(async move {
+        state.jobs.start(job_id);
+        let root = PathBuf::from(&state.settings.read().output_dir);
+        let mut deliveries = Vec::with_capacity(files.len());
+        for path in &files {
+            let name = recordings::relative_name(&root, path);
+            let delivery = push_file(&state.webhook, &target, name, path).await;
+            *state.last_webhook.lock() = Some(delivery.clone());
+            deliveries.push(delivery);
+        }
+        state.jobs.finish_webhook(job_id, deliveries);
+    });
+    tracing::info!("Queued webhook job {}", job_id);
+    (job_id, handle)
+}
@@ -0,0 +1,399 @@
+// The library as an embedder uses it: state is built and fed through the
+// public API, and the app is served without any audio device.
+
+use std::path::Path;
+use std::sync::Arc;
+use actix_web::{http::StatusCode, test};
+
+use misteragent_voice_rust::capture_audio::{self, BufferMode};
+use misteragent_voice_rust::config::Settings;
+use misteragent_voice_rust::logging;
+use misteragent_voice_rust::encoding::{OutputFormat, OutputOptions, RawEncoding, SampleKind, WavEncoding};
+use misteragent_voice_rust::error::VoiceError;
+use misteragent_voice_rust::synthetic::{Signal, SyntheticSource};
+use misteragent_voice_rust::{app, buffer_capacity, join_capture, spawn_capture, AppOptions, AudioState};
+
+const SAMPLE_RATE: u32 = 16_000;
+
+fn input_config() -> cpal::SupportedStreamConfig {
+    cpal::SupportedStreamConfig::new(
+        1,
+        cpal::SampleRate(SAMPLE_RATE),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    )
+}
+
+// Hashed here rather than with the crate's own helper, so a bug there can't hide
+fn sha256_of(bytes: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, bytes).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn output() -> OutputOptions {
+    OutputOptions {
+        format: OutputFormat::Wav,
+        wav: WavEncoding::new(SampleKind::Int, 16).unwrap(),
+        mp3_bitrate_kbps: 128,
+        opus_bitrate_kbps: 24,
+        raw: RawEncoding::S16Le,
+        sample_rate: None,
+        encryption: None,
+    }
+}
+
+fn state(output_dir: &Path) -> Arc<AudioState> {
+    Arc::new(AudioState::new(
+        input_config(),
+        "embedded source".to_string(),
+        buffer_capacity(&input_config(), 2),
+        BufferMode::Overwrite,
+        output(),
+        Settings {
+            output_dir: output_dir.display().to_string(),
+            gain: 1.0,
+            wakeword_cooldown_ms: 0,
+            health_timeout_secs: 5,
+            buffer_seconds: 2,
+        },
+        1,
+    ))
+}
+
+#[actix_web::test]
+async fn pushed_audio_is_reported_and_saved() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = state(dir.path());
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+
+    assert_eq!(state.push_samples(&[0.25; 8000]), Some((0, 8000)));
+
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!(status["state"], "recording");
+    assert_eq!(status["buffered_samples"], 8000);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    let reader = hound::WavReader::open(saved["path"].as_str().unwrap()).unwrap();
+    assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+    assert_eq!(reader.len(), 8000);
+
+    // Paused, nothing more is buffered
+    test::call_service(&app, test::TestRequest::post().uri("/pause").to_request()).await;
+    assert_eq!(state.push_samples(&[0.25; 100]), None);
+}
+
+#[actix_web::test]
+async fn recordings_can_be_encrypted_at_rest() {
+    use misteragent_voice_rust::encryption::{self, Key};
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("recordings.key");
+    std::fs::write(&key_file, format!("{}\n", "5a".repeat(32))).unwrap();
+    let key = Key::load(&key_file).unwrap();
+    let settings = Settings {
+        output_dir: dir.path().join("out").display().to_string(),
+        gain: 1.0,
+        wakeword_cooldown_ms: 0,
+        health_timeout_secs: 5,
+        buffer_seconds: 2,
+    };
+    let output = OutputOptions { encryption: Some(key), ..output() };
+    let state = Arc::new(AudioState::new(input_config(), "embedded source".to_string(), 32_000, BufferMode::Overwrite, output, settings, 1));
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+    state.push_samples(&[0.25; 1600]);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    let sealed = std::fs::read(saved["path"].as_str().unwrap()).unwrap();
+    assert!(encryption::is_encrypted(&sealed));
+    assert_eq!(saved["sha256"], sha256_of(&sealed));
+    let wav = key.decrypt(&sealed).unwrap();
+    assert_eq!(hound::WavReader::new(std::io::Cursor::new(wav)).unwrap().len(), 1600);
+
+    let listing: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/recordings").to_request()).await,
+    ).await;
+    assert_eq!(listing[0]["encrypted"], true, "{}", listing);
+    assert!(listing[0]["parse_error"].is_null());
+
+    // Downloads are encoded in memory and stay plain
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?download=true").to_request()).await;
+    assert!(test::read_body(response).await.starts_with(b"RIFF"));
+}
+
+#[actix_web::test]
+async fn requests_need_the_configured_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = AppOptions { api_token: Some("secret".to_string()), ..AppOptions::default() };
+    let app = test::init_service(app(state(dir.path()), &options)).await;
+
+    let request = test::TestRequest::get().uri("/status").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    let request = test::TestRequest::get()
+        .uri("/status")
+        .insert_header(("Authorization", "Bearer secret"))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_process_is_held_to_the_upload_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = AppOptions { upload_limit: 1024, ..AppOptions::default() };
+    let app = test::init_service(app(state(dir.path()), &options)).await;
+
+    let upload = test::TestRequest::post().uri("/process").insert_header(("Content-Type", "audio/wav")).set_payload(vec![0; 2048]);
+    assert_eq!(test::call_service(&app, upload.to_request()).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    // Bodies elsewhere keep their extractors' own limits
+    let mark = test::TestRequest::post().uri("/mark").set_json(serde_json::json!({ "label": "x".repeat(2048) }));
+    assert_ne!(test::call_service(&app, mark.to_request()).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[actix_web::test]
+async fn audio_is_saved_straight_to_a_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clip.wav");
+    let saved = capture_audio::save_audio_to_file(&[0.5; 1600], &path, &input_config(), output()).unwrap();
+    assert_eq!(saved.samples, 1600);
+    assert!((saved.duration_seconds - 0.1).abs() < 1e-9);
+    assert_eq!(hound::WavReader::open(&path).unwrap().len(), 1600);
+
+    // A file where the directory should be fails the first step
+    let blocked = path.join("clip.wav");
+    let error = capture_audio::save_audio_to_file(&[0.5; 16], &blocked, &input_config(), output()).unwrap_err();
+    assert!(matches!(error, VoiceError::Wav(capture_audio::SaveError::CreateDir { .. })), "{:?}", error);
+}
+
+#[actix_web::test]
+async fn responses_carry_the_request_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = test::init_service(app(state(dir.path()), &AppOptions::default())).await;
+
+    let request = test::TestRequest::get().uri("/health").insert_header(("X-Request-Id", "abc-123")).to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.headers().get("x-request-id").unwrap(), "abc-123");
+    let response = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert!(response.headers().get("x-request-id").is_some_and(|id| !id.is_empty()));
+}
+
+#[actix_web::test]
+async fn each_request_is_logged_with_its_status_and_duration() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = state(dir.path());
+    let lines = Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let sink = Arc::clone(&lines);
+    let logger = logging::Logger::new("error,access=info", logging::LogFormat::Text, Box::new(move |line| sink.lock().push(line.to_string())));
+    // The test runtime runs on this thread, so the handlers log here too
+    let _logger = tracing::subscriber::set_default(logger);
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+    state.push_samples(&[0.25; 1600]);
+
+    test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    test::call_service(&app, test::TestRequest::get().uri("/no-such-route?token=hunter2").to_request()).await;
+    let lines = lines.lock().clone();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    let save = lines[0].split_once("] ").unwrap().1;
+    assert!(save.contains("POST /save 200 ") && save.contains("ms ") && save.contains(" saved="), "{}", save);
+    assert!(lines[1].contains(" WARN  access]") && lines[1].contains("GET /no-such-route?token=REDACTED 404 "), "{}", lines[1]);
+}
+
+#[actix_web::test]
+async fn a_synthetic_tone_is_captured_and_saved() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = state(dir.path());
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+    let options = capture_audio::CaptureOptions {
+        latency: std::time::Duration::from_millis(20),
+        wakeword_queue: std::time::Duration::from_secs(1),
+    };
+    let capture = spawn_capture(Arc::clone(&state), options, Box::new(SyntheticSource::new(Signal::Sine(440.0))));
+
+    // Half a second of tone, delivered in real time
+    let started = std::time::Instant::now();
+    loop {
+        let status: serde_json::Value = test::read_body_json(
+            test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+        ).await;
+        if status["buffered_samples"].as_u64().unwrap() >= 8000 {
+            break;
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "no audio arrived: {}", status);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    state.request_shutdown();
+    capture.join().unwrap();
+
+    let mut reader = hound::WavReader::open(saved["path"].as_str().unwrap()).unwrap();
+    assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+    let samples: Vec<f32> = reader.samples::<i16>().map(|s| s.unwrap() as f32 / 32768.0).collect();
+    assert!(samples.len() >= 8000, "{} samples", samples.len());
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    assert!((peak - 0.5).abs() < 0.01, "peak {}", peak);
+    // Two zero crossings per cycle
+    let crossings = samples.windows(2).filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0)).count();
+    let hz = crossings as f64 / 2.0 / (samples.len() as f64 / SAMPLE_RATE as f64);
+    assert!((hz - 440.0).abs() < 5.0, "{} Hz", hz);
+}
+
+#[actix_web::test]
+async fn a_wav_file_is_looped_in_as_input() {
+    let dir = tempfile::tempdir().unwrap();
+    // A ramp with no repeats inside one loop, so any saved span can be placed
+    let ramp: Vec<i16> = (0..1000).map(|i| (i - 500) * 50).collect();
+    let input = dir.path().join("input.wav");
+    let spec = hound::WavSpec { channels: 1, sample_rate: SAMPLE_RATE, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut writer = hound::WavWriter::create(&input, spec).unwrap();
+    ramp.iter().for_each(|&sample| writer.write_sample(sample).unwrap());
+    writer.finalize().unwrap();
+
+    let state = state(dir.path());
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+    let options = capture_audio::CaptureOptions {
+        latency: std::time::Duration::from_millis(20),
+        wakeword_queue: std::time::Duration::from_secs(1),
+    };
+    let capture = spawn_capture(Arc::clone(&state), options, Box::new(SyntheticSource::new(Signal::File(input))));
+
+    let started = std::time::Instant::now();
+    loop {
+        let status: serde_json::Value = test::read_body_json(
+            test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+        ).await;
+        if status["buffered_samples"].as_u64().unwrap() >= 4000 {
+            break;
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "no audio arrived: {}", status);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    state.request_shutdown();
+    capture.join().unwrap();
+
+    let saved: Vec<i16> = hound::WavReader::open(saved["path"].as_str().unwrap())
+        .unwrap()
+        .samples::<i16>()
+        .map(Result::unwrap)
+        .collect();
+    assert!(saved.len() >= 4000, "{} samples", saved.len());
+    // Round trips through f32 may move a sample by one step
+    let close = |a: i16, b: i16| (a as i32 - b as i32).abs() <= 2;
+    let offset = ramp.iter().position(|&sample| close(sample, saved[0])).unwrap();
+    for (i, &sample) in saved.iter().enumerate() {
+        let expected = ramp[(offset + i) % ramp.len()];
+        assert!(close(sample, expected), "sample {}: {} where the file has {}", i, sample, expected);
+    }
+}
+
+#[actix_web::test]
+async fn buffer_sizes_are_checked_before_allocating() {
+    use misteragent_voice_rust::sample_buffer::SampleType;
+    use misteragent_voice_rust::{buffer_bytes, BufferSizeError};
+    let mono = input_config();
+    assert_eq!(buffer_bytes(&[&mono], 60, SampleType::F32, u64::MAX), Ok(60 * 16_000 * 4));
+    assert_eq!(buffer_bytes(&[&mono, &mono], 60, SampleType::I16, u64::MAX), Ok(2 * 60 * 16_000 * 2));
+    assert_eq!(buffer_bytes(&[&mono], 0, SampleType::F32, u64::MAX), Err(BufferSizeError::Empty));
+
+    // More samples than fit in usize, on any target, instead of wrapping around
+    let huge = cpal::SupportedStreamConfig::new(
+        u16::MAX,
+        cpal::SampleRate(u32::MAX),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    );
+    assert_eq!(buffer_bytes(&[&huge], u32::MAX, SampleType::F32, u64::MAX), Err(BufferSizeError::Overflow { seconds: u32::MAX }));
+    assert_eq!(buffer_capacity(&huge, u32::MAX), usize::MAX);
+
+    // A day of audio against a 100 MiB cap, naming what it needs
+    let error = buffer_bytes(&[&mono], 86_400, SampleType::F32, 100 << 20).unwrap_err();
+    assert_eq!(error, BufferSizeError::TooLarge { seconds: 86_400, needed: 5_529_600_000, limit: 100 << 20 });
+    assert!(error.to_string().contains("needs 5529600000 bytes (5273.4 MiB)"), "{}", error);
+
+    // The cap holds for a PATCH /config too
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = Arc::into_inner(state(dir.path())).unwrap();
+    state.max_buffer_bytes = 1 << 20;
+    let app = test::init_service(app(Arc::new(state), &AppOptions::default())).await;
+    let patch = |seconds: u32| test::TestRequest::patch().uri("/config").set_json(serde_json::json!({ "buffer_seconds": seconds })).to_request();
+    assert_eq!(test::call_service(&app, patch(10)).await.status(), StatusCode::OK);
+    let response = test::call_service(&app, patch(60)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = test::read_body_json(response).await;
+    assert!(error["error"].as_str().unwrap().contains("needs 3840000 bytes"), "{}", error);
+}
+
+#[actix_web::test]
+async fn a_stereo_buffer_holds_the_requested_seconds() {
+    let dir = tempfile::tempdir().unwrap();
+    let stereo = cpal::SupportedStreamConfig::new(
+        2,
+        cpal::SampleRate(SAMPLE_RATE),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    );
+    let settings = Settings {
+        output_dir: dir.path().display().to_string(),
+        gain: 1.0,
+        wakeword_cooldown_ms: 0,
+        health_timeout_secs: 5,
+        buffer_seconds: 2,
+    };
+    let capacity = buffer_capacity(&stereo, 2);
+    assert_eq!(capacity, 2 * 2 * SAMPLE_RATE as usize);
+    let state = Arc::new(AudioState::new(stereo, "stereo source".to_string(), capacity, BufferMode::Overwrite, output(), settings, 1));
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+
+    // Three seconds of frames, of which the newest two are kept
+    for second in 0..3 {
+        state.push_samples(&vec![second as f32 / 4.0; 2 * SAMPLE_RATE as usize]);
+    }
+    let status: serde_json::Value = test::read_body_json(
+        test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+    ).await;
+    assert_eq!((status["buffered_seconds"].as_f64(), status["buffer_seconds"].as_f64()), (Some(2.0), Some(2.0)));
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/save?seconds=1.5").to_request()).await;
+    let saved: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(saved["duration_seconds"], 1.5);
+    let reader = hound::WavReader::open(saved["path"].as_str().unwrap()).unwrap();
+    assert_eq!((reader.spec().channels, reader.duration()), (2, 3 * SAMPLE_RATE / 2));
+}
+
+#[actix_web::test]
+async fn capture_is_torn_down_within_a_second() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = state(dir.path());
+    let app = test::init_service(app(Arc::clone(&state), &AppOptions::default())).await;
+    let options = capture_audio::CaptureOptions {
+        latency: std::time::Duration::from_millis(20),
+        wakeword_queue: std::time::Duration::from_secs(1),
+    };
+    let capture = spawn_capture(Arc::clone(&state), options, Box::new(SyntheticSource::new(Signal::Noise)));
+    let captured = || async {
+        let status: serde_json::Value = test::read_body_json(
+            test::call_service(&app, test::TestRequest::get().uri("/status").to_request()).await,
+        ).await;
+        status["samples_captured"].as_u64().unwrap()
+    };
+    let started = std::time::Instant::now();
+    while captured().await == 0 {
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "no audio arrived");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let halted = std::time::Instant::now();
+    state.request_shutdown();
+    assert!(join_capture(capture, std::time::Duration::from_secs(1)), "capture still running");
+    assert!(halted.elapsed() < std::time::Duration::from_secs(1));
+    // The stream is closed, so no more audio arrives
+    let after = captured().await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(captured().await, after);
+}
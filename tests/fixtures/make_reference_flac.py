@@ -0,0 +1,231 @@
+#!/usr/bin/env python3
+"""Writes reference.flac, a FLAC stream built from the format specification
+(RFC 9639) independently of the crate's encoder, for the decoder to be checked
+against. It uses what the crate's encoder never writes and libFLAC does:
+extra metadata blocks, an MD5 signature, explicit rate and size codes, LPC
+subframes, mid/side and left/side stereo, wasted bits, escaped Rice
+partitions and a short last block coded by its 16-bit size.
+
+The samples are integer-only so the Rust test can rebuild them exactly; see
+reference_samples in src/flac.rs.
+"""
+import hashlib
+import math
+import os
+import struct
+
+RATE, BITS, BLOCK, FRAMES = 44_100, 16, 4096, 6000
+
+
+def samples():
+    seed, out = 12345, []
+    tri = lambda i, period, amp: abs((i * 4 * amp // period) % (4 * amp) - 2 * amp) - amp
+    for i in range(FRAMES):
+        seed = (seed * 1103515245 + 12345) % (1 << 31)
+        left = tri(i, 100, 9000) + tri(i, 37, 2000) + (seed >> 16) % 33 - 16
+        right = ((left * 3) >> 2) + tri(i, 250, 1500)
+        if i >= BLOCK:
+            left, right = left & ~3, right & ~3
+        out.append((left, right))
+    return out
+
+
+class Bits:
+    def __init__(self):
+        self.value, self.count = 0, 0
+
+    def write(self, value, count):
+        self.value = (self.value << count) | (value & ((1 << count) - 1))
+        self.count += count
+
+    def signed(self, value, count):
+        assert -(1 << (count - 1)) <= value < (1 << (count - 1)), (value, count)
+        self.write(value, count)
+
+    def unary(self, zeros):
+        self.write(1, zeros + 1)
+
+    def align(self):
+        self.write(0, -self.count % 8)
+
+    def bytes(self):
+        assert self.count % 8 == 0
+        return self.value.to_bytes(self.count // 8, "big")
+
+
+def crc8(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x07) & 0xFF if crc & 0x80 else (crc << 1) & 0xFF
+    return crc
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x8005) & 0xFFFF if crc & 0x8000 else (crc << 1) & 0xFFFF
+    return crc
+
+
+def zigzag(value):
+    return value << 1 if value >= 0 else (-value << 1) - 1
+
+
+def residual_bits(values):
+    """Rice parameter and cost of one partition"""
+    best = None
+    for k in range(15):
+        cost = sum((zigzag(v) >> k) + 1 + k for v in values)
+        if best is None or cost < best[1]:
+            best = (k, cost)
+    return best
+
+
+def write_residual(out, residual, block, order, partition_order, escaped=()):
+    out.write(0, 2)  # 4-bit Rice parameters
+    out.write(partition_order, 4)
+    size = block >> partition_order
+    start = 0
+    for partition in range(1 << partition_order):
+        end = (partition + 1) * size - order
+        values = residual[start:end]
+        if partition in escaped:
+            raw = max(max(v.bit_length() for v in values) + 1, 1)
+            out.write(15, 4)
+            out.write(raw, 5)
+            for v in values:
+                out.signed(v, raw)
+        else:
+            k, _ = residual_bits(values)
+            out.write(k, 4)
+            for v in values:
+                z = zigzag(v)
+                out.unary(z >> k)
+                out.write(z, k)
+        start = end
+
+
+def subframe_header(out, kind, wasted):
+    out.write(0, 1)
+    out.write(kind, 6)
+    if wasted:
+        out.write(1, 1)
+        out.unary(wasted - 1)
+    else:
+        out.write(0, 1)
+
+
+def fixed(out, samples, bits, order, partition_order, wasted=0, escaped=()):
+    samples = [s >> wasted for s in samples]
+    bits -= wasted
+    subframe_header(out, 0b001000 | order, wasted)
+    for s in samples[:order]:
+        out.signed(s, bits)
+    residual = list(samples)
+    for _ in range(order):
+        residual = [residual[0]] + [b - a for a, b in zip(residual, residual[1:])]
+    write_residual(out, residual[order:], len(samples), order, partition_order, escaped)
+
+
+def lpc(out, samples, bits, order, precision, partition_order, wasted=0):
+    samples = [s >> wasted for s in samples]
+    bits -= wasted
+    n = len(samples)
+    autoc = [sum(samples[i] * samples[i - lag] for i in range(lag, n)) for lag in range(order + 1)]
+    # Levinson-Durbin
+    coefficients, error = [], float(autoc[0])
+    for i in range(order):
+        reflection = -(autoc[i + 1] + sum(c * autoc[i - j] for j, c in enumerate(coefficients))) / error
+        coefficients = [c + reflection * coefficients[i - 1 - j] for j, c in enumerate(coefficients)] + [reflection]
+        error *= 1 - reflection * reflection
+    predictor = [-c for c in coefficients]
+    _, exponent = math.frexp(max(abs(c) for c in predictor))
+    shift = min(max(precision - 1 - exponent, 0), 15)
+    limit = 1 << (precision - 1)
+    quantized = [min(max(round(c * (1 << shift)), -limit), limit - 1) for c in predictor]
+
+    subframe_header(out, 0b100000 | (order - 1), wasted)
+    for s in samples[:order]:
+        out.signed(s, bits)
+    out.write(precision - 1, 4)
+    out.signed(shift, 5)
+    for c in quantized:
+        out.signed(c, precision)
+    residual = [
+        samples[i] - (sum(c * samples[i - 1 - j] for j, c in enumerate(quantized)) >> shift)
+        for i in range(order, n)
+    ]
+    write_residual(out, residual, n, order, partition_order)
+
+
+def frame(number, block):
+    frames = len(block)
+    out = Bits()
+    out.write(0b1111_1111_1111_100, 15)
+    out.write(0, 1)  # fixed block size
+    out.write(12 if frames == 4096 else 7, 4)  # 256 << 4, or a 16-bit size
+    out.write(9, 4)  # 44.1 kHz
+    out.write(10 if number == 0 else 8, 4)  # mid/side, then left/side
+    out.write(4, 3)  # 16 bits
+    out.write(0, 1)
+    out.write(number, 8)
+    if frames != 4096:
+        out.write(frames - 1, 16)
+    header = out.bytes()
+    out.write(crc8(header), 8)
+
+    left = [l for l, _ in block]
+    right = [r for _, r in block]
+    side = [l - r for l, r in block]
+    if number == 0:
+        mid = [(l + r) >> 1 for l, r in block]
+        lpc(out, mid, BITS, order=8, precision=12, partition_order=2)
+        fixed(out, side, BITS + 1, order=2, partition_order=1, escaped={1})
+    else:
+        lpc(out, left, BITS, order=4, precision=14, partition_order=3, wasted=2)
+        fixed(out, side, BITS + 1, order=1, partition_order=0, wasted=2)
+    out.align()
+    out.write(crc16(out.bytes()), 16)
+    return out.bytes()
+
+
+def metadata_block(kind, body, last):
+    return bytes([(0x80 if last else 0) | kind]) + len(body).to_bytes(3, "big") + body
+
+
+def main():
+    audio = samples()
+    frames = [frame(n, audio[n * BLOCK:(n + 1) * BLOCK]) for n in range((FRAMES + BLOCK - 1) // BLOCK)]
+    md5 = hashlib.md5(b"".join(struct.pack("<hh", l, r) for l, r in audio)).digest()
+
+    info = Bits()
+    info.write(BLOCK, 16)
+    info.write(BLOCK, 16)
+    info.write(min(map(len, frames)), 24)
+    info.write(max(map(len, frames)), 24)
+    info.write(RATE, 20)
+    info.write(2 - 1, 3)
+    info.write(BITS - 1, 5)
+    info.write(FRAMES, 36)
+    vendor = b"reference fixture"
+    comment = b"TITLE=reference fixture"
+    vorbis = struct.pack("<I", len(vendor)) + vendor + struct.pack("<I", 1) + struct.pack("<I", len(comment)) + comment
+    stream = (
+        b"fLaC"
+        + metadata_block(0, info.bytes() + md5, False)
+        + metadata_block(4, vorbis, False)
+        + metadata_block(1, bytes(64), True)
+        + b"".join(frames)
+    )
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "reference.flac")
+    with open(path, "wb") as f:
+        f.write(stream)
+    print(f"{path}: {len(stream)} bytes")
+
+
+if __name__ == "__main__":
+    main()